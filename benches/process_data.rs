@@ -0,0 +1,169 @@
+//! Hot path of the process data exchange for 8, 32 and 128 slaves:
+//! building the frame, dispatching the responses and copying the process image.
+//! The frames are returned by a loopback device instead of slaves.
+//!
+//! cargo bench --features std
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use embedded_hal::timer::CountDown;
+use ethercat_master::arch::Device;
+use ethercat_master::interface::*;
+use ethercat_master::packet::*;
+use ethercat_master::slave_status::*;
+use fugit::MicrosDurationU32;
+use std::collections::VecDeque;
+
+const SLAVE_COUNTS: [usize; 3] = [8, 32, 128];
+/// Outputs and inputs of a slave, e.g. a small drive.
+const OUTPUT_SIZE: usize = 4;
+const INPUT_SIZE: usize = 4;
+const BUFFER_SIZE: usize = 4096;
+
+/// Sends every frame back as if it had passed all slaves.
+struct LoopbackDevice {
+    tx_buf: [u8; 1514],
+    frames: VecDeque<Vec<u8>>,
+}
+
+impl LoopbackDevice {
+    fn new() -> Self {
+        Self {
+            tx_buf: [0; 1514],
+            frames: VecDeque::new(),
+        }
+    }
+}
+
+impl Device for LoopbackDevice {
+    fn send<R, F>(&mut self, len: usize, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut [u8]) -> Option<R>,
+    {
+        let ret = f(&mut self.tx_buf[..len]);
+        let mut frame = self.tx_buf[..len].to_vec();
+        // スレーブを通過したフレームは送信元MACのビットが変わっている
+        frame[6] ^= 0x02;
+        self.frames.push_back(frame);
+        ret
+    }
+
+    fn recv<R, F>(&mut self, f: F) -> Option<R>
+    where
+        F: FnOnce(&[u8]) -> Option<R>,
+    {
+        let frame = self.frames.pop_front()?;
+        f(&frame)
+    }
+
+    fn max_transmission_unit(&self) -> usize {
+        1500
+    }
+}
+
+/// Never expires, the loopback device answers immediately.
+struct NoTimer;
+
+impl CountDown for NoTimer {
+    type Time = MicrosDurationU32;
+
+    fn start<T>(&mut self, _count: T)
+    where
+        T: Into<Self::Time>,
+    {
+    }
+
+    fn wait(&mut self) -> nb::Result<(), void::Void> {
+        Err(nb::Error::WouldBlock)
+    }
+}
+
+fn slaves_with_mappings<'a>(num_slaves: usize) -> Vec<Slave<'a>> {
+    (0..num_slaves)
+        .map(|_| {
+            let mut slave = Slave::default();
+            let rx_data = Box::leak(Box::new([0u8; INPUT_SIZE]));
+            let tx_data = Box::leak(Box::new([0u8; OUTPUT_SIZE]));
+            let rx_entries = Box::leak(Box::new([PDOEntry::new(0x7000, 1, rx_data)]));
+            let tx_entries = Box::leak(Box::new([PDOEntry::new(0x6000, 1, tx_data)]));
+            slave.set_rx_pdo_mapping(Box::leak(Box::new([PDOMapping::new(0x1600, rx_entries)])));
+            slave.set_tx_pdo_mapping(Box::leak(Box::new([PDOMapping::new(0x1A00, tx_entries)])));
+            slave
+        })
+        .collect()
+}
+
+fn frame_build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frame_build");
+    for num_slaves in SLAVE_COUNTS {
+        let image_size = num_slaves * (OUTPUT_SIZE + INPUT_SIZE);
+        let mut tx_buf = [0; BUFFER_SIZE];
+        let mut rx_buf = [0; BUFFER_SIZE];
+        let mut iface =
+            EtherCATInterface::new(LoopbackDevice::new(), NoTimer, &mut tx_buf, &mut rx_buf);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(num_slaves),
+            &image_size,
+            |b, size| {
+                b.iter(|| {
+                    iface
+                        .add_logical_command(0, CommandType::LRW, 0, *size, |buf| buf.fill(0))
+                        .unwrap();
+                    iface.poll(MicrosDurationU32::from_ticks(1000)).unwrap();
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn response_dispatch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("response_dispatch");
+    for num_slaves in SLAVE_COUNTS {
+        let mut tx_buf = [0; BUFFER_SIZE];
+        let mut rx_buf = [0; BUFFER_SIZE];
+        let mut iface =
+            EtherCATInterface::new(LoopbackDevice::new(), NoTimer, &mut tx_buf, &mut rx_buf);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(num_slaves),
+            &num_slaves,
+            |b, n| {
+                b.iter(|| {
+                    // スレーブごとにALステータスを読む
+                    for i in 0..*n {
+                        iface
+                            .add_command(
+                                i as u8,
+                                CommandType::FPRD,
+                                0x1000 + i as u16,
+                                0x0130,
+                                2,
+                                |_| (),
+                            )
+                            .unwrap();
+                    }
+                    iface.poll(MicrosDurationU32::from_ticks(1000)).unwrap();
+                    iface
+                        .consume_command()
+                        .map(|pdu| pdu.wkc().unwrap_or(0) as u32 + pdu.data()[0] as u32)
+                        .sum::<u32>()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn process_image_copy(c: &mut Criterion) {
+    let mut group = c.benchmark_group("process_image_copy");
+    for num_slaves in SLAVE_COUNTS {
+        let mut slaves = slaves_with_mappings(num_slaves);
+        let mut image = vec![0u8; num_slaves * (OUTPUT_SIZE + INPUT_SIZE)];
+        group.bench_function(BenchmarkId::from_parameter(num_slaves), |b| {
+            b.iter(|| process_cyclic_data(&mut image, &mut slaves))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, frame_build, response_dispatch, process_image_copy);
+criterion_main!(benches);
@@ -0,0 +1,133 @@
+//! Minimal cyclic-only master for a preconfigured network, built for a Cortex-M4 with
+//! `--no-default-features` to measure what a firmware links from the library.
+//! The device and the timer do nothing: CI only checks the size of the ELF.
+//!
+//! cargo size --release --target thumbv7em-none-eabihf
+
+#![no_std]
+#![no_main]
+
+use core::hint::black_box;
+use core::panic::PanicInfo;
+use embedded_hal::timer::CountDown;
+use ethercat_master::al_state_transfer::ALStateTransfer;
+use ethercat_master::arch::Device;
+use ethercat_master::initializer::SlaveInitilizer;
+use ethercat_master::interface::{EtherCATInterface, SlaveAddress};
+use ethercat_master::master::{CyclicProcessingUnit, EtherCATMaster};
+use ethercat_master::network::{ConfigBlob, RedundancyStatus};
+use ethercat_master::process_data::ProcessDataUnit;
+use ethercat_master::slave_status::{AlState, Slave};
+use fugit::MicrosDurationU32;
+
+const MTU: usize = 1500;
+const MAX_SLAVES: usize = 8;
+const IMAGE_SIZE: usize = 256;
+
+/// Stands in for the Ethernet MAC. The frames are hidden from the optimizer,
+/// so that the code building and parsing them is kept.
+struct DummyDevice {
+    tx: [u8; MTU],
+    rx: [u8; MTU],
+}
+
+impl Device for DummyDevice {
+    fn send<R, F>(&mut self, len: usize, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut [u8]) -> Option<R>,
+    {
+        let result = f(&mut self.tx[..len]);
+        black_box(&self.tx);
+        result
+    }
+
+    fn recv<R, F>(&mut self, f: F) -> Option<R>
+    where
+        F: FnOnce(&[u8]) -> Option<R>,
+    {
+        f(black_box(&self.rx[..]))
+    }
+
+    fn max_transmission_unit(&self) -> usize {
+        MTU
+    }
+}
+
+/// Expires at once.
+struct DummyTimer;
+
+impl CountDown for DummyTimer {
+    type Time = MicrosDurationU32;
+
+    fn start<C: Into<Self::Time>>(&mut self, _count: C) {}
+
+    fn wait(&mut self) -> nb::Result<(), void::Void> {
+        Ok(())
+    }
+}
+
+fn device() -> DummyDevice {
+    DummyDevice {
+        tx: [0; MTU],
+        rx: [0; MTU],
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    // ファームウェアではフラッシュに置いた設定を読み出す
+    let config: ConfigBlob<MAX_SLAVES> = black_box(ConfigBlob {
+        slaves: heapless::Vec::new(),
+        redundancy: RedundancyStatus::Line,
+        process_image: None,
+    });
+    let mut tx_buffer = [0; MTU];
+    let mut rx_buffer = [0; MTU];
+    let mut slaves: [Slave; MAX_SLAVES] = Default::default();
+    let num_slaves = config.slaves.len();
+
+    {
+        let mut iface =
+            EtherCATInterface::new(device(), DummyTimer, &mut tx_buffer, &mut rx_buffer);
+        let mut timer = DummyTimer;
+        let mut initializer = SlaveInitilizer::new(&mut iface, &mut timer);
+        if initializer
+            .init_from_config(&config, &mut slaves, 0)
+            .is_err()
+        {
+            panic!();
+        }
+    }
+
+    let mut iface = EtherCATInterface::new(device(), DummyTimer, &mut tx_buffer, &mut rx_buffer);
+    let mut image_buffer = [0; IMAGE_SIZE];
+    let image = match config.process_image.clone() {
+        Some(image) => image,
+        None => panic!(),
+    };
+    let unit = match ProcessDataUnit::new(&mut slaves[..num_slaves], image, &mut image_buffer) {
+        Some(unit) => unit,
+        None => panic!(),
+    };
+    let mut units = [CyclicProcessingUnit::ProcessData(unit)];
+    let mut master = EtherCATMaster::new(&mut iface, &mut units);
+
+    master.acyclic_window(|window| {
+        let mut timer = DummyTimer;
+        let mut transfer = ALStateTransfer::new(window.iface(), &mut timer);
+        for slave in config.slaves.iter() {
+            let address = SlaveAddress::StationAddress(slave.configured_address);
+            let _ = transfer.change_al_state(address, AlState::Operational);
+        }
+    });
+
+    loop {
+        let _ = master.process_and_enqueue();
+        let _ = master.poll(MicrosDurationU32::from_ticks(1000));
+    }
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {}
+}
@@ -0,0 +1,91 @@
+//! Emulates a simple slave on a network interface, for testing the master on a second machine
+//! or NIC without commercial slaves.
+//! The mailbox echoes the requests back, so CoE requests return their own request.
+//!
+//! cargo run --example emulated_slave --features emulation -- <interface>
+
+use ethercat_master::emulation::EscEmulator;
+use ethercat_master::sii::sii_config_crc;
+use pnet::datalink::{self, Channel::Ethernet, NetworkInterface};
+use std::env;
+
+/// Mailboxes in the process memory, 128 bytes each.
+const MAILBOX_OUT_ADDRESS: u16 = 0x1000;
+const MAILBOX_IN_ADDRESS: u16 = 0x1080;
+const MAILBOX_SIZE: u16 = 0x0080;
+
+fn main() {
+    env::set_var("RUST_LOG", "info");
+    env_logger::init();
+
+    let args: Vec<String> = env::args().collect();
+
+    if let Some(name) = args.get(1) {
+        run(name);
+    } else {
+        println!("Specify the name of network interface as an argument from the following.");
+        for (i, interface) in datalink::interfaces().iter().enumerate() {
+            println!("{}:", i);
+            println!("    Description: {}", interface.description);
+            println!("    Name: {}", interface.name);
+        }
+    }
+}
+
+fn run(interf_name: &str) {
+    let interface_names_match = |iface: &NetworkInterface| iface.name == interf_name;
+    let interface = datalink::interfaces()
+        .into_iter()
+        .find(interface_names_match)
+        .expect("interface not found");
+    let (mut tx, mut rx) = match datalink::channel(&interface, Default::default()) {
+        Ok(Ethernet(tx, rx)) => (tx, rx),
+        Ok(_) => panic!("unhandled interface"),
+        Err(_e) => panic!("unenable to create channel"),
+    };
+
+    let sii = sii_image();
+    let mut emulator: EscEmulator = EscEmulator::new(&sii);
+    let mut frame = Vec::new();
+    loop {
+        let received = match rx.next() {
+            Ok(received) => received,
+            Err(_) => continue,
+        };
+        // 送信元MACのビットが立っているフレームはこのスレーブを通過済み
+        if received.len() <= 6 || received[6] & 0x02 != 0 {
+            continue;
+        }
+        frame.clear();
+        frame.extend_from_slice(received);
+        if emulator.process_frame(&mut frame) {
+            tx.send_to(&frame, None);
+        }
+    }
+}
+
+/// EEPROM with the identity and the mailbox configuration of a CoE slave.
+fn sii_image() -> [u8; 256] {
+    let mut sii = [0xFF; 256];
+    sii[..0x40 * 2].iter_mut().for_each(|b| *b = 0);
+    let mut write_word = |word: usize, value: u16| {
+        sii[word * 2..word * 2 + 2].copy_from_slice(&value.to_le_bytes());
+    };
+    // Product code
+    write_word(0x000A, 0x0001);
+    // Standard mailbox
+    write_word(0x0018, MAILBOX_OUT_ADDRESS);
+    write_word(0x0019, MAILBOX_SIZE);
+    write_word(0x001A, MAILBOX_IN_ADDRESS);
+    write_word(0x001B, MAILBOX_SIZE);
+    // CoE
+    write_word(0x001C, 0x0004);
+    // Size in kbit - 1 and version
+    write_word(0x003E, 0x0001);
+    write_word(0x003F, 0x0001);
+    // カテゴリなし
+    write_word(0x0040, 0xFFFF);
+    // コンフィグ領域のチェックサム
+    sii[0x0007 * 2] = sii_config_crc(&sii);
+    sii
+}
@@ -110,10 +110,11 @@ fn main() {
 
 fn simple_test(interf_name: &str) {
     let timer = Timer::new();
-    let mut buf = [0; 1500];
+    let mut tx_buf = [0; 1500];
+    let mut rx_buf = [0; 1500];
     let device = PnetDevice::open(&interf_name);
 
-    let mut master = EtherCATInterface::new(device, timer, &mut buf);
+    let mut master = EtherCATInterface::new(device, timer, &mut tx_buf, &mut rx_buf);
     master
         .add_command(CommandType::BRD, 0, 0, 1, |_| ())
         .unwrap();
@@ -0,0 +1,241 @@
+//! The resource split recommended in `ethercat_master::rtic`, with threads standing in for the
+//! RTIC tasks so that it runs on a PC:
+//! - `init` scans the slaves with a blocking device, as RTIC `#[init]` runs before the
+//!   Ethernet interrupt is enabled.
+//! - The `eth_isr` thread owns the `Producer` of the RX queue and only calls `feed_rx_frame`.
+//! - The `cyclic` task owns the master, built on a `QueuedDevice` and a `MonotonicTimer`,
+//!   and watches the AL status of the slaves.
+//!
+//! cargo run --example rtic_split -- <interface>
+
+use ethercat_master::al_state_transfer::ALStateTransfer;
+use ethercat_master::al_status_monitor::{AlStatusChange, AlStatusMonitor};
+use ethercat_master::arch::Device;
+use ethercat_master::initializer::SlaveInitilizer;
+use ethercat_master::interface::{EtherCATInterface, SlaveAddress};
+use ethercat_master::master::{CyclicProcessingUnit, EtherCATMaster};
+use ethercat_master::rtic::*;
+use ethercat_master::slave_status::{AlState, Slave};
+use fugit::MicrosDurationU32;
+use heapless::spsc::Producer;
+use pnet::datalink::{self, Channel::Ethernet, DataLinkReceiver, DataLinkSender};
+use std::env;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+const MTU: usize = 1500;
+const FRAME_SIZE: usize = 1514;
+const QUEUE_LEN: usize = 8;
+const CYCLE: Duration = Duration::from_millis(1);
+
+/// Monotonic clock of the example, `Mono::now()` on RTIC.
+fn now() -> MicrosInstant {
+    static START: OnceLock<Instant> = OnceLock::new();
+    MicrosInstant::from_ticks(START.get_or_init(Instant::now).elapsed().as_micros() as u64)
+}
+
+fn station_address(position: usize) -> u16 {
+    0x1001 + position as u16
+}
+
+fn timer() -> MonotonicTimer<fn() -> MicrosInstant> {
+    MonotonicTimer::new(now)
+}
+
+/// Sends and receives on the channel directly, for the initialization.
+struct BlockingDevice {
+    tx_buf: [u8; MTU],
+    tx: Box<dyn DataLinkSender>,
+    rx: Box<dyn DataLinkReceiver>,
+}
+
+impl Device for BlockingDevice {
+    fn send<R, F>(&mut self, len: usize, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut [u8]) -> Option<R>,
+    {
+        let result = f(&mut self.tx_buf[..len]);
+        self.tx.send_to(&self.tx_buf[..len], None)?.ok()?;
+        result
+    }
+
+    fn recv<R, F>(&mut self, f: F) -> Option<R>
+    where
+        F: FnOnce(&[u8]) -> Option<R>,
+    {
+        self.rx.next().ok().and_then(f)
+    }
+
+    fn max_transmission_unit(&self) -> usize {
+        MTU
+    }
+}
+
+/// Only sends. The frames are received from the queue fed by `eth_isr`.
+struct SendOnlyDevice {
+    tx_buf: [u8; MTU],
+    tx: Box<dyn DataLinkSender>,
+}
+
+impl Device for SendOnlyDevice {
+    fn send<R, F>(&mut self, len: usize, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut [u8]) -> Option<R>,
+    {
+        let result = f(&mut self.tx_buf[..len]);
+        self.tx.send_to(&self.tx_buf[..len], None)?.ok()?;
+        result
+    }
+
+    fn recv<R, F>(&mut self, _f: F) -> Option<R>
+    where
+        F: FnOnce(&[u8]) -> Option<R>,
+    {
+        None
+    }
+
+    fn max_transmission_unit(&self) -> usize {
+        MTU
+    }
+}
+
+fn main() {
+    env::set_var("RUST_LOG", "info");
+    env_logger::init();
+
+    let args: Vec<String> = env::args().collect();
+
+    if let Some(name) = args.get(1) {
+        run(name);
+    } else {
+        println!("Specify the name of network interface as an argument from the following.");
+        for (i, interface) in datalink::interfaces().iter().enumerate() {
+            println!("{}:", i);
+            println!("    Description: {}", interface.description);
+            println!("    Name: {}", interface.name);
+        }
+    }
+}
+
+fn open(interf_name: &str) -> (Box<dyn DataLinkSender>, Box<dyn DataLinkReceiver>) {
+    let interface = datalink::interfaces()
+        .into_iter()
+        .find(|iface| iface.name == interf_name)
+        .expect("interface not found");
+    match datalink::channel(&interface, Default::default()) {
+        Ok(Ethernet(tx, rx)) => (tx, rx),
+        Ok(_) => panic!("unhandled interface"),
+        Err(_e) => panic!("unenable to create channel"),
+    }
+}
+
+fn run(interf_name: &str) {
+    let slaves = init(interf_name);
+
+    // RTICでは#[init]がキューを分割し、ProducerをISRに、Consumerを周期タスクに渡す
+    let queue: &'static mut RxQueue<FRAME_SIZE, QUEUE_LEN> = Box::leak(Box::new(RxQueue::new()));
+    let (producer, consumer) = queue.split();
+    let (tx, rx) = open(interf_name);
+    std::thread::spawn(move || eth_isr(producer, rx));
+
+    let device = QueuedDevice::new(
+        SendOnlyDevice {
+            tx_buf: [0; MTU],
+            tx,
+        },
+        consumer,
+    );
+    cyclic(device, slaves);
+}
+
+/// Scans the slaves and gives them station addresses.
+fn init(interf_name: &str) -> Vec<Slave<'static>> {
+    let (tx, rx) = open(interf_name);
+    let device = BlockingDevice {
+        tx_buf: [0; MTU],
+        tx,
+        rx,
+    };
+    let iface = Box::leak(Box::new(EtherCATInterface::new(
+        device,
+        timer(),
+        Box::leak(Box::new([0; MTU])),
+        Box::leak(Box::new([0; MTU])),
+    )));
+    let mut init_timer = timer();
+    let mut initializer = SlaveInitilizer::new(iface, &mut init_timer);
+    let num_slaves = initializer.count_slaves().expect("no slaves");
+    let mut slaves: Vec<Slave> = (0..num_slaves).map(|_| Slave::default()).collect();
+    initializer.init_slaves(&mut slaves).expect("scan failed");
+    for (position, slave) in slaves.iter_mut().enumerate() {
+        initializer
+            .set_station_address(slave, station_address(position))
+            .expect("failed to set the station address");
+    }
+    println!("{} slaves found", slaves.len());
+    slaves
+}
+
+/// Ethernet RX interrupt: pushes the received frames to the queue and returns.
+fn eth_isr(
+    mut producer: Producer<'static, RxFrame<FRAME_SIZE>, QUEUE_LEN>,
+    mut rx: Box<dyn DataLinkReceiver>,
+) {
+    while let Ok(frame) = rx.next() {
+        if !feed_rx_frame(&mut producer, frame) {
+            log::warn!("RX queue full, frame dropped");
+        }
+    }
+}
+
+fn print_change(change: &AlStatusChange) {
+    println!(
+        "slave {:#06x}: {:?} -> {:?} (error: {}, code: {:#06x})",
+        change.slave, change.previous, change.current, change.error, change.status_code
+    );
+}
+
+/// Cyclic task: owns the master and exchanges one frame per cycle.
+fn cyclic<D: Device>(device: QueuedDevice<'static, D, FRAME_SIZE, QUEUE_LEN>, slaves: Vec<Slave>) {
+    let iface = Box::leak(Box::new(EtherCATInterface::new(
+        device,
+        timer(),
+        Box::leak(Box::new([0; MTU])),
+        Box::leak(Box::new([0; MTU])),
+    )));
+    let mut monitor = AlStatusMonitor::new();
+    monitor.set_handler(print_change);
+    for slave in slaves.iter() {
+        monitor.add_slave(slave);
+    }
+    let units = Box::leak(Box::new([CyclicProcessingUnit::AlStatus(monitor)]));
+    let mut master = EtherCATMaster::new(iface, units);
+    master.set_clock(now);
+
+    // 状態遷移のような待ちのある処理は、サイクルの合間にacyclic_windowで行う
+    master.acyclic_window(|window| {
+        let mut transfer_timer = timer();
+        let mut transfer = ALStateTransfer::new(window.iface(), &mut transfer_timer);
+        for position in 0..slaves.len() {
+            let address = SlaveAddress::StationAddress(station_address(position));
+            if let Err(err) = transfer.change_al_state(address, AlState::PreOperational) {
+                println!("slave {}: {:?}", position, err);
+            }
+        }
+    });
+
+    let timeout = MicrosDurationU32::from_ticks(1000);
+    loop {
+        let start = Instant::now();
+        if let Err(err) = master.process_and_enqueue() {
+            log::warn!("{:?}", err);
+        }
+        if let Err(err) = master.poll(timeout) {
+            log::warn!("{:?}", err);
+        }
+        // RTICでは Mono::delay(1.millis()).await
+        if let Some(rest) = CYCLE.checked_sub(start.elapsed()) {
+            std::thread::sleep(rest);
+        }
+    }
+}
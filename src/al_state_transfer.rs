@@ -1,10 +1,14 @@
 use crate::arch::*;
 use crate::error::*;
+use crate::initializer::ProcessImage;
 use crate::interface::*;
+use crate::master::Command;
+use crate::network::*;
 use crate::packet::*;
 use crate::register::{application::*, datalink::*};
 use crate::slave_status::*;
 use crate::util::*;
+use crate::wkc::check_command_wkc;
 use crate::*;
 use embedded_hal::timer::CountDown;
 use fugit::*;
@@ -12,8 +16,22 @@ use fugit::*;
 #[derive(Debug, Clone)]
 pub enum AlStateTransitionError {
     Common(CommonError),
-    TimeoutMs(u32),
+    Timeout(MillisDurationU32),
     AlStatusCode(AlStatusCode),
+    /// The slave indicated an error with this AL status code instead of changing the state.
+    Refused(u16),
+}
+
+impl Classify for AlStateTransitionError {
+    fn classify(&self) -> (ErrorCategory, RecoveryHint) {
+        use ErrorCategory::*;
+        use RecoveryHint::*;
+        match self {
+            Self::Common(err) => err.classify(),
+            Self::Timeout(_) => (SlaveConfig, Retry),
+            Self::AlStatusCode(_) | Self::Refused(_) => (SlaveConfig, FixConfig),
+        }
+    }
 }
 
 impl From<CommonError> for AlStateTransitionError {
@@ -22,6 +40,34 @@ impl From<CommonError> for AlStateTransitionError {
     }
 }
 
+/// AL status (0x0130) to the end of the AL status code (0x0135).
+pub(crate) const AL_STATUS_READ_SIZE: usize = 6;
+
+/// Default number of cycles the process data is exchanged before Op is requested.
+pub const OP_PRE_EXCHANGE_CYCLES_DEFAULT: u8 = 3;
+
+/// Process image exchanged while slaves change from SafeOp to Op.
+/// Some ESC and PDI combinations refuse Op until the PDI has seen process data,
+/// so the outputs are written before Op is requested and while it is awaited.
+#[derive(Debug, Clone, Copy)]
+pub struct OpTransitionData<'a> {
+    pub logical_start_address: u32,
+    /// Whole process image, sent with LRW. The received data is discarded.
+    pub image: &'a [u8],
+    /// Cycles exchanged before Op is requested.
+    pub cycles_before_request: u8,
+}
+
+impl<'a> OpTransitionData<'a> {
+    pub fn new(image: &ProcessImage, data: &'a [u8]) -> Self {
+        Self {
+            logical_start_address: image.logical_start_address,
+            image: &data[..image.size.min(data.len())],
+            cycles_before_request: OP_PRE_EXCHANGE_CYCLES_DEFAULT,
+        }
+    }
+}
+
 pub struct ALStateTransfer<'a, 'b, D, T, U>
 where
     D: Device,
@@ -30,6 +76,8 @@ where
 {
     iface: &'a mut EtherCATInterface<'b, D, T>,
     timer: &'a mut U,
+    op_data: Option<OpTransitionData<'a>>,
+    op_retries: u8,
 }
 
 impl<'a, 'b, D, T, U> ALStateTransfer<'a, 'b, D, T, U>
@@ -39,7 +87,24 @@ where
     U: CountDown<Time = MicrosDurationU32>,
 {
     pub fn new(iface: &'a mut EtherCATInterface<'b, D, T>, timer: &'a mut U) -> Self {
-        Self { iface, timer }
+        Self {
+            iface,
+            timer,
+            op_data: None,
+            op_retries: 0,
+        }
+    }
+
+    /// Exchanges `data` while slaves change to Op.
+    pub fn set_op_transition_data(&mut self, data: OpTransitionData<'a>) {
+        self.op_data = Some(data);
+    }
+
+    /// Number of times a slave refusing Op with AL status code 0x001D
+    /// (invalid output configuration) is acknowledged and requested Op again.
+    /// 0 waits until the timeout as for other errors.
+    pub fn set_op_retries(&mut self, retries: u8) {
+        self.op_retries = retries;
     }
 
     pub fn al_state(
@@ -61,28 +126,46 @@ where
             return Ok(());
         }
 
-        let timeout = match (current_al_state, al_state) {
-            (AlState::PreOperational, AlState::SafeOperational)
-            | (AlState::SafeOperational, AlState::Operational) => SAFEOP_OP_TIMEOUT_DEFAULT_MS,
-            (_, AlState::PreOperational) | (_, AlState::Bootstrap) => PREOP_TIMEOUT_DEFAULT_MS,
-            (_, AlState::Init) => BACK_TO_INIT_TIMEOUT_DEFAULT_MS,
-            (_, AlState::SafeOperational) => BACK_TO_SAFEOP_TIMEOUT_DEFAULT_MS,
-        };
+        let timeout = transition_timeout(current_al_state, al_state);
+        let is_op_request = al_state == AlState::Operational;
+        if is_op_request {
+            self.exchange_before_op()?;
+        }
 
         let mut al_control = ALControl::new();
         al_control.set_state(al_state as u8);
         self.iface
             .write_al_control(slave_address, Some(al_control))?;
-        self.timer
-            .start(MillisDurationU32::from_ticks(timeout).convert());
+        self.timer.start(timeout.convert());
+        let mut retries = 0;
         loop {
-            let current_al_status = self.iface.read_al_status(slave_address)?;
+            if is_op_request {
+                self.exchange_op_data()?;
+            }
+            let pdu =
+                self.iface
+                    .read_register(slave_address, ALStatus::ADDRESS, AL_STATUS_READ_SIZE)?;
+            let current_al_status = ALStatus(pdu.data());
             let current_al_state = AlState::from(current_al_status.state());
             if al_state == current_al_state {
                 return Ok(());
             }
+            if is_op_request && is_invalid_output_config(&current_al_status) {
+                if retries >= self.op_retries {
+                    return Err(AlStateTransitionError::AlStatusCode(
+                        AlStatusCode::InvalidOutputConfig,
+                    ));
+                }
+                retries += 1;
+                // エラーを確認しつつOpを再度要求する
+                let mut al_control = ALControl::new();
+                al_control.set_state(al_state as u8);
+                al_control.set_acknowledge(true);
+                self.iface
+                    .write_al_control(slave_address, Some(al_control))?;
+            }
             match self.timer.wait() {
-                Ok(_) => return Err(AlStateTransitionError::TimeoutMs(timeout)),
+                Ok(_) => return Err(AlStateTransitionError::Timeout(timeout)),
                 Err(nb::Error::Other(_)) => {
                     return Err(AlStateTransitionError::Common(CommonError::UnspcifiedTimerError))
                 }
@@ -90,11 +173,410 @@ where
             }
         }
     }
+
+    /// Requests `al_state` to the slaves in `slave_positions` at the same time
+    /// and waits until all of them reach it or time out.
+    /// The result of `slave_positions[n]` is stored in `results[n]`,
+    /// so one faulty slave does not abort the transition of the others.
+    /// Up to 255 slaves can be requested at once.
+    pub fn request_al_state(
+        &mut self,
+        slave_positions: &[u16],
+        al_state: AlState,
+        results: &mut [Result<(), AlStateTransitionError>],
+    ) -> Result<(), AlStateTransitionError> {
+        let num_slaves = slave_positions.len().min(results.len());
+        if num_slaves >= u8::MAX as usize {
+            return Err(AlStateTransitionError::Common(CommonError::BufferExhausted));
+        }
+        let slave_positions = &slave_positions[..num_slaves];
+        let results = &mut results[..num_slaves];
+
+        // 現在の状態を読み、遷移が必要なスレーブを待ち状態(Timeout)にする
+        for (i, position) in slave_positions.iter().enumerate() {
+            results[i] = Err(AlStateTransitionError::Common(CommonError::PacketDropped));
+            self.add_al_status_read(i, *position)?;
+        }
+        self.iface.poll(RECEIVE_TIMEOUT_DEFAULT)?;
+        let mut max_timeout = MillisDurationU32::from_ticks(0);
+        for pdu in self.iface.consume_command() {
+            let result = match results.get_mut(pdu.index() as usize) {
+                Some(result) => result,
+                None => continue,
+            };
+            if let Err(err) = check_command_wkc(&pdu, 1) {
+                *result = Err(err.into());
+                continue;
+            }
+            let current_al_state = AlState::from(ALStatus(pdu.data()).state());
+            if current_al_state == al_state {
+                *result = Ok(());
+            } else {
+                let timeout = transition_timeout(current_al_state, al_state);
+                if timeout > max_timeout {
+                    max_timeout = timeout;
+                }
+                *result = Err(AlStateTransitionError::Timeout(timeout));
+            }
+        }
+
+        if !results.iter().any(is_pending) {
+            return Ok(());
+        }
+        let is_op_request = al_state == AlState::Operational;
+        if is_op_request {
+            self.exchange_before_op()?;
+        }
+        for (i, position) in slave_positions.iter().enumerate() {
+            if is_pending(&results[i]) {
+                self.add_al_control_write(i, *position, al_state, false)?;
+            }
+        }
+        self.iface.poll(RECEIVE_TIMEOUT_DEFAULT)?;
+        for pdu in self.iface.consume_command() {
+            if let Some(result) = results.get_mut(pdu.index() as usize) {
+                if let Err(err) = check_command_wkc(&pdu, 1) {
+                    *result = Err(err.into());
+                }
+            }
+        }
+
+        self.timer.start(max_timeout.convert());
+        let mut retries = [0u8; u8::MAX as usize];
+        loop {
+            let mut has_pending = false;
+            for (i, position) in slave_positions.iter().enumerate() {
+                if is_pending(&results[i]) {
+                    has_pending = true;
+                    self.add_al_status_read(i, *position)?;
+                }
+            }
+            if !has_pending {
+                return Ok(());
+            }
+            if is_op_request {
+                self.add_op_data()?;
+            }
+            self.iface.poll(RECEIVE_TIMEOUT_DEFAULT)?;
+            let mut has_refused = false;
+            for pdu in self.iface.consume_command() {
+                let i = pdu.index() as usize;
+                let result = match results.get_mut(i) {
+                    Some(result) => result,
+                    None => continue,
+                };
+                if let Err(err) = check_command_wkc(&pdu, 1) {
+                    *result = Err(err.into());
+                    continue;
+                }
+                let al_status = ALStatus(pdu.data());
+                if AlState::from(al_status.state()) == al_state {
+                    *result = Ok(());
+                } else if is_op_request && is_invalid_output_config(&al_status) {
+                    if retries[i] >= self.op_retries {
+                        *result = Err(AlStateTransitionError::AlStatusCode(
+                            AlStatusCode::InvalidOutputConfig,
+                        ));
+                    } else {
+                        retries[i] += 1;
+                        has_refused = true;
+                    }
+                }
+            }
+            // Opを拒否したスレーブはエラーを確認しつつ再度要求する
+            if has_refused {
+                for (i, position) in slave_positions.iter().enumerate() {
+                    if is_pending(&results[i]) && retries[i] > 0 {
+                        self.add_al_control_write(i, *position, al_state, true)?;
+                    }
+                }
+                self.iface.poll(RECEIVE_TIMEOUT_DEFAULT)?;
+                self.iface.consume_command();
+            }
+            match self.timer.wait() {
+                // 残っているスレーブはTimeoutのまま
+                Ok(_) => return Ok(()),
+                Err(nb::Error::Other(_)) => {
+                    return Err(AlStateTransitionError::Common(CommonError::UnspcifiedTimerError))
+                }
+                Err(nb::Error::WouldBlock) => (),
+            }
+        }
+    }
+
+    /// Same as `request_al_state` for the slaves of `group`, so that part of the machine
+    /// can change its state while the other groups stay as they are.
+    /// The results are stored in the order of `NetworkDescription::slaves_in`.
+    pub fn request_group_al_state(
+        &mut self,
+        network: &NetworkDescription,
+        group: SlaveGroup,
+        al_state: AlState,
+        results: &mut [Result<(), AlStateTransitionError>],
+    ) -> Result<(), AlStateTransitionError> {
+        let mut positions = [0; u8::MAX as usize - 1];
+        let mut num_slaves = 0;
+        for slave in network.slaves_in(group) {
+            let position = positions
+                .get_mut(num_slaves)
+                .ok_or(AlStateTransitionError::Common(CommonError::BufferExhausted))?;
+            *position = slave.position_address;
+            num_slaves += 1;
+        }
+        self.request_al_state(&positions[..num_slaves], al_state, results)
+    }
+
+    fn add_al_status_read(
+        &mut self,
+        pdu_index: usize,
+        slave_position: u16,
+    ) -> Result<(), CommonError> {
+        self.iface.add_command(
+            pdu_index as u8,
+            CommandType::APRD,
+            get_ap_adp(slave_position),
+            ALStatus::ADDRESS,
+            AL_STATUS_READ_SIZE,
+            |buf| buf.iter_mut().for_each(|b| *b = 0),
+        )
+    }
+
+    fn add_al_control_write(
+        &mut self,
+        pdu_index: usize,
+        slave_position: u16,
+        al_state: AlState,
+        acknowledge: bool,
+    ) -> Result<(), CommonError> {
+        self.iface.add_command(
+            pdu_index as u8,
+            CommandType::APWR,
+            get_ap_adp(slave_position),
+            ALControl::ADDRESS,
+            ALControl::SIZE,
+            |buf| {
+                buf.iter_mut().for_each(|b| *b = 0);
+                let mut al_control = ALControl(buf);
+                al_control.set_state(al_state as u8);
+                al_control.set_acknowledge(acknowledge);
+            },
+        )
+    }
+
+    /// Adds the LRW of the Op transition data, if any, with the PDU index 255,
+    /// which no slave of `request_al_state` uses.
+    fn add_op_data(&mut self) -> Result<(), CommonError> {
+        let data = match self.op_data {
+            Some(data) => data,
+            None => return Ok(()),
+        };
+        self.iface.add_logical_command(
+            u8::MAX,
+            CommandType::LRW,
+            data.logical_start_address,
+            data.image.len(),
+            |buf| buf.copy_from_slice(data.image),
+        )
+    }
+
+    fn exchange_op_data(&mut self) -> Result<(), CommonError> {
+        if self.op_data.is_none() {
+            return Ok(());
+        }
+        self.add_op_data()?;
+        self.iface.poll(RECEIVE_TIMEOUT_DEFAULT)?;
+        self.iface.consume_command();
+        Ok(())
+    }
+
+    fn exchange_before_op(&mut self) -> Result<(), CommonError> {
+        let cycles = self
+            .op_data
+            .map(|data| data.cycles_before_request)
+            .unwrap_or(0);
+        for _ in 0..cycles {
+            self.exchange_op_data()?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AlTransitionPhase {
+    Idle,
+    /// The current state is read before the request.
+    Check,
+    Request,
+    Wait,
+    /// The request of a cancelled transition is withdrawn.
+    Restore,
+}
+
+/// AL state transition of one slave executed by the master with one datagram per cycle, e.g. to
+/// bring a slave back to Op without stopping the cyclic exchange of the others.
+/// Place it in `CyclicProcessingUnit::AlTransition` and access it with
+/// `EtherCATMaster::al_transition_unit_mut`.
+/// The unit has no timeout: `cancel` ends a transition that takes too long.
+#[derive(Debug)]
+pub struct AlTransitionUnit {
+    station_address: u16,
+    al_state: AlState,
+    /// State of the slave when the transition was requested.
+    previous_al_state: AlState,
+    /// The error indication of the slave is acknowledged with the request and has not
+    /// been seen cleared yet.
+    acknowledge: bool,
+    phase: AlTransitionPhase,
+    result: Option<Result<(), AlStateTransitionError>>,
+    buffer: [u8; AL_STATUS_READ_SIZE],
+}
+
+impl AlTransitionUnit {
+    pub fn new(slave: &Slave) -> Self {
+        Self {
+            station_address: slave.configured_address,
+            al_state: slave.al_state,
+            previous_al_state: slave.al_state,
+            acknowledge: false,
+            phase: AlTransitionPhase::Idle,
+            result: None,
+            buffer: [0; AL_STATUS_READ_SIZE],
+        }
+    }
+
+    pub fn station_address(&self) -> u16 {
+        self.station_address
+    }
+
+    /// False while a transition, or the withdrawal of a cancelled one, is in progress.
+    pub fn is_idle(&self) -> bool {
+        self.phase == AlTransitionPhase::Idle
+    }
+
+    /// Starts the transition to `al_state`. The slave is only requested if it is in another state,
+    /// and its error indication is acknowledged with the request.
+    pub fn start(&mut self, al_state: AlState) -> Result<(), AlStateTransitionError> {
+        if !self.is_idle() {
+            return Err(AlStateTransitionError::Common(CommonError::BufferExhausted));
+        }
+        self.al_state = al_state;
+        self.phase = AlTransitionPhase::Check;
+        self.result = None;
+        Ok(())
+    }
+
+    /// Result of the last transition. None while the transition is in progress and after `cancel`.
+    pub fn result(&self) -> Option<Result<(), AlStateTransitionError>> {
+        self.result.clone()
+    }
+
+    /// Ends the transition in progress. If the request may have reached the slave, the state
+    /// the slave was in is requested again, and the unit is idle again once it is written.
+    pub fn cancel(&mut self) {
+        self.acknowledge = false;
+        self.phase = match self.phase {
+            AlTransitionPhase::Request | AlTransitionPhase::Wait => AlTransitionPhase::Restore,
+            AlTransitionPhase::Restore => AlTransitionPhase::Restore,
+            _ => AlTransitionPhase::Idle,
+        };
+    }
+
+    pub(crate) fn process(&mut self) -> Option<(Command, &[u8])> {
+        self.buffer = [0; AL_STATUS_READ_SIZE];
+        let (command, size) = match self.phase {
+            AlTransitionPhase::Idle => return None,
+            AlTransitionPhase::Check | AlTransitionPhase::Wait => (
+                Command::new_fprd(self.station_address, ALStatus::ADDRESS),
+                AL_STATUS_READ_SIZE,
+            ),
+            AlTransitionPhase::Request | AlTransitionPhase::Restore => {
+                let al_state = if self.phase == AlTransitionPhase::Request {
+                    self.al_state
+                } else {
+                    self.previous_al_state
+                };
+                let mut al_control = ALControl(&mut self.buffer[..ALControl::SIZE]);
+                al_control.set_state(al_state as u8);
+                al_control.set_acknowledge(self.acknowledge);
+                (
+                    Command::new_fpwr(self.station_address, ALControl::ADDRESS),
+                    ALControl::SIZE,
+                )
+            }
+        };
+        Some((command, &self.buffer[..size]))
+    }
+
+    pub(crate) fn receive(&mut self, _command: Command, data: &[u8], wkc: u16) -> bool {
+        if self.phase == AlTransitionPhase::Idle {
+            return true;
+        }
+        // 取り下げは一度だけ書き込む
+        if self.phase == AlTransitionPhase::Restore {
+            self.phase = AlTransitionPhase::Idle;
+            return wkc == 1;
+        }
+        if wkc != 1 {
+            self.finish(Err(CommonError::UnexpectedWKC(wkc).into()));
+            return false;
+        }
+        match self.phase {
+            AlTransitionPhase::Check | AlTransitionPhase::Wait
+                if data.len() >= AL_STATUS_READ_SIZE =>
+            {
+                let al_status = ALStatus(data);
+                let al_state = AlState::from(al_status.state());
+                if al_state == self.al_state {
+                    self.finish(Ok(()));
+                } else if self.phase == AlTransitionPhase::Check {
+                    self.previous_al_state = al_state;
+                    self.acknowledge = al_status.change_err();
+                    self.phase = AlTransitionPhase::Request;
+                } else if !al_status.change_err() {
+                    self.acknowledge = false;
+                } else if !self.acknowledge {
+                    // 確認したエラーが消えた後のエラーは要求の拒否
+                    self.finish(Err(AlStateTransitionError::Refused(
+                        al_status.al_status_code(),
+                    )));
+                }
+            }
+            AlTransitionPhase::Request => self.phase = AlTransitionPhase::Wait,
+            _ => self.finish(Err(CommonError::ResponseMismatch.into())),
+        }
+        true
+    }
+
+    fn finish(&mut self, result: Result<(), AlStateTransitionError>) {
+        self.phase = AlTransitionPhase::Idle;
+        self.result = Some(result);
+    }
+}
+
+fn transition_timeout(current_al_state: AlState, al_state: AlState) -> MillisDurationU32 {
+    match (current_al_state, al_state) {
+        (AlState::PreOperational, AlState::SafeOperational)
+        | (AlState::SafeOperational, AlState::Operational) => SAFEOP_OP_TIMEOUT_DEFAULT,
+        (_, AlState::PreOperational) | (_, AlState::Bootstrap) => PREOP_TIMEOUT_DEFAULT,
+        (_, AlState::Init) => BACK_TO_INIT_TIMEOUT_DEFAULT,
+        (_, AlState::SafeOperational) => BACK_TO_SAFEOP_TIMEOUT_DEFAULT,
+        (_, AlState::Operational) | (_, AlState::Invalid) => SAFEOP_OP_TIMEOUT_DEFAULT,
+    }
+}
+
+fn is_pending(result: &Result<(), AlStateTransitionError>) -> bool {
+    matches!(result, Err(AlStateTransitionError::Timeout(_)))
+}
+
+/// `al_status` must be read with `AL_STATUS_READ_SIZE` bytes.
+fn is_invalid_output_config<B: AsRef<[u8]>>(al_status: &ALStatus<B>) -> bool {
+    al_status.change_err() && al_status.al_status_code() == AlStatusCode::InvalidOutputConfig as u16
 }
 
 //TODO
 #[derive(Debug, Clone)]
 pub enum AlStatusCode {
     NoError = 0,
+    InvalidOutputConfig = 0x001D,
     InvalidInputConfig = 0x001E,
 }
@@ -0,0 +1,200 @@
+use crate::al_state_transfer::AL_STATUS_READ_SIZE;
+use crate::master::Command;
+use crate::packet::CommandType;
+use crate::register::application::ALStatus;
+use crate::slave_status::*;
+use crate::storage::VecStorage;
+use crate::wkc::expected_wkc;
+
+/// Number of slaves an `AlStatusMonitor` can watch.
+pub const AL_STATUS_MONITOR_MAX_SLAVES: usize = 32;
+
+/// AL state and error indication bits of the AL status.
+const STATE_AND_ERROR_MASK: u8 = 0x1F;
+const ERROR_INDICATION: u8 = 0x10;
+
+/// Change of the AL status of a slave found by `AlStatusMonitor`.
+#[derive(Debug, Clone)]
+pub struct AlStatusChange {
+    /// Station address of the slave.
+    pub slave: u16,
+    pub previous: AlState,
+    pub current: AlState,
+    /// Error indication of the AL status.
+    pub error: bool,
+    /// AL status code, valid if `error` is set.
+    pub status_code: u16,
+}
+
+pub type AlStatusHandler = fn(&AlStatusChange);
+
+#[derive(Debug, Clone, Copy)]
+pub struct MonitoredSlave {
+    pub station_address: u16,
+    /// AL state and error indication read last.
+    pub status: u8,
+}
+
+/// Default storage of the watched slaves.
+pub type MonitoredSlaves = heapless::Vec<MonitoredSlave, AL_STATUS_MONITOR_MAX_SLAVES>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MonitorPhase {
+    Broadcast,
+    Locate(usize),
+}
+
+/// Cyclic unit that supervises the AL status of all slaves with one BRD per cycle.
+/// The slaves OR their AL state and error indication into the datagram, so a change of any
+/// slave changes the combined value or the WKC in most cases. Only then the slaves are read
+/// one per cycle to find which one changed, and the handler is called for it.
+/// A change hidden by the bits of other slaves, e.g. a second slave falling to SafeOp while
+/// another one is already in SafeOp, is found at the next change of the combined value.
+#[derive(Debug, Clone)]
+pub struct AlStatusMonitor<S = MonitoredSlaves> {
+    slaves: S,
+    handler: Option<AlStatusHandler>,
+    phase: MonitorPhase,
+    combined: Option<u8>,
+    responding: u16,
+    buffer: [u8; AL_STATUS_READ_SIZE],
+    changes: u32,
+    last_change: Option<AlStatusChange>,
+}
+
+impl AlStatusMonitor {
+    pub fn new() -> Self {
+        Self::with_storage(MonitoredSlaves::new())
+    }
+}
+
+impl Default for AlStatusMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: VecStorage<MonitoredSlave>> AlStatusMonitor<S> {
+    /// Same as `new`, with the slaves stored in `slaves`.
+    pub fn with_storage(mut slaves: S) -> Self {
+        slaves.clear();
+        Self {
+            slaves,
+            handler: None,
+            phase: MonitorPhase::Broadcast,
+            combined: None,
+            responding: 0,
+            buffer: [0; AL_STATUS_READ_SIZE],
+            changes: 0,
+            last_change: None,
+        }
+    }
+
+    /// Watches a slave, starting from its `al_state`. Add all slaves of the network,
+    /// since every slave answers the BRD. Returns false if the monitor is full.
+    pub fn add_slave(&mut self, slave: &Slave) -> bool {
+        let slave = MonitoredSlave {
+            station_address: slave.configured_address,
+            status: slave.al_state as u8,
+        };
+        self.slaves.push(slave).is_ok()
+    }
+
+    pub fn set_handler(&mut self, handler: AlStatusHandler) {
+        self.handler = Some(handler);
+    }
+
+    /// OR of the AL state and error indication of all slaves, None before the first cycle.
+    pub fn combined_status(&self) -> Option<u8> {
+        self.combined
+    }
+
+    /// Number of slaves that answered the last BRD.
+    pub fn responding(&self) -> u16 {
+        self.responding
+    }
+
+    /// Number of changes found.
+    pub fn changes(&self) -> u32 {
+        self.changes
+    }
+
+    pub fn last_change(&self) -> Option<&AlStatusChange> {
+        self.last_change.as_ref()
+    }
+
+    pub(crate) fn process(&mut self) -> Option<(Command, &[u8])> {
+        self.buffer = [0; AL_STATUS_READ_SIZE];
+        match self.phase {
+            MonitorPhase::Broadcast => {
+                if self.slaves.is_empty() {
+                    return None;
+                }
+                let command = Command::new(CommandType::BRD, 0, ALStatus::ADDRESS);
+                Some((command, &self.buffer[..ALStatus::SIZE]))
+            }
+            MonitorPhase::Locate(i) => {
+                let address = self.slaves.as_slice().get(i)?.station_address;
+                // ステータスコードまで読む
+                let command = Command::new_fprd(address, ALStatus::ADDRESS);
+                Some((command, &self.buffer))
+            }
+        }
+    }
+
+    pub(crate) fn receive(&mut self, command: Command, data: &[u8], wkc: u16) -> bool {
+        match self.phase {
+            MonitorPhase::Broadcast => {
+                let combined = match data.first() {
+                    Some(status) => status & STATE_AND_ERROR_MASK,
+                    None => return false,
+                };
+                // 前回と同じなら個別に読む必要はない
+                if self.combined != Some(combined) || self.responding != wkc {
+                    self.combined = Some(combined);
+                    self.responding = wkc;
+                    self.phase = MonitorPhase::Locate(0);
+                }
+                wkc >= expected_wkc(command.command_type(), self.slaves.len() as u16)
+            }
+            MonitorPhase::Locate(i) => {
+                let is_ok = wkc == expected_wkc(command.command_type(), 1)
+                    && data.len() >= AL_STATUS_READ_SIZE;
+                if is_ok {
+                    self.compare(i, data);
+                }
+                self.phase = if i + 1 < self.slaves.len() {
+                    MonitorPhase::Locate(i + 1)
+                } else {
+                    MonitorPhase::Broadcast
+                };
+                is_ok
+            }
+        }
+    }
+
+    fn compare(&mut self, index: usize, data: &[u8]) {
+        let slave = match self.slaves.as_mut_slice().get_mut(index) {
+            Some(slave) => slave,
+            None => return,
+        };
+        let al_status = ALStatus(data);
+        let status = data[0] & STATE_AND_ERROR_MASK;
+        if status == slave.status {
+            return;
+        }
+        let change = AlStatusChange {
+            slave: slave.station_address,
+            previous: AlState::from(slave.status & !ERROR_INDICATION),
+            current: AlState::from(al_status.state()),
+            error: al_status.change_err(),
+            status_code: al_status.al_status_code(),
+        };
+        slave.status = status;
+        self.changes = self.changes.wrapping_add(1);
+        if let Some(handler) = self.handler {
+            handler(&change);
+        }
+        self.last_change = Some(change);
+    }
+}
@@ -11,6 +11,124 @@ pub trait Device {
         F: FnOnce(&[u8]) -> Option<R>;
 
     fn max_transmission_unit(&self) -> usize;
+
+    /// Alignment in bytes the device requires for the buffers given to
+    /// `EtherCATInterface::new`, e.g. the D-cache line size (32 on Cortex-M7) for DMA.
+    /// The buffers must start on this alignment and their length must be a multiple of it,
+    /// so that cache maintenance on them does not touch other data. The interface does not
+    /// check it: allocate the buffers with `AlignedBuffer<N, ALIGN>` of the same `ALIGN`.
+    fn buffer_alignment(&self) -> usize {
+        1
+    }
+
+    /// Hardware timestamp (ns) taken by the MAC when the last frame given to `send` left.
+    /// None if the device has no hardware timestamps.
+    /// `DcInitializer::init_dc` uses it as the master time, so the time base must be the
+    /// system time of the master (ns since 2000).
+    fn tx_timestamp(&mut self) -> Option<u64> {
+        None
+    }
+
+    /// Same as `tx_timestamp` for the arrival of the last frame given to `recv`.
+    fn rx_timestamp(&mut self) -> Option<u64> {
+        None
+    }
+}
+
+/// Maps an alignment in bytes to a zero-sized type with that alignment.
+pub struct ConstAlign<const ALIGN: usize>;
+
+pub trait Alignment {
+    type Type;
+}
+
+macro_rules! define_alignments {
+    ($($name:ident, $align:literal;)*) => {
+        $(
+            #[repr(align($align))]
+            pub struct $name;
+
+            impl Alignment for ConstAlign<$align> {
+                type Type = $name;
+            }
+        )*
+    };
+}
+
+define_alignments! {
+    Align1, 1;
+    Align2, 2;
+    Align4, 4;
+    Align8, 8;
+    Align16, 16;
+    Align32, 32;
+    Align64, 64;
+    Align128, 128;
+}
+
+/// Buffer of `N` bytes starting at an `ALIGN`-byte boundary, for DMA-capable devices.
+/// `N` must be a multiple of `ALIGN`, so that no other data shares its cache lines.
+///
+/// ```ignore
+/// let tx_buffer: &'static mut AlignedBuffer<1536, 32> =
+///     cortex_m::singleton!(: AlignedBuffer<1536, 32> = AlignedBuffer::new()).unwrap();
+/// ```
+#[repr(C)]
+pub struct AlignedBuffer<const N: usize, const ALIGN: usize>
+where
+    ConstAlign<ALIGN>: Alignment,
+{
+    _align: [<ConstAlign<ALIGN> as Alignment>::Type; 0],
+    data: [u8; N],
+}
+
+impl<const N: usize, const ALIGN: usize> AlignedBuffer<N, ALIGN>
+where
+    ConstAlign<ALIGN>: Alignment,
+{
+    const IS_PADDED: () = assert!(N % ALIGN == 0, "length must be a multiple of the alignment");
+
+    pub const fn new() -> Self {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::IS_PADDED;
+        Self {
+            _align: [],
+            data: [0; N],
+        }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl<const N: usize, const ALIGN: usize> Default for AlignedBuffer<N, ALIGN>
+where
+    ConstAlign<ALIGN>: Alignment,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize, const ALIGN: usize> core::ops::Deref for AlignedBuffer<N, ALIGN>
+where
+    ConstAlign<ALIGN>: Alignment,
+{
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl<const N: usize, const ALIGN: usize> core::ops::DerefMut for AlignedBuffer<N, ALIGN>
+where
+    ConstAlign<ALIGN>: Alignment,
+{
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
 }
 
 pub struct SmoltcpWrapper<D: for<'a> smoltcp::phy::Device<'a>>(D);
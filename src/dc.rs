@@ -0,0 +1,559 @@
+use crate::arch::*;
+use crate::error::*;
+use crate::interface::*;
+use crate::master::Command;
+use crate::network::*;
+use crate::packet::*;
+use crate::register::datalink::*;
+use crate::slave_status::*;
+use crate::wkc::{check_command_wkc, expected_wkc};
+use crate::RECEIVE_TIMEOUT_DEFAULT;
+use embedded_hal::timer::CountDown;
+use fugit::*;
+
+/// Difference `a - b` of two DC times. 32-bit DC units wrap around about every 4.29 s,
+/// so only the lower 32 bits are compared for them.
+pub fn dc_time_diff(a: u64, b: u64, is_64bits: bool) -> i64 {
+    if is_64bits {
+        a.wrapping_sub(b) as i64
+    } else {
+        (a as u32).wrapping_sub(b as u32) as i32 as i64
+    }
+}
+
+/// Truncates a DC time to the width of the DC unit.
+pub fn dc_time(time: u64, is_64bits: bool) -> u64 {
+    if is_64bits {
+        time
+    } else {
+        time & u32::MAX as u64
+    }
+}
+
+/// Transmission time of a byte at 100 Mbit/s.
+const BYTE_TIME_NS: u64 = 80;
+/// Preamble, Ethernet header, FCS and interframe gap, and the EtherCAT header, datagram header
+/// and WKC around the process image.
+const FRAME_OVERHEAD_BYTES: u64 = 8 + 14 + 4 + 12 + 2 + 10 + 2;
+
+/// Recommended delay of Sync0 after the start of the frame transmission, so that the outputs
+/// are valid in all slaves before Sync0: `copy_time` of the application, the frame delay and
+/// `margin`. The frame delay is the transmission time of the process image plus the propagation
+/// delay to the last DC slave, or half of the round trip time `rtt` if it is not known.
+pub fn sync0_shift(
+    slaves: &[Slave],
+    rtt: MicrosDurationU32,
+    process_image_size: usize,
+    copy_time: NanosDurationU32,
+    margin: NanosDurationU32,
+) -> NanosDurationU32 {
+    let transmission = (FRAME_OVERHEAD_BYTES + process_image_size as u64) * BYTE_TIME_NS;
+    let propagation = slaves
+        .iter()
+        .filter(|slave| slave.support_dc)
+        .map(|slave| slave.dc_propagation_delay as u64)
+        .max()
+        .filter(|delay| *delay != 0)
+        .unwrap_or(rtt.ticks() as u64 * 1000 / 2);
+    let shift = copy_time.ticks() as u64 + transmission + propagation + margin.ticks() as u64;
+    NanosDurationU32::from_ticks(shift.min(u32::MAX as u64) as u32)
+}
+
+/// Typical time an EBUS port needs to send a frame back after receiving it,
+/// when the frame passes the processing unit and the closed ports behind it.
+pub const EBUS_LOOP_DELAY_NS: u32 = 120;
+/// Same as `EBUS_LOOP_DELAY_NS` for MII ports. The frame also passes the receive and
+/// transmit paths of the PHY.
+pub const MII_LOOP_DELAY_NS: u32 = 560;
+
+/// Time a port with `physics` needs to send a received frame back.
+pub fn loop_delay(physics: Option<PortPhysics>) -> u32 {
+    match physics {
+        Some(PortPhysics::EBUS) => EBUS_LOOP_DELAY_NS,
+        Some(PortPhysics::MII) => MII_LOOP_DELAY_NS,
+        None => 0,
+    }
+}
+
+/// Propagation delay from the reference clock to `child` connected to `port` of `parent`.
+/// The loop delay of the child depends on the physics of its port 0, and is only spent on the
+/// way back, so it is removed from the round trip before halving.
+fn propagation_delay(parent: &Slave, port: usize, child: &Slave) -> u32 {
+    let times = &parent.dc_receive_times;
+    // 親がこのポートへ送り出すのは、処理順で1つ前のポートからフレームが戻ってきた時
+    let sent = downstream_ports(parent)
+        .take_while(|p| *p != port)
+        .last()
+        .map(|p| times[p])
+        .unwrap_or(times[0]);
+    let returned = times[port];
+    // 子の先につながっているスレーブを回ってくる時間
+    let child_loop = downstream_ports(child)
+        .last()
+        .map(|p| child.dc_receive_times[p].wrapping_sub(child.dc_receive_times[0]))
+        .unwrap_or(0);
+    // EBUSとMIIでは折り返しの遅延が大きく違うので、往復時間から除いてから半分にする
+    let round_trip = returned.wrapping_sub(sent).wrapping_sub(child_loop) as i32;
+    let cable_delay = round_trip
+        .saturating_sub(loop_delay(child.ports[0]) as i32)
+        .max(0) as u32
+        / 2;
+    parent.dc_propagation_delay + sent.wrapping_sub(times[0]) + cable_delay
+}
+
+/// Speed counter start written by `DcFilterSettings::default()`, the ESC default.
+pub const DC_SPEED_COUNTER_START_DEFAULT: u16 = 0x1000;
+/// System time difference filter depth written by `DcFilterSettings::default()`.
+pub const DC_TIME_DIFFERENCE_DEPTH_DEFAULT: u8 = 4;
+/// Speed counter filter depth written by `DcFilterSettings::default()`.
+pub const DC_SPEED_COUNTER_DEPTH_DEFAULT: u8 = 12;
+
+/// Settings of the drift compensation of the slaves, written after the offset and delay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DcFilterSettings {
+    /// Speed counter start (0x0930). Smaller values follow the drift faster but rougher.
+    pub speed_counter_start: u16,
+    /// System time difference filter depth (0x0934), 0 to 15.
+    /// `EscQuirks::dc_filter_depth` of the slave takes precedence.
+    pub time_difference_depth: u8,
+    /// Speed counter filter depth (0x0935), 0 to 15.
+    pub speed_counter_depth: u8,
+}
+
+impl Default for DcFilterSettings {
+    fn default() -> Self {
+        Self {
+            speed_counter_start: DC_SPEED_COUNTER_START_DEFAULT,
+            time_difference_depth: DC_TIME_DIFFERENCE_DEPTH_DEFAULT,
+            speed_counter_depth: DC_SPEED_COUNTER_DEPTH_DEFAULT,
+        }
+    }
+}
+
+pub struct DcInitializer<'a, 'b, D, T>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+{
+    iface: &'a mut EtherCATInterface<'b, D, T>,
+    filter: Option<DcFilterSettings>,
+}
+
+impl<'a, 'b, D, T> DcInitializer<'a, 'b, D, T>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+{
+    pub fn new(iface: &'a mut EtherCATInterface<'b, D, T>) -> Self {
+        Self {
+            iface,
+            filter: None,
+        }
+    }
+
+    /// Writes `filter` to every DC slave in `init_dc`, `restore_dc` and `reinit_dc`.
+    /// Without it, only slaves with `EscQuirks::dc_filter_depth` are written, with the other
+    /// values of `DcFilterSettings::default()`.
+    pub fn set_filter_settings(&mut self, filter: DcFilterSettings) {
+        self.filter = Some(filter);
+    }
+
+    /// Distributes the system time of the reference clock up to `max_rounds` times, until the
+    /// system time difference of every slave is below `threshold_ns`, so that the drift
+    /// filters have settled before the slaves go to SafeOp.
+    /// The differences are read with a BRD in the same frame, which ORs them together; the
+    /// result is never smaller than the largest difference.
+    /// Returns the number of rounds, or None if the differences did not settle.
+    pub fn wait_for_settle(
+        &mut self,
+        reference_clock: &Slave,
+        threshold_ns: u32,
+        max_rounds: u32,
+    ) -> Result<Option<u32>, CommonError> {
+        for round in 1..=max_rounds {
+            self.iface.add_command(
+                0,
+                CommandType::FRMW,
+                reference_clock.configured_address,
+                DCSystemTime::ADDRESS,
+                DCSystemTime::SIZE,
+                |buf| buf.iter_mut().for_each(|b| *b = 0),
+            )?;
+            self.iface.add_command(
+                1,
+                CommandType::BRD,
+                0,
+                DCSystemTimeDifference::ADDRESS,
+                DCSystemTimeDifference::SIZE,
+                |buf| buf.iter_mut().for_each(|b| *b = 0),
+            )?;
+            self.iface.poll(RECEIVE_TIMEOUT_DEFAULT)?;
+            let mut difference = None;
+            for pdu in self.iface.consume_command() {
+                // 応答したスレーブがいなければ差は読めていない
+                if pdu.index() == 1 && pdu.wkc().unwrap_or(0) != 0 {
+                    difference = Some(DCSystemTimeDifference(pdu.data()).difference_magnitude());
+                }
+            }
+            let difference = difference.ok_or(CommonError::PacketDropped)?;
+            if difference < threshold_ns {
+                return Ok(Some(round));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Measures the propagation delays and writes the system time offset and delay
+    /// of every DC slave, so that their system time starts at `master_time` (ns since 2000).
+    /// If the device has hardware timestamps, the transmission time of the latch frame is
+    /// used instead, which removes the software delay between reading the clock and sending.
+    /// `slaves` must be all slaves in the order of the positions, so that junctions are
+    /// taken into account.
+    /// Returns the index of the reference clock (the first DC slave) in `slaves`.
+    pub fn init_dc(
+        &mut self,
+        slaves: &mut [Slave],
+        master_time: u64,
+    ) -> Result<Option<usize>, CommonError> {
+        // 全スレーブのポート受信時刻をラッチする
+        self.iface.add_command(
+            u8::MAX,
+            CommandType::BWR,
+            0,
+            DCRecieveTime::ADDRESS,
+            4,
+            |buf| buf.iter_mut().for_each(|b| *b = 0),
+        )?;
+        self.iface.poll(RECEIVE_TIMEOUT_DEFAULT)?;
+        self.iface.consume_command();
+        // ハードウェアタイムスタンプがあれば、ラッチしたフレームの送信時刻を基準にする
+        let master_time = self.iface.last_tx_timestamp().unwrap_or(master_time);
+
+        // 分岐があってもポートの順に親子関係をたどって遅延を求める
+        build_topology(slaves);
+        let mut reference = None;
+        for i in 0..slaves.len() {
+            let (upstream, rest) = slaves.split_at_mut(i);
+            let slave = &mut rest[0];
+            let parent = slave
+                .parent
+                .and_then(|(position, port)| Some((upstream.get(position as usize)?, port)));
+            if !slave.support_dc {
+                // DCのないスレーブは時刻をラッチできないので、親の遅延で代用する
+                slave.dc_propagation_delay = parent
+                    .map(|(parent, _)| parent.dc_propagation_delay)
+                    .unwrap_or(0);
+                continue;
+            }
+            let address = SlaveAddress::StationAddress(slave.configured_address);
+            // ポート受信時刻はDCの幅に関わらず32bit
+            let receive_time = self.iface.read_dc_recieve_time(address)?;
+            slave.dc_receive_times = [
+                receive_time.receive_time_port0(),
+                receive_time.receive_time_port1(),
+                receive_time.receive_time_port2(),
+                receive_time.receive_time_port3(),
+            ];
+            let delay = match (reference, parent) {
+                (None, _) => {
+                    reference = Some(i);
+                    0
+                }
+                (Some(_), Some((parent, port))) if parent.support_dc => {
+                    propagation_delay(parent, port as usize, slave)
+                }
+                (Some(_), Some((parent, _))) => parent.dc_propagation_delay,
+                (Some(_), None) => 0,
+            };
+            slave.dc_propagation_delay = delay;
+
+            let local_time = self
+                .iface
+                .read_dc_recieve_time_processing_unit(address)?
+                .receive_time_processing_unit();
+            let offset = master_time
+                .wrapping_add(delay as u64)
+                .wrapping_sub(local_time);
+            self.write_offset_and_delay(slave, offset)?;
+        }
+        Ok(reference)
+    }
+
+    /// Same as `init_dc`, but uses the propagation delays already stored in `slaves`,
+    /// e.g. loaded from a `ConfigBlob`, instead of measuring them.
+    pub fn restore_dc(
+        &mut self,
+        slaves: &[Slave],
+        master_time: u64,
+    ) -> Result<Option<usize>, CommonError> {
+        // オフセットの計算に使うローカル時刻をラッチする
+        self.iface.add_command(
+            u8::MAX,
+            CommandType::BWR,
+            0,
+            DCRecieveTime::ADDRESS,
+            4,
+            |buf| buf.iter_mut().for_each(|b| *b = 0),
+        )?;
+        self.iface.poll(RECEIVE_TIMEOUT_DEFAULT)?;
+        self.iface.consume_command();
+
+        let mut reference = None;
+        for (i, slave) in slaves.iter().enumerate().filter(|(_, slave)| slave.support_dc) {
+            if reference.is_none() {
+                reference = Some(i);
+            }
+            let address = SlaveAddress::StationAddress(slave.configured_address);
+            let local_time = self
+                .iface
+                .read_dc_recieve_time_processing_unit(address)?
+                .receive_time_processing_unit();
+            let offset = master_time
+                .wrapping_add(slave.dc_propagation_delay as u64)
+                .wrapping_sub(local_time);
+            self.write_offset_and_delay(slave, offset)?;
+        }
+        Ok(reference)
+    }
+
+    /// Sets the system time offset and delay of a slave that came back after a power cycle,
+    /// without disturbing the clocks of the other slaves.
+    /// The propagation delay measured by `init_dc` is reused since the topology is unchanged.
+    pub fn reinit_dc(&mut self, slave: &Slave, reference_clock: &Slave) -> Result<(), CommonError> {
+        let address = SlaveAddress::StationAddress(slave.configured_address);
+        self.iface.write_dc_system_time_offset(address, None)?;
+
+        // 同じフレームでリファレンスクロックのシステム時刻とスレーブのローカル時刻を読む
+        for (i, slave_address) in [reference_clock.configured_address, slave.configured_address]
+            .iter()
+            .enumerate()
+        {
+            self.iface.add_command(
+                i as u8,
+                CommandType::FPRD,
+                *slave_address,
+                DCSystemTime::ADDRESS,
+                DCSystemTime::SIZE,
+                |_| (),
+            )?;
+        }
+        self.iface.poll(RECEIVE_TIMEOUT_DEFAULT)?;
+        let mut times = [None; 2];
+        for pdu in self.iface.consume_command() {
+            check_command_wkc(&pdu, 1)?;
+            if let Some(time) = times.get_mut(pdu.index() as usize) {
+                *time = Some(DCSystemTime(pdu.data()).local_system_time());
+            }
+        }
+        let reference_time = times[0].ok_or(CommonError::PacketDropped)?;
+        let local_time = times[1].ok_or(CommonError::PacketDropped)?;
+
+        // フレームはリファレンスクロックから伝搬遅延の差だけ遅れてスレーブに届く
+        let offset = reference_time
+            .wrapping_add(slave.dc_propagation_delay as u64)
+            .wrapping_sub(reference_clock.dc_propagation_delay as u64)
+            .wrapping_sub(local_time);
+        self.write_offset_and_delay(slave, offset)
+    }
+
+    fn write_offset_and_delay(&mut self, slave: &Slave, offset: u64) -> Result<(), CommonError> {
+        let address = SlaveAddress::StationAddress(slave.configured_address);
+        let is_64bits = slave.is_dc_range_64bits;
+        let offset = dc_time(offset, is_64bits);
+        // 32bitのスレーブには下位4バイトだけ書く
+        let offset_size = if is_64bits {
+            DCSystemTimeOffset::SIZE
+        } else {
+            4
+        };
+        self.iface.write_register(
+            address,
+            DCSystemTimeOffset::ADDRESS,
+            offset_size,
+            |buf| buf.copy_from_slice(&offset.to_le_bytes()[..offset_size]),
+        )?;
+        let mut transmission_delay = DCSystemTimeTransmissionDelay::new();
+        transmission_delay.set_system_time_transmission_delay(slave.dc_propagation_delay);
+        self.iface
+            .write_dc_system_time_transmission_delay(address, Some(transmission_delay))?;
+        self.write_filter(slave)
+    }
+
+    fn write_filter(&mut self, slave: &Slave) -> Result<(), CommonError> {
+        let mut filter = match (self.filter, slave.quirks.dc_filter_depth) {
+            (None, None) => return Ok(()),
+            (filter, _) => filter.unwrap_or_default(),
+        };
+        if let Some(depth) = slave.quirks.dc_filter_depth {
+            filter.time_difference_depth = depth;
+        }
+        let address = SlaveAddress::StationAddress(slave.configured_address);
+        let mut depth = DCFilterDepth::new();
+        depth.set_system_time_difference_depth(filter.time_difference_depth);
+        depth.set_speed_counter_depth(filter.speed_counter_depth);
+        self.iface.write_dc_filter_depth(address, Some(depth))?;
+        // スピードカウンタ開始値の書き込みでフィルタがリセットされるので最後に書く
+        let mut speed_counter = DCSpeedCounterStart::new();
+        speed_counter.set_speed_counter_start(filter.speed_counter_start);
+        self.iface
+            .write_dc_speed_counter_start(address, Some(speed_counter))?;
+        Ok(())
+    }
+}
+
+/// Distributes the system time of the reference clock to the other DC slaves
+/// every cycle with FRMW, which keeps their clocks from drifting apart.
+#[derive(Debug, Clone)]
+pub struct SystemTimeDistributor {
+    reference_clock: u16,
+    is_64bits: bool,
+    num_dc_slaves: u16,
+    buffer: [u8; DCSystemTime::SIZE],
+    system_time: u64,
+}
+
+impl SystemTimeDistributor {
+    /// `num_dc_slaves` includes the reference clock.
+    /// With a 32-bit reference clock, only the lower 32 bits of the system time are valid.
+    pub fn new(reference_clock: &Slave, num_dc_slaves: u16) -> Self {
+        Self {
+            reference_clock: reference_clock.configured_address,
+            is_64bits: reference_clock.is_dc_range_64bits,
+            num_dc_slaves,
+            buffer: [0; DCSystemTime::SIZE],
+            system_time: 0,
+        }
+    }
+
+    /// The reference clock reads (+1) and every other DC slave writes (+1).
+    pub fn expected_wkc(&self) -> u16 {
+        expected_wkc(CommandType::FRMW, self.num_dc_slaves)
+    }
+
+    /// System time of the reference clock received in the last cycle.
+    pub fn system_time(&self) -> u64 {
+        self.system_time
+    }
+
+    pub(crate) fn process(&mut self) -> Option<(Command, &[u8])> {
+        self.buffer = [0; DCSystemTime::SIZE];
+        let command = Command::new(
+            CommandType::FRMW,
+            self.reference_clock,
+            DCSystemTime::ADDRESS,
+        );
+        Some((command, &self.buffer))
+    }
+
+    pub(crate) fn receive(&mut self, _command: Command, data: &[u8], wkc: u16) -> bool {
+        if wkc != self.expected_wkc() || data.len() < DCSystemTime::SIZE {
+            return false;
+        }
+        self.system_time = dc_time(DCSystemTime(data).local_system_time(), self.is_64bits);
+        true
+    }
+}
+
+/// Default depth of the filter of `BusTimeConverter`.
+/// Each observation corrects the mapping by 1/2^depth of its error.
+pub const BUS_TIME_FILTER_DEPTH_DEFAULT: u8 = 3;
+
+/// Limit of the drift estimated by `BusTimeConverter`, ±500 ppm.
+/// Quartz oscillators of the master and the slaves stay well within it.
+pub const BUS_TIME_MAX_DRIFT_PPB: i64 = 500_000;
+
+/// Observations closer than this to the last one are ignored by `BusTimeConverter::update`,
+/// as the jitter of the timestamps would dominate the drift computed from them.
+pub const BUS_TIME_MIN_INTERVAL_NS: i64 = 100_000;
+
+/// Affine mapping between a monotonic clock of the master (ns) and the system time of the
+/// DC bus (ns since 2000), e.g. to schedule the transmission of a frame at a bus time
+/// or to timestamp events of the application in bus time.
+///
+/// Create it after `init_dc` with the `master_time` passed to it and the local time at which
+/// it was read, then refine it every cycle with `update`, e.g. with
+/// `SystemTimeDistributor::system_time` and the local time the frame was sent.
+#[derive(Debug, Clone)]
+pub struct BusTimeConverter {
+    is_64bits: bool,
+    local_origin: u64,
+    bus_origin: u64,
+    drift_ppb: i64,
+    jitter_ns: u32,
+    filter_depth: u8,
+}
+
+impl BusTimeConverter {
+    /// `local_time` and `bus_time` are the same instant in both clocks.
+    /// With a 32-bit reference clock, only the lower 32 bits of the bus time are valid.
+    pub fn new(reference_clock: &Slave, local_time: u64, bus_time: u64) -> Self {
+        let is_64bits = reference_clock.is_dc_range_64bits;
+        Self {
+            is_64bits,
+            local_origin: local_time,
+            bus_origin: dc_time(bus_time, is_64bits),
+            drift_ppb: 0,
+            jitter_ns: 0,
+            filter_depth: BUS_TIME_FILTER_DEPTH_DEFAULT,
+        }
+    }
+
+    /// A deeper filter is less affected by the jitter of the observations,
+    /// but follows changes of the drift more slowly.
+    pub fn set_filter_depth(&mut self, depth: u8) {
+        self.filter_depth = depth.min(32);
+    }
+
+    /// Rate of the bus clock relative to the local clock, in parts per billion.
+    pub fn drift_ppb(&self) -> i64 {
+        self.drift_ppb
+    }
+
+    /// Average deviation of the observations from the mapping, in ns.
+    /// An estimate of the uncertainty of the conversions.
+    pub fn jitter_ns(&self) -> u32 {
+        self.jitter_ns
+    }
+
+    /// Refines the mapping with an observation of the same instant in both clocks.
+    /// Observations older than the last one or within `BUS_TIME_MIN_INTERVAL_NS` of it
+    /// are ignored.
+    pub fn update(&mut self, local_time: u64, bus_time: u64) {
+        let elapsed = local_time.wrapping_sub(self.local_origin) as i64;
+        if elapsed < BUS_TIME_MIN_INTERVAL_NS {
+            return;
+        }
+        let predicted = self.to_bus_time(local_time);
+        let error = dc_time_diff(bus_time, predicted, self.is_64bits);
+        let divisor = 1i64 << self.filter_depth;
+        // 誤差をオフセットとドリフトの両方に少しずつ反映する(PLLの比例項と積分項)
+        let drift_error = (error as i128 * 1_000_000_000 / elapsed as i128) as i64;
+        self.drift_ppb = self
+            .drift_ppb
+            .saturating_add(drift_error / divisor)
+            .clamp(-BUS_TIME_MAX_DRIFT_PPB, BUS_TIME_MAX_DRIFT_PPB);
+        let deviation = error.unsigned_abs().min(u32::MAX as u64) as i64;
+        self.jitter_ns =
+            (self.jitter_ns as i64 + (deviation - self.jitter_ns as i64) / divisor) as u32;
+        self.local_origin = local_time;
+        self.bus_origin = dc_time(
+            predicted.wrapping_add((error / divisor) as u64),
+            self.is_64bits,
+        );
+    }
+
+    /// Bus time at `local_time`.
+    pub fn to_bus_time(&self, local_time: u64) -> u64 {
+        let elapsed = local_time.wrapping_sub(self.local_origin) as i64 as i128;
+        let elapsed = elapsed + elapsed * self.drift_ppb as i128 / 1_000_000_000;
+        dc_time(self.bus_origin.wrapping_add(elapsed as u64), self.is_64bits)
+    }
+
+    /// Local time at `bus_time`.
+    /// With a 32-bit reference clock, `bus_time` must be within about 2 s of the last update.
+    pub fn to_local_time(&self, bus_time: u64) -> u64 {
+        let elapsed = dc_time_diff(bus_time, self.bus_origin, self.is_64bits) as i128;
+        let elapsed = elapsed * 1_000_000_000 / (1_000_000_000 + self.drift_ppb as i128);
+        self.local_origin.wrapping_add(elapsed as u64)
+    }
+}
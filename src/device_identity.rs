@@ -0,0 +1,133 @@
+use crate::arch::*;
+use crate::interface::*;
+use crate::sdo::*;
+use crate::slave_status::*;
+use embedded_hal::timer::CountDown;
+use fugit::*;
+
+pub const DEVICE_TYPE_INDEX: u16 = 0x1000;
+pub const DEVICE_NAME_INDEX: u16 = 0x1008;
+pub const HARDWARE_VERSION_INDEX: u16 = 0x1009;
+pub const SOFTWARE_VERSION_INDEX: u16 = 0x100A;
+pub const IDENTITY_INDEX: u16 = 0x1018;
+
+/// Maximum length of the name and version strings. Longer strings are left None.
+pub const IDENTITY_STRING_LENGTH: usize = 64;
+
+/// Identity of a slave from the SII and the standard CoE objects, e.g. for asset management.
+/// CoE objects the slave does not have are None.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceIdentity {
+    pub position: u16,
+    pub sii: Identification,
+    pub device_type: Option<u32>,
+    pub device_name: Option<heapless::String<IDENTITY_STRING_LENGTH>>,
+    pub hardware_version: Option<heapless::String<IDENTITY_STRING_LENGTH>>,
+    pub software_version: Option<heapless::String<IDENTITY_STRING_LENGTH>>,
+    pub vender_id: Option<u32>,
+    pub product_code: Option<u32>,
+    pub revision_number: Option<u32>,
+    pub serial_number: Option<u32>,
+}
+
+impl DeviceIdentity {
+    /// False if the identity object (0x1018) disagrees with the SII.
+    pub fn is_consistent(&self) -> bool {
+        // SIIの値は下位16bitしか保持していない
+        let matches = |coe: Option<u32>, sii: u16| coe.map_or(true, |coe| coe as u16 == sii);
+        matches(self.vender_id, self.sii.vender_id)
+            && matches(self.product_code, self.sii.product_code)
+            && matches(self.revision_number, self.sii.revision_number)
+    }
+}
+
+pub struct DeviceIdentityReader<'a, 'b, D, T, U>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+    U: CountDown<Time = MicrosDurationU32>,
+{
+    sdo: SdoClient<'a, 'b, D, T, U>,
+}
+
+impl<'a, 'b, D, T, U> DeviceIdentityReader<'a, 'b, D, T, U>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+    U: CountDown<Time = MicrosDurationU32>,
+{
+    pub fn new(iface: &'a mut EtherCATInterface<'b, D, T>, timer: &'a mut U) -> Self {
+        Self {
+            sdo: SdoClient::new(iface, timer),
+        }
+    }
+
+    /// Reads the identity of every slave into `identities[n]` for `slaves[n]`.
+    /// Slaves without CoE only have the SII identity.
+    pub fn read_all(
+        &mut self,
+        slaves: &mut [Slave],
+        identities: &mut [DeviceIdentity],
+    ) -> Result<(), SdoError> {
+        for (slave, identity) in slaves.iter_mut().zip(identities.iter_mut()) {
+            *identity = self.read(slave)?;
+        }
+        Ok(())
+    }
+
+    /// Objects the slave aborts are left None. Other errors, e.g. a mailbox timeout, are returned.
+    pub fn read(&mut self, slave: &mut Slave) -> Result<DeviceIdentity, SdoError> {
+        let mut identity = DeviceIdentity {
+            position: slave.position_address,
+            sii: slave.id.clone(),
+            ..Default::default()
+        };
+        if !slave.has_coe {
+            return Ok(identity);
+        }
+        identity.device_type = self.read_u32(slave, DEVICE_TYPE_INDEX, 0)?;
+        identity.device_name = self.read_string(slave, DEVICE_NAME_INDEX)?;
+        identity.hardware_version = self.read_string(slave, HARDWARE_VERSION_INDEX)?;
+        identity.software_version = self.read_string(slave, SOFTWARE_VERSION_INDEX)?;
+        identity.vender_id = self.read_u32(slave, IDENTITY_INDEX, 1)?;
+        identity.product_code = self.read_u32(slave, IDENTITY_INDEX, 2)?;
+        identity.revision_number = self.read_u32(slave, IDENTITY_INDEX, 3)?;
+        identity.serial_number = self.read_u32(slave, IDENTITY_INDEX, 4)?;
+        Ok(identity)
+    }
+
+    fn read_u32(
+        &mut self,
+        slave: &mut Slave,
+        index: u16,
+        sub_index: u8,
+    ) -> Result<Option<u32>, SdoError> {
+        let mut buf = [0; 4];
+        match self.sdo.upload(slave, index, sub_index, &mut buf) {
+            Ok(_) => Ok(Some(u32::from_le_bytes(buf))),
+            Err(SdoError::Abort(_)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn read_string(
+        &mut self,
+        slave: &mut Slave,
+        index: u16,
+    ) -> Result<Option<heapless::String<IDENTITY_STRING_LENGTH>>, SdoError> {
+        let mut buf = [0; IDENTITY_STRING_LENGTH];
+        let size = match self.sdo.upload(slave, index, 0, &mut buf) {
+            Ok(size) => size,
+            // 長すぎる文字列は読まない
+            Err(SdoError::Abort(_)) | Err(SdoError::SmallBuffer) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        // 終端のNULで切り詰める
+        let len = buf[..size].iter().position(|b| *b == 0).unwrap_or(size);
+        let mut string = heapless::String::new();
+        for c in buf[..len].iter().map(|b| *b as char) {
+            let _ = string.push(if c.is_ascii() { c } else { '?' });
+        }
+        Ok(Some(string))
+    }
+}
@@ -0,0 +1,195 @@
+use crate::arch::*;
+use crate::error::*;
+use crate::interface::*;
+use crate::packet::CommandType;
+use crate::register::datalink::*;
+use crate::slave_status::*;
+use crate::RECEIVE_TIMEOUT_DEFAULT;
+use embedded_hal::timer::CountDown;
+use fugit::*;
+
+// 0x0300から0x0313までをまとめて読む
+const ERROR_COUNTERS_SIZE: usize =
+    (LostLinkCounter::ADDRESS - RxErrorCounter::ADDRESS) as usize + LostLinkCounter::SIZE;
+// 1フレームにまとめるスレーブ数
+const SLAVES_PER_FRAME: usize = 32;
+
+/// Error counters of the ports of a slave at one point in time.
+/// The counters saturate at 255 and are cleared by writing them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PortErrorCounters {
+    pub frame_errors: [u8; 4],
+    pub phy_errors: [u8; 4],
+    pub forwarded_errors: [u8; 4],
+    pub lost_links: [u8; 4],
+}
+
+impl PortErrorCounters {
+    fn from_registers(buf: &[u8]) -> Self {
+        let rx = RxErrorCounter(&buf[..RxErrorCounter::SIZE]);
+        let forwarded_offset =
+            (ForwardedRxErrorCounter::ADDRESS - RxErrorCounter::ADDRESS) as usize;
+        let forwarded = ForwardedRxErrorCounter(&buf[forwarded_offset..]);
+        let lost_offset = (LostLinkCounter::ADDRESS - RxErrorCounter::ADDRESS) as usize;
+        let lost = LostLinkCounter(&buf[lost_offset..]);
+        Self {
+            frame_errors: [
+                rx.frame_error_count_port0(),
+                rx.frame_error_count_port1(),
+                rx.frame_error_count_port2(),
+                rx.frame_error_count_port3(),
+            ],
+            phy_errors: [
+                rx.phy_error_count_port0(),
+                rx.phy_error_count_port1(),
+                rx.phy_error_count_port2(),
+                rx.phy_error_count_port3(),
+            ],
+            forwarded_errors: [
+                forwarded.forwarded_error_count_port0(),
+                forwarded.forwarded_error_count_port1(),
+                forwarded.forwarded_error_count_port2(),
+                forwarded.forwarded_error_count_port3(),
+            ],
+            lost_links: [
+                lost.lost_link_count_port0(),
+                lost.lost_link_count_port1(),
+                lost.lost_link_count_port2(),
+                lost.lost_link_count_port3(),
+            ],
+        }
+    }
+}
+
+/// A link where errors are introduced: the cable at `port` of the slave at `position`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkSuspect {
+    pub position: u16,
+    pub port: u8,
+    /// CRC errors first detected at this port, not forwarded by a previous slave.
+    pub frame_errors: u8,
+    pub phy_errors: u8,
+    pub lost_links: u8,
+}
+
+impl LinkSuspect {
+    /// Weight used to rank the suspects. Errors of the frame count most.
+    pub fn score(&self) -> u32 {
+        self.frame_errors as u32 * 4 + self.lost_links as u32 * 2 + self.phy_errors as u32
+    }
+}
+
+/// Locates the links that corrupt frames from the change of the error counters
+/// of all slaves during a test interval.
+pub struct FrameLossAnalyzer<'a, 'b, D, T>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+{
+    iface: &'a mut EtherCATInterface<'b, D, T>,
+}
+
+impl<'a, 'b, D, T> FrameLossAnalyzer<'a, 'b, D, T>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+{
+    pub fn new(iface: &'a mut EtherCATInterface<'b, D, T>) -> Self {
+        Self { iface }
+    }
+
+    /// Reads the error counters of `slaves` into `counters`, e.g. all slaves or
+    /// `NetworkDescription::slaves_in(group)`.
+    /// Call before and after the test interval and pass both to `analyze`.
+    pub fn snapshot<'s, 'c: 's, I: IntoIterator<Item = &'s Slave<'c>>>(
+        &mut self,
+        slaves: I,
+        counters: &mut [PortErrorCounters],
+    ) -> Result<(), CommonError> {
+        let mut slaves = slaves.into_iter().peekable();
+        let mut start = 0;
+        while start < counters.len() && slaves.peek().is_some() {
+            let mut transaction = self.iface.transaction()?;
+            let mut end = start;
+            while end < counters.len() && end - start < SLAVES_PER_FRAME {
+                let slave = match slaves.next() {
+                    Some(slave) => slave,
+                    None => break,
+                };
+                transaction.read(
+                    SlaveAddress::StationAddress(slave.configured_address),
+                    RxErrorCounter::ADDRESS,
+                    ERROR_COUNTERS_SIZE,
+                )?;
+                end += 1;
+            }
+            let results = transaction.execute()?;
+            for (entry, counter) in counters[start..end].iter_mut().enumerate() {
+                *counter = PortErrorCounters::from_registers(results.data(entry)?);
+            }
+            start = end;
+        }
+        Ok(())
+    }
+
+    /// Clears the error counters of all slaves.
+    pub fn clear(&mut self) -> Result<(), CommonError> {
+        // 値に関わらず書き込むとカウンタはクリアされる
+        self.iface.add_command(
+            u8::MAX,
+            CommandType::BWR,
+            0,
+            RxErrorCounter::ADDRESS,
+            ERROR_COUNTERS_SIZE,
+            |buf| buf.iter_mut().for_each(|b| *b = 0),
+        )?;
+        self.iface.poll(RECEIVE_TIMEOUT_DEFAULT)?;
+        self.iface.consume_command();
+        Ok(())
+    }
+}
+
+/// Fills `suspects` with the links where errors appeared between `before` and `after`,
+/// most suspicious first, and returns the number of suspects.
+/// If there are more suspects than `suspects` can hold, only the highest ranked ones are kept.
+/// `slaves` must be the same as given to `FrameLossAnalyzer::snapshot`.
+pub fn analyze<'s, 'b: 's, I: IntoIterator<Item = &'s Slave<'b>>>(
+    slaves: I,
+    before: &[PortErrorCounters],
+    after: &[PortErrorCounters],
+    suspects: &mut [LinkSuspect],
+) -> usize {
+    let mut num_suspects = 0;
+    for (i, slave) in slaves.into_iter().enumerate() {
+        let (before, after) = match (before.get(i), after.get(i)) {
+            (Some(before), Some(after)) => (before, after),
+            _ => break,
+        };
+        for port in 0..4 {
+            let frame_errors = after.frame_errors[port].saturating_sub(before.frame_errors[port]);
+            let forwarded =
+                after.forwarded_errors[port].saturating_sub(before.forwarded_errors[port]);
+            // 前のスレーブで検出済みのエラーは、このポートのケーブルが原因ではない
+            let suspect = LinkSuspect {
+                position: slave.position_address,
+                port: port as u8,
+                frame_errors: frame_errors.saturating_sub(forwarded),
+                phy_errors: after.phy_errors[port].saturating_sub(before.phy_errors[port]),
+                lost_links: after.lost_links[port].saturating_sub(before.lost_links[port]),
+            };
+            if suspect.score() == 0 {
+                continue;
+            }
+            if num_suspects < suspects.len() {
+                suspects[num_suspects] = suspect;
+                num_suspects += 1;
+            } else if let Some(lowest) = suspects.iter_mut().min_by_key(|s| s.score()) {
+                if lowest.score() < suspect.score() {
+                    *lowest = suspect;
+                }
+            }
+        }
+    }
+    suspects[..num_suspects].sort_unstable_by(|a, b| b.score().cmp(&a.score()));
+    num_suspects
+}
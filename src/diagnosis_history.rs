@@ -0,0 +1,257 @@
+use crate::arch::*;
+use crate::interface::*;
+use crate::packet::byte_order::*;
+use crate::sdo::*;
+use crate::slave_status::*;
+use embedded_hal::timer::CountDown;
+use fugit::*;
+
+pub const DIAGNOSIS_HISTORY_INDEX: u16 = 0x10F3;
+
+/// Maximum bytes of the parameters of a message. The parameters of longer messages are cut off.
+pub const DIAG_PARAMETERS_LENGTH: usize = 128;
+
+const MAXIMUM_MESSAGES: u8 = 1;
+const NEWEST_MESSAGE: u8 = 2;
+const NEWEST_ACKNOWLEDGED_MESSAGE: u8 = 3;
+const NEW_MESSAGES_AVAILABLE: u8 = 4;
+const FLAGS: u8 = 5;
+/// Sub-index of the first message.
+pub const DIAG_MESSAGE_FIRST_SUB_INDEX: u8 = 6;
+
+// 診断コード、フラグ、テキストID、タイムスタンプ
+const DIAG_MESSAGE_HEADER_LENGTH: usize = 16;
+
+/// Entries of the diagnosis history object before the messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiagHistory {
+    /// Number of messages the slave keeps.
+    pub maximum_messages: u8,
+    /// Sub-index of the newest message, 0 if there is none.
+    pub newest_message: u8,
+    /// Sub-index of the newest acknowledged message, 0 if none is acknowledged.
+    pub newest_acknowledged_message: u8,
+    pub new_messages_available: bool,
+    /// Flags (sub-index 5), e.g. whether new messages are sent as emergencies.
+    pub flags: Option<u16>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagMessageType {
+    Info,
+    Warning,
+    Error,
+    Reserved(u8),
+}
+
+impl From<u8> for DiagMessageType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::Info,
+            1 => Self::Warning,
+            2 => Self::Error,
+            other => Self::Reserved(other),
+        }
+    }
+}
+
+/// Message of the diagnosis history.
+#[derive(Debug, Clone)]
+pub struct DiagMessage {
+    pub sub_index: u8,
+    pub diag_code: u32,
+    pub message_type: DiagMessageType,
+    /// The timestamp is the local time of the slave instead of the DC system time.
+    pub is_local_time: bool,
+    /// Text of the message in the ESI of the slave.
+    pub text_id: u16,
+    /// Time of the message in ns, the DC system time unless `is_local_time`.
+    pub timestamp: u64,
+    pub number_of_parameters: u8,
+    /// Parameters of the message as sent, each a 16-bit flags word followed by its data.
+    pub parameters: heapless::Vec<u8, DIAG_PARAMETERS_LENGTH>,
+}
+
+impl DiagMessage {
+    /// Decodes a message entry. Returns None for an empty entry.
+    pub fn parse(sub_index: u8, data: &[u8]) -> Option<Self> {
+        if data.len() < DIAG_MESSAGE_HEADER_LENGTH {
+            return None;
+        }
+        // 未使用のエントリは0で埋まっていることがある
+        let diag_code = read_u32(data, 0)?;
+        if diag_code == 0 {
+            return None;
+        }
+        let flags = read_u16(data, 4)?;
+        let mut parameters = heapless::Vec::new();
+        let rest = &data[DIAG_MESSAGE_HEADER_LENGTH..];
+        parameters
+            .extend_from_slice(&rest[..rest.len().min(DIAG_PARAMETERS_LENGTH)])
+            .ok()?;
+        Some(Self {
+            sub_index,
+            diag_code,
+            message_type: DiagMessageType::from(flags as u8 & 0x0F),
+            is_local_time: flags & (1 << 4) != 0,
+            text_id: read_u16(data, 6)?,
+            timestamp: read_u64(data, 8)?,
+            number_of_parameters: (flags >> 8) as u8,
+            parameters,
+        })
+    }
+}
+
+/// Reads the diagnosis history object (0x10F3), through which many slaves report their
+/// diagnostics with text IDs of their ESI and timestamps.
+pub struct DiagnosisHistoryReader<'a, 'b, D, T, U>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+    U: CountDown<Time = MicrosDurationU32>,
+{
+    sdo: SdoClient<'a, 'b, D, T, U>,
+    buffer: [u8; DIAG_MESSAGE_HEADER_LENGTH + DIAG_PARAMETERS_LENGTH],
+}
+
+impl<'a, 'b, D, T, U> DiagnosisHistoryReader<'a, 'b, D, T, U>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+    U: CountDown<Time = MicrosDurationU32>,
+{
+    pub fn new(iface: &'a mut EtherCATInterface<'b, D, T>, timer: &'a mut U) -> Self {
+        Self {
+            sdo: SdoClient::new(iface, timer),
+            buffer: [0; DIAG_MESSAGE_HEADER_LENGTH + DIAG_PARAMETERS_LENGTH],
+        }
+    }
+
+    pub fn history(&mut self, slave: &mut Slave) -> Result<DiagHistory, SdoError> {
+        let maximum_messages = self.read(slave, MAXIMUM_MESSAGES)? as u8;
+        let newest_message = self.read(slave, NEWEST_MESSAGE)? as u8;
+        let newest_acknowledged_message = self.read(slave, NEWEST_ACKNOWLEDGED_MESSAGE)? as u8;
+        let new_messages_available = self.read(slave, NEW_MESSAGES_AVAILABLE)? & 1 != 0;
+        let flags = match self.read(slave, FLAGS) {
+            Ok(flags) => Some(flags as u16),
+            // フラグはオプション
+            Err(SdoError::Abort(_)) => None,
+            Err(err) => return Err(err),
+        };
+        Ok(DiagHistory {
+            maximum_messages,
+            newest_message,
+            newest_acknowledged_message,
+            new_messages_available,
+            flags,
+        })
+    }
+
+    /// Reads the message at `sub_index`. Returns None for an empty entry.
+    /// Parameters beyond `DIAG_PARAMETERS_LENGTH` bytes are cut off.
+    pub fn message(
+        &mut self,
+        slave: &mut Slave,
+        sub_index: u8,
+    ) -> Result<Option<DiagMessage>, SdoError> {
+        let buffer = &mut self.buffer;
+        let mut size = 0;
+        self.sdo
+            .upload_stream(slave, DIAGNOSIS_HISTORY_INDEX, sub_index, |data| {
+                // 長すぎるメッセージは残りを捨てて読み進める
+                let len = data.len().min(buffer.len() - size);
+                buffer[size..size + len].copy_from_slice(&data[..len]);
+                size += len;
+            })?;
+        Ok(DiagMessage::parse(sub_index, &self.buffer[..size]))
+    }
+
+    /// Messages of the slave from the oldest to the newest.
+    pub fn messages<'r, 's>(
+        &'r mut self,
+        slave: &'r mut Slave<'s>,
+    ) -> Result<DiagMessages<'r, 's, 'a, 'b, D, T, U>, SdoError> {
+        let history = self.history(slave)?;
+        let last = DIAG_MESSAGE_FIRST_SUB_INDEX.saturating_add(history.maximum_messages) - 1;
+        // 最新のメッセージの次が最も古い。リングバッファが一周していなければ空のエントリになる
+        let next = if (DIAG_MESSAGE_FIRST_SUB_INDEX..last).contains(&history.newest_message) {
+            history.newest_message + 1
+        } else {
+            DIAG_MESSAGE_FIRST_SUB_INDEX
+        };
+        let remaining = if history.newest_message == 0 {
+            0
+        } else {
+            history.maximum_messages
+        };
+        Ok(DiagMessages {
+            reader: self,
+            slave,
+            next,
+            last,
+            remaining,
+        })
+    }
+
+    /// Acknowledges the messages up to `sub_index`.
+    pub fn acknowledge(&mut self, slave: &mut Slave, sub_index: u8) -> Result<(), SdoError> {
+        self.sdo.download(
+            slave,
+            DIAGNOSIS_HISTORY_INDEX,
+            NEWEST_ACKNOWLEDGED_MESSAGE,
+            &[sub_index],
+        )
+    }
+
+    fn read(&mut self, slave: &mut Slave, sub_index: u8) -> Result<u32, SdoError> {
+        let mut buf = [0; 4];
+        self.sdo
+            .upload(slave, DIAGNOSIS_HISTORY_INDEX, sub_index, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+}
+
+/// Messages of one slave returned by `DiagnosisHistoryReader::messages`.
+/// Empty entries are skipped; an error ends the iteration.
+pub struct DiagMessages<'r, 's, 'a, 'b, D, T, U>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+    U: CountDown<Time = MicrosDurationU32>,
+{
+    reader: &'r mut DiagnosisHistoryReader<'a, 'b, D, T, U>,
+    slave: &'r mut Slave<'s>,
+    next: u8,
+    last: u8,
+    remaining: u8,
+}
+
+impl<'r, 's, 'a, 'b, D, T, U> Iterator for DiagMessages<'r, 's, 'a, 'b, D, T, U>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+    U: CountDown<Time = MicrosDurationU32>,
+{
+    type Item = Result<DiagMessage, SdoError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.remaining > 0 {
+            self.remaining -= 1;
+            let sub_index = self.next;
+            self.next = if sub_index >= self.last {
+                DIAG_MESSAGE_FIRST_SUB_INDEX
+            } else {
+                sub_index + 1
+            };
+            match self.reader.message(self.slave, sub_index) {
+                Ok(Some(message)) => return Some(Ok(message)),
+                Ok(None) => continue,
+                Err(err) => {
+                    self.remaining = 0;
+                    return Some(Err(err));
+                }
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,405 @@
+use crate::packet::byte_order::*;
+use crate::packet::coe::*;
+use crate::packet::*;
+use crate::register::{application::*, datalink::*};
+use crate::slave_status::AlState;
+
+/// Registers (0x0000 to 0x0FFF) and 4 KiB of process memory.
+pub const ESC_EMULATOR_MEMORY_SIZE: usize = 0x2000;
+/// Number of FMMUs of the emulated ESC.
+pub const ESC_EMULATOR_FMMUS: usize = 3;
+/// Number of sync managers of the emulated ESC.
+pub const ESC_EMULATOR_SYNC_MANAGERS: usize = 4;
+
+/// Emulation of a simple ESC for hardware-in-the-loop tests of the master without slaves.
+/// A companion program receives frames on a second NIC, passes them to `process_frame` and
+/// sends them back, like the last slave of a segment.
+///
+/// The register space is plain memory, except for the AL control, which is accepted at once,
+/// the SII, which reads from the given image, and the mailbox. With the mailbox echo, a message
+/// written to the sync manager 0 mailbox is returned in the sync manager 1 mailbox, e.g. to
+/// exercise the CoE layer. With an SDO object set by `set_sdo_object`, SDO uploads are answered
+/// instead.
+/// Bit-wise FMMU mappings, DC and the SII write are not emulated.
+#[derive(Debug, Clone)]
+pub struct EscEmulator<'a, const N: usize = ESC_EMULATOR_MEMORY_SIZE> {
+    memory: [u8; N],
+    sii: &'a [u8],
+    mailbox_echo: bool,
+    /// Index, sub-index and data of the object answered to SDO uploads.
+    sdo_object: Option<(u16, u8, &'a [u8])>,
+    /// Bytes of the object already sent in the segmented upload.
+    sdo_upload_offset: usize,
+}
+
+impl<'a, const N: usize> EscEmulator<'a, N> {
+    /// `sii` is the EEPROM image in bytes, starting at word 0.
+    pub fn new(sii: &'a [u8]) -> Self {
+        let mut emulator = Self {
+            memory: [0; N],
+            sii,
+            mailbox_echo: true,
+            sdo_object: None,
+            sdo_upload_offset: 0,
+        };
+        emulator.reset();
+        emulator
+    }
+
+    /// Clears the memory and restores the power-on values of the registers.
+    pub fn reset(&mut self) {
+        self.memory = [0; N];
+        let memory = &mut self.memory;
+        // ET1100相当の構成。DCはなし
+        memory[0] = 0x11;
+        memory[4] = ESC_EMULATOR_FMMUS as u8;
+        memory[5] = ESC_EMULATOR_SYNC_MANAGERS as u8;
+        memory[6] = ((N.saturating_sub(0x1000)) / 1024) as u8;
+        // PDI動作中、ポート0はリンクあり、ポート1から3は閉じている
+        let dl_status: u16 = 1 | 1 << 4 | 1 << 8 | 1 << 9 | 1 << 10 | 1 << 12 | 1 << 14;
+        write_u16(memory, DLStatus::ADDRESS as usize, dl_status);
+        write_u16(memory, ALStatus::ADDRESS as usize, AlState::Init as u16);
+        // 読み出しは8バイト単位
+        write_u16(memory, SIIControl::ADDRESS as usize, SII_READ_SIZE_8);
+    }
+
+    /// Sets whether the mailbox echo is enabled. Enabled by default.
+    pub fn set_mailbox_echo(&mut self, enable: bool) {
+        self.mailbox_echo = enable;
+    }
+
+    /// Answers SDO uploads of the object with `data`, expedited, normal or segmented as its size
+    /// requires. Other SDO requests are aborted, and messages are no longer echoed.
+    pub fn set_sdo_object(&mut self, index: u16, sub_index: u8, data: &'a [u8]) {
+        self.sdo_object = Some((index, sub_index, data));
+        self.sdo_upload_offset = 0;
+    }
+
+    /// Register space and process memory, e.g. to set the inputs or check the outputs.
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    /// Processes the datagrams of an Ethernet frame in place.
+    /// Returns false if `frame` is not an EtherCAT frame.
+    pub fn process_frame(&mut self, frame: &mut [u8]) -> bool {
+        match EthernetHeader::new(&frame[..]) {
+            Some(header) if header.ether_type() == ETHERCAT_TYPE => (),
+            _ => return false,
+        }
+        // ESCを通過したフレームは送信元MACの2ビット目が立つ
+        frame[6] |= 0x02;
+        let mut offset = ETHERNET_HEADER_LENGTH + ETHERCAT_HEADER_LENGTH;
+        loop {
+            let rest = match frame.get_mut(offset..) {
+                Some(rest) => rest,
+                None => return true,
+            };
+            let (length, has_next) = match EtherCATPDU::new(&rest[..]) {
+                Some(pdu) => (pdu.length() as usize, pdu.has_next()),
+                None => return true,
+            };
+            let size = ETHERCATPDU_HEADER_LENGTH + length + WKC_LENGTH;
+            match rest.get_mut(..size) {
+                Some(datagram) => self.process_datagram(datagram),
+                None => return true,
+            }
+            if !has_next {
+                return true;
+            }
+            offset += size;
+        }
+    }
+
+    fn process_datagram(&mut self, datagram: &mut [u8]) {
+        let mut pdu = EtherCATPDU::new_unchecked(&mut datagram[..]);
+        let command = CommandType::new(pdu.command_type());
+        let adp = pdu.adp();
+        let ado = pdu.ado() as usize;
+        let logical_address = pdu.logical_address();
+        let station_address =
+            read_u16(&self.memory, FixedStationAddress::ADDRESS as usize).unwrap_or_default();
+        use CommandType::*;
+        // 位置アドレスとブロードキャストは通過するたびにADPを進める
+        if matches!(command, APRD | APWR | APRW | ARMW | BRD | BWR | BRW) {
+            pdu.set_adp(adp.wrapping_add(1));
+        }
+        let length = datagram.len() - ETHERCATPDU_HEADER_LENGTH - WKC_LENGTH;
+        let (_, rest) = datagram.split_at_mut(ETHERCATPDU_HEADER_LENGTH);
+        let (data, wkc) = rest.split_at_mut(length);
+        let count = match command {
+            APRD if adp == 0 => self.read(ado, data, false),
+            FPRD if adp == station_address => self.read(ado, data, false),
+            BRD => self.read(ado, data, true),
+            APWR if adp == 0 => self.write(ado, data),
+            FPWR if adp == station_address => self.write(ado, data),
+            BWR => self.write(ado, data),
+            APRW if adp == 0 => self.read_write(ado, data),
+            FPRW if adp == station_address => self.read_write(ado, data),
+            BRW => self.read_write(ado, data),
+            ARMW if adp == 0 => self.read(ado, data, false),
+            FRMW if adp == station_address => self.read(ado, data, false),
+            ARMW | FRMW => self.write(ado, data),
+            LRD | LWR | LRW => self.logical(command, logical_address, data),
+            _ => 0,
+        };
+        let wkc_value = read_u16(wkc, 0).unwrap_or_default().wrapping_add(count);
+        write_u16(wkc, 0, wkc_value);
+    }
+
+    fn read(&mut self, address: usize, data: &mut [u8], is_broadcast: bool) -> u16 {
+        let memory = match self.memory.get(address..address + data.len()) {
+            Some(memory) => memory,
+            None => return 0,
+        };
+        if is_broadcast {
+            data.iter_mut().zip(memory).for_each(|(d, m)| *d |= *m);
+        } else {
+            data.copy_from_slice(memory);
+        }
+        self.after_read(address, data.len());
+        1
+    }
+
+    fn write(&mut self, address: usize, data: &[u8]) -> u16 {
+        match self.memory.get_mut(address..address + data.len()) {
+            Some(memory) => memory.copy_from_slice(data),
+            None => return 0,
+        }
+        self.after_write(address, data.len());
+        1
+    }
+
+    fn read_write(&mut self, address: usize, data: &mut [u8]) -> u16 {
+        match self.memory.get_mut(address..address + data.len()) {
+            Some(memory) => memory.swap_with_slice(data),
+            None => return 0,
+        }
+        self.after_read(address, data.len());
+        self.after_write(address, data.len());
+        3
+    }
+
+    fn logical(&mut self, command: CommandType, logical_address: u32, data: &mut [u8]) -> u16 {
+        let mut is_read = false;
+        let mut is_written = false;
+        // 入力で上書きする前に出力を取り込む
+        for pass_write in [true, false] {
+            for i in 0..ESC_EMULATOR_FMMUS {
+                let offset = FMMURegister::ADDRESS0 as usize + i * FMMURegister::SIZE;
+                let fmmu = FMMURegister(&self.memory[offset..offset + FMMURegister::SIZE]);
+                if !fmmu.enable() {
+                    continue;
+                }
+                let enabled = if pass_write {
+                    fmmu.write_enable() && command != CommandType::LRD
+                } else {
+                    fmmu.read_enable() && command != CommandType::LWR
+                };
+                if !enabled {
+                    continue;
+                }
+                let start = fmmu.logical_start_address() as u64;
+                let end = start + fmmu.length() as u64;
+                let physical = fmmu.physical_start_address() as u64;
+                let from = start.max(logical_address as u64);
+                let to = end.min(logical_address as u64 + data.len() as u64);
+                if from >= to {
+                    continue;
+                }
+                let data_range = (from - logical_address as u64) as usize
+                    ..(to - logical_address as u64) as usize;
+                let memory_start = (physical + from - start) as usize;
+                let memory_range = memory_start..memory_start + data_range.len();
+                if memory_range.end > N {
+                    continue;
+                }
+                if pass_write {
+                    self.memory[memory_range].copy_from_slice(&data[data_range]);
+                    is_written = true;
+                } else {
+                    data[data_range].copy_from_slice(&self.memory[memory_range]);
+                    is_read = true;
+                }
+            }
+        }
+        let write_count = match command {
+            CommandType::LRW => 2,
+            _ => 1,
+        };
+        is_read as u16 + if is_written { write_count } else { 0 }
+    }
+
+    fn after_read(&mut self, address: usize, length: usize) {
+        // 送信メールボックスの最後のバイトを読むと空になる
+        if let Some((start, size)) = self.mailbox(1) {
+            if covers(address, length, start + size - 1) {
+                self.memory[sm_status_address(1)] &= !SM_MAILBOX_FULL;
+            }
+        }
+    }
+
+    fn after_write(&mut self, address: usize, length: usize) {
+        if covers(address, length, ALControl::ADDRESS as usize) {
+            self.change_al_state();
+        }
+        // コマンドのビットは上位バイトにある
+        if covers(address, length, SIIControl::ADDRESS as usize + 1) {
+            self.execute_sii_command();
+        }
+        if let Some((start, size)) = self.mailbox(0) {
+            if covers(address, length, start + size - 1) {
+                self.echo_mailbox(start, size);
+            }
+        }
+    }
+
+    fn change_al_state(&mut self) {
+        let state = AlState::from(self.memory[ALControl::ADDRESS as usize] & 0x0F);
+        if state == AlState::Invalid {
+            return;
+        }
+        // 遷移はすぐに完了し、エラーも確認される
+        write_u16(&mut self.memory, ALStatus::ADDRESS as usize, state as u16);
+        write_u16(&mut self.memory, AL_STATUS_CODE_ADDRESS, 0);
+    }
+
+    fn execute_sii_command(&mut self) {
+        let control_address = SIIControl::ADDRESS as usize;
+        let control = read_u16(&self.memory, control_address).unwrap_or_default();
+        let mut status = SII_READ_SIZE_8;
+        if control & SII_READ_OPERATION != 0 {
+            let word = read_u32(&self.memory, SIIAddress::ADDRESS as usize).unwrap_or_default();
+            let data_address = SIIData::ADDRESS as usize;
+            for i in 0..SIIData::SIZE {
+                // 範囲外は未書き込みのEEPROMと同じく0xFF
+                self.memory[data_address + i] =
+                    *self.sii.get(word as usize * 2 + i).unwrap_or(&0xFF);
+            }
+        } else if control & SII_WRITE_OPERATION != 0 {
+            status |= SII_COMMAND_ERROR;
+        }
+        write_u16(&mut self.memory, control_address, status);
+    }
+
+    fn echo_mailbox(&mut self, start: usize, size: usize) {
+        if !self.mailbox_echo && self.sdo_object.is_none() {
+            return;
+        }
+        let (reply_start, reply_size) = match self.mailbox(1) {
+            Some(mailbox) => mailbox,
+            None => return,
+        };
+        let status_address = sm_status_address(1);
+        if self.memory[status_address] & SM_MAILBOX_FULL != 0 {
+            return;
+        }
+        if self.sdo_object.is_some() {
+            self.answer_sdo(start, reply_start, reply_size);
+            self.memory[status_address] |= SM_MAILBOX_FULL;
+            return;
+        }
+        let size = size.min(reply_size);
+        self.memory.copy_within(start..start + size, reply_start);
+        self.memory[status_address] |= SM_MAILBOX_FULL;
+    }
+
+    /// Writes the response to the SDO request at `start` into the read mailbox.
+    fn answer_sdo(&mut self, start: usize, reply_start: usize, reply_size: usize) {
+        let (index, sub_index, data) = match self.sdo_object {
+            Some(object) => object,
+            None => return,
+        };
+        let request = start + MAILBOX_HEADER_LENGTH + COE_HEADER_LENGTH;
+        let command = self.memory[request];
+        let is_object = read_u16(&self.memory, request + 1) == Some(index)
+            && self.memory[request + 3] == sub_index;
+        let sdo = reply_start + MAILBOX_HEADER_LENGTH + COE_HEADER_LENGTH;
+        let sdo_size = reply_size - MAILBOX_HEADER_LENGTH - COE_HEADER_LENGTH;
+        self.memory[reply_start..reply_start + reply_size].fill(0);
+        let length = if command == SDOCommand::UpReq as u8 && is_object {
+            self.memory.copy_within(request + 1..request + 4, sdo + 1);
+            if data.len() <= SDO_DATA_LENGTH {
+                let unused = (SDO_DATA_LENGTH - data.len()) as u8;
+                self.memory[sdo] = SDOCommand::UpExpRes4 as u8 | unused << 2;
+                self.memory[sdo + 4..sdo + 4 + data.len()].copy_from_slice(data);
+                SDO_HEADER_LENGTH + SDO_DATA_LENGTH
+            } else {
+                // 最初の応答に収まる分を送り、残りはセグメントで送る
+                let size = data
+                    .len()
+                    .min(sdo_size - SDO_HEADER_LENGTH - SDO_DATA_LENGTH);
+                self.memory[sdo] = SDOCommand::UpNormalRes as u8;
+                write_u32(&mut self.memory, sdo + 4, data.len() as u32);
+                let offset = sdo + SDO_HEADER_LENGTH + SDO_DATA_LENGTH;
+                self.memory[offset..offset + size].copy_from_slice(&data[..size]);
+                self.sdo_upload_offset = size;
+                SDO_HEADER_LENGTH + SDO_DATA_LENGTH + size
+            }
+        } else if command & SDO_COMMAND_SPECIFIER_MASK == SDO_UPLOAD_SEGMENT_REQUEST {
+            let rest = &data[self.sdo_upload_offset.min(data.len())..];
+            let size = rest.len().min(sdo_size - SDO_SEGMENT_HEADER_LENGTH);
+            let is_last = size == rest.len();
+            // 7バイトに満たないセグメントは未使用のバイト数を示す
+            let unused = SDO_SEGMENT_MIN_DATA_LENGTH.saturating_sub(size) as u8;
+            self.memory[sdo] = SDO_UPLOAD_SEGMENT_RESPONSE
+                | command & SDO_SEGMENT_TOGGLE
+                | unused << 1
+                | is_last as u8;
+            let offset = sdo + SDO_SEGMENT_HEADER_LENGTH;
+            self.memory[offset..offset + size].copy_from_slice(&rest[..size]);
+            self.sdo_upload_offset += size;
+            SDO_SEGMENT_HEADER_LENGTH + size.max(SDO_SEGMENT_MIN_DATA_LENGTH)
+        } else {
+            self.memory.copy_within(request + 1..request + 4, sdo + 1);
+            self.memory[sdo] = SDOCommand::Abort as u8;
+            write_u32(
+                &mut self.memory,
+                sdo + 4,
+                AbortCode::DoesNotExistInDict as u32,
+            );
+            SDO_HEADER_LENGTH + SDO_DATA_LENGTH
+        };
+        let length = (COE_HEADER_LENGTH + length) as u16;
+        write_u16(&mut self.memory, reply_start, length);
+        self.memory[reply_start + 5] = MailboxType::CoE as u8;
+        // SDO応答のサービス
+        write_u16(
+            &mut self.memory,
+            reply_start + MAILBOX_HEADER_LENGTH,
+            (CANOpenServiceType::SDORes as u16) << 12,
+        );
+    }
+
+    /// Physical start address and length of sync manager `index` if it is an enabled mailbox.
+    fn mailbox(&self, index: usize) -> Option<(usize, usize)> {
+        let offset = SyncManagerRegister::ADDRESS0 as usize + index * SyncManagerRegister::SIZE;
+        let sm = SyncManagerRegister(&self.memory[offset..offset + SyncManagerRegister::SIZE]);
+        let start = sm.physical_start_address() as usize;
+        let size = sm.length() as usize;
+        // バッファタイプ2はメールボックス
+        if !sm.channel_enable() || sm.buffer_type() != 2 || size == 0 || start + size > N {
+            return None;
+        }
+        Some((start, size))
+    }
+}
+
+const AL_STATUS_CODE_ADDRESS: usize = 0x0134;
+const SII_READ_SIZE_8: u16 = 1 << 6;
+const SII_READ_OPERATION: u16 = 1 << 8;
+const SII_WRITE_OPERATION: u16 = 1 << 9;
+const SII_COMMAND_ERROR: u16 = 1 << 13;
+const SM_MAILBOX_FULL: u8 = 1 << 3;
+
+fn sm_status_address(index: usize) -> usize {
+    SyncManagerRegister::ADDRESS0 as usize + index * SyncManagerRegister::SIZE + 5
+}
+
+fn covers(address: usize, length: usize, target: usize) -> bool {
+    address <= target && target < address + length
+}
@@ -0,0 +1,165 @@
+use crate::arch::*;
+use crate::error::*;
+use crate::interface::*;
+use crate::mailbox::*;
+use crate::packet::{eoe::*, MailboxType};
+use crate::slave_status::*;
+use embedded_hal::timer::CountDown;
+use fugit::*;
+
+#[derive(Debug, Clone)]
+pub enum EoEError {
+    Mailbox(MailboxError),
+    UnexpectedResponse,
+    ResultCode(u16),
+    TooLongDnsName,
+}
+
+impl Classify for EoEError {
+    fn classify(&self) -> (ErrorCategory, RecoveryHint) {
+        use ErrorCategory::*;
+        use RecoveryHint::*;
+        match self {
+            Self::Mailbox(err) => err.classify(),
+            Self::UnexpectedResponse => (Protocol, Retry),
+            Self::ResultCode(_) => (SlaveConfig, FixConfig),
+            Self::TooLongDnsName => (ApplicationBug, FixConfig),
+        }
+    }
+}
+
+impl From<MailboxError> for EoEError {
+    fn from(err: MailboxError) -> Self {
+        Self::Mailbox(err)
+    }
+}
+
+/// IP parameters tunneled to the slave. Fields set to None are left unchanged.
+/// Addresses are in network byte order.
+#[derive(Debug, Clone, Default)]
+pub struct IpParameter<'a> {
+    pub mac_address: Option<[u8; EOE_MAC_ADDRESS_LENGTH]>,
+    pub ip_address: Option<[u8; EOE_IP_ADDRESS_LENGTH]>,
+    pub subnet_mask: Option<[u8; EOE_IP_ADDRESS_LENGTH]>,
+    pub default_gateway: Option<[u8; EOE_IP_ADDRESS_LENGTH]>,
+    pub dns_server: Option<[u8; EOE_IP_ADDRESS_LENGTH]>,
+    pub dns_name: Option<&'a str>,
+}
+
+impl<'a> IpParameter<'a> {
+    fn size(&self) -> usize {
+        let mut size = EOE_IP_PARAMETER_FLAGS_LENGTH;
+        if self.mac_address.is_some() {
+            size += EOE_MAC_ADDRESS_LENGTH;
+        }
+        for address in [
+            self.ip_address,
+            self.subnet_mask,
+            self.default_gateway,
+            self.dns_server,
+        ] {
+            if address.is_some() {
+                size += EOE_IP_ADDRESS_LENGTH;
+            }
+        }
+        if self.dns_name.is_some() {
+            size += EOE_DNS_NAME_LENGTH;
+        }
+        size
+    }
+
+    fn write(&self, buf: &mut [u8]) {
+        let (flags, buf) = buf.split_at_mut(EOE_IP_PARAMETER_FLAGS_LENGTH);
+        let mut flags = EoEIpParameterFlags::new_unchecked(flags);
+        let mut offset = 0;
+        if let Some(mac_address) = self.mac_address {
+            flags.set_mac_address_included(true);
+            buf[offset..offset + EOE_MAC_ADDRESS_LENGTH].copy_from_slice(&mac_address);
+            offset += EOE_MAC_ADDRESS_LENGTH;
+        }
+        if let Some(ip_address) = self.ip_address {
+            flags.set_ip_address_included(true);
+            buf[offset..offset + EOE_IP_ADDRESS_LENGTH].copy_from_slice(&ip_address);
+            offset += EOE_IP_ADDRESS_LENGTH;
+        }
+        if let Some(subnet_mask) = self.subnet_mask {
+            flags.set_subnet_mask_included(true);
+            buf[offset..offset + EOE_IP_ADDRESS_LENGTH].copy_from_slice(&subnet_mask);
+            offset += EOE_IP_ADDRESS_LENGTH;
+        }
+        if let Some(default_gateway) = self.default_gateway {
+            flags.set_default_gateway_included(true);
+            buf[offset..offset + EOE_IP_ADDRESS_LENGTH].copy_from_slice(&default_gateway);
+            offset += EOE_IP_ADDRESS_LENGTH;
+        }
+        if let Some(dns_server) = self.dns_server {
+            flags.set_dns_server_included(true);
+            buf[offset..offset + EOE_IP_ADDRESS_LENGTH].copy_from_slice(&dns_server);
+            offset += EOE_IP_ADDRESS_LENGTH;
+        }
+        if let Some(dns_name) = self.dns_name {
+            flags.set_dns_name_included(true);
+            let name = dns_name.as_bytes();
+            buf[offset..offset + name.len()].copy_from_slice(name);
+        }
+    }
+}
+
+pub struct EoE<'a, 'b, D, T, U>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+    U: CountDown<Time = MicrosDurationU32>,
+{
+    mailbox: Mailbox<'a, 'b, D, T, U>,
+}
+
+impl<'a, 'b, D, T, U> EoE<'a, 'b, D, T, U>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+    U: CountDown<Time = MicrosDurationU32>,
+{
+    pub fn new(iface: &'a mut EtherCATInterface<'b, D, T>, timer: &'a mut U) -> Self {
+        Self {
+            mailbox: Mailbox::new(iface, timer),
+        }
+    }
+
+    /// Sets the IP parameters of the slave's virtual Ethernet port
+    /// with the "set IP parameter" service.
+    pub fn set_ip_parameter(
+        &mut self,
+        slave: &mut Slave,
+        port: u8,
+        parameter: &IpParameter,
+    ) -> Result<(), EoEError> {
+        if let Some(dns_name) = parameter.dns_name {
+            if dns_name.len() > EOE_DNS_NAME_LENGTH {
+                return Err(EoEError::TooLongDnsName);
+            }
+        }
+
+        let size = EOE_HEADER_LENGTH + parameter.size();
+        self.mailbox.write(slave, MailboxType::EoE, size, |buf| {
+            let mut header = EoEHeader::new_unchecked(&mut buf[..EOE_HEADER_LENGTH]);
+            header.set_frame_type(EoEFrameType::SetIpParameterReq as u8);
+            header.set_port(port);
+            header.set_last_fragment(true);
+            parameter.write(&mut buf[EOE_HEADER_LENGTH..]);
+        })?;
+
+        let response = self.mailbox.read_response(slave, MailboxType::EoE)?;
+        if response.mailbox_type() != MailboxType::EoE as u8 {
+            return Err(EoEError::UnexpectedResponse);
+        }
+        let header = EoEHeader::new(response.data()).ok_or(EoEError::UnexpectedResponse)?;
+        if header.frame_type() != EoEFrameType::SetIpParameterRes as u8 {
+            return Err(EoEError::UnexpectedResponse);
+        }
+        match header.result() {
+            0 => Ok(()),
+            code => Err(EoEError::ResultCode(code)),
+        }
+    }
+}
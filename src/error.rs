@@ -9,6 +9,69 @@ pub enum CommonError {
     UnspcifiedTimerError,
     ReceiveTimeout,
     UnexpectedWKC(u16),
+    /// The response does not match the index, command or register of the request,
+    /// e.g. a late response to another access.
+    ResponseMismatch,
+    /// Commands added to the interface were not sent yet, e.g. when starting a transaction.
+    CommandsPending,
+}
+
+/// Where an error comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// Lost or corrupted frames, timeouts and link changes. Usually gone in the next cycle.
+    TransientBus,
+    /// A slave is missing, in a wrong state, or rejected its configuration.
+    SlaveConfig,
+    /// A response broke the mailbox, CoE or other protocol.
+    Protocol,
+    /// Wrong arguments or buffers given by the application.
+    ApplicationBug,
+}
+
+/// Recommended action to recover from an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryHint {
+    Retry,
+    /// Scan the network again, the topology may have changed.
+    Rescan,
+    PowerCycleSlave,
+    /// Retrying does not help until the configuration or the code is fixed.
+    FixConfig,
+}
+
+/// Classification of the errors of this crate, so that recovery policies can be written
+/// without matching every error type.
+pub trait Classify {
+    fn classify(&self) -> (ErrorCategory, RecoveryHint);
+
+    fn category(&self) -> ErrorCategory {
+        self.classify().0
+    }
+
+    fn recovery_hint(&self) -> RecoveryHint {
+        self.classify().1
+    }
+
+    fn is_recoverable(&self) -> bool {
+        self.recovery_hint() != RecoveryHint::FixConfig
+    }
+}
+
+impl Classify for CommonError {
+    fn classify(&self) -> (ErrorCategory, RecoveryHint) {
+        use ErrorCategory::*;
+        use RecoveryHint::*;
+        match self {
+            Self::DeviceErrorTx | Self::DeviceErrorRx => (TransientBus, Retry),
+            Self::PacketDropped | Self::ReceiveTimeout => (TransientBus, Retry),
+            Self::ResponseMismatch => (TransientBus, Retry),
+            // スレーブが抜けたか増えた可能性がある
+            Self::UnexpectedWKC(_) => (TransientBus, Rescan),
+            Self::BufferExhausted | Self::UnspcifiedTimerError => (ApplicationBug, FixConfig),
+            Self::CommandsPending => (ApplicationBug, FixConfig),
+        }
+    }
 }
 
 // TODO: 整理する
@@ -0,0 +1,94 @@
+/// Polls of the SII busy flag before a read gives up, for ESCs with an EEPROM chip.
+pub const SII_BUSY_POLLS_DEFAULT: u32 = 1000;
+
+/// ESC type, revision and build from `DLInformation` (0x0000).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EscInfo {
+    pub esc_type: u8,
+    pub revision: u8,
+    pub build: u16,
+}
+
+/// ESC families distinguished by the type register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EscFamily {
+    Et1100,
+    Et1200,
+    /// Beckhoff IP core in an FPGA
+    IpCore,
+    /// Microchip LAN9252
+    Lan9252,
+    /// Infineon XMC4000 with integrated ESC
+    Xmc,
+    Other(u8),
+}
+
+impl EscInfo {
+    pub fn family(&self) -> EscFamily {
+        match self.esc_type {
+            0x04 => EscFamily::IpCore,
+            0x11 => EscFamily::Et1100,
+            0x12 => EscFamily::Et1200,
+            0x98 => EscFamily::Xmc,
+            0xC0 => EscFamily::Lan9252,
+            other => EscFamily::Other(other),
+        }
+    }
+}
+
+/// Adjustments for the known behavior of an ESC type, applied while the slave is scanned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EscQuirks {
+    /// Polls of the SII busy flag before a read gives up. ESCs emulating the EEPROM in the
+    /// PDI answer much slower than an EEPROM chip.
+    pub sii_busy_polls: u32,
+    /// Uses LRD/LWR instead of LRW even if `DLInformation` reports LRW support.
+    pub avoid_lrw: bool,
+    /// Avoids BRW, APRW and FPRW even if `DLInformation` reports support.
+    pub avoid_rw: bool,
+    /// Depth of the system time difference filter (0x0934) written during DC setup.
+    /// None keeps the ESC default.
+    pub dc_filter_depth: Option<u8>,
+}
+
+impl Default for EscQuirks {
+    fn default() -> Self {
+        Self {
+            sii_busy_polls: SII_BUSY_POLLS_DEFAULT,
+            avoid_lrw: false,
+            avoid_rw: false,
+            dc_filter_depth: None,
+        }
+    }
+}
+
+/// Quirks of the ESC type `esc_type`.
+#[derive(Debug, Clone, Copy)]
+pub struct QuirkEntry {
+    pub esc_type: u8,
+    pub quirks: EscQuirks,
+}
+
+/// Quirk table used by `SlaveInitilizer` unless replaced with `set_quirk_table`.
+/// ESC types not in the table use `EscQuirks::default()`.
+pub const DEFAULT_QUIRK_TABLE: &[QuirkEntry] = &[QuirkEntry {
+    // EEPROMはフラッシュでエミュレーションされていて読み出しが遅い
+    esc_type: 0x98,
+    quirks: EscQuirks {
+        sii_busy_polls: SII_BUSY_POLLS_DEFAULT * 10,
+        avoid_lrw: false,
+        avoid_rw: false,
+        dc_filter_depth: None,
+    },
+}];
+
+/// Looks up the quirks of the ESC in `table`.
+pub fn quirks_for(esc: &EscInfo, table: &[QuirkEntry]) -> EscQuirks {
+    table
+        .iter()
+        .find(|entry| entry.esc_type == esc.esc_type)
+        .map(|entry| entry.quirks)
+        .unwrap_or_default()
+}
@@ -71,6 +71,11 @@ impl<B: AsRef<[u8]> + AsMut<[u8]>> EtherCATFrame<B> {
         self.index = 0;
     }
 
+    /// Sets the source MAC address, `SRC_MAC` after `init`.
+    pub fn set_source(&mut self, mac_address: u64) {
+        EthernetHeader(self.buffer.as_mut()).set_source(mac_address);
+    }
+
     #[inline]
     pub fn packet_mut<'a>(&'a mut self) -> &'a mut [u8] {
         &mut self.buffer.as_mut()[..self.free_offset]
@@ -79,8 +84,7 @@ impl<B: AsRef<[u8]> + AsMut<[u8]>> EtherCATFrame<B> {
     pub fn add_command(
         &mut self,
         command: CommandType,
-        adp: u16,
-        ado: u16,
+        address: DatagramAddress,
         data: &[u8],
         index: Option<u8>,
     ) -> bool {
@@ -103,8 +107,7 @@ impl<B: AsRef<[u8]> + AsMut<[u8]>> EtherCATFrame<B> {
             EtherCATPDU::new(&mut self.buffer.as_mut()[self.free_offset..]).unwrap();
 
         dlpdu_frame.set_command_type(command as u8);
-        dlpdu_frame.set_adp(adp);
-        dlpdu_frame.set_ado(ado);
+        dlpdu_frame.set_address(address);
         dlpdu_frame.set_index(index.unwrap_or(self.index));
         dlpdu_frame.set_is_circulated(false);
         dlpdu_frame.set_has_next(false);
@@ -131,11 +134,6 @@ impl<B: AsRef<[u8]> + AsMut<[u8]>> EtherCATFrame<B> {
     }
 }
 
-#[inline]
-fn divide_address(adr: u32) -> (u16, u16) {
-    ((adr & 0x0000_ffff) as u16, (adr >> 16) as u16)
-}
-
 #[derive(Debug)]
 pub struct EtherCATPDUOffsets<B> {
     buffer: B,
@@ -177,7 +175,7 @@ impl<B: AsRef<[u8]>> Iterator for EtherCATPDUOffsets<B> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct EtherCATPDUs<'a> {
     buffer: &'a [u8],
     offset: usize,
@@ -210,10 +208,11 @@ impl<'a> Iterator for EtherCATPDUs<'a> {
         }
         let start = self.offset;
         if self.offset < self.length {
-            self.offset += ETHERCATPDU_HEADER_LENGTH + len as usize + WKC_LENGTH;
-            Some(EtherCATPDU::new_unchecked(
-                &self.buffer.as_ref()[start..self.offset],
-            ))
+            let end = start + ETHERCATPDU_HEADER_LENGTH + len as usize + WKC_LENGTH;
+            // 途中で切れたデータグラムで終わる
+            let pdu = self.buffer.as_ref().get(start..end)?;
+            self.offset = end;
+            Some(EtherCATPDU::new_unchecked(pdu))
         } else {
             None
         }
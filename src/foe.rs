@@ -0,0 +1,589 @@
+use crate::arch::*;
+use crate::error::*;
+use crate::interface::*;
+use crate::mailbox::*;
+use crate::master::Command;
+use crate::packet::{foe::*, MailboxPDU, MailboxType, MAILBOX_HEADER_LENGTH};
+use crate::slave_status::*;
+use embedded_hal::timer::CountDown;
+use fugit::*;
+
+/// Password of files without one.
+pub const FOE_NO_PASSWORD: u32 = 0;
+
+/// Busy replies accepted for one packet before the transfer fails.
+const FOE_BUSY_RETRIES: u32 = 100;
+
+#[derive(Debug, Clone)]
+pub enum FoEError {
+    Mailbox(MailboxError),
+    /// Error request of the slave with its code, e.g. `AccessDenied` for a wrong password.
+    Error(FoEErrorCode),
+    UnexpectedResponse,
+    /// The slave acknowledged another packet than the one sent.
+    PacketNumber(u32),
+    TooLongFileName,
+    SmallBuffer,
+    /// The slave kept replying busy.
+    Busy,
+}
+
+impl Classify for FoEError {
+    fn classify(&self) -> (ErrorCategory, RecoveryHint) {
+        use ErrorCategory::*;
+        use RecoveryHint::*;
+        match self {
+            Self::Mailbox(err) => err.classify(),
+            Self::Error(_) => (SlaveConfig, FixConfig),
+            Self::UnexpectedResponse | Self::PacketNumber(_) => (Protocol, Retry),
+            Self::Busy => (SlaveConfig, Retry),
+            Self::TooLongFileName | Self::SmallBuffer => (ApplicationBug, FixConfig),
+        }
+    }
+}
+
+impl From<MailboxError> for FoEError {
+    fn from(err: MailboxError) -> Self {
+        Self::Mailbox(err)
+    }
+}
+
+/// File access over EtherCAT, e.g. firmware files in Boot or parameter files in PreOp.
+/// Files protected by the vendor are accessed with their 32-bit password,
+/// `FOE_NO_PASSWORD` otherwise.
+pub struct FoE<'a, 'b, D, T, U>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+    U: CountDown<Time = MicrosDurationU32>,
+{
+    mailbox: Mailbox<'a, 'b, D, T, U>,
+}
+
+impl<'a, 'b, D, T, U> FoE<'a, 'b, D, T, U>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+    U: CountDown<Time = MicrosDurationU32>,
+{
+    pub fn new(iface: &'a mut EtherCATInterface<'b, D, T>, timer: &'a mut U) -> Self {
+        Self {
+            mailbox: Mailbox::new(iface, timer),
+        }
+    }
+
+    pub fn mailbox(&mut self) -> &mut Mailbox<'a, 'b, D, T, U> {
+        &mut self.mailbox
+    }
+
+    /// Reads the file `file_name` into `buf`. Returns the size of the file.
+    pub fn read(
+        &mut self,
+        slave: &mut Slave,
+        file_name: &str,
+        password: u32,
+        buf: &mut [u8],
+    ) -> Result<usize, FoEError> {
+        self.read_with_progress(slave, file_name, password, buf, |_| ())
+    }
+
+    /// Same as `read`, but calls `progress(received_bytes)` after each data packet.
+    /// The size of the file is not known before its last packet.
+    pub fn read_with_progress<F: FnMut(usize)>(
+        &mut self,
+        slave: &mut Slave,
+        file_name: &str,
+        password: u32,
+        buf: &mut [u8],
+        progress: F,
+    ) -> Result<usize, FoEError> {
+        let mut size = 0;
+        let sink = |data: &[u8]| {
+            buf.get_mut(size..size + data.len())
+                .ok_or(FoEError::SmallBuffer)?
+                .copy_from_slice(data);
+            size += data.len();
+            Ok(())
+        };
+        let result = self.read_transfer(slave, file_name, password, sink, progress);
+        self.abort_on_violation(slave, result)
+    }
+
+    /// Reads the file `file_name` and passes each data packet to `sink` in order.
+    /// Returns the size of the file.
+    /// Nothing is buffered, so files of any size can be read, e.g. firmware readback
+    /// written straight to an external flash on targets with little RAM.
+    pub fn read_stream<S: FnMut(&[u8])>(
+        &mut self,
+        slave: &mut Slave,
+        file_name: &str,
+        password: u32,
+        sink: S,
+    ) -> Result<usize, FoEError> {
+        self.read_stream_with_progress(slave, file_name, password, sink, |_| ())
+    }
+
+    /// Same as `read_stream`, but calls `progress(received_bytes)` after each data packet.
+    pub fn read_stream_with_progress<S: FnMut(&[u8]), F: FnMut(usize)>(
+        &mut self,
+        slave: &mut Slave,
+        file_name: &str,
+        password: u32,
+        mut sink: S,
+        progress: F,
+    ) -> Result<usize, FoEError> {
+        let sink = |data: &[u8]| {
+            sink(data);
+            Ok(())
+        };
+        let result = self.read_transfer(slave, file_name, password, sink, progress);
+        self.abort_on_violation(slave, result)
+    }
+
+    fn read_transfer<S, F>(
+        &mut self,
+        slave: &mut Slave,
+        file_name: &str,
+        password: u32,
+        mut sink: S,
+        mut progress: F,
+    ) -> Result<usize, FoEError>
+    where
+        S: FnMut(&[u8]) -> Result<(), FoEError>,
+        F: FnMut(usize),
+    {
+        // データは読み出し用のメールボックスいっぱいに詰めて送られてくる
+        let max_data = max_data_size(slave.sm_mailbox_out)?;
+        self.request(slave, FoEOpCode::ReadReq, file_name, password)?;
+
+        let mut size = 0;
+        let mut packet_number = 1;
+        loop {
+            let response = self.mailbox.read_response(slave, MailboxType::FoE)?;
+            let (header, data) = foe_response(&response)?;
+            if header.op_code() != FoEOpCode::Data as u8 {
+                return Err(FoEError::UnexpectedResponse);
+            }
+            if header.parameter() != packet_number {
+                return Err(FoEError::PacketNumber(header.parameter()));
+            }
+            let len = data.len();
+            sink(data)?;
+            size += len;
+            progress(size);
+            // 最大長に満たないデータが最後のパケット
+            let is_last = len < max_data;
+            let ack = |buf: &mut [u8]| {
+                let mut header = FoEHeader::new_unchecked(buf);
+                header.set_op_code(FoEOpCode::Ack as u8);
+                header.set_parameter(packet_number);
+            };
+            if is_last {
+                self.mailbox.write_unconfirmed(
+                    slave,
+                    MailboxType::FoE,
+                    MailboxPriority::Lowest,
+                    FOE_HEADER_LENGTH,
+                    ack,
+                )?;
+                return Ok(size);
+            }
+            self.mailbox
+                .write(slave, MailboxType::FoE, FOE_HEADER_LENGTH, ack)?;
+            packet_number += 1;
+        }
+    }
+
+    /// Writes `data` to the file `file_name`.
+    pub fn write(
+        &mut self,
+        slave: &mut Slave,
+        file_name: &str,
+        password: u32,
+        data: &[u8],
+    ) -> Result<(), FoEError> {
+        self.write_with_progress(slave, file_name, password, data, |_, _| ())
+    }
+
+    /// Same as `write`, but calls `progress(transferred_bytes, total_bytes)`
+    /// each time the slave acknowledges a data packet.
+    pub fn write_with_progress<F: FnMut(usize, usize)>(
+        &mut self,
+        slave: &mut Slave,
+        file_name: &str,
+        password: u32,
+        data: &[u8],
+        progress: F,
+    ) -> Result<(), FoEError> {
+        let result = self.write_transfer(slave, file_name, password, data, progress);
+        self.abort_on_violation(slave, result)
+    }
+
+    fn write_transfer<F: FnMut(usize, usize)>(
+        &mut self,
+        slave: &mut Slave,
+        file_name: &str,
+        password: u32,
+        data: &[u8],
+        mut progress: F,
+    ) -> Result<(), FoEError> {
+        let max_data = max_data_size(slave.sm_mailbox_in)?;
+        self.request(slave, FoEOpCode::WriteReq, file_name, password)?;
+        self.wait_ack(slave, 0)?;
+
+        let mut sent = 0;
+        let mut packet_number = 1;
+        loop {
+            // 最大長ちょうどで終わるときは空のパケットで終わりを示す
+            let packet = &data[sent..data.len().min(sent + max_data)];
+            let mut retries = 0;
+            loop {
+                self.mailbox.write(
+                    slave,
+                    MailboxType::FoE,
+                    FOE_HEADER_LENGTH + packet.len(),
+                    |buf| {
+                        let mut header = FoEHeader::new_unchecked(&mut buf[..FOE_HEADER_LENGTH]);
+                        header.set_op_code(FoEOpCode::Data as u8);
+                        header.set_parameter(packet_number);
+                        buf[FOE_HEADER_LENGTH..].copy_from_slice(packet);
+                    },
+                )?;
+                match self.wait_ack(slave, packet_number) {
+                    // ビジーなら同じパケットを送り直す
+                    Err(FoEError::Busy) if retries < FOE_BUSY_RETRIES => retries += 1,
+                    result => break result?,
+                }
+            }
+            sent += packet.len();
+            progress(sent, data.len());
+            if packet.len() < max_data {
+                return Ok(());
+            }
+            packet_number += 1;
+        }
+    }
+
+    /// Ends the transfer on the slave with an error request of `error_code`, e.g. when the
+    /// application gives up waiting for the response. A response already in the read mailbox
+    /// is discarded.
+    pub fn abort(&mut self, slave: &mut Slave, error_code: FoEErrorCode) -> Result<(), FoEError> {
+        self.mailbox.discard(slave)?;
+        self.mailbox.end_request(slave);
+        // エラー要求には応答がない
+        self.mailbox.write_unconfirmed(
+            slave,
+            MailboxType::FoE,
+            MailboxPriority::Lowest,
+            FOE_HEADER_LENGTH,
+            |buf| {
+                let mut header = FoEHeader::new_unchecked(buf);
+                header.set_op_code(FoEOpCode::Error as u8);
+                header.set_parameter(error_code.into());
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Ends the transfer on the slave when the master gives it up, so that the FoE server of
+    /// the slave is not left in the middle of the transfer.
+    fn abort_on_violation<R>(
+        &mut self,
+        slave: &mut Slave,
+        result: Result<R, FoEError>,
+    ) -> Result<R, FoEError> {
+        let error_code = match result.as_ref().err().and_then(violation_error_code) {
+            Some(error_code) => error_code,
+            None => return result,
+        };
+        // エラー要求が送れなくても元のエラーを返す
+        let _ = self.abort(slave, error_code);
+        result
+    }
+
+    fn request(
+        &mut self,
+        slave: &mut Slave,
+        op_code: FoEOpCode,
+        file_name: &str,
+        password: u32,
+    ) -> Result<(), FoEError> {
+        let name = file_name.as_bytes();
+        if name.len() > max_data_size(slave.sm_mailbox_in)? {
+            return Err(FoEError::TooLongFileName);
+        }
+        self.mailbox.write(
+            slave,
+            MailboxType::FoE,
+            FOE_HEADER_LENGTH + name.len(),
+            |buf| {
+                let mut header = FoEHeader::new_unchecked(&mut buf[..FOE_HEADER_LENGTH]);
+                header.set_op_code(op_code as u8);
+                header.set_parameter(password);
+                buf[FOE_HEADER_LENGTH..].copy_from_slice(name);
+            },
+        )?;
+        Ok(())
+    }
+
+    fn wait_ack(&mut self, slave: &mut Slave, packet_number: u32) -> Result<(), FoEError> {
+        let response = self.mailbox.read_response(slave, MailboxType::FoE)?;
+        ack_result(&response, packet_number)
+    }
+}
+
+/// File write executed by the master with one datagram per cycle, e.g. to download a parameter
+/// file in Op without stopping the cyclic exchange. Place it in `CyclicProcessingUnit::FoE`
+/// and access it with `EtherCATMaster::foe_unit_mut`.
+/// The unit has no timeout: `cancel` ends a transfer that takes too long.
+#[derive(Debug)]
+pub struct FoEUnit<'a> {
+    mailbox: CyclicMailbox<'a>,
+    data: &'a [u8],
+    /// Packet awaiting its acknowledge, 0 for the write request.
+    packet_number: Option<u32>,
+    sent: usize,
+    retries: u32,
+    result: Option<Result<(), FoEError>>,
+    /// The response to a cancelled request may still arrive.
+    has_cancelled: bool,
+}
+
+impl<'a> FoEUnit<'a> {
+    /// `buffer` holds the mailbox messages. None if the slave has no mailbox or `buffer`
+    /// is shorter than one of its mailboxes.
+    pub fn new(slave: &Slave, buffer: &'a mut [u8]) -> Option<Self> {
+        Some(Self {
+            mailbox: CyclicMailbox::new(slave, buffer)?,
+            data: &[],
+            packet_number: None,
+            sent: 0,
+            retries: 0,
+            result: None,
+            has_cancelled: false,
+        })
+    }
+
+    pub fn station_address(&self) -> u16 {
+        self.mailbox.station_address()
+    }
+
+    /// False while a transfer, or the error request of a cancelled one, is in progress.
+    pub fn is_idle(&self) -> bool {
+        self.packet_number.is_none() && self.mailbox.is_idle()
+    }
+
+    /// Starts writing `data` to the file `file_name`.
+    pub fn start_write(
+        &mut self,
+        file_name: &str,
+        password: u32,
+        data: &'a [u8],
+    ) -> Result<(), FoEError> {
+        if !self.is_idle() {
+            return Err(MailboxError::Busy.into());
+        }
+        let name = file_name.as_bytes();
+        if name.len() > self.max_packet_size() {
+            return Err(FoEError::TooLongFileName);
+        }
+        self.mailbox.write(
+            MailboxType::FoE,
+            true,
+            FOE_HEADER_LENGTH + name.len(),
+            |buf| {
+                let mut header = FoEHeader::new_unchecked(&mut buf[..FOE_HEADER_LENGTH]);
+                header.set_op_code(FoEOpCode::WriteReq as u8);
+                header.set_parameter(password);
+                buf[FOE_HEADER_LENGTH..].copy_from_slice(name);
+            },
+        )?;
+        self.data = data;
+        self.packet_number = Some(0);
+        self.sent = 0;
+        self.result = None;
+        Ok(())
+    }
+
+    /// Bytes acknowledged by the slave and the size of the file.
+    pub fn progress(&self) -> (usize, usize) {
+        (self.sent, self.data.len())
+    }
+
+    /// Result of the last transfer. None while the transfer is in progress and after `cancel`.
+    pub fn result(&self) -> Option<Result<(), FoEError>> {
+        self.result.clone()
+    }
+
+    /// Ends the transfer in progress. If a request may have reached the slave, the transfer is
+    /// ended on the slave with an error request of `FoEErrorCode::NotDefined`, and the unit is
+    /// idle again once the error request is written. A late response is discarded.
+    pub fn cancel(&mut self) {
+        if self.packet_number.take().is_none() {
+            return;
+        }
+        let is_written = self.mailbox.is_written();
+        self.mailbox.stop();
+        if is_written {
+            self.has_cancelled = true;
+            self.write_error(FoEErrorCode::NotDefined);
+        }
+    }
+
+    fn max_packet_size(&self) -> usize {
+        self.mailbox
+            .max_data_size()
+            .saturating_sub(FOE_HEADER_LENGTH)
+    }
+
+    fn packet(&self) -> &'a [u8] {
+        let data = self.data;
+        // 最大長ちょうどで終わるときは空のパケットで終わりを示す
+        &data[self.sent..data.len().min(self.sent + self.max_packet_size())]
+    }
+
+    fn write_packet(&mut self, packet_number: u32) -> Result<(), FoEError> {
+        let packet = self.packet();
+        self.mailbox.write(
+            MailboxType::FoE,
+            true,
+            FOE_HEADER_LENGTH + packet.len(),
+            |buf| {
+                let mut header = FoEHeader::new_unchecked(&mut buf[..FOE_HEADER_LENGTH]);
+                header.set_op_code(FoEOpCode::Data as u8);
+                header.set_parameter(packet_number);
+                buf[FOE_HEADER_LENGTH..].copy_from_slice(packet);
+            },
+        )?;
+        self.packet_number = Some(packet_number);
+        Ok(())
+    }
+
+    fn write_error(&mut self, error_code: FoEErrorCode) {
+        // エラー要求には応答がない
+        let _ = self
+            .mailbox
+            .write(MailboxType::FoE, false, FOE_HEADER_LENGTH, |buf| {
+                let mut header = FoEHeader::new_unchecked(buf);
+                header.set_op_code(FoEOpCode::Error as u8);
+                header.set_parameter(error_code.into());
+            });
+    }
+
+    pub(crate) fn data_size(&self) -> usize {
+        self.mailbox.data_size()
+    }
+
+    pub(crate) fn process(&mut self) -> Option<(Command, &[u8])> {
+        self.mailbox.process()
+    }
+
+    pub(crate) fn receive(&mut self, command: Command, data: &[u8], wkc: u16) -> bool {
+        let response = match self.mailbox.receive(command, data, wkc) {
+            Some(response) => response,
+            None => return true,
+        };
+        let packet_number = match self.packet_number.take() {
+            Some(packet_number) => packet_number,
+            None => return true,
+        };
+        let is_wkc_ok = !matches!(
+            response,
+            Err(MailboxError::Common(CommonError::UnexpectedWKC(_)))
+        );
+        let result = response
+            .map_err(FoEError::from)
+            .and_then(|response| ack_result(&response, packet_number));
+        // 中断した転送への応答が遅れて届いたら読み捨てる
+        if self.has_cancelled && matches!(result, Err(FoEError::PacketNumber(_))) {
+            self.has_cancelled = false;
+            self.packet_number = Some(packet_number);
+            self.mailbox.wait_response();
+            return true;
+        }
+        self.has_cancelled = false;
+        let result = match result {
+            Ok(()) => {
+                let packet_len = self.packet().len();
+                if packet_number > 0 {
+                    self.sent += packet_len;
+                    self.retries = 0;
+                }
+                // 最大長に満たないパケットで転送は終わる
+                if packet_number > 0 && packet_len < self.max_packet_size() {
+                    Ok(())
+                } else {
+                    match self.write_packet(packet_number + 1) {
+                        Ok(()) => return is_wkc_ok,
+                        Err(err) => Err(err),
+                    }
+                }
+            }
+            // ビジーなら同じパケットを送り直す
+            Err(FoEError::Busy) if packet_number > 0 && self.retries < FOE_BUSY_RETRIES => {
+                self.retries += 1;
+                match self.write_packet(packet_number) {
+                    Ok(()) => return is_wkc_ok,
+                    Err(err) => Err(err),
+                }
+            }
+            Err(err) => Err(err),
+        };
+        self.retries = 0;
+        // スレーブ側の転送を終わらせておく
+        if let Some(error_code) = result.as_ref().err().and_then(violation_error_code) {
+            self.write_error(error_code);
+        }
+        self.result = Some(result);
+        is_wkc_ok
+    }
+}
+
+/// Error code of the error request that ends the transfer on the slave when the master gives
+/// it up with `err`. None if the slave has already ended it.
+fn violation_error_code(err: &FoEError) -> Option<FoEErrorCode> {
+    match err {
+        FoEError::SmallBuffer => Some(FoEErrorCode::DiskFull),
+        FoEError::PacketNumber(_) => Some(FoEErrorCode::PacketNumberWrong),
+        FoEError::UnexpectedResponse => Some(FoEErrorCode::Illegal),
+        FoEError::Busy => Some(FoEErrorCode::NotDefined),
+        _ => None,
+    }
+}
+
+/// Checks that the response acknowledges the packet `packet_number`.
+fn ack_result(response: &MailboxPDU<&[u8]>, packet_number: u32) -> Result<(), FoEError> {
+    let (header, _) = foe_response(response)?;
+    match header.op_code() {
+        op_code if op_code == FoEOpCode::Ack as u8 => {
+            if header.parameter() == packet_number {
+                Ok(())
+            } else {
+                Err(FoEError::PacketNumber(header.parameter()))
+            }
+        }
+        op_code if op_code == FoEOpCode::Busy as u8 => Err(FoEError::Busy),
+        _ => Err(FoEError::UnexpectedResponse),
+    }
+}
+
+/// Data bytes of a data packet that fills the mailbox `sm`.
+fn max_data_size(sm: Option<MailboxSyncManager>) -> Result<usize, FoEError> {
+    let sm = sm.ok_or(MailboxError::NoMailbox)?;
+    Ok((sm.size as usize).saturating_sub(MAILBOX_HEADER_LENGTH + FOE_HEADER_LENGTH))
+}
+
+/// Header and data of an FoE response. Error requests are returned as `FoEError::Error`.
+fn foe_response<'r>(
+    response: &'r MailboxPDU<&[u8]>,
+) -> Result<(FoEHeader<&'r [u8]>, &'r [u8]), FoEError> {
+    if response.mailbox_type() != MailboxType::FoE as u8 {
+        return Err(FoEError::UnexpectedResponse);
+    }
+    let data = response.data();
+    let header = FoEHeader::new(data).ok_or(FoEError::UnexpectedResponse)?;
+    if header.op_code() == FoEOpCode::Error as u8 {
+        return Err(FoEError::Error(FoEErrorCode::from(header.parameter())));
+    }
+    Ok((header, &data[FOE_HEADER_LENGTH..]))
+}
@@ -0,0 +1,110 @@
+use crate::ethercat_frame::EtherCATPDUs;
+use crate::initializer::ProcessImage;
+use crate::packet::*;
+use crate::slave_status::Slave;
+
+/// Inputs of a logical image borrowed from the received frame, without copying them into
+/// the PDO entries. The view borrows the interface, so it is only valid until the next poll.
+/// Suited to single-task applications that consume the inputs right after the exchange.
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessImageView<'a> {
+    logical_start_address: u32,
+    data: &'a [u8],
+    wkc: u16,
+}
+
+impl<'a> ProcessImageView<'a> {
+    /// Finds the response to the LRD or LRW datagram of `image` in `pdus`,
+    /// e.g. from `EtherCATInterface::consume_command`.
+    pub fn find(pdus: EtherCATPDUs<'a>, image: &ProcessImage) -> Option<Self> {
+        for pdu in pdus {
+            let command = CommandType::new(pdu.command_type());
+            if command != CommandType::LRD && command != CommandType::LRW {
+                continue;
+            }
+            if pdu.address() != DatagramAddress::Logical(image.logical_start_address) {
+                continue;
+            }
+            let len = pdu.length() as usize;
+            let wkc = pdu.wkc()?;
+            // pdu.data()はpduの借用になるので、フレームのバッファから直接切り出す
+            let buf: &'a [u8] = pdu.0;
+            let data = buf.get(ETHERCATPDU_HEADER_LENGTH..ETHERCATPDU_HEADER_LENGTH + len)?;
+            return Some(Self {
+                logical_start_address: image.logical_start_address,
+                data,
+                wkc,
+            });
+        }
+        None
+    }
+
+    pub fn wkc(&self) -> u16 {
+        self.wkc
+    }
+
+    /// Whether all slaves of `image` took part in the LRW exchange.
+    /// Not meaningful for images exchanged with LWR and LRD.
+    pub fn is_complete(&self, image: &ProcessImage) -> bool {
+        self.wkc == image.expected_wkc
+    }
+
+    /// The whole image as received.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Inputs (TxPDO) of `slave`, which follow its outputs (RxPDO) in the image.
+    /// None if the slave has no process data in this image.
+    pub fn inputs(&self, slave: &Slave) -> Option<&'a [u8]> {
+        let offset = slave
+            .logical_address?
+            .checked_sub(self.logical_start_address)? as usize
+            + slave.rx_pdo_size() as usize;
+        self.data.get(offset..offset + slave.tx_pdo_size() as usize)
+    }
+
+    /// Input PDO entry `index`:`sub_index` of `slave`.
+    pub fn input_entry(&self, slave: &Slave, index: u16, sub_index: u8) -> Option<&'a [u8]> {
+        let (offset, len) = slave.tx_pdo_entry_range(index, sub_index)?;
+        self.inputs(slave)?.get(offset..offset + len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slave_status::process_cyclic_data;
+    use crate::slave_status::tests::{entry_data, slave};
+
+    #[test]
+    fn inputs_match_process_cyclic_data() {
+        let mut slaves = [
+            slave(&[(0x7000, &[1, 2])], &[(0x6000, &[0])]),
+            slave(&[(0x7000, &[3])], &[(0x6000, &[0, 0]), (0x6001, &[0])]),
+        ];
+        slaves[0].logical_address = Some(0x1000);
+        slaves[1].logical_address = Some(0x1003);
+        let received = [0, 0, 0xA1, 0, 0xB1, 0xB2, 0xB3];
+        let mut image = received;
+        process_cyclic_data(&mut image, &mut slaves);
+        let view = ProcessImageView {
+            logical_start_address: 0x1000,
+            data: &received,
+            wkc: 3,
+        };
+        assert_eq!(
+            view.inputs(&slaves[0]),
+            Some(entry_data(&slaves[0].tx_pdo_mapping, 0))
+        );
+        assert_eq!(view.inputs(&slaves[1]), Some(&[0xB1, 0xB2, 0xB3][..]));
+        assert_eq!(
+            view.input_entry(&slaves[1], 0x6000, 1),
+            Some(entry_data(&slaves[1].tx_pdo_mapping, 0))
+        );
+        assert_eq!(
+            view.input_entry(&slaves[1], 0x6001, 1),
+            Some(entry_data(&slaves[1].tx_pdo_mapping, 1))
+        );
+    }
+}
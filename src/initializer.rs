@@ -1,15 +1,36 @@
 use crate::al_state_transfer::*;
 use crate::arch::*;
+use crate::dc::{DcFilterSettings, DcInitializer};
 use crate::error::*;
+use crate::esc::*;
 use crate::interface::*;
+use crate::lifecycle::*;
+use crate::network::*;
 use crate::packet::*;
 use crate::register::datalink::*;
+use crate::register::application::*;
 use crate::sii::*;
 use crate::slave_status::*;
+use crate::util::get_ap_adp;
+use crate::wkc::*;
+use crate::{
+    BACK_TO_INIT_TIMEOUT_DEFAULT, EEPROM_LOAD_TIMEOUT_DEFAULT, LOGICAL_START_ADDRESS,
+    RECEIVE_TIMEOUT_DEFAULT,
+};
 use bit_field::BitField;
 use embedded_hal::timer::*;
 use fugit::*;
 
+/// First SII word read by the scan: the identity, followed by the mailbox configuration
+/// up to the mailbox protocol word.
+const SII_INFO_ADDRESS: u16 = sii_reg::VenderID::ADDRESS;
+const SII_INFO_SIZE: usize =
+    (sii_reg::MailboxProtocol::ADDRESS - SII_INFO_ADDRESS + 1) as usize * 2;
+/// Slaves whose SII reads share frames in `init_slaves`.
+const SII_PIPELINE_SLAVES: usize = 16;
+/// SII control, address and data registers, read at once to poll a read.
+const SII_READ_REGISTERS_SIZE: usize = SIIControl::SIZE + SIIAddress::SIZE + SIIData::SIZE;
+
 #[derive(Debug, Clone)]
 pub enum InitError {
     Common(CommonError),
@@ -17,6 +38,39 @@ pub enum InitError {
     SII(SIIError),
     FailedToLoadEEPROM,
     TooManySlaves,
+    NotPreOperational,
+    ProcessDataNotSupported,
+    ProcessDataSizeOver,
+    NotExistSlave(u16),
+    SlaveMismatch(u16),
+    UnexpectedBufferType(u8),
+    InvalidPort(u8),
+    EnhancedLinkDetectionDisabled(u8),
+    InvalidProcessDataLink,
+    /// The system time differences stayed above the threshold set with `set_dc_settling`.
+    DcNotSettled,
+}
+
+impl Classify for InitError {
+    fn classify(&self) -> (ErrorCategory, RecoveryHint) {
+        use ErrorCategory::*;
+        use RecoveryHint::*;
+        match self {
+            Self::Common(err) => err.classify(),
+            Self::AlStateTransition(err) => err.classify(),
+            Self::SII(err) => err.classify(),
+            Self::FailedToLoadEEPROM => (SlaveConfig, PowerCycleSlave),
+            Self::NotPreOperational | Self::DcNotSettled => (SlaveConfig, Retry),
+            Self::NotExistSlave(_) | Self::SlaveMismatch(_) => (SlaveConfig, Rescan),
+            Self::ProcessDataNotSupported
+            | Self::ProcessDataSizeOver
+            | Self::UnexpectedBufferType(_)
+            | Self::EnhancedLinkDetectionDisabled(_) => (SlaveConfig, FixConfig),
+            Self::TooManySlaves | Self::InvalidPort(_) | Self::InvalidProcessDataLink => {
+                (ApplicationBug, FixConfig)
+            }
+        }
+    }
 }
 
 impl From<CommonError> for InitError {
@@ -37,12 +91,139 @@ impl From<SIIError> for InitError {
     }
 }
 
+/// Step of the initialization, reported by `InitFailure`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitStep {
+    CountSlaves,
+    ResetSlaves,
+    CheckIdentity,
+    SetStationAddress,
+    ReadDlInformation,
+    ClearFmmus,
+    ReadPorts,
+    /// SII read starting at the word address.
+    ReadSii(u16),
+    ClearSyncManagers,
+    ConfigureMailbox,
+    ResetDc,
+    RestoreDc,
+    SettleDc,
+    VerifyLinkDetection,
+    ChangeAlState(AlState),
+    ReadAlStatus,
+    /// Process data sync manager 2 or 3.
+    WriteSyncManager(u8),
+    /// Process data FMMU 0 or 1.
+    WriteFmmu(u8),
+    VerifySyncManagers,
+}
+
+/// Error of the initialization with the step that failed, e.g. `WriteSyncManager(2)` on the
+/// slave at position 5, and the last datagram issued.
+#[derive(Debug, Clone)]
+pub struct InitFailure {
+    pub step: InitStep,
+    /// Position of the slave. None for steps addressing all slaves.
+    pub slave: Option<u16>,
+    pub command: Option<(CommandType, DatagramAddress)>,
+    pub error: InitError,
+}
+
+impl Classify for InitFailure {
+    fn classify(&self) -> (ErrorCategory, RecoveryHint) {
+        self.error.classify()
+    }
+}
+
+impl From<InitFailure> for InitError {
+    fn from(failure: InitFailure) -> Self {
+        failure.error
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum ConfiguredAddress {
     StationAlias,
     StationAddress(u16),
 }
 
+/// Logical process image built by `SlaveInitilizer::configure_process_data`.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProcessImage {
+    pub logical_start_address: u32,
+    pub size: usize,
+    /// Expected WKC of the LRW of the image.
+    pub expected_wkc: u16,
+    /// False if a slave of the image does not support LRW or avoids it by its quirks.
+    /// The image is then exchanged with LWR and LRD, see `ProcessDataUnit`.
+    pub use_lrw: bool,
+}
+
+/// Copy of inputs of the `producer` slave into outputs of the `consumer` slave, done by the
+/// slaves themselves while the LRW datagram passes, without the master in between.
+/// `producer` and `consumer` are indices in the slice given to `configure_process_data`.
+/// The linked outputs are the last `length` bytes of the consumer's outputs; the master no
+/// longer writes them. If the producer is behind the consumer, the data is one cycle old.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProcessDataLink {
+    pub producer: usize,
+    /// Offset in the inputs of the producer.
+    pub producer_offset: u16,
+    pub consumer: usize,
+    pub length: u16,
+}
+
+/// Raw auto increment access by slave position for the discovery phase,
+/// before station addresses are assigned.
+/// Obtained with `SlaveInitilizer::position_access`.
+pub struct PositionAccess<'a, 'b, D, T>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+{
+    iface: &'a mut EtherCATInterface<'b, D, T>,
+}
+
+impl<'a, 'b, D, T> PositionAccess<'a, 'b, D, T>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+{
+    /// Reads `buf.len()` bytes from the register of the slave at `position` (APRD).
+    pub fn read(
+        &mut self,
+        position: u16,
+        register_address: u16,
+        buf: &mut [u8],
+    ) -> Result<(), CommonError> {
+        let pdu = self.iface.read_register(
+            SlaveAddress::SlaveNumber(position),
+            register_address,
+            buf.len(),
+        )?;
+        buf.copy_from_slice(pdu.data());
+        Ok(())
+    }
+
+    /// Writes `data` to the register of the slave at `position` (APWR).
+    pub fn write(
+        &mut self,
+        position: u16,
+        register_address: u16,
+        data: &[u8],
+    ) -> Result<(), CommonError> {
+        self.iface.write_register(
+            SlaveAddress::SlaveNumber(position),
+            register_address,
+            data.len(),
+            |buf| buf.copy_from_slice(data),
+        )?;
+        Ok(())
+    }
+}
+
 pub struct SlaveInitilizer<'a, D, T, U>
 where
     D: Device,
@@ -51,6 +232,15 @@ where
 {
     iface: &'a mut EtherCATInterface<'a, D, T>,
     timer: &'a mut U,
+    quirk_table: &'static [QuirkEntry],
+    dc_filter: Option<DcFilterSettings>,
+    dc_settling: Option<(u32, u32)>,
+    #[cfg(feature = "sii")]
+    sii_config_check: Option<SiiConfigCheck>,
+    #[cfg(feature = "sii")]
+    verify_sii_categories: bool,
+    lifecycle: MasterLifecycle,
+    step: (InitStep, Option<u16>),
 }
 
 impl<'a, D, T, U> SlaveInitilizer<'a, D, T, U>
@@ -60,28 +250,495 @@ where
     U: CountDown<Time = MicrosDurationU32>,
 {
     pub fn new(iface: &'a mut EtherCATInterface<'a, D, T>, timer: &'a mut U) -> Self {
-        Self { iface, timer }
+        Self {
+            iface,
+            timer,
+            quirk_table: DEFAULT_QUIRK_TABLE,
+            dc_filter: None,
+            dc_settling: None,
+            #[cfg(feature = "sii")]
+            sii_config_check: None,
+            #[cfg(feature = "sii")]
+            verify_sii_categories: false,
+            lifecycle: MasterLifecycle::new(),
+            step: (InitStep::CountSlaves, None),
+        }
+    }
+
+    /// Phase and progress of the scan and configuration.
+    /// Hand it over to `EtherCATMaster::set_lifecycle` after the initialization.
+    pub fn lifecycle(&self) -> &MasterLifecycle {
+        &self.lifecycle
+    }
+
+    pub fn lifecycle_mut(&mut self) -> &mut MasterLifecycle {
+        &mut self.lifecycle
+    }
+
+    /// Moves the lifecycle to `Fault` if `result` is an error,
+    /// and reports the error with the step being executed.
+    fn track<R>(&mut self, result: Result<R, InitError>) -> Result<R, InitFailure> {
+        result.map_err(|error| {
+            self.lifecycle.fault();
+            let (step, slave) = self.step;
+            InitFailure {
+                step,
+                slave,
+                command: self.iface.last_datagram(),
+                error,
+            }
+        })
+    }
+
+    /// Records the step reported if the initialization fails.
+    fn begin(&mut self, step: InitStep, slave: Option<u16>) {
+        self.step = (step, slave);
+    }
+
+    /// Replaces the quirk table looked up by the ESC type of each slave.
+    pub fn set_quirk_table(&mut self, table: &'static [QuirkEntry]) {
+        self.quirk_table = table;
+    }
+
+    /// Drift compensation settings written to the DC slaves.
+    /// See `DcInitializer::set_filter_settings`.
+    pub fn set_dc_filter_settings(&mut self, filter: DcFilterSettings) {
+        self.dc_filter = Some(filter);
+    }
+
+    /// Waits after the DC setup until the system time difference of every slave is below
+    /// `threshold_ns`, distributing the system time up to `max_rounds` times.
+    /// Fails with `InitError::DcNotSettled` otherwise. Disabled by default.
+    pub fn set_dc_settling(&mut self, threshold_ns: u32, max_rounds: u32) {
+        self.dc_settling = Some((threshold_ns, max_rounds));
+    }
+
+    /// Check of the SII config area during the scan, e.g. `check_sii_config_crc`.
+    /// A slave failing it is reported with `SIIError::ConfigAreaCorrupted`. Disabled by default.
+    #[cfg(feature = "sii")]
+    pub fn set_sii_config_check(&mut self, check: Option<SiiConfigCheck>) {
+        self.sii_config_check = check;
+    }
+
+    /// Also follows the SII categories of each slave during the scan, see
+    /// `SlaveInformationInterface::verify_categories`. Disabled by default.
+    #[cfg(feature = "sii")]
+    pub fn set_sii_category_check(&mut self, enable: bool) {
+        self.verify_sii_categories = enable;
     }
 
-    pub fn init_slaves(&mut self, slave_buffer: &mut [Slave]) -> Result<(), InitError> {
+    fn dc_initializer(&mut self) -> DcInitializer<'_, 'a, D, T> {
+        let mut dc = DcInitializer::new(self.iface);
+        if let Some(filter) = self.dc_filter {
+            dc.set_filter_settings(filter);
+        }
+        dc
+    }
+
+    /// Position access for the discovery phase. None in `MasterPhase::SafeOp`, `Op` and
+    /// `ShuttingDown`, since positions shift if the network changes while it runs.
+    pub fn position_access(&mut self) -> Option<PositionAccess<'_, 'a, D, T>> {
+        match self.lifecycle.phase() {
+            MasterPhase::SafeOp | MasterPhase::Op | MasterPhase::ShuttingDown => None,
+            _ => Some(PositionAccess { iface: self.iface }),
+        }
+    }
+
+    /// Scans the slaves into `slave_buffer`. A slave whose entry requires enhanced link
+    /// detection (`Slave::set_enhanced_link_detection_required`) fails the scan with
+    /// `InitError::EnhancedLinkDetectionDisabled` if it is off on a connected port.
+    pub fn init_slaves(&mut self, slave_buffer: &mut [Slave]) -> Result<(), InitFailure> {
+        let result = self.scan_slaves(slave_buffer);
+        self.track(result)
+    }
+
+    fn scan_slaves(&mut self, slave_buffer: &mut [Slave]) -> Result<(), InitError> {
+        self.lifecycle.enter(MasterPhase::Scanning, 0);
         let num_slaves = self.count_slaves()?;
         if num_slaves as usize > slave_buffer.len() {
             return Err(InitError::TooManySlaves);
         }
 
-        for i in 0..num_slaves {
-            let slave = self.init_slave(i)?;
-            slave_buffer[i as usize] = slave.unwrap();
+        // 全スレーブに共通の設定はブロードキャストでまとめて行い、
+        // スレーブごとの設定だけを順番に行う
+        self.reset_all_slaves(num_slaves)?;
+        self.lifecycle.enter(MasterPhase::Configuring, num_slaves);
+        let slave_buffer = &mut slave_buffer[..num_slaves as usize];
+        // EEPROMの読み出しは時間がかかるので、複数のスレーブの読み出しを同じフレームで進める
+        for (chunk, slaves) in slave_buffer.chunks_mut(SII_PIPELINE_SLAVES).enumerate() {
+            let first = (chunk * SII_PIPELINE_SLAVES) as u16;
+            for (i, slave) in slaves.iter_mut().enumerate() {
+                let mut new_slave = self.begin_slave_configuration(first + i as u16)?;
+                // アプリケーションが設定した要求は引き継ぐ
+                new_slave.require_enhanced_link_detection = slave.require_enhanced_link_detection;
+                *slave = new_slave;
+            }
+            let mut sii_info = [[0; SII_INFO_SIZE]; SII_PIPELINE_SLAVES];
+            self.read_sii_info_all(slaves, &mut sii_info)?;
+            for (slave, sii_info) in slaves.iter_mut().zip(sii_info.iter()) {
+                let position = Some(slave.position_address);
+                apply_sii_info(slave, sii_info);
+                self.end_slave_configuration(slave)?;
+                self.begin(InitStep::VerifyLinkDetection, position);
+                self.verify_link_detection(slave)?;
+                self.lifecycle.advance();
+            }
         }
         Ok(())
     }
 
+    /// Reads the SII words from `SII_INFO_ADDRESS` of each slave into `sii_info`.
+    /// The reads of all slaves share frames: each frame carries one datagram per slave,
+    /// which starts the read of the next words or polls the running one.
+    /// Slaves are addressed by position because the station addresses are not unique yet.
+    fn read_sii_info_all(
+        &mut self,
+        slaves: &[Slave],
+        sii_info: &mut [[u8; SII_INFO_SIZE]],
+    ) -> Result<(), InitError> {
+        let num_slaves = slaves.len().min(sii_info.len()).min(SII_PIPELINE_SLAVES);
+        let mut offsets = [0; SII_PIPELINE_SLAVES];
+        let mut is_reading = [false; SII_PIPELINE_SLAVES];
+        let mut polls = [0; SII_PIPELINE_SLAVES];
+        loop {
+            let mut has_pending = false;
+            for (i, slave) in slaves[..num_slaves].iter().enumerate() {
+                if offsets[i] >= SII_INFO_SIZE {
+                    continue;
+                }
+                has_pending = true;
+                if is_reading[i] {
+                    self.iface.add_command(
+                        i as u8,
+                        CommandType::APRD,
+                        get_ap_adp(slave.position_address),
+                        SIIControl::ADDRESS,
+                        SII_READ_REGISTERS_SIZE,
+                        |buf| buf.iter_mut().for_each(|b| *b = 0),
+                    )?;
+                } else {
+                    // アドレスと読み出しコマンドを1つのデータグラムで書く
+                    let sii_address = SII_INFO_ADDRESS + (offsets[i] / 2) as u16;
+                    self.iface.add_command(
+                        i as u8,
+                        CommandType::APWR,
+                        get_ap_adp(slave.position_address),
+                        SIIControl::ADDRESS,
+                        SIIControl::SIZE + SIIAddress::SIZE,
+                        |buf| {
+                            buf.iter_mut().for_each(|b| *b = 0);
+                            let (control, address) = buf.split_at_mut(SIIControl::SIZE);
+                            SIIControl(control).set_read_operation(true);
+                            SIIAddress(address).set_sii_address(sii_address as u32);
+                        },
+                    )?;
+                }
+            }
+            if !has_pending {
+                return Ok(());
+            }
+            self.iface.poll(RECEIVE_TIMEOUT_DEFAULT)?;
+            let mut failure = None;
+            for pdu in self.iface.consume_command() {
+                let i = pdu.index() as usize;
+                if i >= num_slaves || offsets[i] >= SII_INFO_SIZE || failure.is_some() {
+                    continue;
+                }
+                if let Err(err) = check_command_wkc(&pdu, 1) {
+                    failure = Some((i, InitError::from(err)));
+                    continue;
+                }
+                if !is_reading[i] {
+                    is_reading[i] = true;
+                    continue;
+                }
+                let data = pdu.data();
+                let control = SIIControl(&data[..SIIControl::SIZE]);
+                if control.command_error() {
+                    failure = Some((i, SIIError::CommandError.into()));
+                } else if control.check_sum_error() {
+                    failure = Some((i, SIIError::CheckSumError.into()));
+                } else if control.device_info_error() {
+                    failure = Some((i, SIIError::DeviceInfoError.into()));
+                } else if control.busy() || control.read_operation() {
+                    polls[i] += 1;
+                    if polls[i] >= slaves[i].quirks.sii_busy_polls {
+                        failure = Some((i, SIIError::Busy.into()));
+                    }
+                } else {
+                    let read_size = if control.read_size() { 8 } else { 4 };
+                    let len = read_size.min(SII_INFO_SIZE - offsets[i]);
+                    let start = SIIControl::SIZE + SIIAddress::SIZE;
+                    sii_info[i][offsets[i]..offsets[i] + len]
+                        .copy_from_slice(&data[start..start + len]);
+                    offsets[i] += len;
+                    is_reading[i] = false;
+                    polls[i] = 0;
+                }
+            }
+            if let Some((i, err)) = failure {
+                let sii_address = SII_INFO_ADDRESS + (offsets[i] / 2) as u16;
+                self.begin(
+                    InitStep::ReadSii(sii_address),
+                    Some(slaves[i].position_address),
+                );
+                return Err(err);
+            }
+        }
+    }
+
+    /// Same as `reset_slave` for all slaves at once with broadcast writes,
+    /// and waits until every slave has loaded its EEPROM.
+    fn reset_all_slaves(&mut self, num_slaves: u16) -> Result<(), InitError> {
+        self.begin(InitStep::ResetSlaves, None);
+        let mut dl_control = DLControl::new();
+        dl_control.set_forwarding_rule(true);
+        dl_control.set_tx_buffer_size(7);
+        self.broadcast_write(DLControl::ADDRESS, &dl_control.0, num_slaves)?;
+
+        let mut al_control = ALControl::new();
+        al_control.set_state(AlState::Init as u8);
+        self.broadcast_write(ALControl::ADDRESS, &al_control.0, num_slaves)?;
+        self.timer.start(BACK_TO_INIT_TIMEOUT_DEFAULT.convert());
+        loop {
+            self.iface.add_command(
+                u8::MAX,
+                CommandType::BRD,
+                0,
+                ALStatus::ADDRESS,
+                ALStatus::SIZE,
+                |buf| buf.iter_mut().for_each(|b| *b = 0),
+            )?;
+            self.iface.poll(RECEIVE_TIMEOUT_DEFAULT)?;
+            let pdu = self
+                .iface
+                .consume_command()
+                .last()
+                .ok_or(CommonError::PacketDropped)?;
+            check_command_wkc(&pdu, num_slaves)?;
+            // BRDは全スレーブの状態のORなので、Initのビットだけなら全スレーブがInit
+            if ALStatus(pdu.data()).state() == AlState::Init as u8 {
+                break;
+            }
+            match self.timer.wait() {
+                Ok(_) => {
+                    return Err(AlStateTransitionError::Timeout(BACK_TO_INIT_TIMEOUT_DEFAULT).into())
+                }
+                Err(nb::Error::Other(_)) => return Err(CommonError::UnspcifiedTimerError.into()),
+                Err(nb::Error::WouldBlock) => (),
+            }
+        }
+
+        // 値に関わらず書き込むとエラーカウンタはクリアされる
+        self.broadcast_write(RxErrorCounter::ADDRESS, &RxErrorCounter::new().0, num_slaves)?;
+        let mut watchdog_div = WatchDogDivider::new();
+        watchdog_div.set_watch_dog_divider(2498); //100us(default)
+        self.broadcast_write(WatchDogDivider::ADDRESS, &watchdog_div.0, num_slaves)?;
+        let mut dl_watchdog = DLUserWatchDog::new();
+        dl_watchdog.set_dls_user_watch_dog(1000); //defalut 100ms
+        self.broadcast_write(DLUserWatchDog::ADDRESS, &dl_watchdog.0, num_slaves)?;
+        let mut sm_watchdog = SyncManagerChannelWatchDog::new();
+        sm_watchdog.set_sm_channel_watch_dog(1000); //defalut 100ms
+        self.broadcast_write(SyncManagerChannelWatchDog::ADDRESS, &sm_watchdog.0, num_slaves)?;
+
+        // PDIの状態はBRDではORになるので、スレーブごとの読み出しを1フレームにまとめる
+        self.timer.start(EEPROM_LOAD_TIMEOUT_DEFAULT.convert());
+        let mut num_loaded = 0;
+        loop {
+            let end = num_slaves.min(num_loaded + REGISTER_TAG_FIRST as u16);
+            let mut transaction = self.iface.transaction()?;
+            for position in num_loaded..end {
+                transaction.read(
+                    SlaveAddress::SlaveNumber(position),
+                    DLStatus::ADDRESS,
+                    DLStatus::SIZE,
+                )?;
+            }
+            let results = transaction.execute()?;
+            for entry in 0..(end - num_loaded) as usize {
+                if !DLStatus(results.data(entry)?).pdi_operational() {
+                    break;
+                }
+                num_loaded += 1;
+            }
+            if num_loaded == num_slaves {
+                return Ok(());
+            }
+            if num_loaded < end {
+                match self.timer.wait() {
+                    Ok(_) => return Err(InitError::FailedToLoadEEPROM),
+                    Err(nb::Error::Other(_)) => {
+                        return Err(CommonError::UnspcifiedTimerError.into())
+                    }
+                    Err(nb::Error::WouldBlock) => (),
+                }
+            }
+        }
+    }
+
+    fn broadcast_write(
+        &mut self,
+        register_address: u16,
+        data: &[u8],
+        num_slaves: u16,
+    ) -> Result<(), InitError> {
+        self.iface.add_command(
+            u8::MAX,
+            CommandType::BWR,
+            0,
+            register_address,
+            data.len(),
+            |buf| buf.copy_from_slice(data),
+        )?;
+        self.iface.poll(RECEIVE_TIMEOUT_DEFAULT)?;
+        let pdu = self
+            .iface
+            .consume_command()
+            .last()
+            .ok_or(CommonError::PacketDropped)?;
+        check_command_wkc(&pdu, num_slaves)?;
+        Ok(())
+    }
+
+    /// Same as `init_slaves`, and describes the network found.
+    /// The ring is only closed if the second port of the master passes the frames back,
+    /// so a closed ring also proves that frames circulate through it.
+    pub fn init_network<'s, 'b>(
+        &mut self,
+        slave_buffer: &'s mut [Slave<'b>],
+    ) -> Result<NetworkDescription<'s, 'b>, InitFailure> {
+        let result = self.count_slaves();
+        let num_slaves = self.track(result)?;
+        self.init_slaves(slave_buffer)?;
+        Ok(NetworkDescription::new(slave_buffer, num_slaves as usize))
+    }
+
+    /// Programs the slaves from a stored configuration instead of scanning them.
+    /// The SII is only read to check that every slave matches the configuration.
+    /// Station addresses, mailboxes, the process data at the stored logical addresses
+    /// and the DC offsets (from `master_time`, ns since 2000) are restored,
+    /// and the slaves are left in PreOp.
+    /// The PDO mappings set in `slave_buffer` are kept.
+    pub fn init_from_config<'s, 'b, const N: usize>(
+        &mut self,
+        config: &ConfigBlob<N>,
+        slave_buffer: &'s mut [Slave<'b>],
+        master_time: u64,
+    ) -> Result<NetworkDescription<'s, 'b>, InitFailure> {
+        let result = self.restore_config(config, slave_buffer, master_time);
+        self.track(result)
+    }
+
+    fn restore_config<'s, 'b, const N: usize>(
+        &mut self,
+        config: &ConfigBlob<N>,
+        slave_buffer: &'s mut [Slave<'b>],
+        master_time: u64,
+    ) -> Result<NetworkDescription<'s, 'b>, InitError> {
+        self.lifecycle.enter(MasterPhase::Scanning, 0);
+        let num_slaves = config.slaves.len();
+        if num_slaves > slave_buffer.len() {
+            return Err(InitError::TooManySlaves);
+        }
+        let count = self.count_slaves()?;
+        if (count as usize) < num_slaves {
+            return Err(InitError::NotExistSlave(count));
+        }
+        if count as usize > num_slaves {
+            return Err(InitError::SlaveMismatch(num_slaves as u16));
+        }
+
+        self.reset_all_slaves(count)?;
+        self.lifecycle
+            .enter(MasterPhase::Configuring, num_slaves as u16);
+        for (position, info) in config.slaves.iter().enumerate() {
+            let position = position as u16;
+            let address = SlaveAddress::SlaveNumber(position);
+            self.begin(InitStep::CheckIdentity, Some(position));
+            let mut identity = [0; 12];
+            SlaveInformationInterface::new(&mut self.iface).read_words(
+                address,
+                sii_reg::VenderID::ADDRESS,
+                &mut identity,
+            )?;
+            let identity_word = |address: u16| {
+                let i = (address - sii_reg::VenderID::ADDRESS) as usize * 2;
+                byte_order::read_u16(&identity, i).unwrap_or_default()
+            };
+            if identity_word(sii_reg::VenderID::ADDRESS) != info.id.vender_id
+                || identity_word(sii_reg::ProductCode::ADDRESS) != info.id.product_code
+                || identity_word(sii_reg::RevisionNumber::ADDRESS) != info.id.revision_number
+            {
+                return Err(InitError::SlaveMismatch(position));
+            }
+
+            let slave = &mut slave_buffer[position as usize];
+            info.apply(slave);
+            slave.position_address = position;
+            slave.al_state = AlState::Init;
+            slave.mailbox_count = 0;
+            slave.error = None;
+            self.begin(InitStep::SetStationAddress, Some(position));
+            self.set_station_address(slave, info.configured_address)?;
+            self.begin(InitStep::ClearFmmus, Some(position));
+            if slave.fmmu0.is_some() {
+                self.iface.write_fmmu0(address, None)?;
+            }
+            if slave.fmmu1.is_some() {
+                self.iface.write_fmmu1(address, None)?;
+            }
+            self.begin(InitStep::ClearSyncManagers, Some(position));
+            self.clear_sync_managers(address, slave.number_of_sm)?;
+            self.begin(InitStep::ConfigureMailbox, Some(position));
+            self.configure_mailbox_sync_managers(address, slave)?;
+            if slave.support_dc {
+                self.begin(InitStep::ResetDc, Some(position));
+                self.reset_dc(address)?;
+            }
+            self.begin(InitStep::VerifyLinkDetection, Some(position));
+            self.verify_link_detection(slave)?;
+            self.lifecycle.advance();
+        }
+
+        let slaves = &mut slave_buffer[..num_slaves];
+        if slaves.iter().any(|slave| slave.support_dc) {
+            self.begin(InitStep::RestoreDc, None);
+            let reference = self.dc_initializer().restore_dc(slaves, master_time)?;
+            if let (Some(reference), Some((threshold_ns, max_rounds))) =
+                (reference, self.dc_settling)
+            {
+                self.begin(InitStep::SettleDc, None);
+                self.dc_initializer()
+                    .wait_for_settle(&slaves[reference], threshold_ns, max_rounds)?
+                    .ok_or(InitError::DcNotSettled)?;
+            }
+        }
+        for slave in slaves.iter_mut() {
+            let address = SlaveAddress::StationAddress(slave.configured_address);
+            self.begin(
+                InitStep::ChangeAlState(AlState::PreOperational),
+                Some(slave.position_address),
+            );
+            let mut al_transfer = ALStateTransfer::new(self.iface, self.timer);
+            al_transfer.change_al_state(address, AlState::PreOperational)?;
+            slave.al_state = AlState::PreOperational;
+            if let Some(logical_address) = slave.logical_address {
+                self.configure_slave_process_data(slave, logical_address)?;
+            }
+        }
+
+        let mut network = NetworkDescription::new(slave_buffer, num_slaves);
+        network.set_expected_num_slaves(num_slaves as u16);
+        Ok(network)
+    }
+
     pub fn count_slaves(&mut self) -> Result<u16, InitError> {
+        self.begin(InitStep::CountSlaves, None);
         let mut wkc = 0;
         loop {
             self.iface
                 .add_command(u8::MAX, CommandType::BRD, 0, 0, 1, |_| ())?;
-            self.iface.poll(MicrosDurationU32::from_ticks(1000))?;
+            self.iface.poll(RECEIVE_TIMEOUT_DEFAULT)?;
             let pdu = self
                 .iface
                 .consume_command()
@@ -121,6 +778,44 @@ where
         Ok(())
     }
 
+    /// Changes the loop setting of `port` of the slave, e.g. `LoopControl::Closed` to cut off
+    /// a faulty branch. The slaves behind a closed port are no longer reached.
+    pub fn set_loop_control(
+        &mut self,
+        slave: &Slave,
+        port: usize,
+        loop_control: LoopControl,
+    ) -> Result<(), InitError> {
+        if port >= slave.ports.len() {
+            return Err(InitError::InvalidPort(port as u8));
+        }
+        let address = SlaveAddress::StationAddress(slave.configured_address);
+        let mut dl_control = self.iface.read_dl_control(address)?;
+        dl_control.set_loop_control(port, loop_control);
+        self.iface.write_dl_control(address, Some(dl_control))?;
+        Ok(())
+    }
+
+    /// Checks that enhanced link detection is enabled on every connected port
+    /// if the slave requires it.
+    pub fn verify_link_detection(&mut self, slave: &mut Slave) -> Result<(), InitError> {
+        if !slave.require_enhanced_link_detection {
+            return Ok(());
+        }
+        // SIIから読み込まれた値を確認する
+        let address = SlaveAddress::StationAddress(slave.configured_address);
+        let pdi_control = self.iface.read_pdi_control(address)?;
+        for (port, enabled) in slave.enhanced_link_detection.iter_mut().enumerate() {
+            *enabled = pdi_control.enhanced_link_detection(port);
+        }
+        for port in 0..slave.ports.len() {
+            if slave.ports[port].is_some() && !slave.enhanced_link_detection[port] {
+                return Err(InitError::EnhancedLinkDetectionDisabled(port as u8));
+            }
+        }
+        Ok(())
+    }
+
     pub fn set_station_address(
         &mut self,
         slave: &mut Slave,
@@ -135,16 +830,368 @@ where
         Ok(())
     }
 
-    // TODO：もっと分解する
-    fn init_slave(&mut self, slave_number: u16) -> Result<Option<Slave>, InitError> {
+    /// Configures SM2/SM3 and FMMU0/FMMU1 from the current PDO mappings and
+    /// lays out the logical image in the order of `slaves`.
+    /// All slaves must be in PreOp. Call again after changing the mappings.
+    pub fn configure_process_data(
+        &mut self,
+        slaves: &mut [Slave],
+    ) -> Result<ProcessImage, InitFailure> {
+        let total = slaves.len() as u16;
+        let result = self.layout_process_data(slaves.iter_mut(), total, LOGICAL_START_ADDRESS);
+        self.track(result)
+    }
+
+    /// Same as `configure_process_data` for the slaves of `group`, laid out from
+    /// `logical_start_address`. Every group gets its own image, which is exchanged separately,
+    /// so the images of the groups must not overlap.
+    pub fn configure_group_process_data(
+        &mut self,
+        network: &mut NetworkDescription,
+        group: SlaveGroup,
+        logical_start_address: u32,
+    ) -> Result<ProcessImage, InitFailure> {
+        let total = network.slaves_in(group).count() as u16;
+        let result =
+            self.layout_process_data(network.slaves_in_mut(group), total, logical_start_address);
+        self.track(result)
+    }
+
+    fn layout_process_data<'s, 'b: 's, I: Iterator<Item = &'s mut Slave<'b>>>(
+        &mut self,
+        slaves: I,
+        total: u16,
+        logical_start_address: u32,
+    ) -> Result<ProcessImage, InitError> {
+        self.lifecycle.enter(MasterPhase::Configuring, total);
+        let mut logical_address = logical_start_address;
+        let mut expected_wkc = 0;
+        let mut use_lrw = true;
+        for slave in slaves {
+            let (size, wkc) = self.configure_slave_process_data(slave, logical_address)?;
+            logical_address += size;
+            expected_wkc += wkc;
+            // LRWに応答しないスレーブが1台でもあれば、イメージ全体をLWRとLRDでやり取りする
+            use_lrw &= size == 0 || slave.support_lrw;
+            self.lifecycle.advance();
+        }
+
+        Ok(ProcessImage {
+            logical_start_address,
+            size: (logical_address - logical_start_address) as usize,
+            expected_wkc,
+            use_lrw,
+        })
+    }
+
+    /// Maps the outputs of the consumers of `links` onto the inputs of the producers in `image`,
+    /// using FMMU2 of the consumers. Call after `configure_process_data` with the same `slaves`.
+    /// The image must be exchanged with LRW (`image.use_lrw`), since separate LRD/LWR datagrams
+    /// would not carry the data from one slave to the other. `image.expected_wkc` is recounted: a consumer increments
+    /// the WKC once for writing, even if both its own and the linked FMMU are addressed.
+    /// A consumer can have only one link, since it is mapped with its single FMMU2.
+    pub fn configure_process_data_links(
+        &mut self,
+        slaves: &[Slave],
+        links: &[ProcessDataLink],
+        image: &mut ProcessImage,
+    ) -> Result<(), InitError> {
+        // 2つ目のリンクはFMMU2を上書きしてしまう
+        for (i, link) in links.iter().enumerate() {
+            if links[..i].iter().any(|other| other.consumer == link.consumer) {
+                return Err(InitError::InvalidProcessDataLink);
+            }
+        }
+        for link in links {
+            let producer = slaves
+                .get(link.producer)
+                .ok_or(InitError::InvalidProcessDataLink)?;
+            let consumer = slaves
+                .get(link.consumer)
+                .ok_or(InitError::InvalidProcessDataLink)?;
+            let (producer_address, consumer_address) =
+                match (producer.logical_address, consumer.logical_address) {
+                    (Some(producer_address), Some(consumer_address)) => {
+                        (producer_address, consumer_address)
+                    }
+                    _ => return Err(InitError::InvalidProcessDataLink),
+                };
+            let output_size = consumer.rx_pdo_size();
+            if link.length == 0
+                || link.length > output_size
+                || link.producer_offset as u32 + link.length as u32 > producer.tx_pdo_size() as u32
+                || consumer.number_of_fmmu < 3
+                || !image.use_lrw
+            {
+                return Err(InitError::InvalidProcessDataLink);
+            }
+            let start_address = consumer
+                .pdo_start_address
+                .ok_or(InitError::ProcessDataNotSupported)?;
+            let address = SlaveAddress::StationAddress(consumer.configured_address);
+
+            // マスターからの出力がリンクされた領域を上書きしないように、FMMU0を縮める
+            let own_size = output_size - link.length;
+            let mut fmmu = FMMURegister::new();
+            if own_size != 0 {
+                fmmu.set_logical_start_address(consumer_address);
+                fmmu.set_length(own_size);
+                fmmu.set_logical_end_bit(7);
+                fmmu.set_physical_start_address(start_address);
+                fmmu.set_write_enable(true);
+                fmmu.set_enable(true);
+            }
+            self.iface.write_fmmu0(address, Some(fmmu))?;
+
+            let mut fmmu = FMMURegister::new();
+            fmmu.set_logical_start_address(
+                producer_address + producer.rx_pdo_size() as u32 + link.producer_offset as u32,
+            );
+            fmmu.set_length(link.length);
+            fmmu.set_logical_end_bit(7);
+            fmmu.set_physical_start_address(start_address + own_size);
+            fmmu.set_write_enable(true);
+            fmmu.set_enable(true);
+            self.iface.write_fmmu2(address, Some(fmmu))?;
+        }
+
+        image.expected_wkc = slaves
+            .iter()
+            .filter(|slave| slave.logical_address.is_some())
+            .map(|slave| process_data_wkc(slave.rx_pdo_size() != 0, slave.tx_pdo_size() != 0))
+            .sum();
+        Ok(())
+    }
+
+    /// Configures the process data of one slave at `logical_address`.
+    /// Returns the size in the logical image and the expected WKC of the slave.
+    fn configure_slave_process_data(
+        &mut self,
+        slave: &mut Slave,
+        logical_address: u32,
+    ) -> Result<(u32, u16), InitError> {
+        let address = SlaveAddress::StationAddress(slave.configured_address);
+        let position = Some(slave.position_address);
+        self.begin(InitStep::ReadAlStatus, position);
+        let al_state = AlState::from(self.iface.read_al_status(address)?.state());
+        if al_state != AlState::PreOperational {
+            return Err(InitError::NotPreOperational);
+        }
+
+        let output_size = slave.rx_pdo_size();
+        let input_size = slave.tx_pdo_size();
+        if output_size == 0 && input_size == 0 {
+            slave.logical_address = None;
+            return Ok((0, 0));
+        }
+        let start_address = slave
+            .pdo_start_address
+            .ok_or(InitError::ProcessDataNotSupported)?;
+        if slave.number_of_sm < 4 || slave.fmmu1.is_none() {
+            return Err(InitError::ProcessDataNotSupported);
+        }
+        if output_size + input_size > slave.pdo_ram_size {
+            return Err(InitError::ProcessDataSizeOver);
+        }
+        let mut expected_wkc = 0;
+
+        // 出力用シンクマネージャー(SM2)
+        let mut sm = SyncManagerRegister::new();
+        if output_size != 0 {
+            sm.set_physical_start_address(start_address);
+            sm.set_length(output_size);
+            sm.set_buffer_type(slave.output_buffer_type as u8);
+            sm.set_direction(1); //slave read access
+            sm.set_watchdog_enable(true);
+            sm.set_channel_enable(true);
+        }
+        self.begin(InitStep::WriteSyncManager(2), position);
+        self.iface.write_sm2(address, Some(sm))?;
+
+        // 入力用シンクマネージャー(SM3)
+        let mut sm = SyncManagerRegister::new();
+        if input_size != 0 {
+            sm.set_physical_start_address(start_address + output_size);
+            sm.set_length(input_size);
+            sm.set_buffer_type(slave.input_buffer_type as u8);
+            sm.set_direction(0); //slave write access
+            sm.set_channel_enable(true);
+        }
+        self.begin(InitStep::WriteSyncManager(3), position);
+        self.iface.write_sm3(address, Some(sm))?;
+
+        // 論理アドレスはprocess_cyclic_dataと同じく出力、入力の順に並べる
+        let mut fmmu = FMMURegister::new();
+        if output_size != 0 {
+            fmmu.set_logical_start_address(logical_address);
+            fmmu.set_length(output_size);
+            fmmu.set_logical_end_bit(7);
+            fmmu.set_physical_start_address(start_address);
+            fmmu.set_write_enable(true);
+            fmmu.set_enable(true);
+            expected_wkc += WKC_WRITE;
+        }
+        self.begin(InitStep::WriteFmmu(0), position);
+        self.iface.write_fmmu0(address, Some(fmmu))?;
+
+        let mut fmmu = FMMURegister::new();
+        if input_size != 0 {
+            fmmu.set_logical_start_address(logical_address + output_size as u32);
+            fmmu.set_length(input_size);
+            fmmu.set_logical_end_bit(7);
+            fmmu.set_physical_start_address(start_address + output_size);
+            fmmu.set_read_enable(true);
+            fmmu.set_enable(true);
+            expected_wkc += WKC_READ;
+        }
+        self.begin(InitStep::WriteFmmu(1), position);
+        self.iface.write_fmmu1(address, Some(fmmu))?;
+        self.begin(InitStep::VerifySyncManagers, position);
+        self.verify_sync_managers(slave)?;
+
+        slave.logical_address = Some(logical_address);
+        Ok(((output_size + input_size) as u32, expected_wkc))
+    }
+
+    /// Checks that the enabled sync managers use the expected buffer type:
+    /// mailbox for SM0/SM1 and the selected type for SM2/SM3.
+    /// A wrong buffer type does not fail the exchange, it silently gives stale data.
+    pub fn verify_sync_managers(&mut self, slave: &Slave) -> Result<(), InitError> {
+        let address = SlaveAddress::StationAddress(slave.configured_address);
+        let expected = [
+            SyncManagerBufferType::Mailbox,
+            SyncManagerBufferType::Mailbox,
+            slave.output_buffer_type,
+            slave.input_buffer_type,
+        ];
+        for (i, expected) in expected.iter().enumerate().take(slave.number_of_sm as usize) {
+            let sm = match i {
+                0 => self.iface.read_sm0(address)?,
+                1 => self.iface.read_sm1(address)?,
+                2 => self.iface.read_sm2(address)?,
+                _ => self.iface.read_sm3(address)?,
+            };
+            if sm.channel_enable() && sm.buffer_type() != *expected as u8 {
+                return Err(InitError::UnexpectedBufferType(i as u8));
+            }
+        }
+        Ok(())
+    }
+
+    /// Re-runs the initialization of a slave that lost power and came back,
+    /// while the other slaves keep their configuration.
+    /// The station address, the process data at the previous logical address and the DC offset
+    /// are restored, and the slave is brought to `al_state`.
+    /// `reference_clock` is required if the slave supports DC.
+    pub fn reinitialize_slave(
+        &mut self,
+        slave: &mut Slave<'_>,
+        reference_clock: Option<&Slave>,
+        al_state: AlState,
+    ) -> Result<(), InitError> {
+        let position = slave.position_address;
+        let mut new_slave = self
+            .init_slave(position)?
+            .ok_or(InitError::NotExistSlave(position))?;
+        // 別のスレーブに差し替えられていたら設定を復元しない
+        if new_slave.id != slave.id {
+            return Err(InitError::SlaveMismatch(position));
+        }
+        self.set_station_address(&mut new_slave, slave.configured_address)?;
+
+        // アプリケーションが設定したものと測定済みの値を引き継ぐ
+        new_slave.rx_pdo_mapping = slave.rx_pdo_mapping.take();
+        new_slave.tx_pdo_mapping = slave.tx_pdo_mapping.take();
+        new_slave.fail_safe_outputs = slave.fail_safe_outputs;
+        new_slave.require_enhanced_link_detection = slave.require_enhanced_link_detection;
+        new_slave.parent = slave.parent;
+        new_slave.dc_propagation_delay = slave.dc_propagation_delay;
+        new_slave.logical_address = slave.logical_address;
+        new_slave.operation_mode = core::mem::take(&mut slave.operation_mode);
+        new_slave.error_history = core::mem::take(&mut slave.error_history);
+        *slave = new_slave;
+
+        let address = SlaveAddress::StationAddress(slave.configured_address);
+        let mut al_transfer = ALStateTransfer::new(self.iface, self.timer);
+        al_transfer.change_al_state(address, AlState::PreOperational)?;
+        slave.al_state = AlState::PreOperational;
+
+        if let Some(logical_address) = slave.logical_address {
+            self.configure_slave_process_data(slave, logical_address)?;
+        }
+        if let (true, Some(reference_clock)) = (slave.support_dc, reference_clock) {
+            self.dc_initializer().reinit_dc(slave, reference_clock)?;
+        }
+
+        if al_state != AlState::PreOperational {
+            let mut al_transfer = ALStateTransfer::new(self.iface, self.timer);
+            al_transfer.change_al_state(address, al_state)?;
+            slave.al_state = al_state;
+        }
+        Ok(())
+    }
+
+    /// Reads the capabilities, the SII and the ports of the slave at `position` again, e.g. after
+    /// the device was replaced in the field, and updates the topology of `network`.
+    /// The other slaves keep running. The slave keeps its station address and group, and is left
+    /// in Init to be configured like after `init_network`.
+    /// The settings of the application are carried over only if the identity did not change.
+    pub fn rescan_slave(
+        &mut self,
+        network: &mut NetworkDescription,
+        position: u16,
+    ) -> Result<(), InitFailure> {
+        let result = self.rescan(network, position);
+        self.track(result)
+    }
+
+    fn rescan(&mut self, network: &mut NetworkDescription, position: u16) -> Result<(), InitError> {
+        let slave = network
+            .slave_mut(position)
+            .ok_or(InitError::NotExistSlave(position))?;
+        self.begin(InitStep::CountSlaves, None);
+        if position >= self.count_slaves()? {
+            return Err(InitError::NotExistSlave(position));
+        }
+        self.begin(InitStep::ResetSlaves, Some(position));
+        self.reset_slave(position)?;
+        let mut new_slave = self.configure_slave(position)?;
+        self.begin(InitStep::SetStationAddress, Some(position));
+        self.set_station_address(&mut new_slave, slave.configured_address)?;
+
+        new_slave.group = slave.group;
+        // 同じ製品ならアプリケーションの設定を引き継ぐ
+        if new_slave.id == slave.id {
+            new_slave.rx_pdo_mapping = slave.rx_pdo_mapping.take();
+            new_slave.tx_pdo_mapping = slave.tx_pdo_mapping.take();
+            new_slave.output_buffer_type = slave.output_buffer_type;
+            new_slave.input_buffer_type = slave.input_buffer_type;
+            new_slave.fail_safe_outputs = slave.fail_safe_outputs;
+            new_slave.forced_outputs = slave.forced_outputs;
+            new_slave.forced_inputs = slave.forced_inputs;
+            new_slave.detect_input_changes = slave.detect_input_changes;
+            new_slave.require_enhanced_link_detection = slave.require_enhanced_link_detection;
+            new_slave.logical_address = slave.logical_address;
+            new_slave.operation_mode = core::mem::take(&mut slave.operation_mode);
+            new_slave.error_history = core::mem::take(&mut slave.error_history);
+        }
+        *slave = new_slave;
+        // ポートの状態が変わっていれば親子関係も変わる
+        network.refresh_topology();
+        Ok(())
+    }
+
+    fn init_slave<'s>(&mut self, slave_number: u16) -> Result<Option<Slave<'s>>, InitError> {
         let count = self.count_slaves()?;
         if slave_number >= count {
             return Ok(None);
         }
+        self.reset_slave(slave_number)?;
+        self.configure_slave(slave_number).map(Some)
+    }
 
-        let mut slave = Slave::default();
-        slave.position_address = slave_number;
-
+    /// Brings the slave to Init and resets the data link settings and watchdogs.
+    fn reset_slave(&mut self, slave_number: u16) -> Result<(), InitError> {
         // ループポートを設定する。
         // ・EtherCAT以外のフレームを削除する。
         // ・ソースMACアドレスを変更して送信する。
@@ -159,7 +1206,6 @@ where
         // 一応ループポートの設定の後にしている。
         let mut al_transfer = ALStateTransfer::new(self.iface, self.timer);
         al_transfer.change_al_state(SlaveAddress::SlaveNumber(slave_number), AlState::Init)?;
-        slave.al_state = AlState::Init;
 
         // エラーカウンタをリセットする。
         self.iface
@@ -184,8 +1230,7 @@ where
             .write_sm_watch_dog(SlaveAddress::SlaveNumber(slave_number), Some(sm_watchdog))?;
 
         // スレーブでEEPROMが正常にロードされたか確認する。
-        self.timer
-            .start(MillisDurationU32::from_ticks(200).convert());
+        self.timer.start(EEPROM_LOAD_TIMEOUT_DEFAULT.convert());
         loop {
             let is_pdi_operational = self
                 .iface
@@ -200,11 +1245,48 @@ where
                 Err(nb::Error::WouldBlock) => (),
             }
         }
+        Ok(())
+    }
+
+    /// SII access to a slave with its quirks and the EEPROM size read by `configure_slave`.
+    fn sii(&mut self, slave: &Slave) -> SlaveInformationInterface<'_, 'a, D, T> {
+        let mut sii = SlaveInformationInterface::new(self.iface);
+        sii.set_max_busy_polls(slave.quirks.sii_busy_polls);
+        if let Some(size) = slave.eeprom_size {
+            sii.set_eeprom_size(size);
+        }
+        sii
+    }
+
+    // TODO：もっと分解する
+    /// Reads the capabilities of a slave in Init and configures the station address and mailbox.
+    fn configure_slave<'s>(&mut self, slave_number: u16) -> Result<Slave<'s>, InitError> {
+        let mut slave = self.begin_slave_configuration(slave_number)?;
+        self.begin(InitStep::ReadSii(SII_INFO_ADDRESS), Some(slave_number));
+        let mut sii_info = [0; SII_INFO_SIZE];
+        self.sii(&slave).read_words(
+            SlaveAddress::SlaveNumber(slave_number),
+            SII_INFO_ADDRESS,
+            &mut sii_info,
+        )?;
+        apply_sii_info(&mut slave, &sii_info);
+        self.end_slave_configuration(&mut slave)?;
+        Ok(slave)
+    }
+
+    /// First part of `configure_slave`, up to the checks of the SII.
+    fn begin_slave_configuration<'s>(&mut self, slave_number: u16) -> Result<Slave<'s>, InitError> {
+        let mut slave = Slave::default();
+        slave.position_address = slave_number;
+        slave.al_state = AlState::Init;
+        let position = Some(slave_number);
 
         // ステーションアドレスを設定する。
+        self.begin(InitStep::SetStationAddress, position);
         self.set_station_address(&mut slave, slave_number)?;
 
         // dlインフォの入手。各種サポート状況の確認
+        self.begin(InitStep::ReadDlInformation, position);
         let dl_info = self
             .iface
             .read_dl_information(SlaveAddress::SlaveNumber(slave_number))?;
@@ -214,9 +1296,19 @@ where
         slave.support_lrw = !dl_info.not_lrw_supported(); //これが無いと事実上プロセスデータに対応しない。
         slave.support_rw = !dl_info.not_bafrw_supported(); //これが無いと事実上DCに対応しない。
         slave.ram_size_kb = dl_info.ram_size();
+        slave.esc = EscInfo {
+            esc_type: dl_info.ethercat_type(),
+            revision: dl_info.revision(),
+            build: dl_info.build_number(),
+        };
+        // 既知の問題があるESCではサポートビットより品種ごとの設定を優先する
+        slave.quirks = quirks_for(&slave.esc, self.quirk_table);
+        slave.support_lrw &= !slave.quirks.avoid_lrw;
+        slave.support_rw &= !slave.quirks.avoid_rw;
         //fmmuの確認
         //2個はないと入出力のどちらかしかできないはず。
         let number_of_fmmu = dl_info.number_of_supported_fmmu_entities();
+        self.begin(InitStep::ClearFmmus, position);
         if number_of_fmmu >= 1 {
             slave.fmmu0 = Some(0x0600);
             // FMMUの設定をクリア
@@ -229,9 +1321,16 @@ where
             self.iface
                 .write_fmmu1(SlaveAddress::SlaveNumber(slave_number), None)?;
         }
+        if number_of_fmmu >= 3 {
+            // スレーブ間コピー用
+            self.iface
+                .write_fmmu2(SlaveAddress::SlaveNumber(slave_number), None)?;
+        }
+        slave.number_of_fmmu = number_of_fmmu;
         slave.number_of_sm = dl_info.number_of_supported_sm_channels(); //後で使う
 
         // ポートの設定
+        self.begin(InitStep::ReadPorts, position);
         let dl_status = self
             .iface
             .read_dl_status(SlaveAddress::SlaveNumber(slave_number))?;
@@ -247,106 +1346,44 @@ where
         if dl_status.signal_detection_port3() {
             slave.ports[3] = dl_info.port3_type();
         }
+        let pdi_control = self
+            .iface
+            .read_pdi_control(SlaveAddress::SlaveNumber(slave_number))?;
+        for (port, enabled) in slave.enhanced_link_detection.iter_mut().enumerate() {
+            *enabled = pdi_control.enhanced_link_detection(port);
+        }
 
-        //ベンダーIDとかの設定
+        // 壊れたEEPROMの値を使わないように、先に整合性を確認する
+        #[cfg(feature = "sii")]
+        if let Some(check) = self.sii_config_check {
+            self.begin(InitStep::ReadSii(0), position);
+            let mut sii = SlaveInformationInterface::new(&mut self.iface);
+            sii.set_max_busy_polls(slave.quirks.sii_busy_polls);
+            sii.verify_config_area(SlaveAddress::SlaveNumber(slave_number), check)?;
+        }
+        // 以降の読み出しはEEPROMの範囲内に限る
+        self.begin(InitStep::ReadSii(sii_reg::Size::ADDRESS), position);
         let mut sii = SlaveInformationInterface::new(&mut self.iface);
-        let (vender_id, _size) = sii.read(
-            SlaveAddress::SlaveNumber(slave_number),
-            sii_reg::VenderID::ADDRESS,
-        )?;
-        slave.id.vender_id = vender_id.sii_data() as u16;
-        let (product_code, _size) = sii.read(
-            SlaveAddress::SlaveNumber(slave_number),
-            sii_reg::ProductCode::ADDRESS,
-        )?;
-        slave.id.product_code = product_code.sii_data() as u16;
-        let (revision_number, _size) = sii.read(
-            SlaveAddress::SlaveNumber(slave_number),
-            sii_reg::RevisionNumber::ADDRESS,
-        )?;
-        slave.id.revision_number = revision_number.sii_data() as u16;
+        sii.set_max_busy_polls(slave.quirks.sii_busy_polls);
+        slave.eeprom_size = Some(sii.eeprom_size(SlaveAddress::SlaveNumber(slave_number))?);
+        #[cfg(feature = "sii")]
+        if self.verify_sii_categories {
+            self.begin(InitStep::ReadSii(SII_CATEGORY_START_ADDRESS), position);
+            let mut sii = self.sii(&slave);
+            sii.verify_categories(SlaveAddress::SlaveNumber(slave_number))?;
+        }
+        Ok(slave)
+    }
+
+    /// Last part of `configure_slave`, after the SII words are applied with `apply_sii_info`.
+    fn end_slave_configuration(&mut self, slave: &mut Slave) -> Result<(), InitError> {
+        let slave_number = slave.position_address;
+        let position = Some(slave_number);
 
         //シンクマネージャーのサイズとかオフセット
         // Sync Managerの設定をクリア
-        if slave.number_of_sm >= 1 {
-            self.iface
-                .write_sm0(SlaveAddress::SlaveNumber(slave_number), None)?;
-        }
-        if slave.number_of_sm >= 2 {
-            self.iface
-                .write_sm1(SlaveAddress::SlaveNumber(slave_number), None)?;
-        }
-        if slave.number_of_sm >= 3 {
-            self.iface
-                .write_sm2(SlaveAddress::SlaveNumber(slave_number), None)?;
-        }
-        if slave.number_of_sm >= 4 {
-            self.iface
-                .write_sm3(SlaveAddress::SlaveNumber(slave_number), None)?;
-        }
-        //まずは、メールボックスを使うプロトコルに対応しているか？
-        let (mailbox_protocol, _size) = sii.read(
-            SlaveAddress::SlaveNumber(slave_number),
-            sii_reg::MailboxProtocol::ADDRESS,
-        )?;
-        slave.has_coe = mailbox_protocol.0[0].get_bit(2);
-        slave.has_foe = mailbox_protocol.0[0].get_bit(3);
-        // COEに対応するならメールボックス用のシンクマネージャーがあるはず・・・
-        if slave.has_coe {
-            assert!(slave.number_of_sm >= 2);
-            let (sm_rx_offset, _size) = sii.read(
-                SlaveAddress::SlaveNumber(slave_number),
-                sii_reg::StandardRxMailboxOffset::ADDRESS,
-            )?;
-            let (sm_rx_size, _size) = sii.read(
-                SlaveAddress::SlaveNumber(slave_number),
-                sii_reg::StandardRxMailboxSize::ADDRESS,
-            )?;
-            slave.sm_mailbox_in = Some(MailboxSyncManager {
-                size: sm_rx_size.sii_data() as u16,
-                start_address: sm_rx_offset.sii_data() as u16,
-            });
-            let (sm_tx_offset, _size) = sii.read(
-                SlaveAddress::SlaveNumber(slave_number),
-                sii_reg::StandardTxMailboxOffset::ADDRESS,
-            )?;
-            let (sm_tx_size, _size) = sii.read(
-                SlaveAddress::SlaveNumber(slave_number),
-                sii_reg::StandardTxMailboxSize::ADDRESS,
-            )?;
-            slave.sm_mailbox_out = Some(MailboxSyncManager {
-                size: sm_tx_size.sii_data() as u16,
-                start_address: sm_tx_offset.sii_data() as u16,
-            });
-        }
-        // FOEに対応するなら、ブートストラップ用のシンクマネージャーがあるはず・・・
-        if slave.has_foe {
-            assert!(slave.number_of_sm >= 2);
-            let (bootstrap_sm_rx_offset, _size) = sii.read(
-                SlaveAddress::SlaveNumber(slave_number),
-                sii_reg::BootstrapRxMailboxOffset::ADDRESS,
-            )?;
-            let (bootstrap_sm_rx_size, _size) = sii.read(
-                SlaveAddress::SlaveNumber(slave_number),
-                sii_reg::BootstrapRxMailboxSize::ADDRESS,
-            )?;
-            slave.bootstrap_sm_mailbox_in = Some(MailboxSyncManager {
-                size: bootstrap_sm_rx_size.sii_data() as u16,
-                start_address: bootstrap_sm_rx_offset.sii_data() as u16,
-            });
-            let (bootstrap_sm_tx_offset, _size) = sii.read(
-                SlaveAddress::SlaveNumber(slave_number),
-                sii_reg::BootstrapTxMailboxOffset::ADDRESS,
-            )?;
-            let (bootstrap_sm_tx_size, _size) = sii.read(
-                SlaveAddress::SlaveNumber(slave_number),
-                sii_reg::BootstrapTxMailboxSize::ADDRESS,
-            )?;
-            slave.bootstrap_sm_mailbox_out = Some(MailboxSyncManager {
-                size: bootstrap_sm_tx_size.sii_data() as u16,
-                start_address: bootstrap_sm_tx_offset.sii_data() as u16,
-            });
-        }
+        self.begin(InitStep::ClearSyncManagers, position);
+        self.clear_sync_managers(SlaveAddress::SlaveNumber(slave_number), slave.number_of_sm)?;
 
         //プロセスデータ用のスタートアドレスを決める。
         //ただしプロセスデータに対応しているとは限らない。
@@ -363,7 +1400,8 @@ where
                 0
             };
             let sm_end_address = (sm_address0 + sm_size0 - 1).max(sm_address1 + sm_size1 - 1);
-            let end_address = slave.ram_size_kb as u16 * 0x0400 - 1;
+            // プロセスデータRAMは0x1000から始まる
+            let end_address = 0x1000 + slave.ram_size_kb as u16 * 0x0400 - 1;
             let size2 = if end_address > sm_end_address {
                 end_address - sm_end_address
             } else {
@@ -381,11 +1419,48 @@ where
         }
 
         //メールボックス用シンクマネージャーの設定
+        self.begin(InitStep::ConfigureMailbox, position);
+        self.configure_mailbox_sync_managers(SlaveAddress::SlaveNumber(slave_number), slave)?;
+
+        //DC周りの初期化
+        if slave.support_dc {
+            self.begin(InitStep::ResetDc, position);
+            self.reset_dc(SlaveAddress::SlaveNumber(slave_number))?;
+        }
+
+        Ok(())
+    }
+
+    fn clear_sync_managers(
+        &mut self,
+        address: SlaveAddress,
+        number_of_sm: u8,
+    ) -> Result<(), InitError> {
+        if number_of_sm >= 1 {
+            self.iface.write_sm0(address, None)?;
+        }
+        if number_of_sm >= 2 {
+            self.iface.write_sm1(address, None)?;
+        }
+        if number_of_sm >= 3 {
+            self.iface.write_sm2(address, None)?;
+        }
+        if number_of_sm >= 4 {
+            self.iface.write_sm3(address, None)?;
+        }
+        Ok(())
+    }
+
+    fn configure_mailbox_sync_managers(
+        &mut self,
+        address: SlaveAddress,
+        slave: &Slave,
+    ) -> Result<(), InitError> {
         if let Some(sm_in) = slave.sm_mailbox_in {
             let mut sm = SyncManagerRegister::new();
             sm.set_physical_start_address(sm_in.start_address);
             sm.set_length(sm_in.size);
-            sm.set_buffer_type(0b10); //mailbox
+            sm.set_buffer_type(SyncManagerBufferType::Mailbox as u8);
             sm.set_direction(1); //slave read access
             sm.set_dls_user_event_enable(true);
             sm.set_watchdog_enable(true);
@@ -393,12 +1468,13 @@ where
             sm.set_repeat(false);
             sm.set_dc_event_w_bus_w(false);
             sm.set_dc_event_w_loc_w(false);
+            self.iface.write_sm0(address, Some(sm))?;
         }
         if let Some(sm_out) = slave.sm_mailbox_out {
             let mut sm = SyncManagerRegister::new();
             sm.set_physical_start_address(sm_out.start_address);
             sm.set_length(sm_out.size);
-            sm.set_buffer_type(0b10); //mailbox
+            sm.set_buffer_type(SyncManagerBufferType::Mailbox as u8);
             sm.set_direction(0); //slave write access
             sm.set_dls_user_event_enable(true);
             sm.set_watchdog_enable(true);
@@ -406,32 +1482,60 @@ where
             sm.set_repeat(false);
             sm.set_dc_event_w_bus_w(false);
             sm.set_dc_event_w_loc_w(false);
+            self.iface.write_sm1(address, Some(sm))?;
         }
+        Ok(())
+    }
 
-        //DC周りの初期化
-        if slave.support_dc {
-            self.iface
-                .write_dc_activation(SlaveAddress::SlaveNumber(slave_number), None)?;
-            self.iface
-                .write_sync0_cycle_time(SlaveAddress::SlaveNumber(slave_number), None)?;
-            self.iface
-                .write_sync1_cycle_time(SlaveAddress::SlaveNumber(slave_number), None)?;
-            self.iface
-                .write_cyclic_operation_start_time(SlaveAddress::SlaveNumber(slave_number), None)?;
-            self.iface
-                .write_latch0_negative_edge_value(SlaveAddress::SlaveNumber(slave_number), None)?;
-            self.iface
-                .write_latch0_positive_edge_value(SlaveAddress::SlaveNumber(slave_number), None)?;
-            self.iface
-                .write_latch1_negative_edge_value(SlaveAddress::SlaveNumber(slave_number), None)?;
-            self.iface
-                .write_latch1_positive_edge_value(SlaveAddress::SlaveNumber(slave_number), None)?;
-            self.iface
-                .write_latch_edge(SlaveAddress::SlaveNumber(slave_number), None)?;
-            self.iface
-                .write_latch_event(SlaveAddress::SlaveNumber(slave_number), None)?;
-        }
+    /// Clears the DC activation, cycle times and latch settings.
+    fn reset_dc(&mut self, address: SlaveAddress) -> Result<(), InitError> {
+        self.iface.write_dc_activation(address, None)?;
+        self.iface.write_sync0_cycle_time(address, None)?;
+        self.iface.write_sync1_cycle_time(address, None)?;
+        self.iface.write_cyclic_operation_start_time(address, None)?;
+        self.iface.write_latch_edge(address, None)?;
+        self.iface.write_latch_event(address, None)?;
+        Ok(())
+    }
+}
 
-        Ok(Some(slave))
+/// Sets the identity and the mailbox configuration read from `SII_INFO_ADDRESS`.
+fn apply_sii_info(slave: &mut Slave, sii_info: &[u8; SII_INFO_SIZE]) {
+    let word = |address: u16| {
+        let i = (address - SII_INFO_ADDRESS) as usize * 2;
+        byte_order::read_u16(sii_info, i).unwrap_or_default()
+    };
+    //ベンダーIDとかの設定
+    slave.id.vender_id = word(sii_reg::VenderID::ADDRESS);
+    slave.id.product_code = word(sii_reg::ProductCode::ADDRESS);
+    slave.id.revision_number = word(sii_reg::RevisionNumber::ADDRESS);
+
+    //まずは、メールボックスを使うプロトコルに対応しているか？
+    let mailbox_protocol = word(sii_reg::MailboxProtocol::ADDRESS);
+    slave.has_coe = mailbox_protocol.get_bit(2);
+    slave.has_foe = mailbox_protocol.get_bit(3);
+    // COEに対応するならメールボックス用のシンクマネージャーがあるはず・・・
+    if slave.has_coe {
+        assert!(slave.number_of_sm >= 2);
+        slave.sm_mailbox_in = Some(MailboxSyncManager {
+            size: word(sii_reg::StandardRxMailboxSize::ADDRESS),
+            start_address: word(sii_reg::StandardRxMailboxOffset::ADDRESS),
+        });
+        slave.sm_mailbox_out = Some(MailboxSyncManager {
+            size: word(sii_reg::StandardTxMailboxSize::ADDRESS),
+            start_address: word(sii_reg::StandardTxMailboxOffset::ADDRESS),
+        });
+    }
+    // FOEに対応するなら、ブートストラップ用のシンクマネージャーがあるはず・・・
+    if slave.has_foe {
+        assert!(slave.number_of_sm >= 2);
+        slave.bootstrap_sm_mailbox_in = Some(MailboxSyncManager {
+            size: word(sii_reg::BootstrapRxMailboxSize::ADDRESS),
+            start_address: word(sii_reg::BootstrapRxMailboxOffset::ADDRESS),
+        });
+        slave.bootstrap_sm_mailbox_out = Some(MailboxSyncManager {
+            size: word(sii_reg::BootstrapTxMailboxSize::ADDRESS),
+            start_address: word(sii_reg::BootstrapTxMailboxOffset::ADDRESS),
+        });
     }
 }
@@ -2,12 +2,45 @@ use crate::arch::Device;
 use crate::error::CommonError;
 use crate::ethercat_frame::*;
 use crate::packet::ethercat::*;
-use crate::register::{application::*, datalink::*};
+use crate::register::{application::*, datalink::*, ReadableRegister, Register, WritableRegister};
 use crate::util::*;
+use crate::wkc::check_command_wkc;
+use crate::RECEIVE_TIMEOUT_DEFAULT;
 use embedded_hal::timer::CountDown;
 use fugit::MicrosDurationU32;
 use log::*;
 
+/// First PDU index used by `read_register` and `write_register`.
+/// Indices below it are left to batches of datagrams.
+pub const REGISTER_TAG_FIRST: u8 = 0xF0;
+
+/// Frames read after the receive timeout when other traffic was received just before it,
+/// so that responses queued behind a burst of foreign frames are not taken as lost.
+pub const RX_DRAIN_FRAMES: u32 = 16;
+
+// 先頭オクテットのU/Lビット。スレーブは通過したフレームの送信元にこれを立てる
+const LOCALLY_ADMINISTERED_MAC: u64 = 0x02 << 40;
+
+/// Counters of the transmission through the device, to tell backpressure of the NIC driver
+/// from problems on the bus when cycles are missed. The counters wrap around.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InterfaceStatistics {
+    /// Frames given to `Device::send`.
+    pub frames_sent: u32,
+    /// Times `Device::send` declined a TX token, each failing a `poll` with `DeviceErrorTx`.
+    pub tx_declined: u32,
+    /// Frames started because the datagrams of a `poll` did not fit in the MTU.
+    pub mtu_splits: u32,
+    /// Frames sent seen again as sent, e.g. looped back by the NIC driver or a switch.
+    pub echoes: u32,
+    /// Received frames that do not answer the datagrams of the `poll`, e.g. traffic of other
+    /// protocols or EtherCAT frames of another master. Truncated frames are counted here too.
+    pub foreign_frames: u32,
+    /// Frames read after the receive timeout, see `RX_DRAIN_FRAMES`.
+    pub drained_frames: u32,
+}
+
 #[derive(Debug)]
 pub struct EtherCATInterface<'a, D, T>
 where
@@ -15,11 +48,22 @@ where
     T: CountDown<Time = MicrosDurationU32>,
 {
     ethdev: D,
-    buffer: &'a mut [u8],
+    tx_buffer: &'a mut [u8],
+    rx_buffer: &'a mut [u8],
     data_size: usize,
+    rx_data_size: usize,
     buffer_size: usize,
     should_recv_frames: usize,
     timer: T,
+    tx_timestamp: Option<u64>,
+    rx_timestamp: Option<u64>,
+    last_datagram: Option<(CommandType, DatagramAddress)>,
+    register_tag: u8,
+    frame_break: Option<usize>,
+    sent: (usize, Option<usize>),
+    register_response: Option<(usize, usize)>,
+    statistics: InterfaceStatistics,
+    mac_address: u64,
 }
 
 impl<'a, D, T> EtherCATInterface<'a, D, T>
@@ -27,20 +71,38 @@ where
     D: Device,
     T: CountDown<Time = MicrosDurationU32>,
 {
-    pub fn new(ethdev: D, timer: T, buffer: &'a mut [u8]) -> Self {
-        let buffer_size = buffer.len();
+    /// Queued datagrams are kept in `tx_buffer` and the received ones in `rx_buffer`,
+    /// so the next datagrams can be queued before the received ones are consumed.
+    /// Only the size of the smaller buffer is used.
+    /// The buffers must meet `Device::buffer_alignment` of `ethdev`, which is not checked here.
+    pub fn new(ethdev: D, timer: T, tx_buffer: &'a mut [u8], rx_buffer: &'a mut [u8]) -> Self {
+        let buffer_size = tx_buffer.len().min(rx_buffer.len());
         Self {
             ethdev,
-            buffer,
+            tx_buffer,
+            rx_buffer,
             data_size: 0,
+            rx_data_size: 0,
             buffer_size,
             should_recv_frames: 0,
             timer,
+            tx_timestamp: None,
+            rx_timestamp: None,
+            last_datagram: None,
+            register_tag: REGISTER_TAG_FIRST,
+            frame_break: None,
+            sent: (0, None),
+            register_response: None,
+            statistics: InterfaceStatistics::default(),
+            mac_address: SRC_MAC,
         }
     }
 
+    /// Data bytes of one more datagram that fit in the buffers.
     pub fn remaing_capacity(&self) -> usize{
-        self.buffer_size - self.data_size - ETHERCAT_HEADER_LENGTH - WKC_LENGTH
+        // 送信バッファにはデータグラムだけを積み、フレームのヘッダは送信時に付ける
+        self.buffer_size
+            .saturating_sub(self.data_size + ETHERCATPDU_HEADER_LENGTH + WKC_LENGTH)
     }
 
     pub fn add_command<F: FnOnce(&mut [u8])>(
@@ -52,7 +114,42 @@ where
         data_size: usize,
         data_writer: F,
     ) -> Result<(), CommonError> {
-        if self.data_size + ETHERCAT_HEADER_LENGTH + data_size + WKC_LENGTH > self.buffer_size {
+        self.add_datagram(
+            pdu_index,
+            command,
+            DatagramAddress::new(command, adp, ado),
+            data_size,
+            data_writer,
+        )
+    }
+
+    /// Same as `add_command`, with a logical command carrying a 32-bit address.
+    pub fn add_logical_command<F: FnOnce(&mut [u8])>(
+        &mut self,
+        pdu_index: u8,
+        command: CommandType,
+        logical_address: u32,
+        data_size: usize,
+        data_writer: F,
+    ) -> Result<(), CommonError> {
+        self.add_datagram(
+            pdu_index,
+            command,
+            DatagramAddress::Logical(logical_address),
+            data_size,
+            data_writer,
+        )
+    }
+
+    pub fn add_datagram<F: FnOnce(&mut [u8])>(
+        &mut self,
+        pdu_index: u8,
+        command: CommandType,
+        address: DatagramAddress,
+        data_size: usize,
+        data_writer: F,
+    ) -> Result<(), CommonError> {
+        if data_size > self.remaing_capacity() {
             return Err(CommonError::BufferExhausted);
         }
 
@@ -66,54 +163,162 @@ where
             return Err(CommonError::BufferExhausted);
         }
 
+        self.last_datagram = Some((command, address));
         let mut header = [0; ETHERCATPDU_HEADER_LENGTH];
         let mut pdu = EtherCATPDU::new_unchecked(&mut header);
         pdu.set_index(pdu_index);
         pdu.set_command_type(command as u8);
-        pdu.set_adp(adp);
-        pdu.set_ado(ado);
+        pdu.set_address(address);
         pdu.set_length(data_size as u16);
 
-        self.buffer[self.data_size..self.data_size + ETHERCATPDU_HEADER_LENGTH]
+        self.tx_buffer[self.data_size..self.data_size + ETHERCATPDU_HEADER_LENGTH]
             .copy_from_slice(&header);
         data_writer(
-            &mut self.buffer[self.data_size + ETHERCATPDU_HEADER_LENGTH
+            &mut self.tx_buffer[self.data_size + ETHERCATPDU_HEADER_LENGTH
                 ..self.data_size + ETHERCATPDU_HEADER_LENGTH + data_size],
         );
 
         // WKC field
-        self.buffer[self.data_size + ETHERCATPDU_HEADER_LENGTH + data_size + 1] = 0;
-        self.buffer[self.data_size + ETHERCATPDU_HEADER_LENGTH + data_size + 2] = 0;
+        let wkc_offset = self.data_size + ETHERCATPDU_HEADER_LENGTH + data_size;
+        self.tx_buffer[wkc_offset..wkc_offset + WKC_LENGTH].fill(0);
 
         self.data_size += ETHERCATPDU_HEADER_LENGTH + data_size + WKC_LENGTH;
         Ok(())
     }
 
+    /// Sends the datagrams queued after this call in another frame than the ones before,
+    /// e.g. to send copies of datagrams that must survive the loss of one frame.
+    /// Only the last split before `poll` takes effect.
+    pub fn split_frame(&mut self) {
+        let queued = EtherCATPDUs::new(self.tx_buffer, self.data_size, 0).count();
+        self.frame_break = Some(queued);
+    }
+
+    /// Queues a copy of the datagram with `pdu_index` queued since the last `poll`.
+    /// Returns false if there is no such datagram.
+    pub fn requeue(&mut self, pdu_index: u8) -> Result<bool, CommonError> {
+        let mut offset = 0;
+        while offset < self.data_size {
+            let pdu = EtherCATPDU::new_unchecked(&self.tx_buffer[offset..self.data_size]);
+            let size = ETHERCATPDU_HEADER_LENGTH + pdu.length() as usize + WKC_LENGTH;
+            if pdu.index() == pdu_index {
+                if self.data_size + size > self.buffer_size {
+                    return Err(CommonError::BufferExhausted);
+                }
+                self.tx_buffer
+                    .copy_within(offset..offset + size, self.data_size);
+                self.data_size += size;
+                return Ok(true);
+            }
+            offset += size;
+        }
+        Ok(false)
+    }
+
+    /// Command and address of the datagram queued last, e.g. to report which access failed.
+    pub fn last_datagram(&self) -> Option<(CommandType, DatagramAddress)> {
+        self.last_datagram
+    }
+
+    /// Hardware timestamp (ns) of the first frame sent in the last `poll`.
+    pub fn last_tx_timestamp(&self) -> Option<u64> {
+        self.tx_timestamp
+    }
+
+    /// Hardware timestamp (ns) of the last frame received in the last `poll`.
+    pub fn last_rx_timestamp(&self) -> Option<u64> {
+        self.rx_timestamp
+    }
+
+    /// Round trip time (ns) of the last `poll` from the hardware timestamps.
+    /// None if the device has no hardware timestamps.
+    pub fn last_round_trip(&self) -> Option<u64> {
+        self.rx_timestamp?.checked_sub(self.tx_timestamp?)
+    }
+
+    /// Source MAC address of the frames sent. `SRC_MAC` by default.
+    pub fn mac_address(&self) -> u64 {
+        self.mac_address
+    }
+
+    /// Sets the source MAC address, e.g. the address of the NIC when a switch or driver
+    /// drops frames of other addresses. Returns false and keeps the address if it is locally
+    /// administered: the slaves set that bit in the frames they pass, and the interface tells
+    /// the responses from its own echoes by it.
+    pub fn set_mac_address(&mut self, mac_address: u64) -> bool {
+        if mac_address & LOCALLY_ADMINISTERED_MAC != 0 {
+            return false;
+        }
+        self.mac_address = mac_address;
+        true
+    }
+
+    pub fn statistics(&self) -> &InterfaceStatistics {
+        &self.statistics
+    }
+
+    pub fn reset_statistics(&mut self) {
+        self.statistics = InterfaceStatistics::default();
+    }
+
+    /// Datagrams received in the last `poll`.
     pub fn consume_command(&mut self) -> EtherCATPDUs {
-        let pdus = EtherCATPDUs::new(self.buffer, self.data_size, 0);
-        self.data_size = 0;
+        let pdus = EtherCATPDUs::new(self.rx_buffer, self.rx_data_size, 0);
+        self.rx_data_size = 0;
         pdus
     }
 
+    /// Sends the queued datagrams and receives their responses.
+    /// After a `ReceiveTimeout`, the datagrams of the frames that did arrive are still
+    /// returned by `consume_command`.
     pub fn poll<I: Into<MicrosDurationU32>>(&mut self, recv_timeout: I) -> Result<(), CommonError> {
-        if !self.transmit() {
+        self.rx_data_size = 0;
+        self.register_response = None;
+        self.tx_timestamp = None;
+        self.rx_timestamp = None;
+        // 前のpollで届かなかったフレームは待たない
+        self.should_recv_frames = 0;
+        self.sent = (self.data_size, self.frame_break);
+        let is_sent = self.transmit();
+        self.frame_break = None;
+        if !is_sent {
+            self.data_size = 0;
             return Err(CommonError::DeviceErrorTx);
         }
-        match self.receive(recv_timeout) {
+        // 送信済みの分は受信バッファで受け取るので、送信バッファは次の要求に使える
+        let sent_size = core::mem::replace(&mut self.data_size, 0);
+        match self.receive(recv_timeout, sent_size) {
             RxRes::Ok => (),
             RxRes::DeviceError => return Err(CommonError::DeviceErrorRx),
-            //RxRes::TimerError => return Err(CommonError::TimerError),
+            RxRes::TimerError => return Err(CommonError::UnspcifiedTimerError),
             RxRes::Timeout => return Err(CommonError::ReceiveTimeout),
         }
         Ok(())
     }
 
+    /// Sends the datagrams of the last `poll` again, e.g. after a receive timeout.
+    /// Call it before queuing other datagrams. Writes are repeated as well, so a slave that
+    /// received the first frame sees them twice.
+    pub fn resend<I: Into<MicrosDurationU32>>(
+        &mut self,
+        recv_timeout: I,
+    ) -> Result<(), CommonError> {
+        let (data_size, frame_break) = self.sent;
+        self.data_size = data_size;
+        self.frame_break = frame_break;
+        self.poll(recv_timeout)
+    }
+
     fn transmit(&mut self) -> bool {
         let Self {
             ethdev,
-            buffer,
+            tx_buffer: buffer,
             data_size,
             should_recv_frames,
+            tx_timestamp,
+            frame_break,
+            statistics,
+            mac_address,
             ..
         } = self;
         let buffer = &buffer[0..*data_size];
@@ -125,12 +330,17 @@ where
             let pdus = EtherCATPDUs::new(buffer, *data_size, 0);
             let mut send_size = 0;
             let mut send_count = actual_send_count;
-            for pdu in pdus {
+            for pdu in pdus.skip(actual_send_count) {
+                // 分割位置より後ろは次のフレームで送る
+                if send_count > actual_send_count && Some(send_count) == *frame_break {
+                    break;
+                }
                 let pdu_length = pdu.length() as usize + ETHERCATPDU_HEADER_LENGTH + WKC_LENGTH;
                 if mtu > send_size + pdu_length {
                     send_size += pdu_length;
                     send_count += 1;
                 } else {
+                    statistics.mtu_splits = statistics.mtu_splits.wrapping_add(1);
                     break;
                 }
             }
@@ -140,6 +350,7 @@ where
                 |tx_buffer| {
                     let mut ec_frame = EtherCATFrame::new_unchecked(tx_buffer);
                     ec_frame.init();
+                    ec_frame.set_source(*mac_address);
                     let pdus = EtherCATPDUs::new(buffer, *data_size, 0);
                     for (i, pdu) in pdus.into_iter().enumerate().skip(actual_send_count) {
                         if i >= send_count {
@@ -147,10 +358,9 @@ where
                         }
                         let index = pdu.index();
                         let command = CommandType::new(pdu.command_type());
-                        let adp = pdu.adp();
-                        let ado = pdu.ado();
+                        let address = pdu.address();
                         let data = pdu.data();
-                        if !ec_frame.add_command(command, adp, ado, data, Some(index)) {
+                        if !ec_frame.add_command(command, address, data, Some(index)) {
                             error!("Failed to add command");
                             panic!();
                         }
@@ -161,47 +371,112 @@ where
                 },
             ) {
                 error!("Failed to consume TX token");
+                statistics.tx_declined = statistics.tx_declined.wrapping_add(1);
                 return false;
             }
+            statistics.frames_sent = statistics.frames_sent.wrapping_add(1);
+            // 往復時間は最初のフレームの送信から数える
+            if tx_timestamp.is_none() {
+                *tx_timestamp = ethdev.tx_timestamp();
+            }
         }
         true
     }
 
     // TODO: timeout
-    fn receive<I: Into<MicrosDurationU32>>(&mut self, timeout: I) -> RxRes {
+    fn receive<I: Into<MicrosDurationU32>>(&mut self, timeout: I, sent_size: usize) -> RxRes {
         let Self {
             ethdev,
-            buffer,
+            tx_buffer,
+            rx_buffer: buffer,
             should_recv_frames,
+            rx_timestamp,
+            statistics,
+            mac_address,
             ..
         } = self;
+        // 受信処理の間は送信したデータグラムが送信バッファに残っている
+        let sent = &tx_buffer[..sent_size];
         let mut data_size = 0;
+        let mut drain = None;
         self.timer.start(timeout);
         while *should_recv_frames > 0 {
-            if let None = ethdev.recv(|frame| {
+            let received = ethdev.recv(|frame| {
                 info!("something receive");
-                let eth = EthernetHeader::new_unchecked(&frame);
-                if eth.source() == SRC_MAC || eth.ether_type() != ETHERCAT_TYPE {
-                    return Some(());
+                let eth = match EthernetHeader::new(frame) {
+                    Some(eth) if eth.ether_type() == ETHERCAT_TYPE => eth,
+                    _ => {
+                        statistics.foreign_frames = statistics.foreign_frames.wrapping_add(1);
+                        return Some(false);
+                    }
+                };
+                let ec_frame = match EtherCATFrame::new(frame) {
+                    Some(ec_frame) if answers_sent(sent, &ec_frame) => ec_frame,
+                    _ => {
+                        statistics.foreign_frames = statistics.foreign_frames.wrapping_add(1);
+                        return Some(false);
+                    }
+                };
+                // スレーブを通ったフレームは送信元MACアドレスのローカルビットが立っている。
+                // NOPのフレームは内容が変わらないので、内容ではなくアドレスで見分ける
+                if eth.source() == *mac_address {
+                    statistics.echoes = statistics.echoes.wrapping_add(1);
+                    return Some(false);
                 }
-                let ec_frame = EtherCATFrame::new_unchecked(frame);
                 for pdu in ec_frame.iter_dlpdu() {
                     let pdu_size = ETHERCATPDU_HEADER_LENGTH + pdu.length() as usize + WKC_LENGTH;
+                    // 重複したフレームなどで受信バッファに収まらない分は捨てる
+                    if data_size + pdu_size > buffer.len() {
+                        break;
+                    }
                     buffer[data_size..data_size + pdu_size].copy_from_slice(&pdu.0);
                     data_size += pdu_size;
                 }
                 *should_recv_frames -= 1;
-                Some(())
-            }) {
-                return RxRes::DeviceError;
+                Some(true)
+            });
+            match (received, drain) {
+                // 期限後は読み出せるフレームがなくなった時点でタイムアウト
+                (None, Some(_)) => {
+                    self.rx_data_size = data_size;
+                    return RxRes::Timeout;
+                }
+                (None, None) => return RxRes::DeviceError,
+                (Some(true), _) => *rx_timestamp = ethdev.rx_timestamp(),
+                (Some(false), _) => (),
+            }
+            if *should_recv_frames == 0 {
+                break;
+            }
+            match drain {
+                Some(0) => {
+                    self.rx_data_size = data_size;
+                    return RxRes::Timeout;
+                }
+                Some(remaining) => {
+                    statistics.drained_frames = statistics.drained_frames.wrapping_add(1);
+                    drain = Some(remaining - 1);
+                    continue;
+                }
+                None => (),
             }
             match self.timer.wait() {
-                Ok(_) => return RxRes::Timeout,
+                // 他のトラフィックの後ろに応答が溜まっているかもしれない
+                Ok(_) if received == Some(false) => drain = Some(RX_DRAIN_FRAMES),
+                Ok(_) => {
+                    // 届いたフレームの分は応答として残す
+                    self.rx_data_size = data_size;
+                    return RxRes::Timeout;
+                }
                 Err(nb::Error::Other(_)) => return RxRes::TimerError,
                 Err(nb::Error::WouldBlock) => (),
             }
         }
-        assert_eq!(data_size, self.data_size);
+        // 応答の照合はPDUのインデックスで行うので、順序や重複の違いは許容する
+        if data_size != sent_size {
+            warn!("received {} bytes of datagrams for {} bytes sent", data_size, sent_size);
+        }
+        self.rx_data_size = data_size;
         RxRes::Ok
     }
 
@@ -211,6 +486,82 @@ where
     //}
 }
 
+/// Whether every datagram of `frame` answers one in `sent` with the same index, command and ADO.
+/// ADP and WKC are not compared, since the slaves change them.
+fn answers_sent(sent: &[u8], frame: &EtherCATFrame<&[u8]>) -> bool {
+    let mut pdus = frame.iter_dlpdu().peekable();
+    pdus.peek().is_some()
+        && pdus.all(|pdu| {
+            EtherCATPDUs::new(sent, sent.len(), 0).any(|sent_pdu| {
+                sent_pdu.index() == pdu.index()
+                    && sent_pdu.command_type() == pdu.command_type()
+                    && sent_pdu.ado() == pdu.ado()
+                    && sent_pdu.length() == pdu.length()
+            })
+        })
+}
+
+/// Buffer size needed for `num_datagrams` datagrams carrying `total_data_size` bytes in one poll.
+pub const fn datagram_buffer_size(num_datagrams: usize, total_data_size: usize) -> usize {
+    ETHERCAT_HEADER_LENGTH
+        + num_datagrams * (ETHERCATPDU_HEADER_LENGTH + WKC_LENGTH)
+        + total_data_size
+}
+
+/// Largest data size of a single datagram with the MTU of the device.
+pub const fn max_datagram_data_size(mtu: usize) -> usize {
+    mtu.saturating_sub(
+        ETHERNET_HEADER_LENGTH + ETHERCAT_HEADER_LENGTH + ETHERCATPDU_HEADER_LENGTH + WKC_LENGTH,
+    )
+}
+
+/// Minimum buffer size of the interface for a network of up to `max_slaves` slaves:
+/// the process image split into datagrams that fit the MTU, one register datagram per slave
+/// (e.g. the mailbox checks of `SdoClient::transfer_many`) and `max_cyclic_units` custom or
+/// system time units of the master.
+pub const fn min_buffer_size(
+    max_slaves: usize,
+    process_image_size: usize,
+    mtu: usize,
+    max_cyclic_units: usize,
+) -> usize {
+    let max_data_size = max_datagram_data_size(mtu);
+    let image_datagrams = if max_data_size == 0 {
+        0
+    } else {
+        (process_image_size + max_data_size - 1) / max_data_size
+    };
+    let unit_data_size = if crate::master::CUSTOM_COMMAND_MAX_LENGTH > DCSystemTime::SIZE {
+        crate::master::CUSTOM_COMMAND_MAX_LENGTH
+    } else {
+        DCSystemTime::SIZE
+    };
+    datagram_buffer_size(
+        image_datagrams + max_slaves + max_cyclic_units,
+        process_image_size
+            + max_slaves * SyncManagerRegister::SIZE
+            + max_cyclic_units * unit_data_size,
+    )
+}
+
+/// Fails to compile if `$buffer_size` is smaller than
+/// `min_buffer_size($max_slaves, $process_image_size, $mtu, $max_cyclic_units)`.
+#[macro_export]
+macro_rules! assert_buffer_size {
+    ($buffer_size:expr, $max_slaves:expr, $process_image_size:expr, $mtu:expr, $max_cyclic_units:expr) => {
+        const _: () = assert!(
+            $buffer_size
+                >= $crate::interface::min_buffer_size(
+                    $max_slaves,
+                    $process_image_size,
+                    $mtu,
+                    $max_cyclic_units
+                ),
+            "the interface buffer is too small"
+        );
+    };
+}
+
 enum RxRes {
     Ok,
     DeviceError,
@@ -236,31 +587,28 @@ where
         size: usize,
         //timeout: I,
     ) -> Result<EtherCATPDU<&[u8]>, CommonError> {
-        match slave_address {
-            SlaveAddress::StationAddress(adr) => self.add_command(
-                u8::MAX,
-                CommandType::FPRD,
-                adr,
-                register_address,
-                size,
-                |buf| buf.iter_mut().for_each(|b| *b = 0),
-            )?,
-            SlaveAddress::SlaveNumber(adr) => self.add_command(
-                u8::MAX,
-                CommandType::APRD,
-                get_ap_adp(adr),
-                register_address,
-                size,
-                |buf| buf.iter_mut().for_each(|b| *b = 0),
-            )?,
+        let tag = self.next_register_tag();
+        let command = match slave_address {
+            SlaveAddress::StationAddress(adr) => {
+                self.add_command(tag, CommandType::FPRD, adr, register_address, size, |buf| {
+                    buf.iter_mut().for_each(|b| *b = 0)
+                })?;
+                CommandType::FPRD
+            }
+            SlaveAddress::SlaveNumber(adr) => {
+                self.add_command(
+                    tag,
+                    CommandType::APRD,
+                    get_ap_adp(adr),
+                    register_address,
+                    size,
+                    |buf| buf.iter_mut().for_each(|b| *b = 0),
+                )?;
+                CommandType::APRD
+            }
         };
-        self.poll(MicrosDurationU32::from_ticks(1000))?;
-        let pdu = self
-            .consume_command()
-            .last()
-            .ok_or(CommonError::PacketDropped)?;
-        check_wkc(&pdu, 1)?;
-        Ok(pdu)
+        self.poll(RECEIVE_TIMEOUT_DEFAULT)?;
+        self.take_register_response(tag, command, register_address)
     }
 
     pub fn write_register<F: FnOnce(&mut [u8])>(
@@ -271,32 +619,320 @@ where
         //timeout: I,
         buffer_writer: F,
     ) -> Result<EtherCATPDU<&[u8]>, CommonError> {
-        match slave_address {
-            SlaveAddress::StationAddress(adr) => self.add_command(
-                u8::MAX,
-                CommandType::FPWR,
-                adr,
-                register_address,
-                size,
-                buffer_writer,
-            )?,
-            SlaveAddress::SlaveNumber(adr) => self.add_command(
-                u8::MAX,
-                CommandType::APWR,
-                get_ap_adp(adr),
-                register_address,
-                size,
-                buffer_writer,
-            )?,
+        let tag = self.next_register_tag();
+        let command = match slave_address {
+            SlaveAddress::StationAddress(adr) => {
+                self.add_command(
+                    tag,
+                    CommandType::FPWR,
+                    adr,
+                    register_address,
+                    size,
+                    buffer_writer,
+                )?;
+                CommandType::FPWR
+            }
+            SlaveAddress::SlaveNumber(adr) => {
+                self.add_command(
+                    tag,
+                    CommandType::APWR,
+                    get_ap_adp(adr),
+                    register_address,
+                    size,
+                    buffer_writer,
+                )?;
+                CommandType::APWR
+            }
+        };
+        self.poll(RECEIVE_TIMEOUT_DEFAULT)?;
+        self.take_register_response(tag, command, register_address)
+    }
+
+    /// Reads a register, lets `modifier` change some of its fields and writes it back, so the
+    /// other fields and the reserved bits keep the values of the slave. Returns the written value.
+    /// The slave may change the register between the read and the write.
+    ///
+    /// ```ignore
+    /// iface.modify_register::<DLControl<[u8; DLControl::SIZE]>, _>(slave_address, |reg| {
+    ///     reg.set_forwarding_rule(true)
+    /// })?;
+    /// ```
+    pub fn modify_register<R, F>(
+        &mut self,
+        slave_address: SlaveAddress,
+        modifier: F,
+    ) -> Result<R, CommonError>
+    where
+        R: Register + ReadableRegister + WritableRegister,
+        F: FnOnce(&mut R),
+    {
+        let pdu = self.read_register(slave_address, R::ADDRESS, R::SIZE)?;
+        let mut register = R::from_bytes(pdu.data());
+        modifier(&mut register);
+        self.write_register(slave_address, R::ADDRESS, R::SIZE, |buf| {
+            buf.copy_from_slice(register.as_bytes())
+        })?;
+        Ok(register)
+    }
+
+    /// `read_register` restricted to registers the master can read.
+    fn read_specific_register<R: ReadableRegister>(
+        &mut self,
+        slave_address: SlaveAddress,
+        register_address: u16,
+        size: usize,
+    ) -> Result<EtherCATPDU<&[u8]>, CommonError> {
+        self.read_register(slave_address, register_address, size)
+    }
+
+    /// `write_register` restricted to registers the master can write.
+    fn write_specific_register<R: WritableRegister, F: FnOnce(&mut [u8])>(
+        &mut self,
+        slave_address: SlaveAddress,
+        register_address: u16,
+        size: usize,
+        buffer_writer: F,
+    ) -> Result<EtherCATPDU<&[u8]>, CommonError> {
+        self.write_register(slave_address, register_address, size, buffer_writer)
+    }
+
+    /// PDU index of the next register access. The indices rotate through
+    /// `REGISTER_TAG_FIRST..=u8::MAX`, so a late response to an earlier access is not taken
+    /// for the current one.
+    fn next_register_tag(&mut self) -> u8 {
+        let tag = self.register_tag;
+        self.register_tag = if tag == u8::MAX {
+            REGISTER_TAG_FIRST
+        } else {
+            tag + 1
+        };
+        tag
+    }
+
+    /// Finds the response of a register access among the received datagrams.
+    fn take_register_response(
+        &mut self,
+        tag: u8,
+        command: CommandType,
+        register_address: u16,
+    ) -> Result<EtherCATPDU<&[u8]>, CommonError> {
+        let mut has_response = false;
+        let mut offset = 0;
+        let mut range = None;
+        for pdu in self.consume_command() {
+            has_response = true;
+            let size = pdu.0.len();
+            if pdu.index() == tag
+                && CommandType::new(pdu.command_type()) == command
+                && pdu.ado() == register_address
+            {
+                range = Some((offset, size));
+                break;
+            }
+            offset += size;
+        }
+        self.register_response = range;
+        match (range, has_response) {
+            (Some(_), _) => (),
+            (None, true) => return Err(CommonError::ResponseMismatch),
+            (None, false) => return Err(CommonError::PacketDropped),
+        }
+        let pdu = self
+            .last_register_response()
+            .ok_or(CommonError::PacketDropped)?;
+        check_command_wkc(&pdu, 1)?;
+        Ok(pdu)
+    }
+
+    /// Response of the last `read_register` or `write_register`, until the next `poll`.
+    pub(crate) fn last_register_response(&self) -> Option<EtherCATPDU<&[u8]>> {
+        let (offset, size) = self.register_response?;
+        Some(EtherCATPDU::new_unchecked(
+            &self.rx_buffer[offset..offset + size],
+        ))
+    }
+
+    /// Largest data size of a single datagram, limited by the MTU of the device and the
+    /// interface buffers.
+    pub fn max_pdu_payload(&self) -> usize {
+        let overhead = ETHERCAT_HEADER_LENGTH + ETHERCATPDU_HEADER_LENGTH + WKC_LENGTH;
+        let by_mtu = self
+            .ethdev
+            .max_transmission_unit()
+            .saturating_sub(ETHERNET_HEADER_LENGTH + overhead);
+        let by_buffer = self.buffer_size.saturating_sub(overhead);
+        by_mtu.min(by_buffer)
+    }
+
+    /// Reads `buffer.len()` bytes from `register_address`. Blocks larger than
+    /// `max_pdu_payload` are read with consecutive datagrams, one per round trip.
+    pub fn read_block(
+        &mut self,
+        slave_address: SlaveAddress,
+        register_address: u16,
+        buffer: &mut [u8],
+    ) -> Result<(), CommonError> {
+        let chunk_size = self.block_chunk_size(register_address, buffer.len())?;
+        let mut address = register_address;
+        for chunk in buffer.chunks_mut(chunk_size) {
+            let pdu = self.read_register(slave_address, address, chunk.len())?;
+            chunk.copy_from_slice(&pdu.data()[..chunk.len()]);
+            address = address.wrapping_add(chunk.len() as u16);
+        }
+        Ok(())
+    }
+
+    /// Writes `data` to `register_address`. Blocks larger than `max_pdu_payload` are written
+    /// with consecutive datagrams, one per round trip, so the slave may see the first part
+    /// before the rest.
+    pub fn write_block(
+        &mut self,
+        slave_address: SlaveAddress,
+        register_address: u16,
+        data: &[u8],
+    ) -> Result<(), CommonError> {
+        let chunk_size = self.block_chunk_size(register_address, data.len())?;
+        let mut address = register_address;
+        for chunk in data.chunks(chunk_size) {
+            self.write_register(slave_address, address, chunk.len(), |buf| {
+                buf.copy_from_slice(chunk)
+            })?;
+            address = address.wrapping_add(chunk.len() as u16);
+        }
+        Ok(())
+    }
+
+    fn block_chunk_size(&self, register_address: u16, size: usize) -> Result<usize, CommonError> {
+        // ESCのアドレス空間(64KB)を超えるブロックは分割しても読めない
+        if register_address as usize + size > u16::MAX as usize + 1 {
+            return Err(CommonError::BufferExhausted);
+        }
+        match self.max_pdu_payload() {
+            0 => Err(CommonError::BufferExhausted),
+            max => Ok(max),
+        }
+    }
+}
+
+impl<'a, D, T> EtherCATInterface<'a, D, T>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+{
+    /// Starts a batch of register accesses executed in a single round trip.
+    /// Fails with `CommonError::CommandsPending` if commands added with `add_command`
+    /// have not been sent yet, since their responses would be taken for entries.
+    pub fn transaction(&mut self) -> Result<Transaction<'_, 'a, D, T>, CommonError> {
+        if self.data_size != 0 {
+            return Err(CommonError::CommandsPending);
+        }
+        Ok(Transaction {
+            iface: self,
+            num_entries: 0,
+        })
+    }
+}
+
+/// Register reads and writes to several slaves queued into one frame.
+/// Each access returns an entry number to look up its result after `execute`.
+pub struct Transaction<'a, 'b, D, T>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+{
+    iface: &'a mut EtherCATInterface<'b, D, T>,
+    num_entries: u8,
+}
+
+impl<'a, 'b, D, T> Transaction<'a, 'b, D, T>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+{
+    pub fn read(
+        &mut self,
+        slave_address: SlaveAddress,
+        register_address: u16,
+        size: usize,
+    ) -> Result<usize, CommonError> {
+        let (command, adp) = match slave_address {
+            SlaveAddress::StationAddress(adr) => (CommandType::FPRD, adr),
+            SlaveAddress::SlaveNumber(adr) => (CommandType::APRD, get_ap_adp(adr)),
+        };
+        self.add(command, adp, register_address, size, |buf| {
+            buf.iter_mut().for_each(|b| *b = 0)
+        })
+    }
+
+    pub fn write<F: FnOnce(&mut [u8])>(
+        &mut self,
+        slave_address: SlaveAddress,
+        register_address: u16,
+        size: usize,
+        buffer_writer: F,
+    ) -> Result<usize, CommonError> {
+        let (command, adp) = match slave_address {
+            SlaveAddress::StationAddress(adr) => (CommandType::FPWR, adr),
+            SlaveAddress::SlaveNumber(adr) => (CommandType::APWR, get_ap_adp(adr)),
+        };
+        self.add(command, adp, register_address, size, buffer_writer)
+    }
+
+    fn add<F: FnOnce(&mut [u8])>(
+        &mut self,
+        command: CommandType,
+        adp: u16,
+        ado: u16,
+        size: usize,
+        buffer_writer: F,
+    ) -> Result<usize, CommonError> {
+        // REGISTER_TAG_FIRSTからu8::MAXまでのインデックスは単発のレジスタアクセスが使う
+        if self.num_entries >= REGISTER_TAG_FIRST {
+            return Err(CommonError::BufferExhausted);
+        }
+        let entry = self.num_entries;
+        self.iface
+            .add_command(entry, command, adp, ado, size, buffer_writer)?;
+        self.num_entries += 1;
+        Ok(entry as usize)
+    }
+
+    pub fn execute(self) -> Result<TransactionResults<'a>, CommonError> {
+        if self.num_entries == 0 {
+            return Ok(TransactionResults {
+                pdus: EtherCATPDUs::new(&[], 0, 0),
+            });
         }
-        self.poll(MicrosDurationU32::from_ticks(1000))?;
+        self.iface.poll(RECEIVE_TIMEOUT_DEFAULT)?;
+        Ok(TransactionResults {
+            pdus: self.iface.consume_command(),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TransactionResults<'a> {
+    pdus: EtherCATPDUs<'a>,
+}
+
+impl<'a> TransactionResults<'a> {
+    /// Result of the entry. Every access is expected to be processed by exactly one slave.
+    pub fn get(&self, entry: usize) -> Result<EtherCATPDU<&'a [u8]>, CommonError> {
         let pdu = self
-            .consume_command()
-            .last()
+            .pdus
+            .clone()
+            .find(|pdu| pdu.index() as usize == entry)
             .ok_or(CommonError::PacketDropped)?;
-        check_wkc(&pdu, 1)?;
+        check_command_wkc(&pdu, 1)?;
         Ok(pdu)
     }
+
+    /// Data of the entry, e.g. `ALStatus(results.data(entry)?)`.
+    pub fn data(&self, entry: usize) -> Result<&'a [u8], CommonError> {
+        let pdu = self.get(entry)?;
+        let size = pdu.length() as usize;
+        Ok(&pdu.0[ETHERCATPDU_HEADER_LENGTH..ETHERCATPDU_HEADER_LENGTH + size])
+    }
 }
 
 macro_rules! define_read_specific_register {
@@ -310,7 +946,11 @@ macro_rules! define_read_specific_register {
                 &mut self,
                 slave_address: SlaveAddress,
             ) -> Result<$reg<[u8; $reg::SIZE]>, CommonError> {
-                self.read_register(slave_address, $reg::$address, $reg::SIZE)
+                self.read_specific_register::<$reg<[u8; $reg::SIZE]>>(
+                    slave_address,
+                    $reg::$address,
+                    $reg::SIZE,
+                )
                 .map(|pdu| {
                     let mut copied = [0; $reg::SIZE];
                     copied.copy_from_slice(&pdu.0[ETHERCATPDU_HEADER_LENGTH..ETHERCATPDU_HEADER_LENGTH + $reg::SIZE]);
@@ -335,7 +975,10 @@ macro_rules! define_write_specific_register {
                 initial_value: Option<$reg::<[u8; $reg::SIZE]>>,
                 //data_writer: F,
             ) -> Result<$reg<&[u8]>, CommonError> {
-                self.write_register(slave_address, $reg::$address, $reg::SIZE,
+                self.write_specific_register::<$reg<[u8; $reg::SIZE]>, _>(
+                    slave_address,
+                    $reg::$address,
+                    $reg::SIZE,
                     |buf|{
                     let mut initial_value = initial_value.unwrap_or($reg([0;$reg::SIZE]));
                     //data_writer(&mut initial_value);
@@ -352,7 +995,13 @@ define_read_specific_register! {
     read_fixed_station_address, FixedStationAddress, ADDRESS;
     read_dl_control, DLControl, ADDRESS;
     read_dl_status, DLStatus, ADDRESS;
+    read_ecat_event_mask, ECATEventMask, ADDRESS;
+    read_al_event_mask, ALEventMask, ADDRESS;
+    read_ecat_event_request, ECATEventRequest, ADDRESS;
+    read_al_event_request, ALEventRequest, ADDRESS;
     read_rx_error_counter, RxErrorCounter, ADDRESS;
+    read_forwarded_rx_error_counter, ForwardedRxErrorCounter, ADDRESS;
+    read_lost_link_counter, LostLinkCounter, ADDRESS;
     read_watch_dog_divider, WatchDogDivider, ADDRESS;
     read_dl_user_watch_dog, DLUserWatchDog, ADDRESS;
     read_sm_watch_dog, SyncManagerChannelWatchDog, ADDRESS;
@@ -370,7 +1019,12 @@ define_read_specific_register! {
     read_sm3, SyncManagerRegister, ADDRESS3;
     read_dc_recieve_time, DCRecieveTime, ADDRESS;
     read_dc_system_time, DCSystemTime, ADDRESS;
-    read_al_control, ALControl, ADDRESS;
+    read_dc_recieve_time_processing_unit, DCRecieveTimeProcessingUnit, ADDRESS;
+    read_dc_system_time_offset, DCSystemTimeOffset, ADDRESS;
+    read_dc_system_time_transmission_delay, DCSystemTimeTransmissionDelay, ADDRESS;
+    read_dc_system_time_difference, DCSystemTimeDifference, ADDRESS;
+    read_dc_speed_counter_start, DCSpeedCounterStart, ADDRESS;
+    read_dc_filter_depth, DCFilterDepth, ADDRESS;
     read_al_status, ALStatus, ADDRESS;
     read_pdi_control, PDIControl, ADDRESS;
     read_pdi_config, PDIConfig, ADDRESS;
@@ -392,7 +1046,10 @@ define_read_specific_register! {
 define_write_specific_register! {
     write_fixed_station_address, FixedStationAddress, ADDRESS;
     write_dl_control, DLControl, ADDRESS;
+    write_ecat_event_mask, ECATEventMask, ADDRESS;
     write_rx_error_counter, RxErrorCounter, ADDRESS;
+    write_forwarded_rx_error_counter, ForwardedRxErrorCounter, ADDRESS;
+    write_lost_link_counter, LostLinkCounter, ADDRESS;
     write_watch_dog_divider, WatchDogDivider, ADDRESS;
     write_dl_user_watch_dog, DLUserWatchDog, ADDRESS;
     write_sm_watch_dog, SyncManagerChannelWatchDog, ADDRESS;
@@ -409,6 +1066,10 @@ define_write_specific_register! {
     write_sm3, SyncManagerRegister, ADDRESS3;
     write_dc_recieve_time, DCRecieveTime, ADDRESS;
     write_dc_system_time, DCSystemTime, ADDRESS;
+    write_dc_system_time_offset, DCSystemTimeOffset, ADDRESS;
+    write_dc_system_time_transmission_delay, DCSystemTimeTransmissionDelay, ADDRESS;
+    write_dc_speed_counter_start, DCSpeedCounterStart, ADDRESS;
+    write_dc_filter_depth, DCFilterDepth, ADDRESS;
     write_al_control, ALControl, ADDRESS;
     write_dc_activation, DCActivation, ADDRESS;
     write_cyclic_operation_start_time, CyclicOperationStartTime, ADDRESS;
@@ -416,8 +1077,4 @@ define_write_specific_register! {
     write_sync1_cycle_time, Sync1CycleTime, ADDRESS;
     write_latch_edge, LatchEdge, ADDRESS;
     write_latch_event, LatchEvent, ADDRESS;
-    write_latch0_positive_edge_value, Latch0PositiveEdgeValue, ADDRESS;
-    write_latch0_negative_edge_value, Latch0NegativeEdgeValue, ADDRESS;
-    write_latch1_positive_edge_value, Latch1PositiveEdgeValue, ADDRESS;
-    write_latch1_negative_edge_value, Latch1NegativeEdgeValue, ADDRESS;
 }
@@ -0,0 +1,213 @@
+use crate::dc::BusTimeConverter;
+use crate::master::Command;
+use crate::register::application::*;
+use crate::slave_status::*;
+use crate::storage::VecStorage;
+use crate::wkc::expected_wkc;
+
+/// Number of slaves a `LatchMonitor` can watch.
+pub const LATCH_MONITOR_MAX_SLAVES: usize = 16;
+
+/// Latch status (0x09AE) to the end of the Latch1 negative edge value (0x09CF).
+pub const LATCH_READ_SIZE: usize = 0x09D0 - 0x09AE;
+
+/// Resolution of the latch values, one cycle of the 100 MHz DC clock.
+pub const LATCH_RESOLUTION_NS: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LatchEdgeKind {
+    Positive,
+    Negative,
+}
+
+/// Edge captured by a latch input of a slave.
+#[derive(Debug, Clone)]
+pub struct LatchTimestamp {
+    /// Station address of the slave.
+    pub slave: u16,
+    /// Latch input, 0 or 1.
+    pub channel: u8,
+    pub edge: LatchEdgeKind,
+    /// System time of the edge. Only the lower 32 bits are valid for 32-bit DC units.
+    pub bus_time: u64,
+    /// Time of the edge in the master clock, None without a `BusTimeConverter`.
+    pub master_time: Option<u64>,
+    /// Uncertainty of `master_time` in ns.
+    pub jitter_ns: u32,
+}
+
+pub type LatchHandler = fn(&LatchTimestamp);
+
+#[derive(Debug, Clone, Copy)]
+pub struct LatchSlave {
+    pub station_address: u16,
+    pub is_64bits: bool,
+}
+
+/// Default storage of the watched slaves.
+pub type LatchSlaves = heapless::Vec<LatchSlave, LATCH_MONITOR_MAX_SLAVES>;
+
+/// Cyclic unit that timestamps the edges on the latch inputs of DC slaves.
+/// It first switches the latch units of the watched slaves to single event mode, then reads
+/// the latch status and values of one slave per cycle, which also re-arms the latches.
+/// The captured bus time is converted to the master clock with the `BusTimeConverter`, which
+/// the application keeps refined, and passed to the handler.
+/// An edge is lost if the same latch sees another edge before its slave is read.
+#[derive(Debug, Clone)]
+pub struct LatchMonitor<S = LatchSlaves> {
+    slaves: S,
+    handler: Option<LatchHandler>,
+    converter: Option<BusTimeConverter>,
+    configured: usize,
+    next: usize,
+    buffer: [u8; LATCH_READ_SIZE],
+    events: u32,
+    failures: u32,
+    last_event: Option<LatchTimestamp>,
+}
+
+impl LatchMonitor {
+    pub fn new() -> Self {
+        Self::with_storage(LatchSlaves::new())
+    }
+}
+
+impl Default for LatchMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: VecStorage<LatchSlave>> LatchMonitor<S> {
+    /// Same as `new`, with the slaves stored in `slaves`.
+    pub fn with_storage(mut slaves: S) -> Self {
+        slaves.clear();
+        Self {
+            slaves,
+            handler: None,
+            converter: None,
+            configured: 0,
+            next: 0,
+            buffer: [0; LATCH_READ_SIZE],
+            events: 0,
+            failures: 0,
+            last_event: None,
+        }
+    }
+
+    /// Watches the latch inputs of a DC slave. Returns false if the monitor is full.
+    pub fn add_slave(&mut self, slave: &Slave) -> bool {
+        let slave = LatchSlave {
+            station_address: slave.configured_address,
+            is_64bits: slave.is_dc_range_64bits,
+        };
+        self.slaves.push(slave).is_ok()
+    }
+
+    pub fn set_handler(&mut self, handler: LatchHandler) {
+        self.handler = Some(handler);
+    }
+
+    pub fn set_converter(&mut self, converter: BusTimeConverter) {
+        self.converter = Some(converter);
+    }
+
+    /// The converter to refine every cycle, e.g. with `SystemTimeDistributor::system_time`.
+    pub fn converter_mut(&mut self) -> Option<&mut BusTimeConverter> {
+        self.converter.as_mut()
+    }
+
+    /// Number of edges captured.
+    pub fn events(&self) -> u32 {
+        self.events
+    }
+
+    /// Number of reads and configuration writes a slave did not answer.
+    /// The monitor goes on with the next slave after a failure.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn last_event(&self) -> Option<&LatchTimestamp> {
+        self.last_event.as_ref()
+    }
+
+    pub(crate) fn process(&mut self) -> Option<(Command, &[u8])> {
+        self.buffer = [0; LATCH_READ_SIZE];
+        let slaves = self.slaves.as_slice();
+        if let Some(slave) = slaves.get(self.configured) {
+            // 両方のラッチの両エッジをシングルイベントモードにする
+            let mut edge = LatchEdge(&mut self.buffer[..LatchEdge::SIZE]);
+            edge.set_latch0_positive_edge(true);
+            edge.set_latch0_negative_edge(true);
+            edge.set_latch1_positive_edge(true);
+            edge.set_latch1_negative_edge(true);
+            let command = Command::new_fpwr(slave.station_address, LatchEdge::ADDRESS);
+            return Some((command, &self.buffer[..LatchEdge::SIZE]));
+        }
+        let slave = slaves.get(self.next)?;
+        let command = Command::new_fprd(slave.station_address, LatchEvent::ADDRESS);
+        Some((command, &self.buffer))
+    }
+
+    pub(crate) fn receive(&mut self, command: Command, data: &[u8], wkc: u16) -> bool {
+        let is_ok = wkc == expected_wkc(command.command_type(), 1);
+        if self.configured < self.slaves.len() {
+            self.configured += 1;
+            if !is_ok {
+                self.failures = self.failures.wrapping_add(1);
+            }
+            return is_ok;
+        }
+        // 応答がなくても次のスレーブに進み、1台が止まっても他の監視を続ける
+        let slave = match self.slaves.as_slice().get(self.next) {
+            Some(slave) => *slave,
+            None => return false,
+        };
+        self.next = (self.next + 1) % self.slaves.len();
+        if !is_ok || data.len() < LATCH_READ_SIZE {
+            self.failures = self.failures.wrapping_add(1);
+            return false;
+        }
+        let status = LatchEvent(&data[..LatchEvent::SIZE]);
+        use LatchEdgeKind::*;
+        let captured = [
+            (status.latch0_positive_event(), 0, Positive, 0x09B0),
+            (status.latch0_negative_event(), 0, Negative, 0x09B8),
+            (status.latch1_positive_event(), 1, Positive, 0x09C0),
+            (status.latch1_negative_event(), 1, Negative, 0x09C8),
+        ];
+        for (_, channel, edge, address) in captured.iter().filter(|(event, ..)| *event) {
+            // 値のオフセットは0x09AEからの位置
+            let offset = (address - LatchEvent::ADDRESS) as usize;
+            let mut value = [0; 8];
+            value.copy_from_slice(&data[offset..offset + 8]);
+            let mut bus_time = u64::from_le_bytes(value);
+            if !slave.is_64bits {
+                bus_time &= u32::MAX as u64;
+            }
+            let event = LatchTimestamp {
+                slave: slave.station_address,
+                channel: *channel,
+                edge: *edge,
+                bus_time,
+                master_time: self
+                    .converter
+                    .as_ref()
+                    .map(|converter| converter.to_local_time(bus_time)),
+                jitter_ns: LATCH_RESOLUTION_NS
+                    + self
+                        .converter
+                        .as_ref()
+                        .map(|converter| converter.jitter_ns())
+                        .unwrap_or(0),
+            };
+            self.events = self.events.wrapping_add(1);
+            if let Some(handler) = self.handler {
+                handler(&event);
+            }
+            self.last_event = Some(event);
+        }
+        true
+    }
+}
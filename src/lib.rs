@@ -1,28 +1,92 @@
 #![no_std]
+
+#[cfg(feature = "std")]
+extern crate std;
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
 pub mod al_state_transfer;
+pub mod al_status_monitor;
 pub mod arch;
+pub mod dc;
+#[cfg(feature = "emulation")]
+pub mod emulation;
+#[cfg(feature = "coe")]
+pub mod device_identity;
+#[cfg(feature = "diagnostics")]
+pub mod diagnosis;
+#[cfg(feature = "coe")]
+pub mod diagnosis_history;
+#[cfg(feature = "eoe")]
+pub mod eoe;
 mod error;
+pub use error::*;
+pub mod esc;
 pub mod ethercat_frame;
+#[cfg(feature = "foe")]
+pub mod foe;
+pub mod image_view;
 pub mod initializer;
 pub mod interface;
+pub mod latch;
+pub mod lifecycle;
+pub mod link_monitor;
 pub mod mailbox;
+#[cfg(feature = "coe")]
+pub mod mailbox_pdo;
 pub mod master;
+#[cfg(all(feature = "coe", feature = "diagnostics"))]
+pub mod master_od;
+pub mod network;
 //pub mod network_config;
 pub mod packet;
+#[cfg(feature = "coe")]
+pub mod parameter_set;
+pub mod passive_scan;
+pub mod process_data;
 pub mod register;
+pub mod rtic;
+#[cfg(feature = "coe")]
+pub mod sdo;
+#[cfg(any(feature = "std", feature = "critical-section"))]
+pub mod shared;
 pub mod sii;
+pub mod slave_reset;
 pub mod slave_status;
+#[cfg(feature = "diagnostics")]
+pub mod snapshot;
+pub mod storage;
+#[cfg(feature = "coe")]
+pub mod sync_parameters;
 pub(crate) mod util;
+pub mod watchdog;
+pub mod wkc;
 
-pub const MAILBOX_REQUEST_RETRY_TIMEOUT_DEFAULT_MS: u32 = 100;
-pub const MAILBOX_RESPONSE_RETRY_TIMEOUT_DEFAULT_MS: u32 = 2000;
+// Timeout. Receiving the response of a register access
+pub const RECEIVE_TIMEOUT_DEFAULT: fugit::MicrosDurationU32 =
+    fugit::MicrosDurationU32::from_ticks(1000);
+// Timeout. Slave loading the EEPROM after power on
+pub const EEPROM_LOAD_TIMEOUT_DEFAULT: fugit::MillisDurationU32 =
+    fugit::MillisDurationU32::from_ticks(200);
+pub const MAILBOX_REQUEST_RETRY_TIMEOUT_DEFAULT: fugit::MillisDurationU32 =
+    fugit::MillisDurationU32::from_ticks(100);
+pub const MAILBOX_RESPONSE_RETRY_TIMEOUT_DEFAULT: fugit::MillisDurationU32 =
+    fugit::MillisDurationU32::from_ticks(2000);
 // Timeout. Init -> PreOp or Init -> Boot
-pub const PREOP_TIMEOUT_DEFAULT_MS: u32 = 3000;
+pub const PREOP_TIMEOUT_DEFAULT: fugit::MillisDurationU32 =
+    fugit::MillisDurationU32::from_ticks(3000);
 // Timeout. SafeOp -> Op or PreOp -> SafeOp
-pub const SAFEOP_OP_TIMEOUT_DEFAULT_MS: u32 = 10000;
+pub const SAFEOP_OP_TIMEOUT_DEFAULT: fugit::MillisDurationU32 =
+    fugit::MillisDurationU32::from_ticks(10000);
 // Timeout. Op/SafeOp/PreOp/Boot -> Init or SafeOp -> PreOp
-pub const BACK_TO_INIT_TIMEOUT_DEFAULT_MS: u32 = 5000;
+pub const BACK_TO_INIT_TIMEOUT_DEFAULT: fugit::MillisDurationU32 =
+    fugit::MillisDurationU32::from_ticks(5000);
 // Timeout. Op -> SafeOp
-pub const BACK_TO_SAFEOP_TIMEOUT_DEFAULT_MS: u32 = 200;
+pub const BACK_TO_SAFEOP_TIMEOUT_DEFAULT: fugit::MillisDurationU32 =
+    fugit::MillisDurationU32::from_ticks(200);
+// Timeout. Storing or restoring parameters in non-volatile memory
+pub const STORE_PARAMETERS_TIMEOUT_DEFAULT: fugit::MillisDurationU32 =
+    fugit::MillisDurationU32::from_ticks(10000);
 
 pub(crate) const LOGICAL_START_ADDRESS: u32 = 0;
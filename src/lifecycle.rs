@@ -0,0 +1,136 @@
+use crate::error::*;
+
+/// Phase of the master, from the scan of the network to the shutdown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MasterPhase {
+    Idle,
+    /// Counting and resetting the slaves.
+    Scanning,
+    /// Reading the SII and configuring the slaves and the process data.
+    Configuring,
+    SafeOp,
+    Op,
+    /// An error stopped the initialization or the exchange.
+    Fault,
+    ShuttingDown,
+}
+
+impl MasterPhase {
+    /// Whether `next` may follow this phase. Every phase may go to `Fault` and `ShuttingDown`.
+    pub fn can_transition_to(&self, next: MasterPhase) -> bool {
+        use MasterPhase::*;
+        match (*self, next) {
+            (current, next) if current == next => true,
+            (_, Fault) | (_, ShuttingDown) => true,
+            (Idle, Scanning) => true,
+            (Scanning, Configuring) => true,
+            // 再スキャンや再設定
+            (Configuring, Scanning) | (SafeOp, Configuring) => true,
+            (Configuring, SafeOp) | (SafeOp, Op) | (Op, SafeOp) => true,
+            (Fault, Idle) | (Fault, Scanning) | (ShuttingDown, Idle) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Progress of the current phase, e.g. 7 of 23 slaves configured.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PhaseProgress {
+    pub done: u16,
+    pub total: u16,
+}
+
+/// Called with the previous and the new phase on every transition.
+pub type PhaseTransitionHandler = fn(MasterPhase, MasterPhase);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LifecycleError {
+    InvalidTransition { from: MasterPhase, to: MasterPhase },
+}
+
+impl Classify for LifecycleError {
+    fn classify(&self) -> (ErrorCategory, RecoveryHint) {
+        match self {
+            Self::InvalidTransition { .. } => {
+                (ErrorCategory::ApplicationBug, RecoveryHint::FixConfig)
+            }
+        }
+    }
+}
+
+/// Current phase and progress of the master.
+/// `SlaveInitilizer` drives it through scanning and configuration, and `EtherCATMaster`
+/// moves it to `Fault` or `ShuttingDown`. The transitions to SafeOp and Op follow the
+/// AL state requests of the application with `transition`.
+#[derive(Debug, Clone)]
+pub struct MasterLifecycle {
+    phase: MasterPhase,
+    progress: PhaseProgress,
+    handler: Option<PhaseTransitionHandler>,
+}
+
+impl MasterLifecycle {
+    pub const fn new() -> Self {
+        Self {
+            phase: MasterPhase::Idle,
+            progress: PhaseProgress { done: 0, total: 0 },
+            handler: None,
+        }
+    }
+
+    pub fn phase(&self) -> MasterPhase {
+        self.phase
+    }
+
+    pub fn progress(&self) -> PhaseProgress {
+        self.progress
+    }
+
+    pub fn set_transition_handler(&mut self, handler: PhaseTransitionHandler) {
+        self.handler = Some(handler);
+    }
+
+    /// Moves to `next` if allowed by `MasterPhase::can_transition_to`.
+    pub fn transition(&mut self, next: MasterPhase) -> Result<(), LifecycleError> {
+        if !self.phase.can_transition_to(next) {
+            return Err(LifecycleError::InvalidTransition {
+                from: self.phase,
+                to: next,
+            });
+        }
+        self.enter(next, 0);
+        Ok(())
+    }
+
+    /// Moves to `next` with `total` steps, regardless of the current phase.
+    pub(crate) fn enter(&mut self, next: MasterPhase, total: u16) {
+        let previous = self.phase;
+        self.phase = next;
+        self.progress = PhaseProgress { done: 0, total };
+        if previous != next {
+            if let Some(handler) = self.handler {
+                handler(previous, next);
+            }
+        }
+    }
+
+    /// Counts one step of the current phase as done.
+    pub(crate) fn advance(&mut self) {
+        self.progress.done = self.progress.done.saturating_add(1);
+    }
+
+    /// Moves to `Fault` unless the master is shutting down.
+    pub(crate) fn fault(&mut self) {
+        if self.phase != MasterPhase::ShuttingDown {
+            self.enter(MasterPhase::Fault, 0);
+        }
+    }
+}
+
+impl Default for MasterLifecycle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,178 @@
+use crate::master::Command;
+use crate::register::datalink::DLStatus;
+use crate::slave_status::*;
+use crate::storage::VecStorage;
+use crate::wkc::expected_wkc;
+use heapless::Deque;
+
+/// Number of slaves a `LinkMonitor` can watch.
+pub const LINK_MONITOR_MAX_SLAVES: usize = 16;
+
+/// Number of link changes kept per slave.
+pub const LINK_HISTORY_DEPTH: usize = 8;
+
+/// Link-up or link-down of a port found by `LinkMonitor`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkEvent {
+    /// Port 0 to 3.
+    pub port: u8,
+    pub is_up: bool,
+    /// Bus time at which the change was found.
+    pub bus_time: u64,
+    /// Bus time of the previous read of the slave. The change happened after it.
+    pub previous_bus_time: u64,
+}
+
+pub type LinkChangeHandler = fn(u16, &LinkEvent);
+
+#[derive(Debug, Clone)]
+pub struct LinkSlave {
+    pub station_address: u16,
+    /// Link status of the ports, bit n for port n. None before the first read.
+    pub links: Option<u8>,
+    /// Bus time of the last read.
+    pub last_read: u64,
+    /// Link changes of the slave, oldest first.
+    pub history: Deque<LinkEvent, LINK_HISTORY_DEPTH>,
+}
+
+/// Default storage of the watched slaves.
+pub type LinkSlaves = heapless::Vec<LinkSlave, LINK_MONITOR_MAX_SLAVES>;
+
+/// Cyclic unit that tracks link-up and link-down of the ports of the slaves, e.g. to correlate
+/// an intermittent connector with vibration or a motion phase of the machine.
+/// The DL status of one slave is read per cycle, so a change is timestamped within one round
+/// through the watched slaves. A link that drops and comes back within that time is missed;
+/// the lost link counters of `FrameLossAnalyzer` still count it.
+/// The timestamps are the bus time set with `set_bus_time` before each cycle, e.g.
+/// `SystemTimeDistributor::system_time`.
+#[derive(Debug, Clone)]
+pub struct LinkMonitor<S = LinkSlaves> {
+    slaves: S,
+    handler: Option<LinkChangeHandler>,
+    next: usize,
+    bus_time: u64,
+    buffer: [u8; DLStatus::SIZE],
+    changes: u32,
+}
+
+impl LinkMonitor {
+    pub fn new() -> Self {
+        Self::with_storage(LinkSlaves::new())
+    }
+}
+
+impl Default for LinkMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: VecStorage<LinkSlave>> LinkMonitor<S> {
+    /// Same as `new`, with the slaves stored in `slaves`.
+    pub fn with_storage(mut slaves: S) -> Self {
+        slaves.clear();
+        Self {
+            slaves,
+            handler: None,
+            next: 0,
+            bus_time: 0,
+            buffer: [0; DLStatus::SIZE],
+            changes: 0,
+        }
+    }
+
+    /// Watches the ports of a slave. Returns false if the monitor is full.
+    pub fn add_slave(&mut self, slave: &Slave) -> bool {
+        let slave = LinkSlave {
+            station_address: slave.configured_address,
+            links: None,
+            last_read: 0,
+            history: Deque::new(),
+        };
+        self.slaves.push(slave).is_ok()
+    }
+
+    pub fn set_handler(&mut self, handler: LinkChangeHandler) {
+        self.handler = Some(handler);
+    }
+
+    /// Bus time used to timestamp the changes found in the next cycles.
+    pub fn set_bus_time(&mut self, bus_time: u64) {
+        self.bus_time = bus_time;
+    }
+
+    pub fn slaves(&self) -> &[LinkSlave] {
+        self.slaves.as_slice()
+    }
+
+    /// Link changes of the slave with `station_address`, oldest first.
+    pub fn history(&self, station_address: u16) -> Option<impl Iterator<Item = &LinkEvent>> {
+        self.slaves
+            .as_slice()
+            .iter()
+            .find(|slave| slave.station_address == station_address)
+            .map(|slave| slave.history.iter())
+    }
+
+    /// Number of changes found.
+    pub fn changes(&self) -> u32 {
+        self.changes
+    }
+
+    pub(crate) fn process(&mut self) -> Option<(Command, &[u8])> {
+        self.buffer = [0; DLStatus::SIZE];
+        let slave = self.slaves.as_slice().get(self.next)?;
+        let command = Command::new_fprd(slave.station_address, DLStatus::ADDRESS);
+        Some((command, &self.buffer))
+    }
+
+    pub(crate) fn receive(&mut self, command: Command, data: &[u8], wkc: u16) -> bool {
+        // 応答がなくても次のスレーブに進み、1台が止まっても他の監視を続ける
+        let index = self.next;
+        self.next = (self.next + 1) % self.slaves.len().max(1);
+        if wkc != expected_wkc(command.command_type(), 1) || data.len() < DLStatus::SIZE {
+            return false;
+        }
+        let bus_time = self.bus_time;
+        let slave = match self.slaves.as_mut_slice().get_mut(index) {
+            Some(slave) => slave,
+            None => return false,
+        };
+        let dl_status = DLStatus(data);
+        let links = [
+            dl_status.link_status_port0(),
+            dl_status.link_status_port1(),
+            dl_status.link_status_port2(),
+            dl_status.link_status_port3(),
+        ]
+        .iter()
+        .enumerate()
+        .fold(0, |links, (port, is_up)| links | ((*is_up as u8) << port));
+        let previous = slave.links.replace(links);
+        let previous_bus_time = core::mem::replace(&mut slave.last_read, bus_time);
+        // 初回は比較しない
+        let changed = match previous {
+            Some(previous) => previous ^ links,
+            None => 0,
+        };
+        for port in (0..4).filter(|port| changed & (1 << port) != 0) {
+            let event = LinkEvent {
+                port,
+                is_up: links & (1 << port) != 0,
+                bus_time,
+                previous_bus_time,
+            };
+            // 古いものから捨てる
+            if slave.history.is_full() {
+                slave.history.pop_front();
+            }
+            let _ = slave.history.push_back(event);
+            self.changes = self.changes.wrapping_add(1);
+            if let Some(handler) = self.handler {
+                handler(slave.station_address, &event);
+            }
+        }
+        true
+    }
+}
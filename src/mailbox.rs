@@ -1,16 +1,875 @@
-use crate::al_state_transfer::*;
 use crate::arch::*;
 use crate::error::*;
 use crate::interface::*;
+use crate::master::Command;
+use crate::packet::ethercat::MailboxError as MailboxErrorPDU;
 use crate::packet::*;
-use crate::register::datalink::*;
-use crate::sii::*;
+use crate::register::datalink::SyncManagerRegister;
 use crate::slave_status::*;
-use bit_field::BitField;
+use crate::*;
 use embedded_hal::timer::*;
 use fugit::*;
 
+/// Service type of a CoE emergency in the upper 4 bits of the CoE header.
+const COE_SERVICE_EMERGENCY: u16 = 1;
+
 #[derive(Debug, Clone)]
 pub enum MailboxError {
     Common(CommonError),
+    NoMailbox,
+    TooLargeData,
+    Timeout(MillisDurationU32),
+    Error(MailboxErrorDetail),
+    /// Another request to the slave waits for its response.
+    Busy,
+    /// The mailbox header does not fit in the received data.
+    UnexpectedResponse,
+}
+
+impl Classify for MailboxError {
+    fn classify(&self) -> (ErrorCategory, RecoveryHint) {
+        use ErrorCategory::*;
+        use RecoveryHint::*;
+        match self {
+            Self::Common(err) => err.classify(),
+            Self::Timeout(_) => (TransientBus, Retry),
+            Self::Error(_) | Self::UnexpectedResponse => (Protocol, Retry),
+            Self::Busy => (ApplicationBug, Retry),
+            Self::NoMailbox | Self::TooLargeData => (ApplicationBug, FixConfig),
+        }
+    }
+}
+
+impl From<CommonError> for MailboxError {
+    fn from(err: CommonError) -> Self {
+        Self::Common(err)
+    }
+}
+
+/// Priority field of the mailbox header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MailboxPriority {
+    Lowest = 0,
+    Low,
+    High,
+    Highest,
+}
+
+impl From<u8> for MailboxPriority {
+    fn from(v: u8) -> Self {
+        match v & 0b11 {
+            0 => Self::Lowest,
+            1 => Self::Low,
+            2 => Self::High,
+            _ => Self::Highest,
+        }
+    }
+}
+
+/// Message waiting in a `MailboxQueue`.
+#[derive(Debug, Clone)]
+pub struct QueuedMessage<const S: usize> {
+    pub mailbox_type: MailboxType,
+    pub priority: MailboxPriority,
+    /// Whether the slave replies, so that the message waits for the request in flight.
+    pub is_request: bool,
+    pub data: heapless::Vec<u8, S>,
+}
+
+/// Outgoing messages of a slave in two queues, filled by `Mailbox::write_or_queue` or `push`
+/// and written by `Mailbox::send_next`. Messages of `MailboxPriority::High` or above,
+/// e.g. aborts, are sent before the normal queue, so they are not stuck behind a long
+/// segmented transfer.
+///
+/// Queuing is opt-in: the application owns one queue per slave and passes it along.
+/// The SDO, FoE and EoE clients and the cyclic units write into the mailbox directly,
+/// and get `MailboxError::Busy` while another request to the slave waits for its response.
+/// That guard holds for every write, queued or not; the queue only saves the caller
+/// from retrying, and a message written around the queue may overtake the queued ones.
+#[derive(Debug)]
+pub struct MailboxQueue<const N: usize, const S: usize> {
+    high: heapless::Deque<QueuedMessage<S>, N>,
+    normal: heapless::Deque<QueuedMessage<S>, N>,
+}
+
+impl<const N: usize, const S: usize> MailboxQueue<N, S> {
+    pub const fn new() -> Self {
+        Self {
+            high: heapless::Deque::new(),
+            normal: heapless::Deque::new(),
+        }
+    }
+
+    /// Queues a request the slave replies to.
+    pub fn push(
+        &mut self,
+        mailbox_type: MailboxType,
+        priority: MailboxPriority,
+        data: &[u8],
+    ) -> Result<(), MailboxError> {
+        self.push_message(mailbox_type, priority, true, data)
+    }
+
+    /// Queues a message without a reply, e.g. an SDO abort or an RxPDO.
+    pub fn push_unconfirmed(
+        &mut self,
+        mailbox_type: MailboxType,
+        priority: MailboxPriority,
+        data: &[u8],
+    ) -> Result<(), MailboxError> {
+        self.push_message(mailbox_type, priority, false, data)
+    }
+
+    fn push_message(
+        &mut self,
+        mailbox_type: MailboxType,
+        priority: MailboxPriority,
+        is_request: bool,
+        data: &[u8],
+    ) -> Result<(), MailboxError> {
+        let data = heapless::Vec::from_slice(data).map_err(|_| MailboxError::TooLargeData)?;
+        let queue = if priority >= MailboxPriority::High {
+            &mut self.high
+        } else {
+            &mut self.normal
+        };
+        queue
+            .push_back(QueuedMessage {
+                mailbox_type,
+                priority,
+                is_request,
+                data,
+            })
+            .map_err(|_| MailboxError::Common(CommonError::BufferExhausted))
+    }
+
+    /// Next message to send, from the high priority queue first.
+    pub fn pop(&mut self) -> Option<QueuedMessage<S>> {
+        self.high.pop_front().or_else(|| self.normal.pop_front())
+    }
+
+    /// Message `pop` returns next.
+    pub fn front(&self) -> Option<&QueuedMessage<S>> {
+        self.high.front().or_else(|| self.normal.front())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.high.is_empty() && self.normal.is_empty()
+    }
+
+    /// Number of queued messages.
+    pub fn len(&self) -> usize {
+        self.high.len() + self.normal.len()
+    }
+
+    /// Drops the normal priority messages, e.g. the remaining segments of a cancelled transfer.
+    pub fn clear_normal(&mut self) {
+        self.normal.clear();
+    }
+}
+
+impl<const N: usize, const S: usize> Default for MailboxQueue<N, S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Handler of a message that arrived while another protocol waited for its response,
+/// called with the station address, the mailbox type and the data after the mailbox header.
+pub type UnsolicitedHandler = fn(u16, u8, &[u8]);
+
+/// Handlers of the messages skipped by `Mailbox::read_response`, by protocol.
+/// Messages without a handler are dropped.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MailboxRouting {
+    /// CoE emergencies, with the CoE header in the data.
+    pub emergency: Option<UnsolicitedHandler>,
+    /// EoE fragments.
+    pub eoe: Option<UnsolicitedHandler>,
+    /// FoE busy and error requests.
+    pub foe: Option<UnsolicitedHandler>,
+    /// Other types, and other CoE services while another protocol waits.
+    pub other: Option<UnsolicitedHandler>,
+}
+
+impl MailboxRouting {
+    /// Passes `message` to its handler unless it is the response to a request of
+    /// `mailbox_type`. Returns true if the message was routed.
+    pub(crate) fn route(
+        &self,
+        station_address: u16,
+        message: &MailboxPDU<&[u8]>,
+        mailbox_type: MailboxType,
+    ) -> bool {
+        let received_type = message.mailbox_type();
+        let is_emergency = received_type == MailboxType::CoE as u8
+            && byte_order::read_u16(message.data(), 0)
+                .map_or(false, |header| header >> 12 == COE_SERVICE_EMERGENCY);
+        if received_type == mailbox_type as u8 && !is_emergency {
+            return false;
+        }
+        let handler = if is_emergency {
+            self.emergency
+        } else if received_type == MailboxType::EoE as u8 {
+            self.eoe
+        } else if received_type == MailboxType::FoE as u8 {
+            self.foe
+        } else {
+            self.other
+        };
+        if let Some(handler) = handler {
+            handler(station_address, received_type, message.data());
+        }
+        true
+    }
+}
+
+pub struct Mailbox<'a, 'b, D, T, U>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+    U: CountDown<Time = MicrosDurationU32>,
+{
+    iface: &'a mut EtherCATInterface<'b, D, T>,
+    timer: &'a mut U,
+    poll_interval: MicrosDurationU32,
+    routing: MailboxRouting,
+}
+
+impl<'a, 'b, D, T, U> Mailbox<'a, 'b, D, T, U>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+    U: CountDown<Time = MicrosDurationU32>,
+{
+    pub fn new(iface: &'a mut EtherCATInterface<'b, D, T>, timer: &'a mut U) -> Self {
+        Self {
+            iface,
+            timer,
+            poll_interval: MicrosDurationU32::from_ticks(0),
+            routing: MailboxRouting::default(),
+        }
+    }
+
+    pub fn set_routing(&mut self, routing: MailboxRouting) {
+        self.routing = routing;
+    }
+
+    pub(crate) fn routing(&self) -> MailboxRouting {
+        self.routing
+    }
+
+    /// Minimum interval between read mailbox checks. 0 checks as fast as possible.
+    pub fn set_poll_interval<I: Into<MicrosDurationU32>>(&mut self, interval: I) {
+        self.poll_interval = interval.into();
+    }
+
+    /// Writes a request into the write mailbox (SM0) of the slave.
+    /// Only one request per slave may wait for its response; another one returns
+    /// `MailboxError::Busy` until the response is read with `read` or `read_response`,
+    /// or the request is given up with `end_request`. A request that timed out stays pending,
+    /// since its response may still arrive. Use `write_or_queue` to queue the request instead.
+    pub fn write<F: FnOnce(&mut [u8])>(
+        &mut self,
+        slave: &mut Slave,
+        mailbox_type: MailboxType,
+        data_size: usize,
+        data_writer: F,
+    ) -> Result<(), MailboxError> {
+        self.write_with_priority(
+            slave,
+            mailbox_type,
+            MailboxPriority::Lowest,
+            data_size,
+            data_writer,
+        )
+    }
+
+    /// Same as `write`, with the priority field of the header set to `priority`.
+    pub fn write_with_priority<F: FnOnce(&mut [u8])>(
+        &mut self,
+        slave: &mut Slave,
+        mailbox_type: MailboxType,
+        priority: MailboxPriority,
+        data_size: usize,
+        data_writer: F,
+    ) -> Result<(), MailboxError> {
+        if slave.mailbox_request_pending {
+            return Err(MailboxError::Busy);
+        }
+        self.write_message(slave, mailbox_type, priority, data_size, data_writer)?;
+        slave.mailbox_request_pending = true;
+        Ok(())
+    }
+
+    /// Writes a message the slave does not reply to, e.g. an SDO abort or an RxPDO.
+    /// It may be written while a request waits for its response.
+    pub fn write_unconfirmed<F: FnOnce(&mut [u8])>(
+        &mut self,
+        slave: &mut Slave,
+        mailbox_type: MailboxType,
+        priority: MailboxPriority,
+        data_size: usize,
+        data_writer: F,
+    ) -> Result<(), MailboxError> {
+        self.write_message(slave, mailbox_type, priority, data_size, data_writer)
+    }
+
+    /// Same as `write_with_priority`, but queues the request in `queue`, the queue of `slave`,
+    /// while another request waits for its response or older requests are queued.
+    /// The queued requests are written one at a time by `send_next`, called after each response.
+    /// Returns true if the request was written.
+    pub fn write_or_queue<const N: usize, const S: usize, F: FnOnce(&mut [u8])>(
+        &mut self,
+        slave: &mut Slave,
+        queue: &mut MailboxQueue<N, S>,
+        mailbox_type: MailboxType,
+        priority: MailboxPriority,
+        data_size: usize,
+        data_writer: F,
+    ) -> Result<bool, MailboxError> {
+        if !slave.mailbox_request_pending && queue.is_empty() {
+            self.write_with_priority(slave, mailbox_type, priority, data_size, data_writer)?;
+            return Ok(true);
+        }
+        let mut data = heapless::Vec::<u8, S>::new();
+        data.resize(data_size, 0)
+            .map_err(|_| MailboxError::TooLargeData)?;
+        data_writer(&mut data);
+        queue.push(mailbox_type, priority, &data)?;
+        Ok(false)
+    }
+
+    /// Adds the write of a request into the write mailbox of the slave to the next frame, with
+    /// the PDU index `pdu_index`, so that requests to several slaves share one frame.
+    /// The request is pending as with `write`. If the WKC of the write is not 1, the request
+    /// has not reached the slave and must be given up with `end_request`.
+    pub(crate) fn add_write<F: FnOnce(&mut [u8])>(
+        &mut self,
+        pdu_index: u8,
+        slave: &mut Slave,
+        mailbox_type: MailboxType,
+        data_size: usize,
+        data_writer: F,
+    ) -> Result<(), MailboxError> {
+        if slave.mailbox_request_pending {
+            return Err(MailboxError::Busy);
+        }
+        let sm = slave.sm_mailbox_in.ok_or(MailboxError::NoMailbox)?;
+        if MAILBOX_HEADER_LENGTH + data_size > sm.size as usize {
+            return Err(MailboxError::TooLargeData);
+        }
+        let count = slave.mailbox_count % 7 + 1;
+        self.iface.add_command(
+            pdu_index,
+            CommandType::FPWR,
+            slave.configured_address,
+            sm.start_address,
+            sm.size as usize,
+            |buf| {
+                write_mailbox_message(
+                    buf,
+                    mailbox_type,
+                    MailboxPriority::Lowest,
+                    count,
+                    data_size,
+                    data_writer,
+                )
+            },
+        )?;
+        slave.mailbox_count = count;
+        slave.mailbox_request_pending = true;
+        Ok(())
+    }
+
+    /// Gives up the request waiting for its response, e.g. after aborting it,
+    /// so that the next request can be written.
+    pub fn end_request(&mut self, slave: &mut Slave) {
+        slave.mailbox_request_pending = false;
+    }
+
+    fn write_message<F: FnOnce(&mut [u8])>(
+        &mut self,
+        slave: &mut Slave,
+        mailbox_type: MailboxType,
+        priority: MailboxPriority,
+        data_size: usize,
+        data_writer: F,
+    ) -> Result<(), MailboxError> {
+        let sm = slave.sm_mailbox_in.ok_or(MailboxError::NoMailbox)?;
+        if MAILBOX_HEADER_LENGTH + data_size > sm.size as usize {
+            return Err(MailboxError::TooLargeData);
+        }
+        let slave_address = SlaveAddress::StationAddress(slave.configured_address);
+
+        // 前のメッセージがスレーブに読まれるまで待つ
+        self.wait_mailbox_state(slave_address, false, MAILBOX_REQUEST_RETRY_TIMEOUT_DEFAULT)?;
+
+        // カウンタは1~7を循環する
+        slave.mailbox_count = slave.mailbox_count % 7 + 1;
+        let count = slave.mailbox_count;
+        // 最後のバイトまで書かないとメールボックスイベントが発生しない
+        self.iface
+            .write_register(slave_address, sm.start_address, sm.size as usize, |buf| {
+                write_mailbox_message(buf, mailbox_type, priority, count, data_size, data_writer)
+            })?;
+        Ok(())
+    }
+
+    /// Reads a message from the read mailbox (SM1) of the slave.
+    /// An error reply from the slave is returned as `MailboxError::Error`.
+    /// The message is taken as the response to the pending request.
+    pub fn read(&mut self, slave: &mut Slave) -> Result<MailboxPDU<&[u8]>, MailboxError> {
+        self.receive(slave)?;
+        // タイムアウトでは応答待ちを解除しない
+        slave.mailbox_request_pending = false;
+        self.received(slave)
+    }
+
+    /// Same as `read`, for the response to a request of `mailbox_type`.
+    /// Messages of other protocols and CoE emergencies are passed to the handlers of
+    /// `set_routing` and the next message is read, so they are not taken for the response.
+    pub fn read_response(
+        &mut self,
+        slave: &mut Slave,
+        mailbox_type: MailboxType,
+    ) -> Result<MailboxPDU<&[u8]>, MailboxError> {
+        self.receive_message(slave, mailbox_type)?;
+        slave.mailbox_request_pending = false;
+        self.received(slave)
+    }
+
+    /// Same as `read_response`, for a message the slave sends without a request,
+    /// e.g. a mailbox TxPDO. A pending request keeps waiting for its response.
+    pub fn read_unsolicited(
+        &mut self,
+        slave: &Slave,
+        mailbox_type: MailboxType,
+    ) -> Result<MailboxPDU<&[u8]>, MailboxError> {
+        self.receive_message(slave, mailbox_type)?;
+        self.received(slave)
+    }
+
+    /// Reads messages until one of `mailbox_type` is in the receive buffer of the interface.
+    fn receive_message(
+        &mut self,
+        slave: &Slave,
+        mailbox_type: MailboxType,
+    ) -> Result<(), MailboxError> {
+        loop {
+            self.receive(slave)?;
+            if !self.route(slave, mailbox_type)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Reads the read mailbox into the receive buffer of the interface.
+    fn receive(&mut self, slave: &Slave) -> Result<(), MailboxError> {
+        let sm = slave.sm_mailbox_out.ok_or(MailboxError::NoMailbox)?;
+        let slave_address = SlaveAddress::StationAddress(slave.configured_address);
+
+        self.wait_mailbox_state(slave_address, true, MAILBOX_RESPONSE_RETRY_TIMEOUT_DEFAULT)?;
+
+        self.iface
+            .read_register(slave_address, sm.start_address, sm.size as usize)?;
+        Ok(())
+    }
+
+    /// Message read by `receive`.
+    fn received(&self, slave: &Slave) -> Result<MailboxPDU<&[u8]>, MailboxError> {
+        let sm = slave.sm_mailbox_out.ok_or(MailboxError::NoMailbox)?;
+        let pdu = self
+            .iface
+            .last_register_response()
+            .ok_or(MailboxError::Common(CommonError::PacketDropped))?;
+        let buf: &[u8] = pdu.0;
+        let buf = buf
+            .get(ETHERCATPDU_HEADER_LENGTH..ETHERCATPDU_HEADER_LENGTH + sm.size as usize)
+            .ok_or(MailboxError::UnexpectedResponse)?;
+        parse_mailbox_message(buf)
+    }
+
+    /// Passes the message read by `receive` to its handler unless it is the response to a
+    /// request of `mailbox_type`. Returns true if the message was routed.
+    fn route(&self, slave: &Slave, mailbox_type: MailboxType) -> Result<bool, MailboxError> {
+        match self.received(slave) {
+            Ok(message) => Ok(self
+                .routing
+                .route(slave.configured_address, &message, mailbox_type)),
+            // エラー応答は待っている要求に対するもの
+            Err(MailboxError::Error(_)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Writes the next message of `queue` into the write mailbox, e.g. after the response to
+    /// the request in flight was read. Returns false if the queue is empty, or if the next
+    /// message is a request and another request waits for its response; the message then
+    /// stays in the queue.
+    pub fn send_next<const N: usize, const S: usize>(
+        &mut self,
+        slave: &mut Slave,
+        queue: &mut MailboxQueue<N, S>,
+    ) -> Result<bool, MailboxError> {
+        match queue.front() {
+            Some(message) if message.is_request && slave.mailbox_request_pending => {
+                return Ok(false)
+            }
+            Some(_) => (),
+            None => return Ok(false),
+        }
+        let message = match queue.pop() {
+            Some(message) => message,
+            None => return Ok(false),
+        };
+        let data = &message.data;
+        let writer = |buf: &mut [u8]| buf.copy_from_slice(data);
+        if message.is_request {
+            self.write_with_priority(
+                slave,
+                message.mailbox_type,
+                message.priority,
+                data.len(),
+                writer,
+            )?;
+        } else {
+            self.write_unconfirmed(
+                slave,
+                message.mailbox_type,
+                message.priority,
+                data.len(),
+                writer,
+            )?;
+        }
+        Ok(true)
+    }
+
+    pub(crate) fn interface(&mut self) -> &mut EtherCATInterface<'b, D, T> {
+        self.iface
+    }
+
+    pub(crate) fn timer(&mut self) -> &mut U {
+        self.timer
+    }
+
+    /// Discards a message left in the read mailbox, e.g. the response to a cancelled request.
+    /// Returns true if a message was discarded.
+    pub fn discard(&mut self, slave: &Slave) -> Result<bool, MailboxError> {
+        let sm = slave.sm_mailbox_out.ok_or(MailboxError::NoMailbox)?;
+        let slave_address = SlaveAddress::StationAddress(slave.configured_address);
+        if !self.mailbox_state(slave_address, true)? {
+            return Ok(false);
+        }
+        // 読み出すことでメールボックスが空になる
+        self.iface
+            .read_register(slave_address, sm.start_address, sm.size as usize)?;
+        Ok(true)
+    }
+
+    fn wait_mailbox_state(
+        &mut self,
+        slave_address: SlaveAddress,
+        is_full: bool,
+        timeout: MillisDurationU32,
+    ) -> Result<(), MailboxError> {
+        // 読み出しメールボックスの確認は指定間隔より速く行わない。
+        // タイマーを間隔の計測に使うので、タイムアウトは経過した間隔の合計で判断する。
+        let interval_us = self.poll_interval.to_micros();
+        if is_full && interval_us != 0 {
+            let timeout_us = timeout.ticks().saturating_mul(1000);
+            let mut elapsed_us: u32 = 0;
+            loop {
+                if self.mailbox_state(slave_address, is_full)? == is_full {
+                    return Ok(());
+                }
+                if elapsed_us >= timeout_us {
+                    return Err(MailboxError::Timeout(timeout));
+                }
+                self.timer.start(self.poll_interval);
+                nb::block!(self.timer.wait())
+                    .map_err(|_| MailboxError::Common(CommonError::UnspcifiedTimerError))?;
+                elapsed_us = elapsed_us.saturating_add(interval_us);
+            }
+        }
+
+        self.timer.start(timeout.convert());
+        loop {
+            if self.mailbox_state(slave_address, is_full)? == is_full {
+                return Ok(());
+            }
+            match self.timer.wait() {
+                Ok(_) => return Err(MailboxError::Timeout(timeout)),
+                Err(nb::Error::Other(_)) => {
+                    return Err(MailboxError::Common(CommonError::UnspcifiedTimerError))
+                }
+                Err(nb::Error::WouldBlock) => (),
+            }
+        }
+    }
+
+    fn mailbox_state(
+        &mut self,
+        slave_address: SlaveAddress,
+        is_read_mailbox: bool,
+    ) -> Result<bool, MailboxError> {
+        let sm = if is_read_mailbox {
+            self.iface.read_sm1(slave_address)?
+        } else {
+            self.iface.read_sm0(slave_address)?
+        };
+        Ok(sm.mailbox_state())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CyclicMailboxPhase {
+    Idle,
+    /// The message is written again until the slave has read the previous one.
+    Write,
+    /// SM1 is read until the response arrives.
+    Check,
+    Read,
+}
+
+/// Mailbox of one slave driven by a cyclic unit with one datagram per cycle: the message is
+/// written into SM0, SM1 is checked until the response arrives, and the response is read.
+/// The unit owns the mailbox of the slave while a message is in progress, so `Mailbox` must not
+/// be used for the slave in the meantime.
+#[derive(Debug)]
+pub(crate) struct CyclicMailbox<'a> {
+    station_address: u16,
+    sm_in: MailboxSyncManager,
+    sm_out: MailboxSyncManager,
+    count: u8,
+    phase: CyclicMailboxPhase,
+    mailbox_type: MailboxType,
+    expects_response: bool,
+    is_written: bool,
+    in_flight: Option<Command>,
+    routing: MailboxRouting,
+    buffer: &'a mut [u8],
+}
+
+impl<'a> CyclicMailbox<'a> {
+    /// None if the slave has no mailbox or `buffer` is shorter than one of its mailboxes.
+    pub(crate) fn new(slave: &Slave, buffer: &'a mut [u8]) -> Option<Self> {
+        let sm_in = slave.sm_mailbox_in?;
+        let sm_out = slave.sm_mailbox_out?;
+        if buffer.len() < sm_in.size.max(sm_out.size) as usize {
+            return None;
+        }
+        Some(Self {
+            station_address: slave.configured_address,
+            sm_in,
+            sm_out,
+            count: slave.mailbox_count,
+            phase: CyclicMailboxPhase::Idle,
+            mailbox_type: MailboxType::CoE,
+            expects_response: false,
+            is_written: false,
+            in_flight: None,
+            routing: MailboxRouting::default(),
+            buffer,
+        })
+    }
+
+    pub(crate) fn set_routing(&mut self, routing: MailboxRouting) {
+        self.routing = routing;
+    }
+
+    pub(crate) fn station_address(&self) -> u16 {
+        self.station_address
+    }
+
+    /// Data bytes of a message that fills the write mailbox.
+    pub(crate) fn max_data_size(&self) -> usize {
+        (self.sm_in.size as usize).saturating_sub(MAILBOX_HEADER_LENGTH)
+    }
+
+    /// Maximum data length of the datagrams returned by `process`.
+    pub(crate) fn data_size(&self) -> usize {
+        self.sm_in.size.max(self.sm_out.size) as usize
+    }
+
+    /// The buffer of the messages, free for other uses while the mailbox is idle.
+    pub(crate) fn buffer(&self) -> &[u8] {
+        self.buffer
+    }
+
+    pub(crate) fn buffer_mut(&mut self) -> &mut [u8] {
+        self.buffer
+    }
+
+    pub(crate) fn is_idle(&self) -> bool {
+        self.phase == CyclicMailboxPhase::Idle
+    }
+
+    /// Whether the last message may have reached the slave, i.e. its write was sent.
+    pub(crate) fn is_written(&self) -> bool {
+        self.is_written
+    }
+
+    /// Starts writing a message. If `expects_response` is false, the mailbox is idle again
+    /// once the message is written.
+    pub(crate) fn write<F: FnOnce(&mut [u8])>(
+        &mut self,
+        mailbox_type: MailboxType,
+        expects_response: bool,
+        data_size: usize,
+        data_writer: F,
+    ) -> Result<(), MailboxError> {
+        if !self.is_idle() {
+            return Err(MailboxError::Busy);
+        }
+        if data_size > self.max_data_size() {
+            return Err(MailboxError::TooLargeData);
+        }
+        // カウンタは1~7を循環する
+        self.count = self.count % 7 + 1;
+        let buf = &mut self.buffer[..self.sm_in.size as usize];
+        write_mailbox_message(
+            buf,
+            mailbox_type,
+            MailboxPriority::Lowest,
+            self.count,
+            data_size,
+            data_writer,
+        );
+        self.mailbox_type = mailbox_type;
+        self.expects_response = expects_response;
+        self.is_written = false;
+        self.phase = CyclicMailboxPhase::Write;
+        Ok(())
+    }
+
+    /// Waits for the next message without writing, e.g. after a response that belongs to
+    /// a cancelled request.
+    pub(crate) fn wait_response(&mut self) {
+        self.phase = CyclicMailboxPhase::Check;
+    }
+
+    /// Stops the message in progress. The response to a datagram already sent is ignored.
+    pub(crate) fn stop(&mut self) {
+        self.phase = CyclicMailboxPhase::Idle;
+        self.in_flight = None;
+    }
+
+    pub(crate) fn process(&mut self) -> Option<(Command, &[u8])> {
+        let (command, size) = match self.phase {
+            CyclicMailboxPhase::Idle => return None,
+            CyclicMailboxPhase::Write => {
+                // 届いたかは応答まで分からないので、送った時点で書き込み済みとする
+                self.is_written = true;
+                let command = Command::new_fpwr(self.station_address, self.sm_in.start_address);
+                self.in_flight = Some(command);
+                return Some((command, &self.buffer[..self.sm_in.size as usize]));
+            }
+            CyclicMailboxPhase::Check => (
+                Command::new_fprd(self.station_address, SyncManagerRegister::ADDRESS1),
+                SyncManagerRegister::SIZE,
+            ),
+            CyclicMailboxPhase::Read => (
+                Command::new_fprd(self.station_address, self.sm_out.start_address),
+                self.sm_out.size as usize,
+            ),
+        };
+        let buf = &mut self.buffer[..size];
+        buf.fill(0);
+        self.in_flight = Some(command);
+        Some((command, buf))
+    }
+
+    /// Returns the message read from SM1, except messages routed to the handlers of
+    /// `set_routing`. A wrong WKC of a read is returned as an error and ends the message.
+    pub(crate) fn receive<'d>(
+        &mut self,
+        command: Command,
+        data: &'d [u8],
+        wkc: u16,
+    ) -> Option<Result<MailboxPDU<&'d [u8]>, MailboxError>> {
+        if self.in_flight.take() != Some(command) {
+            return None;
+        }
+        match self.phase {
+            CyclicMailboxPhase::Idle => None,
+            CyclicMailboxPhase::Write => {
+                // 書き込みメールボックスが空いていなければWKCは0になり、書き直す
+                if wkc == 1 {
+                    self.phase = if self.expects_response {
+                        CyclicMailboxPhase::Check
+                    } else {
+                        CyclicMailboxPhase::Idle
+                    };
+                } else {
+                    self.is_written = false;
+                }
+                None
+            }
+            CyclicMailboxPhase::Check => {
+                if wkc != 1 {
+                    self.phase = CyclicMailboxPhase::Idle;
+                    return Some(Err(CommonError::UnexpectedWKC(wkc).into()));
+                }
+                if SyncManagerRegister(data).mailbox_state() {
+                    self.phase = CyclicMailboxPhase::Read;
+                }
+                None
+            }
+            CyclicMailboxPhase::Read => {
+                if wkc != 1 {
+                    self.phase = CyclicMailboxPhase::Idle;
+                    return Some(Err(CommonError::UnexpectedWKC(wkc).into()));
+                }
+                let message = parse_mailbox_message(data);
+                // 緊急メッセージなどは振り分けて、応答を待ち続ける
+                if let Ok(message) = &message {
+                    if self
+                        .routing
+                        .route(self.station_address, message, self.mailbox_type)
+                    {
+                        self.phase = CyclicMailboxPhase::Check;
+                        return None;
+                    }
+                }
+                self.phase = CyclicMailboxPhase::Idle;
+                Some(message)
+            }
+        }
+    }
+}
+
+/// Fills the whole write mailbox `buf` with the header and the data.
+pub(crate) fn write_mailbox_message<F: FnOnce(&mut [u8])>(
+    buf: &mut [u8],
+    mailbox_type: MailboxType,
+    priority: MailboxPriority,
+    count: u8,
+    data_size: usize,
+    data_writer: F,
+) {
+    buf.iter_mut().for_each(|b| *b = 0);
+    let mut header = MailboxPDU::new_unchecked(&mut buf[..MAILBOX_HEADER_LENGTH]);
+    header.set_length(data_size as u16);
+    header.set_address(0);
+    header.set_prioriry(priority as u8);
+    header.set_mailbox_type(mailbox_type as u8);
+    header.set_count(count);
+    data_writer(&mut buf[MAILBOX_HEADER_LENGTH..MAILBOX_HEADER_LENGTH + data_size]);
+}
+
+/// Parses the contents of the read mailbox. An error reply is returned as `MailboxError::Error`.
+pub(crate) fn parse_mailbox_message(buf: &[u8]) -> Result<MailboxPDU<&[u8]>, MailboxError> {
+    let mailbox = MailboxPDU::new(buf).ok_or(MailboxError::UnexpectedResponse)?;
+    // 長さフィールドが壊れていてもdata()で範囲外を読まないようにする
+    let length = MAILBOX_HEADER_LENGTH + mailbox.length() as usize;
+    let mailbox =
+        MailboxPDU::new_unchecked(buf.get(..length).ok_or(MailboxError::UnexpectedResponse)?);
+    if mailbox.mailbox_type() == MailboxType::Error as u8 {
+        let detail = MailboxErrorPDU::new(mailbox.data())
+            .map(|error| MailboxErrorDetail::from(error.detail() as u8))
+            .unwrap_or(MailboxErrorDetail::Unknown);
+        return Err(MailboxError::Error(detail));
+    }
+    Ok(mailbox)
 }
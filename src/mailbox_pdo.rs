@@ -0,0 +1,138 @@
+use crate::arch::*;
+use crate::error::*;
+use crate::interface::*;
+use crate::mailbox::*;
+use crate::packet::{coe::*, MailboxPDU, MailboxType};
+use crate::slave_status::*;
+use embedded_hal::timer::CountDown;
+use fugit::*;
+
+#[derive(Debug, Clone)]
+pub enum MailboxPdoError {
+    Mailbox(MailboxError),
+    UnexpectedResponse,
+    TooLargeData,
+    SmallBuffer,
+}
+
+impl Classify for MailboxPdoError {
+    fn classify(&self) -> (ErrorCategory, RecoveryHint) {
+        use ErrorCategory::*;
+        use RecoveryHint::*;
+        match self {
+            Self::Mailbox(err) => err.classify(),
+            Self::UnexpectedResponse => (Protocol, Retry),
+            Self::TooLargeData | Self::SmallBuffer => (ApplicationBug, FixConfig),
+        }
+    }
+}
+
+impl From<MailboxError> for MailboxPdoError {
+    fn from(err: MailboxError) -> Self {
+        Self::Mailbox(err)
+    }
+}
+
+/// PDO transfer through the CoE mailbox, for simple slaves without process data sync managers.
+pub struct MailboxPdo<'a, 'b, D, T, U>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+    U: CountDown<Time = MicrosDurationU32>,
+{
+    mailbox: Mailbox<'a, 'b, D, T, U>,
+}
+
+impl<'a, 'b, D, T, U> MailboxPdo<'a, 'b, D, T, U>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+    U: CountDown<Time = MicrosDurationU32>,
+{
+    pub fn new(iface: &'a mut EtherCATInterface<'b, D, T>, timer: &'a mut U) -> Self {
+        Self {
+            mailbox: Mailbox::new(iface, timer),
+        }
+    }
+
+    pub fn mailbox(&mut self) -> &mut Mailbox<'a, 'b, D, T, U> {
+        &mut self.mailbox
+    }
+
+    /// Sends the RxPDO `pdo_number` (outputs) to the slave. The slave does not reply.
+    pub fn write_rx_pdo(
+        &mut self,
+        slave: &mut Slave,
+        pdo_number: u16,
+        data: &[u8],
+    ) -> Result<(), MailboxPdoError> {
+        if data.len() > COE_PDO_MAX_LENGTH {
+            return Err(MailboxPdoError::TooLargeData);
+        }
+        self.mailbox.write_unconfirmed(
+            slave,
+            MailboxType::CoE,
+            MailboxPriority::Lowest,
+            COE_HEADER_LENGTH + data.len(),
+            |buf| {
+                let mut coe = CANOpenPDU::new_unchecked(&mut buf[..COE_HEADER_LENGTH]);
+                coe.set_number(pdo_number);
+                coe.set_service_type(CANOpenServiceType::RxPDO as u8);
+                buf[COE_HEADER_LENGTH..].copy_from_slice(data);
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Requests the TxPDO `pdo_number` (inputs) from the slave, and returns the size of the data.
+    pub fn read_tx_pdo(
+        &mut self,
+        slave: &mut Slave,
+        pdo_number: u16,
+        buf: &mut [u8],
+    ) -> Result<usize, MailboxPdoError> {
+        self.mailbox
+            .write(slave, MailboxType::CoE, COE_HEADER_LENGTH, |buf| {
+                let mut coe = CANOpenPDU::new_unchecked(buf);
+                coe.set_number(pdo_number);
+                coe.set_service_type(CANOpenServiceType::TxPDORemoteReq as u8);
+            })?;
+        let response = self.mailbox.read_response(slave, MailboxType::CoE)?;
+        copy_tx_pdo(&response, pdo_number, buf)
+    }
+
+    /// Waits for a TxPDO sent by the slave without a request, e.g. on an input change.
+    /// A pending request of the slave keeps waiting for its response.
+    pub fn receive_tx_pdo(
+        &mut self,
+        slave: &Slave,
+        pdo_number: u16,
+        buf: &mut [u8],
+    ) -> Result<usize, MailboxPdoError> {
+        let response = self.mailbox.read_unsolicited(slave, MailboxType::CoE)?;
+        copy_tx_pdo(&response, pdo_number, buf)
+    }
+}
+
+/// Copies the data of the TxPDO `pdo_number` in `response` into `buf`.
+fn copy_tx_pdo(
+    response: &MailboxPDU<&[u8]>,
+    pdo_number: u16,
+    buf: &mut [u8],
+) -> Result<usize, MailboxPdoError> {
+    if response.mailbox_type() != MailboxType::CoE as u8 {
+        return Err(MailboxPdoError::UnexpectedResponse);
+    }
+    let data = response.data();
+    let coe = CANOpenPDU::new(data).ok_or(MailboxPdoError::UnexpectedResponse)?;
+    if coe.service_type() != CANOpenServiceType::TxPDO as u8 || coe.number() != pdo_number {
+        return Err(MailboxPdoError::UnexpectedResponse);
+    }
+    let pdo = &data[COE_HEADER_LENGTH..];
+    let len = pdo.len();
+    if buf.len() < len {
+        return Err(MailboxPdoError::SmallBuffer);
+    }
+    buf[..len].copy_from_slice(pdo);
+    Ok(len)
+}
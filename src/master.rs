@@ -1,49 +1,590 @@
 use crate::al_state_transfer::*;
+use crate::al_status_monitor::AlStatusMonitor;
 use crate::arch::*;
+use crate::dc::*;
 use crate::error::*;
+#[cfg(feature = "foe")]
+use crate::foe::FoEUnit;
 use crate::interface::*;
+use crate::lifecycle::*;
+use crate::latch::{LatchMonitor, LATCH_READ_SIZE};
+use crate::link_monitor::LinkMonitor;
 use crate::packet::*;
-use crate::register::datalink::*;
+use crate::process_data::ProcessDataUnit;
+use crate::register::{application::ALControl, datalink::*};
+use crate::rtic::MicrosInstant;
+#[cfg(feature = "coe")]
+use crate::sdo::SdoUnit;
 use crate::sii::*;
 use crate::slave_status::*;
+use crate::util::*;
+use crate::watchdog::WatchdogMonitor;
+use crate::RECEIVE_TIMEOUT_DEFAULT;
 use bit_field::BitField;
 use embedded_hal::timer::*;
 use fugit::*;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Command {
     c_type: CommandType,
-    adp: u16,
-    ado: u16,
+    address: DatagramAddress,
 }
 
+impl Command {
+    pub fn new(c_type: CommandType, adp: u16, ado: u16) -> Self {
+        Self {
+            c_type,
+            address: DatagramAddress::new(c_type, adp, ado),
+        }
+    }
+
+    /// Logical command (LRD, LWR or LRW) at the 32-bit logical `address`.
+    pub fn new_logical(c_type: CommandType, address: u32) -> Self {
+        Self {
+            c_type,
+            address: DatagramAddress::Logical(address),
+        }
+    }
+
+    pub fn new_aprd(slave_position: u16, ado: u16) -> Self {
+        Self::new(CommandType::APRD, get_ap_adp(slave_position), ado)
+    }
+
+    pub fn new_apwr(slave_position: u16, ado: u16) -> Self {
+        Self::new(CommandType::APWR, get_ap_adp(slave_position), ado)
+    }
+
+    pub fn new_aprw(slave_position: u16, ado: u16) -> Self {
+        Self::new(CommandType::APRW, get_ap_adp(slave_position), ado)
+    }
+
+    /// The slave at `slave_position` reads and the following slaves write.
+    pub fn new_armw(slave_position: u16, ado: u16) -> Self {
+        Self::new(CommandType::ARMW, get_ap_adp(slave_position), ado)
+    }
+
+    pub fn new_fprd(station_address: u16, ado: u16) -> Self {
+        Self::new(CommandType::FPRD, station_address, ado)
+    }
+
+    pub fn new_fpwr(station_address: u16, ado: u16) -> Self {
+        Self::new(CommandType::FPWR, station_address, ado)
+    }
+
+    pub fn new_fprw(station_address: u16, ado: u16) -> Self {
+        Self::new(CommandType::FPRW, station_address, ado)
+    }
+
+    /// The slave at `station_address` reads and the other slaves write.
+    pub fn new_frmw(station_address: u16, ado: u16) -> Self {
+        Self::new(CommandType::FRMW, station_address, ado)
+    }
+
+    pub fn new_brd(ado: u16) -> Self {
+        Self::new(CommandType::BRD, 0, ado)
+    }
+
+    pub fn new_bwr(ado: u16) -> Self {
+        Self::new(CommandType::BWR, 0, ado)
+    }
+
+    pub fn new_brw(ado: u16) -> Self {
+        Self::new(CommandType::BRW, 0, ado)
+    }
+
+    pub fn new_lrd(address: u32) -> Self {
+        Self::new_logical(CommandType::LRD, address)
+    }
+
+    pub fn new_lwr(address: u32) -> Self {
+        Self::new_logical(CommandType::LWR, address)
+    }
+
+    pub fn new_lrw(address: u32) -> Self {
+        Self::new_logical(CommandType::LRW, address)
+    }
+
+    pub fn command_type(&self) -> CommandType {
+        self.c_type
+    }
+
+    pub fn address(&self) -> DatagramAddress {
+        self.address
+    }
+}
+
+/// Default maximum data length of a datagram sent by `EtherCATMaster::send_custom`.
+pub const CUSTOM_COMMAND_MAX_LENGTH: usize = 64;
+
+/// Called with the returned command, the data and the WKC of a custom datagram.
+/// It may capture the state of the application, e.g. to store the response.
+pub type CustomCommandHandler<'a> = &'a mut dyn FnMut(Command, &[u8], u16);
+
+/// Unit that sends one datagram of up to `N` bytes given by `EtherCATMaster::send_custom`,
+/// e.g. to access vendor-specific registers.
+pub struct CustomCommandUnit<'a, const N: usize = CUSTOM_COMMAND_MAX_LENGTH> {
+    command: Option<Command>,
+    buffer: [u8; N],
+    len: usize,
+    handler: Option<CustomCommandHandler<'a>>,
+    wkc: u16,
+}
+
+impl<'a, const N: usize> CustomCommandUnit<'a, N> {
+    pub const fn new() -> Self {
+        Self {
+            command: None,
+            buffer: [0; N],
+            len: 0,
+            handler: None,
+            wkc: 0,
+        }
+    }
+
+    /// True while the datagram waits for its response.
+    pub fn is_busy(&self) -> bool {
+        self.command.is_some()
+    }
+
+    /// Data of the last response.
+    pub fn response(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+
+    /// WKC of the last response.
+    pub fn wkc(&self) -> u16 {
+        self.wkc
+    }
+
+    fn request(
+        &mut self,
+        command: Command,
+        data: &[u8],
+        handler: CustomCommandHandler<'a>,
+    ) -> Result<(), CommonError> {
+        if self.is_busy() || data.len() > N {
+            return Err(CommonError::BufferExhausted);
+        }
+        self.buffer[..data.len()].copy_from_slice(data);
+        self.len = data.len();
+        self.command = Some(command);
+        self.handler = Some(handler);
+        Ok(())
+    }
+
+    fn process(&mut self) -> Option<(Command, &[u8])> {
+        // 応答が届くまで毎サイクル送り直す
+        let command = self.command?;
+        Some((command, &self.buffer[..self.len]))
+    }
+
+    fn receive(&mut self, command: Command, data: &[u8], wkc: u16) -> bool {
+        if self.command.take().is_none() {
+            return true;
+        }
+        let len = data.len().min(N);
+        self.buffer[..len].copy_from_slice(&data[..len]);
+        self.len = len;
+        self.wkc = wkc;
+        if let Some(handler) = self.handler.take() {
+            handler(command, &self.buffer[..len], wkc);
+        }
+        // WKCの判断は利用者に任せる
+        true
+    }
+}
+
+impl<const N: usize> Default for CustomCommandUnit<'_, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> core::fmt::Debug for CustomCommandUnit<'_, N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("CustomCommandUnit")
+            .field("command", &self.command)
+            .field("response", &self.response())
+            .field("has_handler", &self.handler.is_some())
+            .field("wkc", &self.wkc)
+            .finish()
+    }
+}
+
+/// Unit implemented outside of the crate, placed in `CyclicProcessingUnit::Dyn`.
+/// The master calls `process` once per cycle and `receive` with the response of the
+/// datagram returned by `process`.
+pub trait CyclicProcess: core::fmt::Debug {
+    /// Maximum data length of the datagrams returned by `process`.
+    fn data_size(&self) -> usize;
+
+    /// Datagram sent in this cycle. None sends nothing.
+    fn process(&mut self) -> Option<(Command, &[u8])>;
+
+    /// Returns false if the WKC is not the expected one.
+    fn receive(&mut self, command: Command, data: &[u8], wkc: u16) -> bool;
+
+    /// Datagram sent while the master is in the fail-safe state. Sends nothing by default.
+    fn fail_safe(&mut self) -> Option<(Command, &[u8])> {
+        None
+    }
+}
+
+/// `N` is the maximum data length of the `Custom` units.
 #[derive(Debug)]
-pub enum CyclicProcessingUnit {
+pub enum CyclicProcessingUnit<'a, const N: usize = CUSTOM_COMMAND_MAX_LENGTH> {
     TEST,
+    SystemTimeDistribution(SystemTimeDistributor),
+    Custom(CustomCommandUnit<'a, N>),
+    Watchdog(WatchdogMonitor),
+    Latch(LatchMonitor),
+    AlStatus(AlStatusMonitor),
+    Link(LinkMonitor),
+    ProcessData(ProcessDataUnit<'a>),
+    #[cfg(feature = "coe")]
+    Sdo(SdoUnit<'a>),
+    #[cfg(feature = "foe")]
+    FoE(FoEUnit<'a>),
+    AlTransition(AlTransitionUnit),
+    /// Unit of any type owned by the application, borrowed for the life of the master.
+    Dyn(&'a mut dyn CyclicProcess),
 }
 
-impl CyclicProcessingUnit {
+impl<'a, const N: usize> CyclicProcessingUnit<'a, N> {
     fn data_size(&self) -> usize{
-        todo!()
+        match self {
+            Self::TEST => todo!(),
+            Self::SystemTimeDistribution(_) => DCSystemTime::SIZE,
+            Self::Custom(unit) => unit.len,
+            Self::Watchdog(_) => ALControl::SIZE,
+            Self::Latch(_) => LATCH_READ_SIZE,
+            Self::AlStatus(_) => AL_STATUS_READ_SIZE,
+            Self::Link(_) => DLStatus::SIZE,
+            Self::ProcessData(unit) => unit.data_size(),
+            #[cfg(feature = "coe")]
+            Self::Sdo(unit) => unit.data_size(),
+            #[cfg(feature = "foe")]
+            Self::FoE(unit) => unit.data_size(),
+            Self::AlTransition(_) => AL_STATUS_READ_SIZE,
+            Self::Dyn(unit) => unit.data_size(),
+        }
     }
 
     fn process(&mut self) -> Option<(Command, &[u8])> {
-        todo!()
+        match self {
+            Self::TEST => todo!(),
+            Self::SystemTimeDistribution(unit) => unit.process(),
+            Self::Custom(unit) => unit.process(),
+            Self::Watchdog(unit) => unit.process(),
+            Self::Latch(unit) => unit.process(),
+            Self::AlStatus(unit) => unit.process(),
+            Self::Link(unit) => unit.process(),
+            Self::ProcessData(unit) => unit.process(),
+            #[cfg(feature = "coe")]
+            Self::Sdo(unit) => unit.process(),
+            #[cfg(feature = "foe")]
+            Self::FoE(unit) => unit.process(),
+            Self::AlTransition(unit) => unit.process(),
+            Self::Dyn(unit) => unit.process(),
+        }
     }
 
     fn receive(&mut self, command: Command, data: &[u8], wkc: u16) -> bool {
-        todo!()
+        match self {
+            Self::TEST => todo!(),
+            Self::SystemTimeDistribution(unit) => unit.receive(command, data, wkc),
+            Self::Custom(unit) => unit.receive(command, data, wkc),
+            Self::Watchdog(unit) => unit.receive(command, data, wkc),
+            Self::Latch(unit) => unit.receive(command, data, wkc),
+            Self::AlStatus(unit) => unit.receive(command, data, wkc),
+            Self::Link(unit) => unit.receive(command, data, wkc),
+            Self::ProcessData(unit) => unit.receive(command, data, wkc),
+            #[cfg(feature = "coe")]
+            Self::Sdo(unit) => unit.receive(command, data, wkc),
+            #[cfg(feature = "foe")]
+            Self::FoE(unit) => unit.receive(command, data, wkc),
+            Self::AlTransition(unit) => unit.receive(command, data, wkc),
+            Self::Dyn(unit) => unit.receive(command, data, wkc),
+        }
+    }
+
+    /// Command carrying the fail-safe image. Units without outputs return None.
+    fn fail_safe(&mut self) -> Option<(Command, &[u8])> {
+        match self {
+            Self::TEST => None,
+            // システム時刻の配信はフェイルセーフ中も続ける
+            Self::SystemTimeDistribution(unit) => unit.process(),
+            Self::Custom(_) => None,
+            // フェイルセーフ中にOpへ戻してはいけない
+            Self::Watchdog(_) => None,
+            Self::Latch(_) => None,
+            // フェイルセーフ中も状態の監視は続ける
+            Self::AlStatus(unit) => unit.process(),
+            Self::Link(unit) => unit.process(),
+            // アプリケーションの出力の代わりにフェイルセーフ値を書き込む
+            Self::ProcessData(unit) => unit.fail_safe(),
+            // 転送は止め、利用者が中断する
+            #[cfg(feature = "coe")]
+            Self::Sdo(_) => None,
+            #[cfg(feature = "foe")]
+            Self::FoE(_) => None,
+            Self::AlTransition(_) => None,
+            Self::Dyn(unit) => unit.fail_safe(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FailSafeConfig {
+    /// Number of cycles the fail-safe image is written before the exchange stops.
+    pub hold_cycles: u16,
+    /// Consecutive cycles with unexpected WKC that trigger the fail-safe state.
+    /// 0 disables the check.
+    pub wkc_error_threshold: u16,
+}
+
+impl Default for FailSafeConfig {
+    fn default() -> Self {
+        Self {
+            hold_cycles: 3,
+            wkc_error_threshold: 0,
+        }
+    }
+}
+
+/// Receive timeout following the measured round trip time.
+#[derive(Debug, Clone)]
+pub struct AdaptiveTimeout {
+    /// Timeout before the first measurement, and the lower limit.
+    pub min_timeout: MicrosDurationU32,
+    pub max_timeout: MicrosDurationU32,
+    /// Timeout in percent of the average round trip time.
+    pub margin_percent: u32,
+    /// Weight of a new measurement in the average, 1/2^`smoothing_shift`.
+    pub smoothing_shift: u8,
+    // 平均RTTは8ビットの小数部を持つ固定小数点
+    average_rtt: Option<u64>,
+}
+
+impl AdaptiveTimeout {
+    pub fn new(min_timeout: MicrosDurationU32, max_timeout: MicrosDurationU32) -> Self {
+        Self {
+            min_timeout,
+            max_timeout,
+            margin_percent: 300,
+            smoothing_shift: 3,
+            average_rtt: None,
+        }
+    }
+
+    /// Average round trip time, None until the first measurement.
+    pub fn average_rtt(&self) -> Option<MicrosDurationU32> {
+        self.average_rtt
+            .map(|average| MicrosDurationU32::from_ticks((average >> 8) as u32))
+    }
+
+    pub fn timeout(&self) -> MicrosDurationU32 {
+        let min = self.min_timeout.ticks();
+        let max = self.max_timeout.ticks().max(min);
+        let timeout = match self.average_rtt {
+            Some(average) => {
+                ((average >> 8) * self.margin_percent as u64 / 100).min(u32::MAX as u64) as u32
+            }
+            None => min,
+        };
+        MicrosDurationU32::from_ticks(timeout.clamp(min, max))
+    }
+
+    pub fn update(&mut self, rtt: MicrosDurationU64) {
+        let sample = rtt.ticks().min(u32::MAX as u64) << 8;
+        self.average_rtt = Some(match self.average_rtt {
+            Some(average) if sample >= average => {
+                average + ((sample - average) >> self.smoothing_shift)
+            }
+            Some(average) => average - ((average - sample) >> self.smoothing_shift),
+            None => sample,
+        });
+    }
+
+    /// Raises the average after a timeout, so that a grown network is tolerated.
+    fn timed_out(&mut self) {
+        let timeout = self.timeout();
+        let doubled = MicrosDurationU64::from_ticks(timeout.ticks() as u64 * 2);
+        self.average_rtt = None;
+        self.update(doubled);
+    }
+}
+
+/// Maximum number of cyclic units of an `EtherCATMaster`.
+/// The PDU index identifies the unit, and index 255 is used by the initializer.
+pub const MAX_CYCLIC_UNITS: usize = u8::MAX as usize;
+
+/// Data sizes of the datagrams sent by `EtherCATMaster::self_test`.
+const SELF_TEST_SIZES: [usize; 5] = [1, 16, 64, 256, 1024];
+const SELF_TEST_PATTERNS: usize = 5;
+
+/// Result of `EtherCATMaster::self_test`.
+#[derive(Debug, Clone)]
+pub struct SelfTestReport {
+    pub frames: u32,
+    /// Frames not returned within the receive timeout.
+    pub lost_frames: u32,
+    /// Frames returned with changed data, command or WKC.
+    pub corrupted_frames: u32,
+    pub min_rtt: MicrosDurationU64,
+    pub max_rtt: MicrosDurationU64,
+}
+
+impl SelfTestReport {
+    /// True if all frames came back intact and the round trip time varied at most `max_jitter`.
+    pub fn is_ok(&self, max_jitter: MicrosDurationU64) -> bool {
+        self.frames != 0
+            && self.lost_frames == 0
+            && self.corrupted_frames == 0
+            && self.max_rtt - self.min_rtt <= max_jitter
+    }
+}
+
+fn self_test_pattern(pattern: usize, i: usize) -> u8 {
+    match pattern {
+        0 => 0x00,
+        1 => 0xFF,
+        2 => 0x55,
+        3 => 0xAA,
+        _ => i as u8,
     }
 }
 
+/// Counters of the cyclic exchange since the master was created.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MasterStatistics {
+    pub cycles: u32,
+    /// Cycles with an unexpected WKC or a missing response.
+    pub wkc_errors: u32,
+    /// Cycles in which the frame could not be sent or received.
+    pub frame_errors: u32,
+    /// Round trip time (ns) of the last cycle from hardware timestamps.
+    pub last_round_trip_ns: Option<u64>,
+    pub max_round_trip_ns: u64,
+    /// Cycles whose responses did not arrive in time, after the retries.
+    pub receive_timeouts: u32,
+    /// Frames sent again by `ReceiveTimeoutPolicy::Retry`.
+    pub retried_frames: u32,
+    /// Cycles with a lost frame whose responses all arrived in the copies of the other frame.
+    pub recovered_frames: u32,
+}
+
+/// Counters of one cyclic unit, to find the units that take up the frame.
+/// The latencies are measured only with `EtherCATMaster::set_clock`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UnitStatistics {
+    /// Datagrams the unit sent.
+    pub commands: u32,
+    /// Data bytes of the datagrams the unit sent.
+    pub bytes: u32,
+    /// Responses the unit rejected, e.g. with an unexpected WKC.
+    pub errors: u32,
+    /// Datagrams whose response did not arrive.
+    pub missed: u32,
+    /// Time from enqueuing the datagram to passing its response to the unit, in the last cycle.
+    pub last_latency: Option<MicrosDurationU64>,
+    pub max_latency: Option<MicrosDurationU64>,
+}
+
+/// What `EtherCATMaster::poll` does when the responses of a cycle do not arrive in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiveTimeoutPolicy {
+    /// Returns `ReceiveTimeout`, counted towards `FailSafeConfig::wkc_error_threshold`.
+    Report,
+    /// Only counts the timeout in the statistics and returns `Ok(false)`.
+    IgnoreAndCount,
+    /// Sends the datagrams of the cycle again up to this many times, then reports the timeout.
+    /// All datagrams are repeated, including writes that are not idempotent: a mailbox
+    /// write whose response was lost reaches the slave twice if the first one arrived.
+    /// Do not use it with units that write to mailboxes or count writes.
+    Retry(u8),
+    /// Reports the timeout and enters the fail-safe state after this many consecutive timeouts.
+    Escalate(u16),
+}
+
+impl Default for ReceiveTimeoutPolicy {
+    fn default() -> Self {
+        Self::Report
+    }
+}
+
+/// Set of unit indices, one bit per PDU index.
+#[derive(Debug, Clone)]
+struct UnitSet([u32; 8]);
+
+impl UnitSet {
+    const fn new() -> Self {
+        Self([0; 8])
+    }
+
+    fn insert(&mut self, index: usize) {
+        self.0[index / 32] |= 1 << (index % 32);
+    }
+
+    fn contains(&self, index: usize) -> bool {
+        self.0[index / 32] & (1 << (index % 32)) != 0
+    }
+
+    /// Returns true if `index` was in the set.
+    fn remove(&mut self, index: usize) -> bool {
+        let mask = 1 << (index % 32);
+        let contains = self.0[index / 32] & mask != 0;
+        self.0[index / 32] &= !mask;
+        contains
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.iter().all(|bits| *bits == 0)
+    }
+
+    fn len(&self) -> usize {
+        self.0.iter().map(|bits| bits.count_ones() as usize).sum()
+    }
+
+    fn clear(&mut self) {
+        self.0 = [0; 8];
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExchangeState {
+    Running,
+    /// Remaining cycles to write the fail-safe image.
+    FailSafe(u16),
+    Stopped,
+}
+
 #[derive(Debug)]
-pub struct EtherCATMaster<'a, D, T>
+pub struct EtherCATMaster<'a, D, T, const N: usize = CUSTOM_COMMAND_MAX_LENGTH>
 where
     D: Device,
     T: CountDown<Time = MicrosDurationU32>,
 {
     iface: &'a mut EtherCATInterface<'a, D, T>,
-    units: &'a mut [CyclicProcessingUnit],
+    units: &'a mut [CyclicProcessingUnit<'a, N>],
     units_len: usize,
+    fail_safe: FailSafeConfig,
+    state: ExchangeState,
+    wkc_error_count: u16,
+    deferred: UnitSet,
+    backlog_cycles: u32,
+    awaiting: UnitSet,
+    duplicated: UnitSet,
+    copies: UnitSet,
+    keep_alive: UnitSet,
+    timeout_policy: ReceiveTimeoutPolicy,
+    consecutive_timeouts: u16,
+    adaptive_timeout: Option<AdaptiveTimeout>,
+    statistics: MasterStatistics,
+    unit_statistics: &'a mut [UnitStatistics],
+    clock: Option<fn() -> MicrosInstant>,
+    enqueued_at: Option<MicrosInstant>,
+    lifecycle: MasterLifecycle,
 }
 
 impl<'a, D, T> EtherCATMaster<'a, D, T>
@@ -51,20 +592,366 @@ where
     D: Device,
     T: CountDown<Time = MicrosDurationU32>,
 {
-    pub fn process_and_enqueue(&mut self) -> Result<bool, CommonError> {
-        let mut complete = true;
+    /// Units beyond `MAX_CYCLIC_UNITS` are not processed.
+    pub fn new(
+        iface: &'a mut EtherCATInterface<'a, D, T>,
+        units: &'a mut [CyclicProcessingUnit<'a>],
+    ) -> Self {
+        Self::with_custom_length(iface, units)
+    }
+}
+
+impl<'a, D, T, const N: usize> EtherCATMaster<'a, D, T, N>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+{
+    /// Same as `new`, with `Custom` units sending up to `N` bytes.
+    pub fn with_custom_length(
+        iface: &'a mut EtherCATInterface<'a, D, T>,
+        units: &'a mut [CyclicProcessingUnit<'a, N>],
+    ) -> Self {
+        let units_len = units.len().min(MAX_CYCLIC_UNITS);
+        let units = &mut units[..units_len];
+        Self {
+            iface,
+            units,
+            units_len,
+            fail_safe: FailSafeConfig::default(),
+            state: ExchangeState::Running,
+            wkc_error_count: 0,
+            deferred: UnitSet::new(),
+            backlog_cycles: 0,
+            awaiting: UnitSet::new(),
+            duplicated: UnitSet::new(),
+            copies: UnitSet::new(),
+            keep_alive: UnitSet::new(),
+            timeout_policy: ReceiveTimeoutPolicy::Report,
+            consecutive_timeouts: 0,
+            adaptive_timeout: None,
+            statistics: MasterStatistics::default(),
+            unit_statistics: &mut [],
+            clock: None,
+            enqueued_at: None,
+            lifecycle: MasterLifecycle::new(),
+        }
+    }
+
+    /// Takes over the lifecycle from `SlaveInitilizer::lifecycle`.
+    pub fn set_lifecycle(&mut self, lifecycle: MasterLifecycle) {
+        self.lifecycle = lifecycle;
+    }
+
+    pub fn lifecycle(&self) -> &MasterLifecycle {
+        &self.lifecycle
+    }
+
+    pub fn lifecycle_mut(&mut self) -> &mut MasterLifecycle {
+        &mut self.lifecycle
+    }
+
+    pub fn set_fail_safe_config(&mut self, config: FailSafeConfig) {
+        self.fail_safe = config;
+    }
+
+    pub fn set_receive_timeout_policy(&mut self, policy: ReceiveTimeoutPolicy) {
+        self.timeout_policy = policy;
+    }
+
+    /// Receive timeout used by `poll_adaptive`.
+    pub fn set_adaptive_timeout(&mut self, timeout: AdaptiveTimeout) {
+        self.adaptive_timeout = Some(timeout);
+    }
+
+    pub fn adaptive_timeout(&self) -> Option<&AdaptiveTimeout> {
+        self.adaptive_timeout.as_ref()
+    }
+
+    pub fn statistics(&self) -> &MasterStatistics {
+        &self.statistics
+    }
+
+    /// Records the counters of each unit in `statistics`, indexed like the units.
+    /// Units beyond its length are not recorded.
+    pub fn set_unit_statistics(&mut self, statistics: &'a mut [UnitStatistics]) {
+        for unit in statistics.iter_mut() {
+            *unit = UnitStatistics::default();
+        }
+        self.unit_statistics = statistics;
+    }
+
+    /// Counters of the unit at `index`, e.g. the index returned by `send_custom`.
+    pub fn unit_statistics(&self, index: usize) -> Option<&UnitStatistics> {
+        self.unit_statistics.get(index)
+    }
+
+    pub fn reset_unit_statistics(&mut self) {
+        for unit in self.unit_statistics.iter_mut() {
+            *unit = UnitStatistics::default();
+        }
+    }
+
+    /// Monotonic clock to measure the latency of each unit in `UnitStatistics`.
+    pub fn set_clock(&mut self, now: fn() -> MicrosInstant) {
+        self.clock = Some(now);
+    }
+
+    pub fn exchange_state(&self) -> ExchangeState {
+        self.state
+    }
+
+    /// Stops the exchange after writing the fail-safe image for `hold_cycles` cycles.
+    pub fn stop(&mut self) {
+        self.lifecycle.enter(MasterPhase::ShuttingDown, 0);
+        if self.state == ExchangeState::Running {
+            self.enter_fail_safe();
+        }
+    }
+
+    /// Sends the datagram of the unit at `index` twice per cycle, the copy in a separate frame,
+    /// e.g. for the watchdog feed or the output image. The first response with a non-zero WKC
+    /// is passed to the unit, so the loss or corruption of one frame does not fail the cycle.
+    /// The copy is skipped in cycles in which it does not fit in the buffer.
+    pub fn set_duplicated(&mut self, index: usize, duplicated: bool) {
+        if index >= self.units_len {
+            return;
+        }
+        if duplicated {
+            self.duplicated.insert(index);
+        } else {
+            self.duplicated.remove(index);
+        }
+    }
+
+    /// Exchanges the unit at `index` in `AcyclicWindow::feed` while the cyclic exchange is paused,
+    /// e.g. the output image, to keep the sync manager watchdogs of the slaves from expiring.
+    pub fn set_keep_alive(&mut self, index: usize, keep_alive: bool) {
+        if index >= self.units_len {
+            return;
+        }
+        if keep_alive {
+            self.keep_alive.insert(index);
+        } else {
+            self.keep_alive.remove(index);
+        }
+    }
+
+    /// Pauses the cyclic exchange while `f` runs long blocking operations through the interface,
+    /// e.g. EEPROM writes or firmware downloads, so they get the full bandwidth of the bus.
+    /// Call it between cycles; the exchange resumes with the next `process_and_enqueue`.
+    /// The units set by `set_keep_alive` are exchanged each time `f` calls `AcyclicWindow::feed`,
+    /// and only then: the blocking helpers run on the interface and do not feed. A single
+    /// call that blocks longer than the watchdog time, e.g. a whole FoE download, lets the
+    /// watchdogs of the slaves expire. Split such operations into shorter ones, e.g. one SDO
+    /// download per entry, and feed between them, or lengthen the watchdog for the window.
+    pub fn acyclic_window<R, F>(&mut self, f: F) -> R
+    where
+        F: FnOnce(&mut AcyclicWindow<'_, 'a, D, T, N>) -> R,
+    {
+        // 前のサイクルの応答待ちは捨てる
+        self.awaiting.clear();
+        self.copies.clear();
+        let result = f(&mut AcyclicWindow { master: self });
+        self.awaiting.clear();
+        self.copies.clear();
+        result
+    }
+
+    /// Units that did not fit in the buffer in the last `process_and_enqueue`.
+    /// They are enqueued first in the next cycle.
+    pub fn backlog(&self) -> usize {
+        self.deferred.len()
+    }
+
+    /// Consecutive cycles in which some units did not fit in the buffer.
+    pub fn backlog_cycles(&self) -> u32 {
+        self.backlog_cycles
+    }
+
+    fn enter_fail_safe(&mut self) {
+        self.lifecycle.fault();
+        self.state = if self.fail_safe.hold_cycles == 0 {
+            ExchangeState::Stopped
+        } else {
+            ExchangeState::FailSafe(self.fail_safe.hold_cycles)
+        };
+    }
+
+    /// Sends `data` with `command` in the next cycles using a free `CyclicProcessingUnit::Custom`
+    /// and calls `handler` with the response. Returns the index of the unit.
+    pub fn send_custom(
+        &mut self,
+        command: Command,
+        data: &[u8],
+        handler: CustomCommandHandler<'a>,
+    ) -> Result<usize, CommonError> {
         for (i, unit) in self.units.iter_mut().enumerate() {
-            if let Some((command, data)) = unit.process() {
-                let len = data.len();
-                if self.iface.remaing_capacity() < len{
-                    complete = false;
-                    break;
+            if let CyclicProcessingUnit::Custom(unit) = unit {
+                if !unit.is_busy() {
+                    unit.request(command, data, handler)?;
+                    return Ok(i);
                 }
-                let _ = self.iface.add_command(
+            }
+        }
+        Err(CommonError::BufferExhausted)
+    }
+
+    /// Custom unit at `index`, e.g. to check the last response of `send_custom`.
+    pub fn custom_unit(&self, index: usize) -> Option<&CustomCommandUnit<'a, N>> {
+        match self.units.get(index) {
+            Some(CyclicProcessingUnit::Custom(unit)) => Some(unit),
+            _ => None,
+        }
+    }
+
+    /// Process data unit at `index`, e.g. to access the PDO entries of its slaves between cycles.
+    pub fn process_data_unit_mut(&mut self, index: usize) -> Option<&mut ProcessDataUnit<'a>> {
+        match self.units.get_mut(index) {
+            Some(CyclicProcessingUnit::ProcessData(unit)) => Some(unit),
+            _ => None,
+        }
+    }
+
+    /// SDO unit at `index`, e.g. to start, check or cancel a transfer between cycles.
+    #[cfg(feature = "coe")]
+    pub fn sdo_unit_mut(&mut self, index: usize) -> Option<&mut SdoUnit<'a>> {
+        match self.units.get_mut(index) {
+            Some(CyclicProcessingUnit::Sdo(unit)) => Some(unit),
+            _ => None,
+        }
+    }
+
+    /// FoE unit at `index`, e.g. to start, check or cancel a transfer between cycles.
+    #[cfg(feature = "foe")]
+    pub fn foe_unit_mut(&mut self, index: usize) -> Option<&mut FoEUnit<'a>> {
+        match self.units.get_mut(index) {
+            Some(CyclicProcessingUnit::FoE(unit)) => Some(unit),
+            _ => None,
+        }
+    }
+
+    /// AL transition unit at `index`, e.g. to start, check or cancel a transition between cycles.
+    pub fn al_transition_unit_mut(&mut self, index: usize) -> Option<&mut AlTransitionUnit> {
+        match self.units.get_mut(index) {
+            Some(CyclicProcessingUnit::AlTransition(unit)) => Some(unit),
+            _ => None,
+        }
+    }
+
+    /// Sends NOP datagrams of several sizes and patterns `repeat` times and checks that they
+    /// come back unchanged, to validate the cabling and the NIC driver before the configuration.
+    /// `now` is a monotonic clock used to measure the round trip time.
+    /// Sizes that do not fit in a frame are skipped.
+    pub fn self_test<F: FnMut() -> MicrosInstant>(
+        &mut self,
+        repeat: u32,
+        mut now: F,
+    ) -> Result<SelfTestReport, CommonError> {
+        let mut report = SelfTestReport {
+            frames: 0,
+            lost_frames: 0,
+            corrupted_frames: 0,
+            min_rtt: MicrosDurationU64::from_ticks(u64::MAX),
+            max_rtt: MicrosDurationU64::from_ticks(0),
+        };
+        for _ in 0..repeat {
+            for size in SELF_TEST_SIZES {
+                if self.iface.remaing_capacity() < size {
+                    continue;
+                }
+                for pattern in 0..SELF_TEST_PATTERNS {
+                    // スレーブはNOPを処理しないので、データとWKCはそのまま戻ってくる
+                    self.iface.add_command(
+                        u8::MAX,
+                        CommandType::NOP,
+                        0,
+                        0,
+                        size,
+                        |buf| {
+                            for (i, b) in buf.iter_mut().enumerate() {
+                                *b = self_test_pattern(pattern, i);
+                            }
+                        },
+                    )?;
+                    let sent_at = now();
+                    report.frames += 1;
+                    match self.iface.poll(RECEIVE_TIMEOUT_DEFAULT) {
+                        Ok(_) => (),
+                        Err(CommonError::ReceiveTimeout) => {
+                            self.iface.consume_command();
+                            report.lost_frames += 1;
+                            continue;
+                        }
+                        Err(err) => return Err(err),
+                    }
+                    let rtt = now()
+                        .checked_duration_since(sent_at)
+                        .unwrap_or(MicrosDurationU64::from_ticks(0));
+                    report.min_rtt = report.min_rtt.min(rtt);
+                    report.max_rtt = report.max_rtt.max(rtt);
+                    let is_intact = self.iface.consume_command().last().map_or(false, |pdu| {
+                        CommandType::new(pdu.command_type()) == CommandType::NOP
+                            && pdu.wkc() == Some(0)
+                            && pdu.data().len() == size
+                            && pdu
+                                .data()
+                                .iter()
+                                .enumerate()
+                                .all(|(i, b)| *b == self_test_pattern(pattern, i))
+                    });
+                    if !is_intact {
+                        report.corrupted_frames += 1;
+                    }
+                }
+            }
+        }
+        if report.frames == report.lost_frames {
+            report.min_rtt = MicrosDurationU64::from_ticks(0);
+        }
+        Ok(report)
+    }
+
+    /// Enqueues the commands of the units. Returns false if some units did not fit in the buffer;
+    /// they are enqueued first in the next cycle, so every unit gets its turn.
+    /// A unit that does not fit does not hold back the smaller units after it.
+    pub fn process_and_enqueue(&mut self) -> Result<bool, CommonError> {
+        let fail_safe = match self.state {
+            ExchangeState::Running => false,
+            ExchangeState::FailSafe(remaining) => {
+                self.state = if remaining <= 1 {
+                    ExchangeState::Stopped
+                } else {
+                    ExchangeState::FailSafe(remaining - 1)
+                };
+                true
+            }
+            ExchangeState::Stopped => return Ok(true),
+        };
+        self.enqueued_at = self.clock.map(|now| now());
+        let num_units = self.units.len();
+        // 前回入りきらなかったユニットを先に詰め、残りの容量に他のユニットを詰める
+        let previous = core::mem::replace(&mut self.deferred, UnitSet::new());
+        let order = (0..num_units)
+            .filter(|i| previous.contains(*i))
+            .chain((0..num_units).filter(|i| !previous.contains(*i)));
+        for i in order {
+            let unit = &mut self.units[i];
+            // 入りきらないユニットは処理せずに次のサイクルへ回し、後ろの小さいユニットは詰める
+            if self.iface.remaing_capacity() < unit.data_size() {
+                self.deferred.insert(i);
+                continue;
+            }
+            let command_and_data = if fail_safe {
+                unit.fail_safe()
+            } else {
+                unit.process()
+            };
+            if let Some((command, data)) = command_and_data {
+                let len = data.len();
+                let _ = self.iface.add_datagram(
                     i as u8,
                     command.c_type,
-                    command.adp,
-                    command.ado,
+                    command.address,
                     len,
                     |buf| {
                         for (b, d) in buf.iter_mut().zip(data) {
@@ -72,29 +959,271 @@ where
                         }
                     },
                 )?;
+                self.awaiting.insert(i);
+                self.count_command(i, len);
             }
         }
+        self.enqueue_copies();
+        let complete = self.deferred.is_empty();
+        self.backlog_cycles = if complete {
+            0
+        } else {
+            self.backlog_cycles.saturating_add(1)
+        };
         Ok(complete)
     }
 
+    /// Exchanges only the keep-alive units in one round trip.
+    fn feed_keep_alive(&mut self) -> Result<bool, CommonError> {
+        if self.state != ExchangeState::Running || self.keep_alive.is_empty() {
+            return Ok(true);
+        }
+        self.enqueued_at = self.clock.map(|now| now());
+        for i in 0..self.units.len() {
+            if !self.keep_alive.contains(i) {
+                continue;
+            }
+            if self.iface.remaing_capacity() < self.units[i].data_size() {
+                break;
+            }
+            if let Some((command, data)) = self.units[i].process() {
+                let len = data.len();
+                self.iface
+                    .add_datagram(i as u8, command.c_type, command.address, len, |buf| {
+                        for (b, d) in buf.iter_mut().zip(data) {
+                            *b = *d;
+                        }
+                    })?;
+                self.awaiting.insert(i);
+                self.count_command(i, len);
+            }
+        }
+        self.poll(RECEIVE_TIMEOUT_DEFAULT)
+    }
+
+    /// Enqueues the copies of the duplicated units in a frame of their own.
+    fn enqueue_copies(&mut self) {
+        let mut is_split = false;
+        for i in 0..self.units.len() {
+            if !self.duplicated.contains(i) || !self.awaiting.contains(i) {
+                continue;
+            }
+            if !is_split {
+                self.iface.split_frame();
+                is_split = true;
+            }
+            // 入りきらなければ複製せずに送る
+            if let Ok(true) = self.iface.requeue(i as u8) {
+                self.copies.insert(i);
+            }
+        }
+    }
+
+    /// Sends the enqueued commands and passes each response to the unit of its PDU index.
+    /// Responses may arrive in any order; duplicates and responses to units that did not send
+    /// in this cycle are ignored, and a missing response counts as a WKC error.
+    /// A cycle in which a frame was lost still succeeds if the copies of `set_duplicated`
+    /// carried the responses of all units in it.
     pub fn poll<I: Into<MicrosDurationU32>>(&mut self, timeout: I) -> Result<bool, CommonError>{
         let mut is_ok = true;
-        self.iface.poll(timeout)?;
+        self.statistics.cycles = self.statistics.cycles.wrapping_add(1);
+        let timeout = timeout.into();
+        let mut result = self.iface.poll(timeout);
+        if let ReceiveTimeoutPolicy::Retry(retries) = self.timeout_policy {
+            for _ in 0..retries {
+                if !matches!(result, Err(CommonError::ReceiveTimeout)) {
+                    break;
+                }
+                self.statistics.retried_frames = self.statistics.retried_frames.wrapping_add(1);
+                result = self.iface.resend(timeout);
+            }
+        }
+        if let Err(err) = result {
+            let mut is_recovered = false;
+            if matches!(err, CommonError::ReceiveTimeout) && !self.copies.is_empty() {
+                // 届いたフレームの応答を渡し、失われたフレームの分が複製で揃えばサイクルは成立する
+                is_ok = self.receive_responses();
+                is_recovered = self.awaiting.is_empty();
+            }
+            if !is_recovered {
+                self.count_missed();
+                self.copies.clear();
+                self.statistics.frame_errors = self.statistics.frame_errors.wrapping_add(1);
+                if let CommonError::ReceiveTimeout = err {
+                    self.statistics.receive_timeouts =
+                        self.statistics.receive_timeouts.wrapping_add(1);
+                    self.consecutive_timeouts = self.consecutive_timeouts.saturating_add(1);
+                    match self.timeout_policy {
+                        ReceiveTimeoutPolicy::IgnoreAndCount => return Ok(false),
+                        ReceiveTimeoutPolicy::Escalate(limit)
+                            if self.consecutive_timeouts >= limit
+                                && self.state == ExchangeState::Running =>
+                        {
+                            self.enter_fail_safe()
+                        }
+                        _ => (),
+                    }
+                }
+                self.count_wkc_error(false);
+                return Err(err);
+            }
+            self.statistics.recovered_frames = self.statistics.recovered_frames.wrapping_add(1);
+            self.consecutive_timeouts = 0;
+        } else {
+            self.consecutive_timeouts = 0;
+            if let Some(rtt) = self.iface.last_round_trip() {
+                self.statistics.last_round_trip_ns = Some(rtt);
+                self.statistics.max_round_trip_ns = self.statistics.max_round_trip_ns.max(rtt);
+            }
+            is_ok = self.receive_responses();
+        }
+        self.copies.clear();
+        if !self.awaiting.is_empty() {
+            self.count_missed();
+            is_ok = false;
+        }
+        if !is_ok {
+            self.statistics.wkc_errors = self.statistics.wkc_errors.wrapping_add(1);
+        }
+        self.count_wkc_error(is_ok);
+        Ok(is_ok)
+    }
+
+    /// Same as `poll`, with the timeout of `set_adaptive_timeout` updated from the round trip
+    /// time measured with `now`, or with the hardware timestamps of the device if available.
+    /// Without it, `RECEIVE_TIMEOUT_DEFAULT` is used.
+    pub fn poll_adaptive<F: FnMut() -> MicrosInstant>(
+        &mut self,
+        mut now: F,
+    ) -> Result<bool, CommonError> {
+        let timeout = self
+            .adaptive_timeout
+            .as_ref()
+            .map_or(RECEIVE_TIMEOUT_DEFAULT, |timeout| timeout.timeout());
+        let sent_at = now();
+        let result = self.poll(timeout);
+        if let Some(adaptive_timeout) = self.adaptive_timeout.as_mut() {
+            match result {
+                Err(CommonError::ReceiveTimeout) => adaptive_timeout.timed_out(),
+                Err(_) => (),
+                Ok(_) => {
+                    // ハードウェアタイムスタンプがあればそちらが正確
+                    let hardware_rtt = self
+                        .iface
+                        .last_round_trip()
+                        .map(|ns| MicrosDurationU64::from_ticks(ns / 1000));
+                    // 応答の処理時間も含むが、送受信に比べて十分短い
+                    if let Some(rtt) =
+                        hardware_rtt.or_else(|| now().checked_duration_since(sent_at))
+                    {
+                        adaptive_timeout.update(rtt);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Passes the received responses to the units of their PDU index.
+    /// Returns false if a unit rejected its response.
+    fn receive_responses(&mut self) -> bool {
+        let mut is_ok = true;
         let pdus = self.iface.consume_command();
         for pdu in pdus{
             let index = pdu.index() as usize;
+            if index >= self.units.len() || !self.awaiting.contains(index) {
+                continue;
+            }
+            let wkc = pdu.wkc().unwrap_or_default();
+            // 複製の片方がWKC 0で戻ったら、もう片方を待つ
+            if self.copies.remove(index) && wkc == 0 {
+                continue;
+            }
+            self.awaiting.remove(index);
+            let latency = self
+                .clock
+                .and_then(|now| now().checked_duration_since(self.enqueued_at?));
             if let Some(unit) = self.units.get_mut(index){
-                let wkc = pdu.wkc().unwrap_or_default();
                 let command = Command{
                     c_type: CommandType::new(pdu.command_type()),
-                    adp: pdu.adp(),
-                    ado: pdu.ado(),
+                    address: pdu.address(),
                 };
-                if !unit.receive(command, pdu.data(), wkc){
+                let is_accepted = unit.receive(command, pdu.data(), wkc);
+                if !is_accepted {
                     is_ok = false;
                 }
+                if let Some(statistics) = self.unit_statistics.get_mut(index) {
+                    if !is_accepted {
+                        statistics.errors = statistics.errors.wrapping_add(1);
+                    }
+                    if latency.is_some() {
+                        statistics.last_latency = latency;
+                        statistics.max_latency = statistics.max_latency.max(latency);
+                    }
+                }
             }
         }
-        Ok(is_ok)
+        is_ok
+    }
+
+    fn count_command(&mut self, index: usize, len: usize) {
+        if let Some(statistics) = self.unit_statistics.get_mut(index) {
+            statistics.commands = statistics.commands.wrapping_add(1);
+            statistics.bytes = statistics.bytes.wrapping_add(len as u32);
+        }
+    }
+
+    /// Counts the units still waiting for a response as missed and stops waiting.
+    fn count_missed(&mut self) {
+        for (index, statistics) in self.unit_statistics.iter_mut().enumerate() {
+            if self.awaiting.contains(index) {
+                statistics.missed = statistics.missed.wrapping_add(1);
+            }
+        }
+        self.awaiting.clear();
+    }
+
+    fn count_wkc_error(&mut self, is_ok: bool) {
+        if is_ok {
+            self.wkc_error_count = 0;
+            return;
+        }
+        self.wkc_error_count = self.wkc_error_count.saturating_add(1);
+        let threshold = self.fail_safe.wkc_error_threshold;
+        if threshold != 0
+            && self.wkc_error_count >= threshold
+            && self.state == ExchangeState::Running
+        {
+            self.enter_fail_safe();
+        }
+    }
+}
+
+/// Access to the interface while the cyclic exchange is paused by
+/// `EtherCATMaster::acyclic_window`.
+pub struct AcyclicWindow<'w, 'a, D, T, const N: usize = CUSTOM_COMMAND_MAX_LENGTH>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+{
+    master: &'w mut EtherCATMaster<'a, D, T, N>,
+}
+
+impl<'w, 'a, D, T, const N: usize> AcyclicWindow<'w, 'a, D, T, N>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+{
+    /// Interface for the blocking helpers, e.g. `SdoClient::new(window.iface(), &mut timer)`.
+    /// The helpers do not call `feed` while they block.
+    pub fn iface(&mut self) -> &mut EtherCATInterface<'a, D, T> {
+        self.master.iface
+    }
+
+    /// Exchanges the units set by `EtherCATMaster::set_keep_alive` once, like `poll`.
+    /// Call it between operations more often than the watchdog time of the slaves.
+    /// Does nothing if there are no such units or the exchange is not running.
+    pub fn feed(&mut self) -> Result<bool, CommonError> {
+        self.master.feed_keep_alive()
     }
 }
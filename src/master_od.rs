@@ -0,0 +1,111 @@
+use crate::master::MasterStatistics;
+use crate::network::NetworkDescription;
+use crate::packet::coe::AbortCode;
+use crate::slave_status::*;
+
+/// Master statistics: 1 cycles, 2 WKC errors, 3 frame errors (UDINT).
+pub const MASTER_STATISTICS_INDEX: u16 = 0x2000;
+/// Configuration data of slave n at 0x8000 + n: 1 station address, 2 vendor ID,
+/// 3 product code, 4 revision number (UINT).
+pub const SLAVE_CONFIGURATION_INDEX: u16 = 0x8000;
+/// Diagnosis data of slave n at 0xA000 + n: 1 AL state (UINT), 2 has an error (BOOL),
+/// 3 connected ports (bit 0 to 3).
+pub const SLAVE_DIAGNOSIS_INDEX: u16 = 0xA000;
+/// Station addresses of the slaves. Sub-index 0 is the number of slaves.
+pub const CONFIGURED_ADDRESS_LIST_INDEX: u16 = 0xF020;
+/// Number of slave objects of 0x8000 and 0xA000.
+pub const MAX_SLAVE_OBJECTS: u16 = 0x1000;
+
+/// Master diagnostics in the layout of a CoE object dictionary, modeled on ETG.1510,
+/// so that HMIs and gateways can read them by index and sub-index.
+/// Values are little endian. Sub-index 0 returns the highest sub-index (USINT).
+pub struct MasterObjectDictionary<'a, 'b> {
+    statistics: &'a MasterStatistics,
+    network: &'a NetworkDescription<'a, 'b>,
+}
+
+impl<'a, 'b> MasterObjectDictionary<'a, 'b> {
+    pub fn new(statistics: &'a MasterStatistics, network: &'a NetworkDescription<'a, 'b>) -> Self {
+        Self {
+            statistics,
+            network,
+        }
+    }
+
+    /// Reads the object into `buf` and returns the size of the data.
+    pub fn read(&self, index: u16, sub_index: u8, buf: &mut [u8]) -> Result<usize, AbortCode> {
+        match index {
+            MASTER_STATISTICS_INDEX => {
+                let statistics = self.statistics;
+                match sub_index {
+                    0 => write_value(buf, &[3]),
+                    1 => write_value(buf, &statistics.cycles.to_le_bytes()),
+                    2 => write_value(buf, &statistics.wkc_errors.to_le_bytes()),
+                    3 => write_value(buf, &statistics.frame_errors.to_le_bytes()),
+                    _ => Err(AbortCode::SubIndexDoesNotExist),
+                }
+            }
+            CONFIGURED_ADDRESS_LIST_INDEX => {
+                let slaves = self.network.slaves();
+                match sub_index {
+                    0 => write_value(buf, &[slaves.len().min(u8::MAX as usize) as u8]),
+                    n => slaves
+                        .get(n as usize - 1)
+                        .ok_or(AbortCode::SubIndexDoesNotExist)
+                        .and_then(|slave| {
+                            write_value(buf, &slave.configured_address.to_le_bytes())
+                        }),
+                }
+            }
+            _ if (SLAVE_CONFIGURATION_INDEX..SLAVE_CONFIGURATION_INDEX + MAX_SLAVE_OBJECTS)
+                .contains(&index) =>
+            {
+                let slave = self.slave(index - SLAVE_CONFIGURATION_INDEX)?;
+                let id = &slave.id;
+                match sub_index {
+                    0 => write_value(buf, &[4]),
+                    1 => write_value(buf, &slave.configured_address.to_le_bytes()),
+                    2 => write_value(buf, &id.vender_id().to_le_bytes()),
+                    3 => write_value(buf, &id.product_code().to_le_bytes()),
+                    4 => write_value(buf, &id.revision_number().to_le_bytes()),
+                    _ => Err(AbortCode::SubIndexDoesNotExist),
+                }
+            }
+            _ if (SLAVE_DIAGNOSIS_INDEX..SLAVE_DIAGNOSIS_INDEX + MAX_SLAVE_OBJECTS)
+                .contains(&index) =>
+            {
+                let slave = self.slave(index - SLAVE_DIAGNOSIS_INDEX)?;
+                match sub_index {
+                    0 => write_value(buf, &[3]),
+                    1 => write_value(buf, &(slave.al_state as u16).to_le_bytes()),
+                    2 => write_value(buf, &[slave.error.is_some() as u8]),
+                    3 => {
+                        let ports = slave
+                            .ports
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, port)| port.is_some())
+                            .fold(0u8, |bits, (i, _)| bits | 1 << i);
+                        write_value(buf, &[ports])
+                    }
+                    _ => Err(AbortCode::SubIndexDoesNotExist),
+                }
+            }
+            _ => Err(AbortCode::DoesNotExistInDict),
+        }
+    }
+
+    fn slave(&self, position: u16) -> Result<&Slave<'b>, AbortCode> {
+        self.network
+            .slave(position)
+            .ok_or(AbortCode::DoesNotExistInDict)
+    }
+}
+
+fn write_value(buf: &mut [u8], value: &[u8]) -> Result<usize, AbortCode> {
+    let len = value.len();
+    buf.get_mut(..len)
+        .ok_or(AbortCode::LengthExceedsMailboxSize)?
+        .copy_from_slice(value);
+    Ok(len)
+}
@@ -0,0 +1,364 @@
+use crate::esc::{EscInfo, EscQuirks};
+use crate::initializer::ProcessImage;
+use crate::register::datalink::PortPhysics;
+use crate::slave_status::*;
+
+/// Order in which an ESC forwards a frame through its ports. Port 0 is the upstream port.
+pub(crate) const PORT_ORDER: [usize; 3] = [3, 1, 2];
+
+/// Redundancy of the cabling seen from the primary port of the master.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RedundancyStatus {
+    /// Port 1 of the last slave is closed.
+    Line,
+    /// Port 1 of the last slave is connected back to the second port of the master.
+    RingClosed,
+    /// The ring is broken behind the slave at this position.
+    /// The slaves behind it can only be reached through the second port of the master.
+    RingOpen(u16),
+}
+
+/// Handle of a user-defined set of slaves, e.g. the axes or the I/O of a machine,
+/// whose AL state and process data are handled separately from the other groups.
+/// Every slave belongs to `SlaveGroup::DEFAULT` until assigned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SlaveGroup(pub u8);
+
+impl SlaveGroup {
+    pub const DEFAULT: SlaveGroup = SlaveGroup(0);
+}
+
+/// Slaves found by a scan and how they are connected.
+#[derive(Debug)]
+pub struct NetworkDescription<'a, 'b> {
+    slaves: &'a mut [Slave<'b>],
+    num_slaves: usize,
+    redundancy: RedundancyStatus,
+    expected_num_slaves: Option<u16>,
+}
+
+impl<'a, 'b> NetworkDescription<'a, 'b> {
+    /// `slaves[..num_slaves]` must be in the order of the positions.
+    pub fn new(slaves: &'a mut [Slave<'b>], num_slaves: usize) -> Self {
+        let num_slaves = num_slaves.min(slaves.len());
+        build_topology(&mut slaves[..num_slaves]);
+        let mut network = Self {
+            slaves,
+            num_slaves,
+            redundancy: RedundancyStatus::Line,
+            expected_num_slaves: None,
+        };
+        network.redundancy = network.detect_redundancy(None);
+        network
+    }
+
+    pub fn slaves(&self) -> &[Slave<'b>] {
+        &self.slaves[..self.num_slaves]
+    }
+
+    pub fn slaves_mut(&mut self) -> &mut [Slave<'b>] {
+        &mut self.slaves[..self.num_slaves]
+    }
+
+    pub fn slave(&self, position: u16) -> Option<&Slave<'b>> {
+        self.slaves().get(position as usize)
+    }
+
+    pub fn slave_mut(&mut self, position: u16) -> Option<&mut Slave<'b>> {
+        self.slaves_mut().get_mut(position as usize)
+    }
+
+    /// Assigns the slave at `position` to `group`. Returns false if there is no such slave.
+    pub fn set_group(&mut self, position: u16, group: SlaveGroup) -> bool {
+        match self.slave_mut(position) {
+            Some(slave) => {
+                slave.group = group;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Slaves of `group` in the order of the positions.
+    pub fn slaves_in(&self, group: SlaveGroup) -> impl Iterator<Item = &Slave<'b>> + Clone {
+        self.slaves()
+            .iter()
+            .filter(move |slave| slave.group == group)
+    }
+
+    /// Slaves of `group` in the order of the positions.
+    pub fn slaves_in_mut(&mut self, group: SlaveGroup) -> impl Iterator<Item = &mut Slave<'b>> {
+        self.slaves_mut()
+            .iter_mut()
+            .filter(move |slave| slave.group == group)
+    }
+
+    /// Positions of the slaves whose inputs changed since the last call, clearing their flags.
+    /// Only slaves with `Slave::set_input_change_detection` enabled are reported.
+    pub fn take_changed_inputs(&mut self) -> impl Iterator<Item = u16> + use<'_, 'a, 'b> {
+        self.slaves_mut()
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, slave)| slave.take_inputs_changed().then(|| i as u16))
+    }
+
+    pub fn num_slaves(&self) -> usize {
+        self.num_slaves
+    }
+
+    pub fn redundancy_status(&self) -> RedundancyStatus {
+        self.redundancy
+    }
+
+    /// Updates the redundancy status with the number of slaves in the configured ring.
+    /// Without it, a broken ring cannot be told apart from a line.
+    pub fn set_expected_num_slaves(&mut self, expected_num_slaves: u16) {
+        self.expected_num_slaves = Some(expected_num_slaves);
+        self.redundancy = self.detect_redundancy(self.expected_num_slaves);
+    }
+
+    /// Marks the information of the slave at `position` as outdated, e.g. after the device
+    /// was replaced, until `SlaveInitilizer::rescan_slave` reads it again.
+    /// Returns false if there is no such slave.
+    pub fn invalidate(&mut self, position: u16) -> bool {
+        match self.slave_mut(position) {
+            Some(slave) => {
+                slave.is_stale = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Positions of the slaves marked by `invalidate`.
+    pub fn stale_slaves(&self) -> impl Iterator<Item = u16> + use<'_, 'a, 'b> {
+        self.slaves()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, slave)| slave.is_stale.then(|| i as u16))
+    }
+
+    /// Finds the parents and the redundancy again after the ports of a slave changed.
+    pub(crate) fn refresh_topology(&mut self) {
+        build_topology(self.slaves_mut());
+        self.redundancy = self.detect_redundancy(self.expected_num_slaves);
+    }
+
+    fn detect_redundancy(&self, expected_num_slaves: Option<u16>) -> RedundancyStatus {
+        let last = match self.slaves().last() {
+            Some(last) => last,
+            None => return RedundancyStatus::Line,
+        };
+        // 最後のスレーブのポート1にリンクがあれば、マスターの2つ目のポートに戻っている
+        if last.ports[1].is_some() {
+            return RedundancyStatus::RingClosed;
+        }
+        match expected_num_slaves {
+            Some(expected) if (expected as usize) > self.num_slaves => {
+                RedundancyStatus::RingOpen(last.position_address)
+            }
+            _ => RedundancyStatus::Line,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'a, 'b> serde::Serialize for NetworkDescription<'a, 'b> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        struct Slaves<'s, 'b>(&'s [Slave<'b>]);
+        impl<'s, 'b> serde::Serialize for Slaves<'s, 'b> {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_seq(self.0.iter().map(SlaveInfo::from))
+            }
+        }
+        let mut state = serializer.serialize_struct("NetworkDescription", 2)?;
+        state.serialize_field("slaves", &Slaves(self.slaves()))?;
+        state.serialize_field("redundancy", &self.redundancy)?;
+        state.end()
+    }
+}
+
+/// Configuration of a slave found by a scan, without the PDO mappings owned by the application.
+/// Restores a slave without reading its SII.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SlaveInfo {
+    pub position_address: u16,
+    pub configured_address: u16,
+    pub id: Identification,
+    pub ports: [Option<PortPhysics>; 4],
+    pub enhanced_link_detection: [bool; 4],
+    pub require_enhanced_link_detection: bool,
+    pub parent: Option<(u16, u8)>,
+    pub group: SlaveGroup,
+    pub ram_size_kb: u8,
+    pub esc: EscInfo,
+    pub quirks: EscQuirks,
+    pub fmmu0: Option<u16>,
+    pub fmmu1: Option<u16>,
+    pub number_of_sm: u8,
+    pub number_of_fmmu: u8,
+    pub pdo_start_address: Option<u16>,
+    pub pdo_ram_size: u16,
+    pub logical_address: Option<u32>,
+    /// Size of the outputs in the process image.
+    pub output_size: u16,
+    /// Size of the inputs in the process image.
+    pub input_size: u16,
+    pub output_buffer_type: SyncManagerBufferType,
+    pub input_buffer_type: SyncManagerBufferType,
+    pub sm_mailbox_in: Option<MailboxSyncManager>,
+    pub sm_mailbox_out: Option<MailboxSyncManager>,
+    pub bootstrap_sm_mailbox_in: Option<MailboxSyncManager>,
+    pub bootstrap_sm_mailbox_out: Option<MailboxSyncManager>,
+    pub support_dc: bool,
+    pub is_dc_range_64bits: bool,
+    pub dc_propagation_delay: u32,
+    pub support_fmmu_bit_operation: bool,
+    pub support_lrw: bool,
+    pub support_rw: bool,
+    pub operation_mode: OperationMode,
+    pub has_coe: bool,
+    pub has_foe: bool,
+}
+
+impl From<&Slave<'_>> for SlaveInfo {
+    fn from(slave: &Slave) -> Self {
+        Self {
+            position_address: slave.position_address,
+            configured_address: slave.configured_address,
+            id: slave.id.clone(),
+            ports: slave.ports,
+            enhanced_link_detection: slave.enhanced_link_detection,
+            require_enhanced_link_detection: slave.require_enhanced_link_detection,
+            parent: slave.parent,
+            group: slave.group,
+            ram_size_kb: slave.ram_size_kb,
+            esc: slave.esc,
+            quirks: slave.quirks,
+            fmmu0: slave.fmmu0,
+            fmmu1: slave.fmmu1,
+            number_of_sm: slave.number_of_sm,
+            number_of_fmmu: slave.number_of_fmmu,
+            pdo_start_address: slave.pdo_start_address,
+            pdo_ram_size: slave.pdo_ram_size,
+            logical_address: slave.logical_address,
+            output_size: slave.rx_pdo_size(),
+            input_size: slave.tx_pdo_size(),
+            output_buffer_type: slave.output_buffer_type,
+            input_buffer_type: slave.input_buffer_type,
+            sm_mailbox_in: slave.sm_mailbox_in,
+            sm_mailbox_out: slave.sm_mailbox_out,
+            bootstrap_sm_mailbox_in: slave.bootstrap_sm_mailbox_in,
+            bootstrap_sm_mailbox_out: slave.bootstrap_sm_mailbox_out,
+            support_dc: slave.support_dc,
+            is_dc_range_64bits: slave.is_dc_range_64bits,
+            dc_propagation_delay: slave.dc_propagation_delay,
+            support_fmmu_bit_operation: slave.support_fmmu_bit_operation,
+            support_lrw: slave.support_lrw,
+            support_rw: slave.support_rw,
+            operation_mode: slave.operation_mode.clone(),
+            has_coe: slave.has_coe,
+            has_foe: slave.has_foe,
+        }
+    }
+}
+
+impl SlaveInfo {
+    /// Copies the configuration into `slave`. The PDO mappings of `slave` are kept.
+    pub fn apply(&self, slave: &mut Slave) {
+        slave.position_address = self.position_address;
+        slave.configured_address = self.configured_address;
+        slave.id = self.id.clone();
+        slave.ports = self.ports;
+        slave.enhanced_link_detection = self.enhanced_link_detection;
+        slave.require_enhanced_link_detection = self.require_enhanced_link_detection;
+        slave.parent = self.parent;
+        slave.group = self.group;
+        slave.ram_size_kb = self.ram_size_kb;
+        slave.esc = self.esc;
+        slave.quirks = self.quirks;
+        slave.fmmu0 = self.fmmu0;
+        slave.fmmu1 = self.fmmu1;
+        slave.number_of_sm = self.number_of_sm;
+        slave.number_of_fmmu = self.number_of_fmmu;
+        slave.pdo_start_address = self.pdo_start_address;
+        slave.pdo_ram_size = self.pdo_ram_size;
+        slave.logical_address = self.logical_address;
+        slave.output_buffer_type = self.output_buffer_type;
+        slave.input_buffer_type = self.input_buffer_type;
+        slave.sm_mailbox_in = self.sm_mailbox_in;
+        slave.sm_mailbox_out = self.sm_mailbox_out;
+        slave.bootstrap_sm_mailbox_in = self.bootstrap_sm_mailbox_in;
+        slave.bootstrap_sm_mailbox_out = self.bootstrap_sm_mailbox_out;
+        slave.support_dc = self.support_dc;
+        slave.is_dc_range_64bits = self.is_dc_range_64bits;
+        slave.dc_propagation_delay = self.dc_propagation_delay;
+        slave.support_fmmu_bit_operation = self.support_fmmu_bit_operation;
+        slave.support_lrw = self.support_lrw;
+        slave.support_rw = self.support_rw;
+        slave.operation_mode = self.operation_mode.clone();
+        slave.has_coe = self.has_coe;
+        slave.has_foe = self.has_foe;
+    }
+}
+
+/// Stored result of a commissioning scan for up to `N` slaves,
+/// e.g. produced on a PC and loaded by the master at boot.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConfigBlob<const N: usize> {
+    pub slaves: heapless::Vec<SlaveInfo, N>,
+    pub redundancy: RedundancyStatus,
+    pub process_image: Option<ProcessImage>,
+}
+
+impl<const N: usize> ConfigBlob<N> {
+    /// Returns None if the network has more than `N` slaves.
+    pub fn new(network: &NetworkDescription, process_image: Option<ProcessImage>) -> Option<Self> {
+        let mut slaves = heapless::Vec::new();
+        for slave in network.slaves() {
+            slaves.push(SlaveInfo::from(slave)).ok()?;
+        }
+        Some(Self {
+            slaves,
+            redundancy: network.redundancy_status(),
+            process_image,
+        })
+    }
+}
+
+/// Active downstream ports of the slave in the forwarding order.
+pub(crate) fn downstream_ports<'s>(slave: &'s Slave) -> impl Iterator<Item = usize> + 's {
+    let ports = &slave.ports;
+    PORT_ORDER
+        .iter()
+        .copied()
+        .filter(move |port| ports[*port].is_some())
+}
+
+/// Finds the parent of every slave from the open ports.
+/// Positions are assigned in the forwarding order, so the subtree behind a port is
+/// complete before the next port of the same slave.
+pub(crate) fn build_topology(slaves: &mut [Slave]) {
+    for i in 0..slaves.len() {
+        slaves[i].parent = None;
+        // 直前のスレーブから親をたどり、まだ子がつながっていないポートを探す
+        let mut candidate = i.checked_sub(1);
+        while let Some(j) = candidate {
+            let num_children = slaves[j + 1..i]
+                .iter()
+                .filter(|slave| matches!(slave.parent, Some((parent, _)) if parent as usize == j))
+                .count();
+            let port = downstream_ports(&slaves[j]).nth(num_children);
+            if let Some(port) = port {
+                slaves[i].parent = Some((j as u16, port as u8));
+                break;
+            }
+            candidate = slaves[j].parent.map(|(parent, _)| parent as usize);
+        }
+    }
+}
@@ -1,4 +1,15 @@
+pub mod byte_order;
+#[cfg(feature = "coe")]
 pub mod coe;
+#[cfg(feature = "eoe")]
+pub mod eoe;
 pub mod ethercat;
+#[cfg(feature = "foe")]
+pub mod foe;
+#[cfg(feature = "coe")]
 pub use coe::*;
+#[cfg(feature = "eoe")]
+pub use eoe::*;
 pub use ethercat::*;
+#[cfg(feature = "foe")]
+pub use foe::*;
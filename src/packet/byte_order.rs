@@ -0,0 +1,47 @@
+//! Little-endian field access independent of the byte order of the host.
+//! EtherCAT fields are little endian, except the Ethernet header (`EthernetHeader` is MSB0).
+
+/// Reads a little-endian u16 at `offset`. None if `buf` is too short.
+pub fn read_u16(buf: &[u8], offset: usize) -> Option<u16> {
+    let bytes = buf.get(offset..offset + 2)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+/// Reads a little-endian u32 at `offset`. None if `buf` is too short.
+pub fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    let bytes = buf.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Reads a little-endian u64 at `offset`. None if `buf` is too short.
+pub fn read_u64(buf: &[u8], offset: usize) -> Option<u64> {
+    let bytes = buf.get(offset..offset + 8)?;
+    let mut value = [0; 8];
+    value.copy_from_slice(bytes);
+    Some(u64::from_le_bytes(value))
+}
+
+/// Writes `value` in little endian at `offset`. Returns false if `buf` is too short.
+pub fn write_u16(buf: &mut [u8], offset: usize, value: u16) -> bool {
+    write_bytes(buf, offset, &value.to_le_bytes())
+}
+
+/// Writes `value` in little endian at `offset`. Returns false if `buf` is too short.
+pub fn write_u32(buf: &mut [u8], offset: usize, value: u32) -> bool {
+    write_bytes(buf, offset, &value.to_le_bytes())
+}
+
+/// Writes `value` in little endian at `offset`. Returns false if `buf` is too short.
+pub fn write_u64(buf: &mut [u8], offset: usize, value: u64) -> bool {
+    write_bytes(buf, offset, &value.to_le_bytes())
+}
+
+fn write_bytes(buf: &mut [u8], offset: usize, bytes: &[u8]) -> bool {
+    match buf.get_mut(offset..offset + bytes.len()) {
+        Some(field) => {
+            field.copy_from_slice(bytes);
+            true
+        }
+        None => false,
+    }
+}
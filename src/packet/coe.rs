@@ -41,6 +41,10 @@ pub enum CANOpenServiceType {
     SDOInfo,
 }
 
+/// Maximum data length of a PDO transferred in the mailbox (service types TxPDO to RxPDORemoteReq).
+/// The PDO number is carried in `CANOpenPDU::number`.
+pub const COE_PDO_MAX_LENGTH: usize = 8;
+
 pub const SDO_HEADER_LENGTH: usize = 4;
 pub const SDO_DATA_LENGTH: usize = 4;
 
@@ -74,6 +78,81 @@ impl<T: AsRef<[u8]>> SDO<T> {
     }
 }
 
+pub const SDO_INFO_HEADER_LENGTH: usize = 4;
+
+bitfield! {
+    pub struct SDOInfo([u8]);
+    pub u8, opcode, set_opcode: 6, 0;
+    pub incomplete, set_incomplete: 7;
+    pub u16, fragments_left, set_fragments_left: 31, 16;
+}
+
+impl<T: AsRef<[u8]>> SDOInfo<T> {
+    pub fn new(buf: T) -> Option<Self> {
+        let packet = Self(buf);
+        if packet.is_buffer_range_ok() {
+            Some(packet)
+        } else {
+            None
+        }
+    }
+
+    pub fn new_unchecked(buf: T) -> Self {
+        Self(buf)
+    }
+
+    pub fn is_buffer_range_ok(&self) -> bool {
+        self.0.as_ref().get(SDO_INFO_HEADER_LENGTH - 1).is_some()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Hash, Copy)]
+pub enum SDOInfoOpCode {
+    ODListReq = 1,
+    ODListRes,
+    ObjectDescriptionReq,
+    ObjectDescriptionRes,
+    EntryDescriptionReq,
+    EntryDescriptionRes,
+    ErrorReq,
+}
+
+pub const SDO_ENTRY_DESCRIPTION_REQUEST_LENGTH: usize = 4;
+pub const SDO_ENTRY_DESCRIPTION_LENGTH: usize = 10;
+
+bitfield! {
+    /// Data of an entry description response, followed by the unit, default and limits
+    /// requested in `value_info`.
+    pub struct SDOEntryDescription([u8]);
+    pub u16, index, set_index: 15, 0;
+    pub u8, sub_index, set_sub_index: 23, 16;
+    pub u8, value_info, set_value_info: 31, 24;
+    pub u16, data_type, _: 47, 32;
+    pub u16, bit_length, _: 63, 48;
+    pub u16, object_access, _: 79, 64;
+}
+
+// オブジェクトアクセスのビット
+pub const ENTRY_ACCESS_READ_PREOP: u16 = 1 << 0;
+pub const ENTRY_ACCESS_READ_SAFEOP: u16 = 1 << 1;
+pub const ENTRY_ACCESS_READ_OP: u16 = 1 << 2;
+pub const ENTRY_ACCESS_WRITE_PREOP: u16 = 1 << 3;
+pub const ENTRY_ACCESS_WRITE_SAFEOP: u16 = 1 << 4;
+pub const ENTRY_ACCESS_WRITE_OP: u16 = 1 << 5;
+pub const ENTRY_ACCESS_RX_PDO_MAPPABLE: u16 = 1 << 6;
+pub const ENTRY_ACCESS_TX_PDO_MAPPABLE: u16 = 1 << 7;
+
+pub const SDO_SEGMENT_HEADER_LENGTH: usize = 1;
+pub const SDO_SEGMENT_MIN_DATA_LENGTH: usize = 7;
+// セグメント転送のコマンド指定子(上位3bit)
+pub const SDO_COMMAND_SPECIFIER_MASK: u8 = 0b1110_0000;
+pub const SDO_DOWNLOAD_SEGMENT_REQUEST: u8 = 0b0000_0000;
+pub const SDO_DOWNLOAD_SEGMENT_RESPONSE: u8 = 0b0010_0000;
+pub const SDO_UPLOAD_SEGMENT_REQUEST: u8 = 0b0110_0000;
+pub const SDO_UPLOAD_SEGMENT_RESPONSE: u8 = 0b0000_0000;
+pub const SDO_SEGMENT_TOGGLE: u8 = 0b0001_0000;
+pub const SDO_SEGMENT_LAST: u8 = 0b0000_0001;
+
 #[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Hash, Copy)]
 pub enum SDOCommand {
     DownExpReq1 = 0b0010_1111,
@@ -0,0 +1,89 @@
+use bitfield::*;
+
+pub const EOE_HEADER_LENGTH: usize = 4;
+
+bitfield! {
+    pub struct EoEHeader([u8]);
+    u8;
+    pub frame_type, set_frame_type: 3, 0;
+    pub port, set_port: 7, 4;
+    pub last_fragment, set_last_fragment: 8;
+    pub time_appended, set_time_appended: 9;
+    pub time_request, set_time_request: 10;
+    pub fragment_number, set_fragment_number: 21, 16;
+    pub offset, set_offset: 27, 22;
+    pub frame_number, set_frame_number: 31, 28;
+    u16;
+    /// Result code of response frames. Shares the bits of the fragment fields.
+    pub result, set_result: 31, 16;
+}
+
+impl<T: AsRef<[u8]>> EoEHeader<T> {
+    pub fn new(buf: T) -> Option<Self> {
+        let packet = Self(buf);
+        if packet.is_buffer_range_ok() {
+            Some(packet)
+        } else {
+            None
+        }
+    }
+
+    pub fn new_unchecked(buf: T) -> Self {
+        Self(buf)
+    }
+
+    pub fn is_buffer_range_ok(&self) -> bool {
+        self.0.as_ref().get(EOE_HEADER_LENGTH - 1).is_some()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Hash, Copy)]
+pub enum EoEFrameType {
+    FragmentData = 0,
+    TimestampRes = 1,
+    SetIpParameterReq = 2,
+    SetIpParameterRes = 3,
+    SetAddressFilterReq = 4,
+    SetAddressFilterRes = 5,
+    GetIpParameterReq = 6,
+    GetIpParameterRes = 7,
+    GetAddressFilterReq = 8,
+    GetAddressFilterRes = 9,
+}
+
+pub const EOE_IP_PARAMETER_FLAGS_LENGTH: usize = 4;
+pub const EOE_MAC_ADDRESS_LENGTH: usize = 6;
+pub const EOE_IP_ADDRESS_LENGTH: usize = 4;
+pub const EOE_DNS_NAME_LENGTH: usize = 32;
+
+bitfield! {
+    pub struct EoEIpParameterFlags([u8]);
+    pub mac_address_included, set_mac_address_included: 0;
+    pub ip_address_included, set_ip_address_included: 1;
+    pub subnet_mask_included, set_subnet_mask_included: 2;
+    pub default_gateway_included, set_default_gateway_included: 3;
+    pub dns_server_included, set_dns_server_included: 4;
+    pub dns_name_included, set_dns_name_included: 5;
+}
+
+impl<T: AsRef<[u8]>> EoEIpParameterFlags<T> {
+    pub fn new(buf: T) -> Option<Self> {
+        let packet = Self(buf);
+        if packet.is_buffer_range_ok() {
+            Some(packet)
+        } else {
+            None
+        }
+    }
+
+    pub fn new_unchecked(buf: T) -> Self {
+        Self(buf)
+    }
+
+    pub fn is_buffer_range_ok(&self) -> bool {
+        self.0
+            .as_ref()
+            .get(EOE_IP_PARAMETER_FLAGS_LENGTH - 1)
+            .is_some()
+    }
+}
@@ -41,7 +41,7 @@ impl<T: AsRef<[u8]>> EthernetHeader<T> {
 impl<T: AsRef<[u8]> + AsMut<[u8]>> EthernetHeader<T> {
     pub fn set_ethercat_default(&mut self) {
         self.set_destination(0xFF_FF_FF_FF_FF_FF);
-        self.set_source(0x01_01_01_01_01_01);
+        self.set_source(SRC_MAC);
         self.set_ether_type(ETHERCAT_TYPE);
     }
 }
@@ -81,6 +81,7 @@ bitfield! {
     u16;
     pub adp, set_adp: 31, 16;
     pub ado, set_ado: 47, 32;
+    pub u32, logical_address, set_logical_address: 47, 16;
     pub length, set_length: 58, 48;
     u8;
     pub is_circulated, set_is_circulated: 62;
@@ -112,11 +113,33 @@ impl<T: AsRef<[u8]>> EtherCATPDU<T> {
             [ETHERCATPDU_HEADER_LENGTH..ETHERCATPDU_HEADER_LENGTH + self.length() as usize]
     }
 
+    /// Address field interpreted according to the command type.
+    pub fn address(&self) -> DatagramAddress {
+        if CommandType::new(self.command_type()).is_logical() {
+            DatagramAddress::Logical(self.logical_address())
+        } else {
+            DatagramAddress::Physical {
+                adp: self.adp(),
+                ado: self.ado(),
+            }
+        }
+    }
+
     pub fn wkc(&self) -> Option<u16> {
         let len = self.length() as usize;
-        let low = self.0.as_ref().get(ETHERCATPDU_HEADER_LENGTH + len)?;
-        let high = self.0.as_ref().get(ETHERCATPDU_HEADER_LENGTH + len + 1)?;
-        Some(((*high as u16) << 8) | (*low as u16))
+        super::byte_order::read_u16(self.0.as_ref(), ETHERCATPDU_HEADER_LENGTH + len)
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> EtherCATPDU<T> {
+    pub fn set_address(&mut self, address: DatagramAddress) {
+        match address {
+            DatagramAddress::Physical { adp, ado } => {
+                self.set_adp(adp);
+                self.set_ado(ado);
+            }
+            DatagramAddress::Logical(address) => self.set_logical_address(address),
+        }
     }
 }
 
@@ -141,18 +164,23 @@ impl<T: AsRef<[u8]>> MailboxPDU<T> {
         }
     }
 
-    //pub fn new_unchecked(buf: T) -> Self {
-    //    Self(buf)
-    //}
+    pub fn new_unchecked(buf: T) -> Self {
+        Self(buf)
+    }
 
     pub fn is_buffer_range_ok(&self) -> bool {
         self.0.as_ref().get(MAILBOX_HEADER_LENGTH - 1).is_some()
     }
+
+    pub fn data(&self) -> &[u8] {
+        &self.0.as_ref()[MAILBOX_HEADER_LENGTH..MAILBOX_HEADER_LENGTH + self.length() as usize]
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Hash, Copy)]
 pub enum MailboxType {
     Error = 0,
+    EoE = 2,
     CoE = 3,
     FoE = 4,
 }
@@ -224,6 +252,40 @@ impl<T: AsRef<[u8]>> FMMU<T> {
     }
 }
 
+/// Address field of a datagram. Logical commands carry one 32-bit address
+/// in place of the position or station address and the register address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DatagramAddress {
+    Physical { adp: u16, ado: u16 },
+    Logical(u32),
+}
+
+impl DatagramAddress {
+    /// Interprets `adp` and `ado` according to `command`.
+    pub fn new(command: CommandType, adp: u16, ado: u16) -> Self {
+        if command.is_logical() {
+            // 論理アドレスはADPが下位16ビット、ADOが上位16ビットになる
+            Self::Logical(adp as u32 | (ado as u32) << 16)
+        } else {
+            Self::Physical { adp, ado }
+        }
+    }
+
+    pub fn adp(&self) -> u16 {
+        match *self {
+            Self::Physical { adp, .. } => adp,
+            Self::Logical(address) => address as u16,
+        }
+    }
+
+    pub fn ado(&self) -> u16 {
+        match *self {
+            Self::Physical { ado, .. } => ado,
+            Self::Logical(address) => (address >> 16) as u16,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Hash, Copy)]
 pub enum CommandType {
     /// No operation
@@ -268,6 +330,8 @@ pub enum CommandType {
     /// Auto Increment Read Multiple Write
     /// A slave increments the Address field. A slave writes data it has read to the EtherCAT datagram when the address received is zero, otherwise it writes data to the memory area.
     ARMW,
+    /// Configured Address Read Multiple Write
+    /// A slave writes data it has read to the EtherCAT datagram if its slave address matches the address in the datagram, otherwise it writes data to the memory area.
     FRMW,
     Invalid,
 }
@@ -293,6 +357,11 @@ impl CommandType {
             _ => Self::Invalid,
         }
     }
+
+    /// LRD, LWR and LRW address the logical image.
+    pub fn is_logical(&self) -> bool {
+        matches!(self, Self::LRD | Self::LWR | Self::LRW)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Hash, Copy)]
@@ -0,0 +1,103 @@
+use bitfield::*;
+
+pub const FOE_HEADER_LENGTH: usize = 6;
+
+bitfield! {
+    pub struct FoEHeader([u8]);
+    u8;
+    pub op_code, set_op_code: 7, 0;
+    u32;
+    /// Password of read and write requests, packet number of data and acknowledge,
+    /// or error code of error requests.
+    pub parameter, set_parameter: 47, 16;
+    u16;
+    /// Progress of busy requests.
+    pub done, _: 31, 16;
+    pub entire, _: 47, 32;
+}
+
+impl<T: AsRef<[u8]>> FoEHeader<T> {
+    pub fn new(buf: T) -> Option<Self> {
+        let packet = Self(buf);
+        if packet.is_buffer_range_ok() {
+            Some(packet)
+        } else {
+            None
+        }
+    }
+
+    pub fn new_unchecked(buf: T) -> Self {
+        Self(buf)
+    }
+
+    pub fn is_buffer_range_ok(&self) -> bool {
+        self.0.as_ref().get(FOE_HEADER_LENGTH - 1).is_some()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Hash, Copy)]
+pub enum FoEOpCode {
+    ReadReq = 1,
+    WriteReq = 2,
+    Data = 3,
+    Ack = 4,
+    Error = 5,
+    Busy = 6,
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Hash, Copy)]
+pub enum FoEErrorCode {
+    NotDefined,
+    NotFound,
+    AccessDenied,
+    DiskFull,
+    Illegal,
+    PacketNumberWrong,
+    AlreadyExists,
+    NoUser,
+    BootstrapOnly,
+    NotBootstrap,
+    NoRights,
+    ProgramError,
+    Unknown(u32),
+}
+
+impl From<u32> for FoEErrorCode {
+    fn from(value: u32) -> Self {
+        match value {
+            0x8000 => Self::NotDefined,
+            0x8001 => Self::NotFound,
+            0x8002 => Self::AccessDenied,
+            0x8003 => Self::DiskFull,
+            0x8004 => Self::Illegal,
+            0x8005 => Self::PacketNumberWrong,
+            0x8006 => Self::AlreadyExists,
+            0x8007 => Self::NoUser,
+            0x8008 => Self::BootstrapOnly,
+            0x8009 => Self::NotBootstrap,
+            0x800A => Self::NoRights,
+            0x800B => Self::ProgramError,
+            other => Self::Unknown(other),
+        }
+    }
+}
+
+impl From<FoEErrorCode> for u32 {
+    fn from(code: FoEErrorCode) -> Self {
+        match code {
+            FoEErrorCode::NotDefined => 0x8000,
+            FoEErrorCode::NotFound => 0x8001,
+            FoEErrorCode::AccessDenied => 0x8002,
+            FoEErrorCode::DiskFull => 0x8003,
+            FoEErrorCode::Illegal => 0x8004,
+            FoEErrorCode::PacketNumberWrong => 0x8005,
+            FoEErrorCode::AlreadyExists => 0x8006,
+            FoEErrorCode::NoUser => 0x8007,
+            FoEErrorCode::BootstrapOnly => 0x8008,
+            FoEErrorCode::NotBootstrap => 0x8009,
+            FoEErrorCode::NoRights => 0x800A,
+            FoEErrorCode::ProgramError => 0x800B,
+            FoEErrorCode::Unknown(other) => other,
+        }
+    }
+}
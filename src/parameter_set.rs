@@ -0,0 +1,145 @@
+use crate::arch::*;
+use crate::error::*;
+use crate::interface::*;
+use crate::sdo::*;
+use crate::slave_status::*;
+use core::marker::PhantomData;
+use embedded_hal::timer::CountDown;
+use fugit::*;
+
+/// A value to download to an object of a slave.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ParameterEntry<'a> {
+    pub slave_position: u16,
+    pub index: u16,
+    pub sub_index: u8,
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    pub value: &'a [u8],
+}
+
+/// A list of parameters deployed together, e.g. a machine recipe.
+/// The entries are borrowed by default. With the `serde` feature, a set is serialized as the
+/// list of its entries and can be loaded, e.g. from postcard or CBOR, into an owned storage
+/// such as `ParameterSet<'a, heapless::Vec<ParameterEntry<'a>, N>>`.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct ParameterSet<'a, S = &'a [ParameterEntry<'a>]> {
+    entries: S,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    _entries: PhantomData<&'a [ParameterEntry<'a>]>,
+}
+
+impl<'a, S: AsRef<[ParameterEntry<'a>]>> ParameterSet<'a, S> {
+    pub fn new(entries: S) -> Self {
+        Self {
+            entries,
+            _entries: PhantomData,
+        }
+    }
+
+    pub fn entries(&self) -> &[ParameterEntry<'a>] {
+        self.entries.as_ref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries().is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ParameterSetError {
+    NotExistSlave(u16),
+    Sdo(SdoError),
+}
+
+impl Classify for ParameterSetError {
+    fn classify(&self) -> (ErrorCategory, RecoveryHint) {
+        use ErrorCategory::*;
+        use RecoveryHint::*;
+        match self {
+            Self::Sdo(err) => err.classify(),
+            Self::NotExistSlave(_) => (SlaveConfig, Rescan),
+        }
+    }
+}
+
+impl From<SdoError> for ParameterSetError {
+    fn from(err: SdoError) -> Self {
+        Self::Sdo(err)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ParameterSetReport {
+    pub downloaded: usize,
+    pub failed: usize,
+}
+
+pub struct ParameterSetDownloader<'a, 'b, D, T, U>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+    U: CountDown<Time = MicrosDurationU32>,
+{
+    sdo: SdoClient<'a, 'b, D, T, U>,
+}
+
+impl<'a, 'b, D, T, U> ParameterSetDownloader<'a, 'b, D, T, U>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+    U: CountDown<Time = MicrosDurationU32>,
+{
+    pub fn new(iface: &'a mut EtherCATInterface<'b, D, T>, timer: &'a mut U) -> Self {
+        Self {
+            sdo: SdoClient::new(iface, timer),
+        }
+    }
+
+    /// Downloads every entry of the set. A failed entry does not stop the others.
+    /// The result of the n-th entry is stored in `results[n]` if it exists.
+    /// `progress` is called with (finished entries, total entries) after each entry.
+    pub fn download<'p, S: AsRef<[ParameterEntry<'p>]>, F: FnMut(usize, usize)>(
+        &mut self,
+        slaves: &mut [Slave],
+        parameter_set: &ParameterSet<'p, S>,
+        results: &mut [Result<(), ParameterSetError>],
+        mut progress: F,
+    ) -> ParameterSetReport {
+        let total = parameter_set.len();
+        let mut report = ParameterSetReport::default();
+        for (i, entry) in parameter_set.entries().iter().enumerate() {
+            let result = self.download_entry(slaves, entry);
+            if result.is_ok() {
+                report.downloaded += 1;
+            } else {
+                report.failed += 1;
+            }
+            if let Some(r) = results.get_mut(i) {
+                *r = result;
+            }
+            progress(i + 1, total);
+        }
+        report
+    }
+
+    fn download_entry(
+        &mut self,
+        slaves: &mut [Slave],
+        entry: &ParameterEntry,
+    ) -> Result<(), ParameterSetError> {
+        let slave = slaves
+            .iter_mut()
+            .find(|slave| slave.position_address == entry.slave_position)
+            .ok_or(ParameterSetError::NotExistSlave(entry.slave_position))?;
+        self.sdo
+            .download(slave, entry.index, entry.sub_index, entry.value)?;
+        Ok(())
+    }
+}
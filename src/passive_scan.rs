@@ -0,0 +1,138 @@
+use crate::al_state_transfer::AL_STATUS_READ_SIZE;
+use crate::arch::*;
+use crate::error::*;
+use crate::esc::EscInfo;
+use crate::interface::*;
+use crate::packet::CommandType;
+use crate::register::{application::*, datalink::*};
+use crate::slave_status::AlState;
+use crate::RECEIVE_TIMEOUT_DEFAULT;
+use embedded_hal::timer::CountDown;
+use fugit::*;
+
+// 1フレームにまとめるスレーブ数。1スレーブにつき4回読む
+const SLAVES_PER_FRAME: usize = 16;
+
+/// Registers of a slave read by `PassiveScanner`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ObservedSlave {
+    pub position: u16,
+    /// Station address set by the master controlling the network, 0 if none is set.
+    pub station_address: u16,
+    /// Alias the ESC loaded from the SII, 0 if none.
+    pub station_alias: u16,
+    pub esc: EscInfo,
+    pub number_of_fmmu: u8,
+    pub number_of_sm: u8,
+    /// Process data RAM in KB.
+    pub ram_size: u8,
+    pub support_dc: bool,
+    pub linked_ports: [bool; 4],
+    /// Closed ports, e.g. the last slave of a line has ports 1 to 3 closed.
+    pub closed_ports: [bool; 4],
+    pub pdi_operational: bool,
+    pub al_state: AlState,
+    pub al_error: bool,
+    pub al_status_code: u16,
+}
+
+/// Discovers the slaves of a network controlled by another master without disturbing it.
+/// Only BRD and APRD are sent: no station address, SII or mailbox access,
+/// so the identity of the slaves in the SII is not available.
+pub struct PassiveScanner<'a, 'b, D, T>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+{
+    iface: &'a mut EtherCATInterface<'b, D, T>,
+}
+
+impl<'a, 'b, D, T> PassiveScanner<'a, 'b, D, T>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+{
+    pub fn new(iface: &'a mut EtherCATInterface<'b, D, T>) -> Self {
+        Self { iface }
+    }
+
+    pub fn count_slaves(&mut self) -> Result<u16, CommonError> {
+        let mut wkc = 0;
+        loop {
+            self.iface
+                .add_command(u8::MAX, CommandType::BRD, 0, 0, 1, |_| ())?;
+            self.iface.poll(RECEIVE_TIMEOUT_DEFAULT)?;
+            let pdu = self
+                .iface
+                .consume_command()
+                .last()
+                .ok_or(CommonError::PacketDropped)?;
+            let new_wkc = pdu.wkc().ok_or(CommonError::PacketDropped)?;
+            if wkc == new_wkc {
+                return Ok(wkc);
+            }
+            wkc = new_wkc;
+        }
+    }
+
+    /// Reads the slaves from position 0 into `slaves`. Returns the number of slaves read,
+    /// fewer than found if `slaves` is too short.
+    pub fn scan(&mut self, slaves: &mut [ObservedSlave]) -> Result<u16, CommonError> {
+        let num_slaves = (self.count_slaves()? as usize).min(slaves.len());
+        let mut start = 0;
+        while start < num_slaves {
+            let end = num_slaves.min(start + SLAVES_PER_FRAME);
+            let mut transaction = self.iface.transaction()?;
+            for position in start..end {
+                let address = SlaveAddress::SlaveNumber(position as u16);
+                transaction.read(address, DLInformation::ADDRESS, DLInformation::SIZE)?;
+                transaction.read(
+                    address,
+                    FixedStationAddress::ADDRESS,
+                    FixedStationAddress::SIZE,
+                )?;
+                transaction.read(address, DLStatus::ADDRESS, DLStatus::SIZE)?;
+                transaction.read(address, ALStatus::ADDRESS, AL_STATUS_READ_SIZE)?;
+            }
+            let results = transaction.execute()?;
+            for (i, slave) in slaves[start..end].iter_mut().enumerate() {
+                let info = DLInformation(results.data(i * 4)?);
+                let fixed = FixedStationAddress(results.data(i * 4 + 1)?);
+                let dl_status = DLStatus(results.data(i * 4 + 2)?);
+                let al_status = ALStatus(results.data(i * 4 + 3)?);
+                *slave = ObservedSlave {
+                    position: (start + i) as u16,
+                    station_address: fixed.configured_station_address(),
+                    station_alias: fixed.configured_station_alias(),
+                    esc: EscInfo {
+                        esc_type: info.ethercat_type(),
+                        revision: info.revision(),
+                        build: info.build_number(),
+                    },
+                    number_of_fmmu: info.number_of_supported_fmmu_entities(),
+                    number_of_sm: info.number_of_supported_sm_channels(),
+                    ram_size: info.ram_size(),
+                    support_dc: info.dc_supported(),
+                    linked_ports: [
+                        dl_status.link_status_port0(),
+                        dl_status.link_status_port1(),
+                        dl_status.link_status_port2(),
+                        dl_status.link_status_port3(),
+                    ],
+                    closed_ports: [
+                        dl_status.loop_status_port0(),
+                        dl_status.loop_status_port1(),
+                        dl_status.loop_status_port2(),
+                        dl_status.loop_status_port3(),
+                    ],
+                    pdi_operational: dl_status.pdi_operational(),
+                    al_state: AlState::from(al_status.state()),
+                    al_error: al_status.change_err(),
+                    al_status_code: al_status.al_status_code(),
+                };
+            }
+            start = end;
+        }
+        Ok(num_slaves as u16)
+    }
+}
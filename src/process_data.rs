@@ -0,0 +1,126 @@
+use crate::initializer::ProcessImage;
+use crate::master::Command;
+use crate::packet::CommandType;
+use crate::slave_status::*;
+
+/// Cyclic unit that exchanges the logical image of a group of slaves with one LRW per cycle,
+/// in the layout of `SlaveInitilizer::configure_process_data`.
+/// If a slave does not support LRW (`ProcessImage::use_lrw` is false), the outputs are sent
+/// with LWR and the inputs read with LRD in alternate cycles, each at half the cycle rate.
+/// While the master is in the fail-safe state, the fail-safe outputs of the slaves set by
+/// `Slave::set_fail_safe_outputs` are sent instead of the application outputs.
+#[derive(Debug)]
+pub struct ProcessDataUnit<'a> {
+    slaves: &'a mut [Slave<'a>],
+    image: ProcessImage,
+    buffer: &'a mut [u8],
+    wkc: u16,
+    outputs_wkc: u16,
+    inputs_wkc: u16,
+    writes_next: bool,
+}
+
+impl<'a> ProcessDataUnit<'a> {
+    /// `slaves` and `image` are the ones given to and returned by
+    /// `SlaveInitilizer::configure_process_data`. Returns None if `buffer` is shorter
+    /// than the image.
+    pub fn new(
+        slaves: &'a mut [Slave<'a>],
+        image: ProcessImage,
+        buffer: &'a mut [u8],
+    ) -> Option<Self> {
+        let buffer = buffer.get_mut(..image.size)?;
+        buffer.fill(0);
+        // LWRとLRDでは出力または入力のあるスレーブが1ずつ増やす
+        let mapped = || {
+            slaves
+                .iter()
+                .filter(|slave| slave.logical_address.is_some())
+        };
+        let outputs_wkc = mapped().filter(|slave| slave.rx_pdo_size() != 0).count() as u16;
+        let inputs_wkc = mapped().filter(|slave| slave.tx_pdo_size() != 0).count() as u16;
+        Some(Self {
+            slaves,
+            image,
+            buffer,
+            wkc: 0,
+            outputs_wkc,
+            inputs_wkc,
+            writes_next: true,
+        })
+    }
+
+    pub fn image(&self) -> &ProcessImage {
+        &self.image
+    }
+
+    pub fn slaves(&self) -> &[Slave<'a>] {
+        self.slaves
+    }
+
+    pub fn slaves_mut(&mut self) -> &mut [Slave<'a>] {
+        self.slaves
+    }
+
+    /// WKC of the last response.
+    pub fn wkc(&self) -> u16 {
+        self.wkc
+    }
+
+    pub(crate) fn data_size(&self) -> usize {
+        self.image.size
+    }
+
+    pub(crate) fn process(&mut self) -> Option<(Command, &[u8])> {
+        if self.buffer.is_empty() {
+            return None;
+        }
+        let command_type = self.next_command_type();
+        if command_type != CommandType::LRD {
+            write_outputs(self.buffer, self.slaves);
+        }
+        let command = Command::new_logical(command_type, self.image.logical_start_address);
+        Some((command, self.buffer))
+    }
+
+    pub(crate) fn fail_safe(&mut self) -> Option<(Command, &[u8])> {
+        if self.buffer.is_empty() {
+            return None;
+        }
+        let command_type = self.next_command_type();
+        if command_type != CommandType::LRD {
+            process_fail_safe_data(self.buffer, self.slaves);
+        }
+        let command = Command::new_logical(command_type, self.image.logical_start_address);
+        Some((command, self.buffer))
+    }
+
+    pub(crate) fn receive(&mut self, command: Command, data: &[u8], wkc: u16) -> bool {
+        self.wkc = wkc;
+        if data.len() != self.buffer.len() {
+            return false;
+        }
+        let expected_wkc = match command.command_type() {
+            CommandType::LWR => return wkc == self.outputs_wkc,
+            CommandType::LRD => self.inputs_wkc,
+            _ => self.image.expected_wkc,
+        };
+        // 応答しなかったスレーブの領域には送った値(前回の入力)が残る
+        self.buffer.copy_from_slice(data);
+        read_inputs(self.buffer, self.slaves);
+        wkc == expected_wkc
+    }
+
+    fn next_command_type(&mut self) -> CommandType {
+        if self.image.use_lrw {
+            return CommandType::LRW;
+        }
+        let writes = self.writes_next;
+        self.writes_next = !writes;
+        if writes {
+            CommandType::LWR
+        } else {
+            CommandType::LRD
+        }
+    }
+}
@@ -1,2 +1,105 @@
+/// Defines an ESC register: a `bitfield!` wrapper over the register bytes,
+/// with the `ADDRESS`, the `SIZE` in bytes, `new()` returning a zeroed register
+/// and an implementation of `Register`.
+/// The access of the master, `RO`, `WO` or `RW`, may be given after the size and
+/// defaults to `RW`.
+///
+/// ```ignore
+/// register! {
+///     #[derive(Debug, Clone)]
+///     pub struct ALStatus: 0x0130, 2, RO;
+///     pub u8, state, _: 3, 0;
+/// }
+/// ```
+macro_rules! register {
+    (@access $name:ident, $size:literal, RO) => {
+        impl $crate::register::ReadableRegister for $name<[u8; $size]> {}
+    };
+    (@access $name:ident, $size:literal, WO) => {
+        impl $crate::register::WritableRegister for $name<[u8; $size]> {}
+    };
+    (@access $name:ident, $size:literal, RW) => {
+        impl $crate::register::ReadableRegister for $name<[u8; $size]> {}
+        impl $crate::register::WritableRegister for $name<[u8; $size]> {}
+    };
+    (@access $name:ident, $size:literal,) => {
+        register!(@access $name, $size, RW);
+    };
+    (
+        $(#[$attr:meta])*
+        pub struct $name:ident: $address:expr, $size:literal $(, $access:ident)?;
+        $($fields:tt)*
+    ) => {
+        ::bitfield::bitfield! {
+            $(#[$attr])*
+            pub struct $name([u8]);
+            $($fields)*
+        }
+
+        impl $name<[u8; $size]> {
+            pub const ADDRESS: u16 = $address;
+            pub const SIZE: usize = $size;
+
+            pub fn new() -> Self {
+                Self([0; Self::SIZE])
+            }
+        }
+
+        impl $crate::register::Register for $name<[u8; $size]> {
+            const ADDRESS: u16 = $address;
+            const SIZE: usize = $size;
+
+            fn from_bytes(bytes: &[u8]) -> Self {
+                let mut value = [0; $size];
+                value.copy_from_slice(&bytes[..$size]);
+                Self(value)
+            }
+
+            fn as_bytes(&self) -> &[u8] {
+                &self.0
+            }
+        }
+
+        register!(@access $name, $size, $($access)?);
+    };
+}
+
+/// Register with a single address, implemented by `register!` for the owned wrapper,
+/// so that helpers like `EtherCATInterface::modify_register` can be generic over registers.
+pub trait Register: Sized {
+    const ADDRESS: u16;
+    const SIZE: usize;
+
+    /// Copies the register from the first `SIZE` bytes.
+    ///
+    /// # Panics
+    /// Panics if `bytes` is shorter than `SIZE`.
+    fn from_bytes(bytes: &[u8]) -> Self;
+
+    fn as_bytes(&self) -> &[u8];
+}
+
+/// Register the master can read, e.g. not `ALControl`. The accessors of `EtherCATInterface`
+/// require it, so that reading a write-only register does not compile.
+///
+/// ```compile_fail,E0277
+/// use ethercat_master::register::{application::ALControl, ReadableRegister};
+///
+/// fn read<R: ReadableRegister>() {}
+/// read::<ALControl<[u8; ALControl::SIZE]>>();
+/// ```
+pub trait ReadableRegister {}
+
+/// Register the master can write, e.g. not `ALStatus`. The accessors of `EtherCATInterface`
+/// require it, so that writing a read-only register does not compile.
+///
+/// ```compile_fail,E0277
+/// use ethercat_master::register::{application::ALStatus, WritableRegister};
+///
+/// fn write<W: WritableRegister>() {}
+/// write::<ALStatus<[u8; ALStatus::SIZE]>>();
+/// ```
+pub trait WritableRegister {}
+
 pub mod application;
 pub mod datalink;
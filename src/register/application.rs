@@ -1,6 +1,7 @@
+use super::{ReadableRegister, WritableRegister};
 use bitfield::*;
 
-const R1: u16 = 0x0120; //RW
+const R1: u16 = 0x0120; //W
 const R2: u16 = 0x0121; //RW
 const R3: u16 = 0x0130; //R
 const R4: u16 = 0x0131; //R
@@ -22,75 +23,54 @@ const DC_USER_P10: u16 = 0x09B8; //R
 const DC_USER_P11: u16 = 0x09C0; //R
 const DC_USER_P12: u16 = 0x09CC; //R
 
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct ALControl([u8]);
+    pub struct ALControl: R1, 2, WO;
     pub u8, state, set_state: 3, 0;
     pub acknowledge, set_acknowledge: 4;
     pub u8, appl_specific, set_appl_specific: 8*2-1, 8*1;
 }
 
-impl ALControl<[u8; 2]> {
-    pub const ADDRESS: u16 = R1;
-    pub const SIZE: usize = 2;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
-}
-
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct ALStatus([u8]);
+    pub struct ALStatus: R3, 2, RO;
     pub u8, state, _: 3, 0;
     pub change_err, _: 4;
     pub u8, appl_specific, _: 8*2-1, 8*1;
     pub u16, al_status_code, _: 8*6-1, 8*4;
 }
 
-impl ALStatus<[u8; 2]> {
-    pub const ADDRESS: u16 = R3;
-    pub const SIZE: usize = 2;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
-}
-
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct PDIControl([u8]);
+    pub struct PDIControl: R7, 2, RO;
     pub u8, pdi_type, _: 7, 0;
     pub strict_al_control, _: 8;
+    /// ESC configuration (0x0141), loaded from the SII.
+    pub enhanced_link_detection_all_ports, _: 9;
+    pub dc_sync_out_unit, _: 10;
+    pub dc_latch_in_unit, _: 11;
+    pub enhanced_link_detection_port0, _: 12;
+    pub enhanced_link_detection_port1, _: 13;
+    pub enhanced_link_detection_port2, _: 14;
+    pub enhanced_link_detection_port3, _: 15;
 }
 
-impl PDIControl<[u8; 2]> {
-    pub const ADDRESS: u16 = R7;
-    pub const SIZE: usize = 2;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
+impl<B: AsRef<[u8]>> PDIControl<B> {
+    /// Enhanced link detection of `port` (0 to 3), enabled for all ports or per port.
+    pub fn enhanced_link_detection(&self, port: usize) -> bool {
+        self.enhanced_link_detection_all_ports() || (self.0.as_ref()[1] >> (4 + port)) & 1 != 0
     }
 }
 
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct PDIConfig([u8]);
+    pub struct PDIConfig: R8, 1, RO;
     pub u8, application_specific, _: 7, 0;
 }
 
-impl PDIConfig<[u8; 1]> {
-    pub const ADDRESS: u16 = R8;
-    pub const SIZE: usize = 1;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
-}
-
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct SyncConfig([u8]);
+    pub struct SyncConfig: R8 + 1, 1, RO;
     pub u8, signal_conditioning_sync0, _: 1, 0;
     pub enable_signal_sync0, _: 2;
     pub enable_interrupt_sync0, _: 3;
@@ -99,126 +79,54 @@ bitfield! {
     pub enbale_interrupt_sync1, _: 7;
 }
 
-impl SyncConfig<[u8; 1]> {
-    pub const ADDRESS: u16 = R8 + 1;
-    pub const SIZE: usize = 1;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
-}
-
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct DCActivation([u8]);
+    pub struct DCActivation: DC_USER_P1, 1;
     pub cyclic_operation_enable, set_cyclic_operation_enable: 0;
     pub sync0_activate, set_sync0_activate: 1;
     pub sync1_activate, set_sync1_activate: 2;
 }
 
-impl DCActivation<[u8; 1]> {
-    pub const ADDRESS: u16 = DC_USER_P1;
-    pub const SIZE: usize = 1;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
-}
-
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct SyncPulse([u8]);
+    pub struct SyncPulse: DC_USER_P2, 2, RO;
     pub u16, sync_pulse, _: 15, 0;
 }
 
-impl SyncPulse<[u8; 2]> {
-    pub const ADDRESS: u16 = DC_USER_P2;
-    pub const SIZE: usize = 2;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
-}
-
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct InterruptStatus([u8]);
+    pub struct InterruptStatus: DC_USER_P3, 2, RO;
     pub interrupt0_status, _: 0;
     pub interrupt1_status, _: 8;
 }
 
-impl InterruptStatus<[u8; 2]> {
-    pub const ADDRESS: u16 = DC_USER_P3;
-    pub const SIZE: usize = 2;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
-}
-
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct CyclicOperationStartTime([u8]);
+    pub struct CyclicOperationStartTime: DC_USER_P4, 4;
     pub u32, cyclic_operation_start_time, set_cyclic_operation_start_time: 31, 0;
 }
 
-impl CyclicOperationStartTime<[u8; 4]> {
-    pub const ADDRESS: u16 = DC_USER_P4;
-    pub const SIZE: usize = 4;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
-}
-
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct Sync0CycleTime([u8]);
+    pub struct Sync0CycleTime: DC_USER_P5, 4;
     pub u32, sync0_cycle_time, set_sync0_cycle_time: 31, 0;
 }
 
-impl Sync0CycleTime<[u8; 4]> {
-    pub const ADDRESS: u16 = DC_USER_P5;
-    pub const SIZE: usize = 4;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
-}
-
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct Sync1CycleTime([u8]);
+    pub struct Sync1CycleTime: DC_USER_P6, 4;
     pub u32, sync1_cycle_time, set_sync1_cycle_time: 31, 0;
 }
 
-impl Sync1CycleTime<[u8; 4]> {
-    pub const ADDRESS: u16 = DC_USER_P6;
-    pub const SIZE: usize = 4;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
-}
-
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct LatchEdge([u8]);
+    pub struct LatchEdge: DC_USER_P7, 2;
     pub latch0_positive_edge, set_latch0_positive_edge: 0;
     pub latch0_negative_edge, set_latch0_negative_edge: 1;
     pub latch1_positive_edge, set_latch1_positive_edge: 8;
     pub latch1_negative_edge, set_latch1_negative_edge: 9;
 }
 
-impl LatchEdge<[u8; 2]> {
-    pub const ADDRESS: u16 = DC_USER_P7;
-    pub const SIZE: usize = 2;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
-}
-
 bitfield! {
     #[derive(Debug, Clone)]
     pub struct LatchEvent([u8]);
@@ -237,62 +145,29 @@ impl LatchEvent<[i8; 2]> {
     }
 }
 
-bitfield! {
+impl ReadableRegister for LatchEvent<[u8; 2]> {}
+impl WritableRegister for LatchEvent<[u8; 2]> {}
+
+register! {
     #[derive(Debug, Clone)]
-    pub struct Latch0PositiveEdgeValue([u8]);
+    pub struct Latch0PositiveEdgeValue: DC_USER_P9, 4, RO;
     pub u32, latch0_positive_edge_value, set_latch0_positive_edge_value: 31, 0;
 }
 
-impl Latch0PositiveEdgeValue<[u8; 4]> {
-    pub const ADDRESS: u16 = DC_USER_P9;
-    pub const SIZE: usize = 4;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
-}
-
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct Latch0NegativeEdgeValue([u8]);
+    pub struct Latch0NegativeEdgeValue: DC_USER_P10, 4, RO;
     pub u32, latch0_negative_edge_value, set_latch0_negative_edge_value: 31, 0;
 }
 
-impl Latch0NegativeEdgeValue<[u8; 4]> {
-    pub const ADDRESS: u16 = DC_USER_P10;
-    pub const SIZE: usize = 4;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
-}
-
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct Latch1PositiveEdgeValue([u8]);
+    pub struct Latch1PositiveEdgeValue: DC_USER_P11, 4, RO;
     pub u32, latch0_positive_edge_value, set_latch0_positive_edge_value: 31, 0;
 }
 
-impl Latch1PositiveEdgeValue<[u8; 4]> {
-    pub const ADDRESS: u16 = DC_USER_P11;
-    pub const SIZE: usize = 4;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
-}
-
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct Latch1NegativeEdgeValue([u8]);
+    pub struct Latch1NegativeEdgeValue: DC_USER_P12, 4, RO;
     pub u32, latch0_negative_edge_value, set_latch0_negative_edge_value: 31, 0;
 }
-
-impl Latch1NegativeEdgeValue<[u8; 4]> {
-    pub const ADDRESS: u16 = DC_USER_P12;
-    pub const SIZE: usize = 4;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
-}
@@ -1,9 +1,10 @@
+use super::{ReadableRegister, WritableRegister};
 use bit_field::*;
 use bitfield::*;
 
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct DLInformation([u8]);
+    pub struct DLInformation: 0x0000, 10, RO;
     pub u8, ethercat_type, _: 8*1-1, 8*0;
     pub u8, revision, _: 8*2-1, 8*1;
     pub u16, build_number, _: 8*4-1, 8*2;
@@ -41,15 +42,6 @@ bitfield! {
     pub is_special_fmmu_sm_configuration, _: 8*9+3;
 }
 
-impl DLInformation<[u8; 10]> {
-    pub const ADDRESS: u16 = 0x0000;
-    pub const SIZE: usize = 10;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
-}
-
 impl<B: AsRef<[u8]>> DLInformation<B> {
     pub fn port0_type(&self) -> Option<PortPhysics> {
         let byte = self.0.as_ref()[7];
@@ -89,30 +81,29 @@ impl<B: AsRef<[u8]>> DLInformation<B> {
 }
 
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PortPhysics {
     MII,
     EBUS,
 }
 
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct FixedStationAddress([u8]);
+    pub struct FixedStationAddress: 0x0010, 4;
     pub u16, configured_station_address, set_configured_station_address: 8*2-1, 8*0;
     pub u16, configured_station_alias, _: 8*4-1, 8*2;
 }
 
-impl FixedStationAddress<[u8; 4]> {
-    pub const ADDRESS: u16 = 0x0010;
-    pub const SIZE: usize = 4;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
+register! {
+    #[derive(Debug, Clone)]
+    /// Writing "R", "E", "S" in three consecutive datagrams resets the ESC.
+    pub struct ECATReset: 0x0040, 1, WO;
+    pub u8, reset, set_reset: 7, 0;
 }
 
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct DLControl([u8]);
+    pub struct DLControl: 0x0100, 4;
     pub forwarding_rule, set_forwarding_rule: 0;
     pub u8, loop_control_port0, set_loop_control_port0: 8*1+1, 8*1;
     pub u8, loop_control_port1, set_loop_control_port1: 8*1+3, 8*1+2;
@@ -122,18 +113,47 @@ bitfield! {
     pub enable_alias_address, set_enable_alias_address: 8*3;
 }
 
-impl DLControl<[u8; 4]> {
-    pub const ADDRESS: u16 = 0x0100;
-    pub const SIZE: usize = 4;
+impl<B: AsRef<[u8]>> DLControl<B> {
+    /// Loop control of `port` (0 to 3).
+    pub fn loop_control(&self, port: usize) -> LoopControl {
+        let byte = self.0.as_ref()[1];
+        LoopControl::from(byte.get_bits(port * 2..port * 2 + 2))
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> DLControl<B> {
+    pub fn set_loop_control(&mut self, port: usize, loop_control: LoopControl) {
+        self.0.as_mut()[1].set_bits(port * 2..port * 2 + 2, loop_control as u8);
+    }
+}
 
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
+/// Loop setting of a port (0x0101).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopControl {
+    /// Closed at link down, opened at link up.
+    Auto = 0b00,
+    /// Closed at link down, opened only by writing `Auto` or `AutoClose` again after link up.
+    AutoClose = 0b01,
+    /// Always open, regardless of the link.
+    Open = 0b10,
+    /// Always closed. Frames are looped back here.
+    Closed = 0b11,
+}
+
+impl From<u8> for LoopControl {
+    fn from(value: u8) -> Self {
+        match value & 0b11 {
+            0b00 => Self::Auto,
+            0b01 => Self::AutoClose,
+            0b10 => Self::Open,
+            _ => Self::Closed,
+        }
     }
 }
 
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct DLStatus([u8]);
+    pub struct DLStatus: 0x0110, 2, RO;
     pub pdi_operational, _: 0;
     pub dls_user_watch_dog_status, _: 1;
     pub extended_link_detection, _: 2;
@@ -151,18 +171,60 @@ bitfield! {
     pub signal_detection_port3, _: 8*1 + 7;
 }
 
-impl DLStatus<[u8; 2]> {
-    pub const ADDRESS: u16 = 0x0110;
-    pub const SIZE: usize = 2;
+register! {
+    /// Events mapped to the IRQ field of the datagrams (ECAT event request 0x0210).
+    #[derive(Debug, Clone)]
+    pub struct ECATEventMask: 0x0200, 2;
+    pub dc_latch_event, set_dc_latch_event: 0;
+    pub dl_status_event, set_dl_status_event: 2;
+    pub al_status_event, set_al_status_event: 3;
+    /// Bit n is the mailbox/buffer status of SM channel n.
+    pub u8, sm_status_events, set_sm_status_events: 11, 4;
+}
 
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
+register! {
+    /// Events mapped to the IRQ line of the PDI (AL event request 0x0220).
+    /// Written by the local application; read only from EtherCAT.
+    #[derive(Debug, Clone)]
+    pub struct ALEventMask: 0x0204, 4;
+    pub al_control_event, set_al_control_event: 0;
+    pub dc_latch_event, set_dc_latch_event: 1;
+    pub dc_sync0_event, set_dc_sync0_event: 2;
+    pub dc_sync1_event, set_dc_sync1_event: 3;
+    pub sm_activation_event, set_sm_activation_event: 4;
+    pub eeprom_emulation_event, set_eeprom_emulation_event: 5;
+    pub watchdog_process_data_event, set_watchdog_process_data_event: 6;
+    /// Bit n is the interrupt of SM channel n.
+    pub u16, sm_events, set_sm_events: 23, 8;
+}
+
+register! {
+    #[derive(Debug, Clone)]
+    pub struct ECATEventRequest: 0x0210, 2, RO;
+    pub dc_latch_event, _: 0;
+    pub dl_status_event, _: 2;
+    pub al_status_event, _: 3;
+    /// Bit n is the mailbox/buffer status of SM channel n.
+    pub u8, sm_status_events, _: 11, 4;
 }
 
-bitfield! {
+register! {
+    #[derive(Debug, Clone)]
+    pub struct ALEventRequest: 0x0220, 4, RO;
+    pub al_control_event, _: 0;
+    pub dc_latch_event, _: 1;
+    pub dc_sync0_event, _: 2;
+    pub dc_sync1_event, _: 3;
+    pub sm_activation_event, _: 4;
+    pub eeprom_emulation_event, _: 5;
+    pub watchdog_process_data_event, _: 6;
+    /// Bit n is the interrupt of SM channel n.
+    pub u16, sm_events, _: 23, 8;
+}
+
+register! {
     #[derive(Debug, Clone)]
-    pub struct RxErrorCounter([u8]);
+    pub struct RxErrorCounter: 0x0300, 8;
     pub u8, frame_error_count_port0, set_frame_error_count_port0: 8*1-1, 8*0;
     pub u8, phy_error_count_port0, set_phy_error_count_port0: 8*2-1, 8*1;
     pub u8, frame_error_count_port1, set_frame_error_count_port1: 8*3-1, 8*2;
@@ -173,95 +235,60 @@ bitfield! {
     pub u8, phy_error_count_port3, set_phy_error_count_port3: 8*8-1, 8*7;
 }
 
-impl RxErrorCounter<[u8; 8]> {
-    pub const ADDRESS: u16 = 0x0300;
-    pub const SIZE: usize = 8;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
-}
-
-bitfield! {
+register! {
+    /// Errors detected by a previous slave, counted at the receiving port.
     #[derive(Debug, Clone)]
-    pub struct WatchDogDivider([u8]);
-    pub u8, watch_dog_divider, set_watch_dog_divider: 8*2-1, 8*0;
+    pub struct ForwardedRxErrorCounter: 0x0308, 4;
+    pub u8, forwarded_error_count_port0, set_forwarded_error_count_port0: 8*1-1, 8*0;
+    pub u8, forwarded_error_count_port1, set_forwarded_error_count_port1: 8*2-1, 8*1;
+    pub u8, forwarded_error_count_port2, set_forwarded_error_count_port2: 8*3-1, 8*2;
+    pub u8, forwarded_error_count_port3, set_forwarded_error_count_port3: 8*4-1, 8*3;
 }
 
-impl WatchDogDivider<[u8; 2]> {
-    pub const ADDRESS: u16 = 0x0400;
-    pub const SIZE: usize = 2;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
-}
-
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct DLUserWatchDog([u8]);
-    pub u8, dls_user_watch_dog, set_dls_user_watch_dog: 8*2-1, 8*0;
+    pub struct LostLinkCounter: 0x0310, 4;
+    pub u8, lost_link_count_port0, set_lost_link_count_port0: 8*1-1, 8*0;
+    pub u8, lost_link_count_port1, set_lost_link_count_port1: 8*2-1, 8*1;
+    pub u8, lost_link_count_port2, set_lost_link_count_port2: 8*3-1, 8*2;
+    pub u8, lost_link_count_port3, set_lost_link_count_port3: 8*4-1, 8*3;
 }
 
-impl DLUserWatchDog<[u8; 2]> {
-    pub const ADDRESS: u16 = 0x0410;
-    pub const SIZE: usize = 2;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
+register! {
+    #[derive(Debug, Clone)]
+    pub struct WatchDogDivider: 0x0400, 2;
+    pub u16, watch_dog_divider, set_watch_dog_divider: 8*2-1, 8*0;
 }
 
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct SyncManagerChannelWatchDog([u8]);
-    pub u8, sm_channel_watch_dog, set_sm_channel_watch_dog: 8*2-1, 8*0;
+    pub struct DLUserWatchDog: 0x0410, 2;
+    pub u16, dls_user_watch_dog, set_dls_user_watch_dog: 8*2-1, 8*0;
 }
 
-impl SyncManagerChannelWatchDog<[u8; 2]> {
-    pub const ADDRESS: u16 = 0x0420;
-    pub const SIZE: usize = 2;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
+register! {
+    #[derive(Debug, Clone)]
+    pub struct SyncManagerChannelWatchDog: 0x0420, 2;
+    pub u16, sm_channel_watch_dog, set_sm_channel_watch_dog: 8*2-1, 8*0;
 }
 
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct SyncManagerChannelWDStatus([u8]);
+    pub struct SyncManagerChannelWDStatus: 0x0440, 2, RO;
     pub sm_channel_wd_status, _: 0;
 }
 
-impl SyncManagerChannelWDStatus<[u8; 2]> {
-    pub const ADDRESS: u16 = 0x0440;
-    pub const SIZE: usize = 2;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
-}
-
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct SIIAccess([u8]);
+    pub struct SIIAccess: 0x0500, 2;
     pub owner, set_owner: 0;
     pub reset_access, set_reset_access: 1;
     pub pdi_accessed, _: 8*1;
 }
 
-impl SIIAccess<[u8; 2]> {
-    pub const ADDRESS: u16 = 0x0500;
-    pub const SIZE: usize = 2;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
-}
-
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct SIIControl([u8]);
+    pub struct SIIControl: 0x0502, 2;
     pub enable_write_access, set_enable_write_access: 0;
     pub read_size, _: 6;
     pub address_algorithm, _: 7;
@@ -274,45 +301,18 @@ bitfield! {
     pub busy, _: 8+7;
 }
 
-impl SIIControl<[u8; 2]> {
-    pub const ADDRESS: u16 = 0x0502;
-    pub const SIZE: usize = 2;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
-}
-
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct SIIAddress([u8]);
+    pub struct SIIAddress: 0x0504, 4;
     pub u32, sii_address, set_sii_address: 8*4-1, 0;
 }
 
-impl SIIAddress<[u8; 4]> {
-    pub const ADDRESS: u16 = 0x0504;
-    pub const SIZE: usize = 4;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
-}
-
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct SIIData([u8]);
+    pub struct SIIData: 0x0508, 8;
     pub u64, sii_data, set_sii_data: 8*8-1, 0;
 }
 
-impl SIIData<[u8; 8]> {
-    pub const ADDRESS: u16 = 0x0508;
-    pub const SIZE: usize = 8;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
-}
-
 // NOTE: MII register is not inplemented
 
 bitfield! {
@@ -325,7 +325,7 @@ bitfield! {
     pub u16, physical_start_address, set_physical_start_address: 8*10-1, 8*8;
     pub u8, physical_start_bit, set_physical_start_bit: 8*10+2, 8*10;
     pub read_enable, set_read_enable: 8*11;
-    pub write_enable, set_write_enable: 8*11;
+    pub write_enable, set_write_enable: 8*11+1;
     pub enable, set_enable: 8*12;
 }
 
@@ -340,6 +340,9 @@ impl FMMURegister<[u8; 16]> {
     }
 }
 
+impl ReadableRegister for FMMURegister<[u8; 16]> {}
+impl WritableRegister for FMMURegister<[u8; 16]> {}
+
 bitfield! {
     #[derive(Debug, Clone)]
     pub struct SyncManagerRegister([u8]);
@@ -373,65 +376,76 @@ impl SyncManagerRegister<[u8; 8]> {
     }
 }
 
-bitfield! {
+impl ReadableRegister for SyncManagerRegister<[u8; 8]> {}
+impl WritableRegister for SyncManagerRegister<[u8; 8]> {}
+
+register! {
     #[derive(Debug, Clone)]
-    pub struct DCRecieveTime([u8]);
+    pub struct DCRecieveTime: 0x0900, 16;
     pub u32, receive_time_port0, set_receive_time_port0: 8*4-1, 8*0;
-    pub u32, receive_time_port0, set_receive_time_port0: 8*8-1, 8*4;
-    pub u32, receive_time_port0, set_receive_time_port0: 8*12-1, 8*8;
-    pub u32, receive_time_port0, set_receive_time_port0: 8*16-1, 8*12;
-}
-
-impl DCRecieveTime<[u8; 16]> {
-    pub const ADDRESS: u16 = 0x0900;
-    pub const SIZE: usize = 16;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
+    pub u32, receive_time_port1, set_receive_time_port1: 8*8-1, 8*4;
+    pub u32, receive_time_port2, set_receive_time_port2: 8*12-1, 8*8;
+    pub u32, receive_time_port3, set_receive_time_port3: 8*16-1, 8*12;
 }
 
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct DCSystemTime([u8]);
+    pub struct DCSystemTime: 0x0910, 8;
     pub u64, local_system_time, set_local_system_time: 8*8-1, 0;
 }
 
-impl DCSystemTime<[u8; 8]> {
-    pub const ADDRESS: u16 = 0x0910;
-    pub const SIZE: usize = 8;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
+register! {
+    #[derive(Debug, Clone)]
+    pub struct DCRecieveTimeProcessingUnit: 0x0918, 8, RO;
+    /// Local time when the frame latching the port receive times arrived at the processing unit.
+    pub u64, receive_time_processing_unit, _: 8*8-1, 0;
 }
 
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct DCSystemTimeOffset([u8]);
+    pub struct DCSystemTimeOffset: 0x0920, 8;
     pub u64, system_time_offset, set_system_time_offset: 8*8-1, 0;
 }
 
-impl DCSystemTimeOffset<[u8; 8]> {
-    pub const ADDRESS: u16 = 0x0920;
-    pub const SIZE: usize = 8;
+register! {
+    #[derive(Debug, Clone)]
+    pub struct DCSystemTimeTransmissionDelay: 0x0928, 4;
+    pub u32, system_time_transmission_delay, set_system_time_transmission_delay: 8*4-1, 0;
+}
 
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
+register! {
+    #[derive(Debug, Clone)]
+    /// Mean difference between the local copy of the system time and the received system time.
+    pub struct DCSystemTimeDifference: 0x092C, 4, RO;
+    pub u32, difference_magnitude, _: 30, 0;
+    /// true: the local copy is smaller than the received system time
+    pub is_local_smaller, _: 31;
+}
+
+impl<T: AsRef<[u8]>> DCSystemTimeDifference<T> {
+    /// Local copy minus received system time in ns.
+    pub fn difference(&self) -> i32 {
+        let magnitude = self.difference_magnitude() as i32;
+        if self.is_local_smaller() {
+            -magnitude
+        } else {
+            magnitude
+        }
     }
 }
 
-bitfield! {
+register! {
     #[derive(Debug, Clone)]
-    pub struct DCSystemTimeTransmissionDelay([u8]);
-    pub u32, system_time_transmission_delay, set_system_time_transmission_delay: 8*4-1, 0;
+    /// Bandwidth of the drift compensation. Writing it resets the filters.
+    pub struct DCSpeedCounterStart: 0x0930, 2;
+    pub u16, speed_counter_start, set_speed_counter_start: 14, 0;
 }
 
-impl DCSystemTimeTransmissionDelay<[u8; 4]> {
-    pub const ADDRESS: u16 = 0x0928;
-    pub const SIZE: usize = 4;
-
-    pub fn new() -> Self {
-        Self([0; Self::SIZE])
-    }
+register! {
+    #[derive(Debug, Clone)]
+    pub struct DCFilterDepth: 0x0934, 2;
+    /// Depth of the system time difference filter (0x0934)
+    pub u8, system_time_difference_depth, set_system_time_difference_depth: 3, 0;
+    /// Depth of the speed counter filter (0x0935)
+    pub u8, speed_counter_depth, set_speed_counter_depth: 11, 8;
 }
@@ -0,0 +1,136 @@
+//! Helpers for running the master on RTIC.
+//!
+//! Recommended resource split:
+//! - The Ethernet RX interrupt owns the `Producer` of an `RxQueue` and calls `feed_rx_frame`.
+//! - The cyclic task owns the `EtherCATMaster` and its cyclic units. The interface is built on a
+//!   `QueuedDevice`, which receives from the `Consumer` side of the queue.
+//! - Other tasks access the master through `shared::SharedMaster` (feature `critical-section`).
+//!
+//! `examples/rtic_split.rs` runs this split on a PC, with threads in place of the tasks.
+//!
+//! ```ignore
+//! #[shared]
+//! struct Shared {}
+//!
+//! #[local]
+//! struct Local {
+//!     rx: Producer<'static, RxFrame<1514>, 8>,
+//!     master: EtherCATMaster<'static, QueuedDevice<'static, Eth, 1514, 8>, MonotonicTimer<fn() -> MicrosInstant>>,
+//! }
+//!
+//! #[task(binds = ETH, local = [rx])]
+//! fn eth(cx: eth::Context) {
+//!     while let Some(frame) = eth_receive() {
+//!         feed_rx_frame(cx.local.rx, frame);
+//!     }
+//! }
+//!
+//! #[task(local = [master], priority = 1)]
+//! async fn cyclic(cx: cyclic::Context) {
+//!     loop {
+//!         cx.local.master.process_and_enqueue().ok();
+//!         cx.local.master.poll(MicrosDurationU32::from_ticks(1000)).ok();
+//!         Mono::delay(1.millis()).await;
+//!     }
+//! }
+//! ```
+
+use crate::arch::Device;
+use crate::packet::{EthernetHeader, ETHERCAT_TYPE};
+use embedded_hal::timer::CountDown;
+use fugit::{MicrosDurationU32, MicrosDurationU64, TimerInstantU64};
+use heapless::spsc::{Consumer, Producer, Queue};
+use heapless::Vec;
+
+pub type MicrosInstant = TimerInstantU64<1_000_000>;
+pub type RxFrame<const LEN: usize> = Vec<u8, LEN>;
+pub type RxQueue<const LEN: usize, const N: usize> = Queue<RxFrame<LEN>, N>;
+
+/// CountDown driven by a monotonic clock, e.g. `MonotonicTimer::new(|| Mono::now())`.
+pub struct MonotonicTimer<F> {
+    now: F,
+    deadline: MicrosInstant,
+}
+
+impl<F: FnMut() -> MicrosInstant> MonotonicTimer<F> {
+    pub fn new(now: F) -> Self {
+        Self {
+            now,
+            deadline: MicrosInstant::from_ticks(0),
+        }
+    }
+}
+
+impl<F: FnMut() -> MicrosInstant> CountDown for MonotonicTimer<F> {
+    type Time = MicrosDurationU32;
+
+    fn start<T>(&mut self, count: T)
+    where
+        T: Into<Self::Time>,
+    {
+        let count: MicrosDurationU64 = count.into().into();
+        self.deadline = (self.now)() + count;
+    }
+
+    fn wait(&mut self) -> nb::Result<(), void::Void> {
+        if (self.now)() >= self.deadline {
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+}
+
+/// Pushes a frame received in the Ethernet ISR to the queue.
+/// Frames other than EtherCAT are ignored.
+/// Returns false if the frame is dropped because the queue is full or the frame is too long.
+pub fn feed_rx_frame<const LEN: usize, const N: usize>(
+    producer: &mut Producer<'_, RxFrame<LEN>, N>,
+    frame: &[u8],
+) -> bool {
+    match EthernetHeader::new(frame) {
+        Some(header) if header.ether_type() == ETHERCAT_TYPE => (),
+        _ => return true,
+    }
+    match Vec::from_slice(frame) {
+        Ok(frame) => producer.enqueue(frame).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Sends with the wrapped device and receives the frames fed by `feed_rx_frame`.
+pub struct QueuedDevice<'q, D, const LEN: usize, const N: usize> {
+    device: D,
+    rx: Consumer<'q, RxFrame<LEN>, N>,
+}
+
+impl<'q, D, const LEN: usize, const N: usize> QueuedDevice<'q, D, LEN, N> {
+    pub fn new(device: D, rx: Consumer<'q, RxFrame<LEN>, N>) -> Self {
+        Self { device, rx }
+    }
+}
+
+impl<'q, D: Device, const LEN: usize, const N: usize> Device for QueuedDevice<'q, D, LEN, N> {
+    fn send<R, F>(&mut self, len: usize, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut [u8]) -> Option<R>,
+    {
+        self.device.send(len, f)
+    }
+
+    fn recv<R, F>(&mut self, f: F) -> Option<R>
+    where
+        F: FnOnce(&[u8]) -> Option<R>,
+    {
+        let frame = self.rx.dequeue()?;
+        f(&frame)
+    }
+
+    fn max_transmission_unit(&self) -> usize {
+        self.device.max_transmission_unit()
+    }
+
+    fn buffer_alignment(&self) -> usize {
+        self.device.buffer_alignment()
+    }
+}
@@ -0,0 +1,1156 @@
+use crate::arch::*;
+use crate::error::*;
+use crate::interface::*;
+use crate::mailbox::*;
+use crate::master::Command;
+use crate::packet::{byte_order, coe::*, CommandType, MailboxPDU, MailboxType};
+use crate::register::datalink::SyncManagerRegister;
+use crate::slave_status::*;
+use crate::wkc::check_command_wkc;
+use crate::*;
+use embedded_hal::timer::CountDown;
+use fugit::*;
+
+pub const STORE_PARAMETERS_INDEX: u16 = 0x1010;
+pub const RESTORE_DEFAULT_PARAMETERS_INDEX: u16 = 0x1011;
+/// "save" in ASCII
+pub const STORE_PARAMETERS_SIGNATURE: u32 = 0x6576_6173;
+/// "load" in ASCII
+pub const RESTORE_DEFAULT_PARAMETERS_SIGNATURE: u32 = 0x6461_6F6C;
+
+pub const SYNC_MANAGER_TYPE_INDEX: u16 = 0x1C00;
+pub const RX_PDO_ASSIGN_INDEX: u16 = 0x1C12;
+pub const TX_PDO_ASSIGN_INDEX: u16 = 0x1C13;
+
+const SDO_REQUEST_LENGTH: usize = COE_HEADER_LENGTH + SDO_HEADER_LENGTH + SDO_DATA_LENGTH;
+const SDO_INFO_REQUEST_LENGTH: usize =
+    COE_HEADER_LENGTH + SDO_INFO_HEADER_LENGTH + SDO_ENTRY_DESCRIPTION_REQUEST_LENGTH;
+
+#[derive(Debug, Clone)]
+pub enum SdoError {
+    Mailbox(MailboxError),
+    Abort(AbortCode),
+    UnexpectedResponse,
+    /// The response broke the SDO protocol. `upload` and `download` abort the transfer
+    /// on the slave with this code.
+    ProtocolViolation(AbortCode),
+    TooLargeData,
+    SmallBuffer,
+    NotSupported,
+}
+
+impl Classify for SdoError {
+    fn classify(&self) -> (ErrorCategory, RecoveryHint) {
+        use ErrorCategory::*;
+        use RecoveryHint::*;
+        match self {
+            Self::Mailbox(err) => err.classify(),
+            Self::Abort(AbortCode::Timeout) => (TransientBus, Retry),
+            Self::Abort(AbortCode::CannotTransferInCurrentState) => (SlaveConfig, Retry),
+            Self::Abort(_) => (SlaveConfig, FixConfig),
+            Self::UnexpectedResponse | Self::ProtocolViolation(_) => (Protocol, Retry),
+            Self::NotSupported => (SlaveConfig, FixConfig),
+            Self::TooLargeData | Self::SmallBuffer => (ApplicationBug, FixConfig),
+        }
+    }
+}
+
+impl From<MailboxError> for SdoError {
+    fn from(err: MailboxError) -> Self {
+        Self::Mailbox(err)
+    }
+}
+
+impl From<CommonError> for SdoError {
+    fn from(err: CommonError) -> Self {
+        Self::Mailbox(MailboxError::Common(err))
+    }
+}
+
+/// Whether the PDO assignment of a process data sync manager can be changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PdoAssignment {
+    Configurable,
+    /// The slave has a fixed mapping, which the PDO mapping of the slave must match.
+    Fixed,
+    /// The slave has the assignment object but no SDO information service,
+    /// so whether it can be written is not known until it is written.
+    Unknown,
+    /// The sync manager is not used for process data.
+    NotProcessData,
+}
+
+/// Expedited transfer executed by `SdoClient::transfer_many`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SdoRequest {
+    Upload {
+        index: u16,
+        sub_index: u8,
+    },
+    /// `size` is 1 to 4 bytes of `data` in little endian.
+    Download {
+        index: u16,
+        sub_index: u8,
+        data: u32,
+        size: usize,
+    },
+}
+
+impl SdoRequest {
+    fn index(&self) -> (u16, u8) {
+        match *self {
+            Self::Upload { index, sub_index } => (index, sub_index),
+            Self::Download {
+                index, sub_index, ..
+            } => (index, sub_index),
+        }
+    }
+
+    fn command(&self) -> Result<(u8, u32), SdoError> {
+        match *self {
+            Self::Upload { .. } => Ok((SDOCommand::UpReq as u8, 0)),
+            Self::Download { data, size, .. } => {
+                let command = expedited_download_command(size).ok_or(SdoError::TooLargeData)?;
+                Ok((command as u8, data))
+            }
+        }
+    }
+}
+
+pub struct SdoClient<'a, 'b, D, T, U>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+    U: CountDown<Time = MicrosDurationU32>,
+{
+    mailbox: Mailbox<'a, 'b, D, T, U>,
+}
+
+impl<'a, 'b, D, T, U> SdoClient<'a, 'b, D, T, U>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+    U: CountDown<Time = MicrosDurationU32>,
+{
+    pub fn new(iface: &'a mut EtherCATInterface<'b, D, T>, timer: &'a mut U) -> Self {
+        Self {
+            mailbox: Mailbox::new(iface, timer),
+        }
+    }
+
+    pub fn mailbox(&mut self) -> &mut Mailbox<'a, 'b, D, T, U> {
+        &mut self.mailbox
+    }
+
+    /// Downloads `data` to the object. Up to 4 bytes are sent as an expedited transfer
+    /// of `data.len()` bytes.
+    /// Data that does not fit in the mailbox is sent as a segmented transfer.
+    /// Segments are written from `data` straight into the frames, so the size is not limited.
+    ///
+    /// Sub-index 0 of a record or an array is the number of entries, an UNSIGNED8:
+    /// pass it as one byte, e.g. `&[count]`.
+    pub fn download(
+        &mut self,
+        slave: &mut Slave,
+        index: u16,
+        sub_index: u8,
+        data: &[u8],
+    ) -> Result<(), SdoError> {
+        self.download_with_progress(slave, index, sub_index, data, |_, _| ())
+    }
+
+    /// Same as `download`, but calls `progress(transferred_bytes, total_bytes)`
+    /// each time the slave acknowledges a part of the data.
+    pub fn download_with_progress<F: FnMut(usize, usize)>(
+        &mut self,
+        slave: &mut Slave,
+        index: u16,
+        sub_index: u8,
+        data: &[u8],
+        progress: F,
+    ) -> Result<(), SdoError> {
+        let result = self.download_transfer(slave, index, sub_index, data, progress);
+        self.abort_on_violation(slave, index, sub_index, result)
+    }
+
+    fn download_transfer<F: FnMut(usize, usize)>(
+        &mut self,
+        slave: &mut Slave,
+        index: u16,
+        sub_index: u8,
+        data: &[u8],
+        mut progress: F,
+    ) -> Result<(), SdoError> {
+        let total = data.len();
+        let max_size = slave_mailbox_size(slave);
+        let max_segment_size =
+            max_size.saturating_sub(COE_HEADER_LENGTH + SDO_SEGMENT_HEADER_LENGTH);
+        let first = if total <= SDO_DATA_LENGTH || SDO_REQUEST_LENGTH + total <= max_size {
+            self.request_download(slave, index, sub_index, data)?;
+            total
+        } else {
+            // 最初のメールボックスに入りきらない分はセグメントで送る
+            if max_segment_size < SDO_SEGMENT_MIN_DATA_LENGTH {
+                return Err(SdoError::TooLargeData);
+            }
+            let first = max_size - SDO_REQUEST_LENGTH;
+            self.request_download_normal(slave, index, sub_index, &data[..first], total)?;
+            first
+        };
+        self.download_response(slave, index, sub_index)?;
+        progress(first, total);
+
+        let mut sent = first;
+        let mut toggle = 0;
+        while sent < total {
+            let segment = &data[sent..total.min(sent + max_segment_size)];
+            let len = segment.len();
+            let mut command = SDO_DOWNLOAD_SEGMENT_REQUEST | toggle;
+            // 7バイトに満たないセグメントは未使用のバイト数を示す
+            if len < SDO_SEGMENT_MIN_DATA_LENGTH {
+                command |= ((SDO_SEGMENT_MIN_DATA_LENGTH - len) as u8) << 1;
+            }
+            if sent + len == total {
+                command |= SDO_SEGMENT_LAST;
+            }
+            let size = COE_HEADER_LENGTH
+                + SDO_SEGMENT_HEADER_LENGTH
+                + len.max(SDO_SEGMENT_MIN_DATA_LENGTH);
+            self.mailbox.write(slave, MailboxType::CoE, size, |buf| {
+                write_sdo_segment(buf, command, segment)
+            })?;
+
+            let response = self.mailbox.read_response(slave, MailboxType::CoE)?;
+            let command = sdo_segment_response(&response)?;
+            check_segment_command(command, SDO_DOWNLOAD_SEGMENT_RESPONSE, toggle)?;
+            sent += len;
+            toggle ^= SDO_SEGMENT_TOGGLE;
+            progress(sent, total);
+        }
+        Ok(())
+    }
+
+    /// Stores parameters to the non-volatile memory of the slave with object 0x1010.
+    /// Sub-index 1 stores all parameters.
+    pub fn store_parameters(&mut self, slave: &mut Slave, sub_index: u8) -> Result<(), SdoError> {
+        self.download_with_signature(
+            slave,
+            STORE_PARAMETERS_INDEX,
+            sub_index,
+            STORE_PARAMETERS_SIGNATURE,
+        )
+    }
+
+    /// Restores default parameters with object 0x1011.
+    /// They become active after the next reset of the slave.
+    /// Sub-index 1 restores all parameters.
+    pub fn restore_default_parameters(
+        &mut self,
+        slave: &mut Slave,
+        sub_index: u8,
+    ) -> Result<(), SdoError> {
+        self.download_with_signature(
+            slave,
+            RESTORE_DEFAULT_PARAMETERS_INDEX,
+            sub_index,
+            RESTORE_DEFAULT_PARAMETERS_SIGNATURE,
+        )
+    }
+
+    fn download_with_signature(
+        &mut self,
+        slave: &mut Slave,
+        index: u16,
+        sub_index: u8,
+        signature: u32,
+    ) -> Result<(), SdoError> {
+        // bit0が立っていなければコマンドによる保存・復元に対応していない
+        let mut capability = [0; 4];
+        self.upload(slave, index, sub_index, &mut capability)?;
+        if u32::from_le_bytes(capability) & 1 == 0 {
+            return Err(SdoError::NotSupported);
+        }
+
+        self.request_download(slave, index, sub_index, &signature.to_le_bytes())?;
+        // スレーブは保存が完了してから応答するので、応答が来るまで読み出しを繰り返す
+        let mut remaining = STORE_PARAMETERS_TIMEOUT_DEFAULT;
+        loop {
+            match self.download_response(slave, index, sub_index) {
+                Err(SdoError::Mailbox(MailboxError::Timeout(timeout))) if remaining > timeout => {
+                    remaining = remaining - timeout;
+                }
+                result => return self.abort_on_violation(slave, index, sub_index, result),
+            }
+        }
+    }
+
+    fn download_response(
+        &mut self,
+        slave: &mut Slave,
+        index: u16,
+        sub_index: u8,
+    ) -> Result<(), SdoError> {
+        let response = self.mailbox.read_response(slave, MailboxType::CoE)?;
+        download_result(&response, index, sub_index)
+    }
+
+    fn request_download(
+        &mut self,
+        slave: &mut Slave,
+        index: u16,
+        sub_index: u8,
+        data: &[u8],
+    ) -> Result<(), SdoError> {
+        let len = data.len();
+        if let Some(command) = expedited_download_command(len) {
+            let mut bytes = [0; SDO_DATA_LENGTH];
+            bytes[..len].copy_from_slice(data);
+            self.mailbox
+                .write(slave, MailboxType::CoE, SDO_REQUEST_LENGTH, |buf| {
+                    write_sdo_request(
+                        buf,
+                        command as u8,
+                        index,
+                        sub_index,
+                        u32::from_le_bytes(bytes),
+                    )
+                })?;
+        } else {
+            self.request_download_normal(slave, index, sub_index, data, len)?;
+        }
+        Ok(())
+    }
+
+    /// `complete_size` is larger than `data` when segments follow.
+    fn request_download_normal(
+        &mut self,
+        slave: &mut Slave,
+        index: u16,
+        sub_index: u8,
+        data: &[u8],
+        complete_size: usize,
+    ) -> Result<(), SdoError> {
+        let size = SDO_REQUEST_LENGTH + data.len();
+        if size > slave_mailbox_size(slave) {
+            return Err(SdoError::TooLargeData);
+        }
+        self.mailbox.write(slave, MailboxType::CoE, size, |buf| {
+            write_sdo_request(
+                buf,
+                SDOCommand::DownNormalReq as u8,
+                index,
+                sub_index,
+                complete_size as u32,
+            );
+            buf[SDO_REQUEST_LENGTH..size].copy_from_slice(data);
+        })?;
+        Ok(())
+    }
+
+    /// Aborts the transfer of the object with `abort_code`, e.g. when the application gives up
+    /// waiting for the response. A response already in the read mailbox is discarded.
+    pub fn abort(
+        &mut self,
+        slave: &mut Slave,
+        index: u16,
+        sub_index: u8,
+        abort_code: AbortCode,
+    ) -> Result<(), SdoError> {
+        self.mailbox.discard(slave)?;
+        self.mailbox.end_request(slave);
+        // アボート要求には応答がない
+        self.mailbox.write_unconfirmed(
+            slave,
+            MailboxType::CoE,
+            MailboxPriority::Lowest,
+            SDO_REQUEST_LENGTH,
+            |buf| {
+                write_sdo_request(
+                    buf,
+                    SDOCommand::Abort as u8,
+                    index,
+                    sub_index,
+                    abort_code as u32,
+                )
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Aborts the transfer on the slave when the response broke the protocol, so that the SDO
+    /// server of the slave is not left in the middle of the transfer.
+    fn abort_on_violation<R>(
+        &mut self,
+        slave: &mut Slave,
+        index: u16,
+        sub_index: u8,
+        result: Result<R, SdoError>,
+    ) -> Result<R, SdoError> {
+        if let Err(SdoError::ProtocolViolation(abort_code)) = &result {
+            // 中断要求が送れなくても元のエラーを返す
+            let _ = self.abort(slave, index, sub_index, *abort_code);
+        }
+        result
+    }
+
+    /// Object access flags (`ENTRY_ACCESS_*`) of the entry, read with the SDO information service.
+    pub fn entry_access(
+        &mut self,
+        slave: &mut Slave,
+        index: u16,
+        sub_index: u8,
+    ) -> Result<u16, SdoError> {
+        self.mailbox
+            .write(slave, MailboxType::CoE, SDO_INFO_REQUEST_LENGTH, |buf| {
+                let mut coe = CANOpenPDU::new_unchecked(&mut buf[..COE_HEADER_LENGTH]);
+                coe.set_number(0);
+                coe.set_service_type(CANOpenServiceType::SDOInfo as u8);
+                let mut info = SDOInfo::new_unchecked(&mut buf[COE_HEADER_LENGTH..]);
+                info.set_opcode(SDOInfoOpCode::EntryDescriptionReq as u8);
+                let offset = COE_HEADER_LENGTH + SDO_INFO_HEADER_LENGTH;
+                let mut entry = SDOEntryDescription(&mut buf[offset..]);
+                entry.set_index(index);
+                entry.set_sub_index(sub_index);
+                // アクセス権だけでよいので付加情報は要求しない
+                entry.set_value_info(0);
+            })?;
+
+        let response = self.mailbox.read_response(slave, MailboxType::CoE)?;
+        if response.mailbox_type() != MailboxType::CoE as u8 {
+            return Err(SdoError::UnexpectedResponse);
+        }
+        let data = response.data();
+        let coe = CANOpenPDU::new(data).ok_or(SdoError::UnexpectedResponse)?;
+        if coe.service_type() != CANOpenServiceType::SDOInfo as u8 {
+            return Err(SdoError::UnexpectedResponse);
+        }
+        let info = SDOInfo::new(&data[COE_HEADER_LENGTH..]).ok_or(SdoError::UnexpectedResponse)?;
+        let body = &data[COE_HEADER_LENGTH + SDO_INFO_HEADER_LENGTH..];
+        if info.opcode() == SDOInfoOpCode::ErrorReq as u8 {
+            let code = byte_order::read_u32(body, 0).ok_or(SdoError::UnexpectedResponse)?;
+            return Err(SdoError::Abort(AbortCode::from(code)));
+        }
+        if info.opcode() != SDOInfoOpCode::EntryDescriptionRes as u8
+            || body.len() < SDO_ENTRY_DESCRIPTION_LENGTH
+        {
+            return Err(SdoError::UnexpectedResponse);
+        }
+        let entry = SDOEntryDescription(body);
+        if entry.index() != index || entry.sub_index() != sub_index {
+            return Err(SdoError::UnexpectedResponse);
+        }
+        Ok(entry.object_access())
+    }
+
+    /// Checks before remapping whether the PDO assignment of SM2 (`sm` = 2, outputs)
+    /// or SM3 (`sm` = 3, inputs) can be written in PreOp, from the sync manager types (0x1C00)
+    /// and the access flags of 0x1C12/0x1C13.
+    /// Slaves without the assignment object have a fixed mapping.
+    /// For slaves without the SDO information service, `Unknown` is returned
+    /// if the assignment object can be read.
+    pub fn pdo_assignment(&mut self, slave: &mut Slave, sm: u8) -> Result<PdoAssignment, SdoError> {
+        let (assign_index, expected_type) = match sm {
+            2 => (RX_PDO_ASSIGN_INDEX, 3),
+            3 => (TX_PDO_ASSIGN_INDEX, 4),
+            _ => return Ok(PdoAssignment::NotProcessData),
+        };
+        // 0x1C00がないスレーブはSMの用途が標準通りと見なす
+        let mut sm_type = [0; 1];
+        match self.upload(slave, SYNC_MANAGER_TYPE_INDEX, sm + 1, &mut sm_type) {
+            Ok(_) if sm_type[0] != expected_type => return Ok(PdoAssignment::NotProcessData),
+            Ok(_) | Err(SdoError::Abort(_)) => (),
+            Err(err) => return Err(err),
+        }
+        match self.entry_access(slave, assign_index, 0) {
+            Ok(access) if access & ENTRY_ACCESS_WRITE_PREOP != 0 => Ok(PdoAssignment::Configurable),
+            Ok(_) | Err(SdoError::Abort(_)) => Ok(PdoAssignment::Fixed),
+            // SDO情報サービスがなければ、割り当てオブジェクトがあるかだけを読んで確かめる
+            Err(SdoError::Mailbox(MailboxError::Error(_))) => {
+                let mut num_pdos = [0; 1];
+                match self.upload(slave, assign_index, 0, &mut num_pdos) {
+                    Ok(_) => Ok(PdoAssignment::Unknown),
+                    Err(SdoError::Abort(_)) => Ok(PdoAssignment::Fixed),
+                    Err(err) => Err(err),
+                }
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Uploads the object into `buf` and returns the size of the data.
+    /// Segments are copied from the received frames straight into `buf`,
+    /// so the size is only limited by `buf`.
+    pub fn upload(
+        &mut self,
+        slave: &mut Slave,
+        index: u16,
+        sub_index: u8,
+        buf: &mut [u8],
+    ) -> Result<usize, SdoError> {
+        self.upload_with_progress(slave, index, sub_index, buf, |_, _| ())
+    }
+
+    /// Same as `upload`, but calls `progress(transferred_bytes, total_bytes)`
+    /// each time a part of the data is received.
+    pub fn upload_with_progress<F: FnMut(usize, usize)>(
+        &mut self,
+        slave: &mut Slave,
+        index: u16,
+        sub_index: u8,
+        buf: &mut [u8],
+        progress: F,
+    ) -> Result<usize, SdoError> {
+        let max_size = buf.len();
+        let mut offset = 0;
+        let sink = |data: &[u8]| {
+            buf[offset..offset + data.len()].copy_from_slice(data);
+            offset += data.len();
+        };
+        let result = self.upload_transfer(slave, index, sub_index, max_size, sink, progress);
+        self.abort_on_violation(slave, index, sub_index, result)
+    }
+
+    /// Uploads the object and passes each received part of the data to `sink` in order.
+    /// Returns the size of the data.
+    /// Nothing is buffered, so objects of any size can be read, e.g. written straight
+    /// to an external flash on targets with little RAM.
+    pub fn upload_stream<S: FnMut(&[u8])>(
+        &mut self,
+        slave: &mut Slave,
+        index: u16,
+        sub_index: u8,
+        sink: S,
+    ) -> Result<usize, SdoError> {
+        self.upload_stream_with_progress(slave, index, sub_index, sink, |_, _| ())
+    }
+
+    /// Same as `upload_stream`, but calls `progress(transferred_bytes, total_bytes)`
+    /// each time a part of the data is received.
+    pub fn upload_stream_with_progress<S: FnMut(&[u8]), F: FnMut(usize, usize)>(
+        &mut self,
+        slave: &mut Slave,
+        index: u16,
+        sub_index: u8,
+        sink: S,
+        progress: F,
+    ) -> Result<usize, SdoError> {
+        let result = self.upload_transfer(slave, index, sub_index, usize::MAX, sink, progress);
+        self.abort_on_violation(slave, index, sub_index, result)
+    }
+
+    /// Uploads at most `max_size` bytes into `sink`.
+    fn upload_transfer<S: FnMut(&[u8]), F: FnMut(usize, usize)>(
+        &mut self,
+        slave: &mut Slave,
+        index: u16,
+        sub_index: u8,
+        max_size: usize,
+        mut sink: S,
+        mut progress: F,
+    ) -> Result<usize, SdoError> {
+        self.mailbox
+            .write(slave, MailboxType::CoE, SDO_REQUEST_LENGTH, |buf| {
+                write_sdo_request(buf, SDOCommand::UpReq as u8, index, sub_index, 0)
+            })?;
+
+        let response = self.mailbox.read_response(slave, MailboxType::CoE)?;
+        let sdo = sdo_response(&response, index, sub_index)?;
+        let command = sdo.command();
+        // ダウンロード応答以外のコマンド指定子は010
+        if command >> 5 != 0b010 {
+            return Err(SdoError::ProtocolViolation(AbortCode::UnknownClient));
+        }
+        let is_expedited = command & 0b10 != 0;
+        if is_expedited {
+            let size = expedited_size(command);
+            if size > max_size {
+                return Err(SdoError::SmallBuffer);
+            }
+            let data = sdo.data().to_le_bytes();
+            sink(&data[..size]);
+            progress(size, size);
+            return Ok(size);
+        }
+
+        let total = sdo.data() as usize;
+        if total > max_size {
+            // スレーブ側のセグメント転送を終わらせておく
+            self.abort(slave, index, sub_index, AbortCode::GeneralError)?;
+            return Err(SdoError::SmallBuffer);
+        }
+        let data = &response.data()[SDO_REQUEST_LENGTH..];
+        let mut received = data.len().min(total);
+        sink(&data[..received]);
+        progress(received, total);
+
+        // 1つのメールボックスに収まらない分はセグメントで受け取る
+        let mut toggle = 0;
+        while received < total {
+            self.mailbox
+                .write(slave, MailboxType::CoE, SDO_REQUEST_LENGTH, |buf| {
+                    write_sdo_segment(buf, SDO_UPLOAD_SEGMENT_REQUEST | toggle, &[])
+                })?;
+
+            let response = self.mailbox.read_response(slave, MailboxType::CoE)?;
+            let command = sdo_segment_response(&response)?;
+            check_segment_command(command, SDO_UPLOAD_SEGMENT_RESPONSE, toggle)?;
+            let segment = &response.data()[COE_HEADER_LENGTH + SDO_SEGMENT_HEADER_LENGTH..];
+            // 7バイト以下のセグメントはパディングされ、未使用のバイト数がコマンドで示される
+            let len = if segment.len() <= SDO_SEGMENT_MIN_DATA_LENGTH {
+                SDO_SEGMENT_MIN_DATA_LENGTH.saturating_sub(((command >> 1) & 0b111) as usize)
+            } else {
+                segment.len()
+            };
+            if received + len > total {
+                return Err(SdoError::ProtocolViolation(
+                    AbortCode::ParameterLengthTooLong,
+                ));
+            }
+            let segment = segment.get(..len).ok_or(SdoError::UnexpectedResponse)?;
+            sink(segment);
+            received += len;
+            toggle ^= SDO_SEGMENT_TOGGLE;
+            progress(received, total);
+            if command & SDO_SEGMENT_LAST != 0 {
+                break;
+            }
+        }
+        Ok(received)
+    }
+}
+
+impl<'a, 'b, D, T, U> SdoClient<'a, 'b, D, T, U>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+    U: CountDown<Time = MicrosDurationU32>,
+{
+    /// Executes expedited transfers to several slaves at the same time, one per slave.
+    /// `requests[n]` is sent to `slaves[n]` and its result is stored in `results[n]`.
+    /// An uploaded value is returned in little endian, a download returns 0.
+    /// The requests, mailbox checks and responses of all slaves share frames,
+    /// so the frame must be large enough to hold all write mailboxes.
+    /// As with `Mailbox::write`, a slave whose response has not arrived keeps its request
+    /// pending, and later requests to it fail with `MailboxError::Busy` until the response is
+    /// read or the request is given up with `Mailbox::discard` and `Mailbox::end_request`.
+    pub fn transfer_many(
+        &mut self,
+        slaves: &mut [Slave],
+        requests: &[Option<SdoRequest>],
+        results: &mut [Result<u32, SdoError>],
+    ) -> Result<(), SdoError> {
+        let num_slaves = slaves.len().min(requests.len()).min(results.len());
+        if num_slaves >= u8::MAX as usize {
+            return Err(CommonError::BufferExhausted.into());
+        }
+        let slaves = &mut slaves[..num_slaves];
+        let results = &mut results[..num_slaves];
+
+        // 要求を書き込んだスレーブを応答待ちにする。応答が届くまで結果はTimeoutにしておく
+        let mut pending = [false; u8::MAX as usize];
+        for (i, slave) in slaves.iter_mut().enumerate() {
+            let request = match requests[i] {
+                Some(request) => request,
+                None => continue,
+            };
+            if slave.sm_mailbox_out.is_none() {
+                results[i] = Err(MailboxError::NoMailbox.into());
+                continue;
+            }
+            let (command, data) = match request.command() {
+                Ok(command) => command,
+                Err(err) => {
+                    results[i] = Err(err);
+                    continue;
+                }
+            };
+            let (index, sub_index) = request.index();
+            // 応答待ちの要求があるスレーブには書き込まない
+            let written = self.mailbox.add_write(
+                i as u8,
+                slave,
+                MailboxType::CoE,
+                SDO_REQUEST_LENGTH,
+                |buf| write_sdo_request(buf, command, index, sub_index, data),
+            );
+            results[i] = match written {
+                Ok(()) => {
+                    pending[i] = true;
+                    Err(MailboxError::Timeout(MAILBOX_RESPONSE_RETRY_TIMEOUT_DEFAULT).into())
+                }
+                Err(MailboxError::TooLargeData) => Err(SdoError::TooLargeData),
+                Err(err) => Err(err.into()),
+            };
+        }
+        if !pending.contains(&true) {
+            return Ok(());
+        }
+        let iface = self.mailbox.interface();
+        iface.poll(RECEIVE_TIMEOUT_DEFAULT)?;
+        for pdu in iface.consume_command() {
+            let i = pdu.index() as usize;
+            if let Some(result) = results.get_mut(i) {
+                // 書き込みメールボックスが空いていなければWKCは0になり、要求は届いていない
+                if let Err(err) = check_command_wkc(&pdu, 1) {
+                    *result = Err(err.into());
+                    pending[i] = false;
+                    slaves[i].mailbox_request_pending = false;
+                }
+            }
+        }
+
+        self.mailbox
+            .timer()
+            .start(MAILBOX_RESPONSE_RETRY_TIMEOUT_DEFAULT.convert());
+        let mut is_full = [false; u8::MAX as usize];
+        let routing = self.mailbox.routing();
+        loop {
+            let iface = self.mailbox.interface();
+            for (i, slave) in slaves.iter().enumerate() {
+                is_full[i] = false;
+                if pending[i] {
+                    iface.add_command(
+                        i as u8,
+                        CommandType::FPRD,
+                        slave.configured_address,
+                        SyncManagerRegister::ADDRESS1,
+                        SyncManagerRegister::SIZE,
+                        |buf| buf.iter_mut().for_each(|b| *b = 0),
+                    )?;
+                }
+            }
+            if !pending.contains(&true) {
+                return Ok(());
+            }
+            iface.poll(RECEIVE_TIMEOUT_DEFAULT)?;
+            for pdu in iface.consume_command() {
+                let i = pdu.index() as usize;
+                if pending.get(i) == Some(&true)
+                    && check_command_wkc(&pdu, 1).is_ok()
+                    && SyncManagerRegister(pdu.data()).mailbox_state()
+                {
+                    is_full[i] = true;
+                }
+            }
+
+            // 応答が届いたスレーブの読み出しメールボックスをまとめて読む
+            let mut has_response = false;
+            for (i, slave) in slaves.iter().enumerate() {
+                if let (true, Some(sm)) = (is_full[i], slave.sm_mailbox_out) {
+                    has_response = true;
+                    iface.add_command(
+                        i as u8,
+                        CommandType::FPRD,
+                        slave.configured_address,
+                        sm.start_address,
+                        sm.size as usize,
+                        |buf| buf.iter_mut().for_each(|b| *b = 0),
+                    )?;
+                }
+            }
+            if has_response {
+                iface.poll(RECEIVE_TIMEOUT_DEFAULT)?;
+                for pdu in iface.consume_command() {
+                    let i = pdu.index() as usize;
+                    let (result, request) = match (results.get_mut(i), requests[i]) {
+                        (Some(result), Some(request)) => (result, request),
+                        _ => continue,
+                    };
+                    // WKCが0なら次の確認で読み直す
+                    if check_command_wkc(&pdu, 1).is_err() {
+                        continue;
+                    }
+                    let response = parse_mailbox_message(pdu.data());
+                    // 緊急メッセージなどは振り分けて、応答を待ち続ける
+                    if let Ok(message) = &response {
+                        if routing.route(slaves[i].configured_address, message, MailboxType::CoE) {
+                            continue;
+                        }
+                    }
+                    *result = response
+                        .map_err(SdoError::from)
+                        .and_then(|response| expedited_response(&response, request));
+                    pending[i] = false;
+                    slaves[i].mailbox_request_pending = false;
+                }
+            }
+
+            match self.mailbox.timer().wait() {
+                // 残っているスレーブはTimeoutのまま
+                Ok(_) => return Ok(()),
+                Err(nb::Error::Other(_)) => {
+                    return Err(CommonError::UnspcifiedTimerError.into());
+                }
+                Err(nb::Error::WouldBlock) => (),
+            }
+        }
+    }
+}
+
+/// SDO transfer to one slave executed by the master with one datagram per cycle, e.g. to change
+/// a parameter in Op without stopping the cyclic exchange. Place it in
+/// `CyclicProcessingUnit::Sdo` and access it with `EtherCATMaster::sdo_unit_mut`.
+/// Only objects that fit in one mailbox are transferred; a segmented upload fails with
+/// `SdoError::TooLargeData`. The unit has no timeout: `cancel` ends a transfer that takes too long.
+#[derive(Debug)]
+pub struct SdoUnit<'a> {
+    mailbox: CyclicMailbox<'a>,
+    object: Option<(u16, u8)>,
+    is_upload: bool,
+    result: Option<Result<usize, SdoError>>,
+    /// The response to a cancelled request may still arrive.
+    has_cancelled: bool,
+}
+
+impl<'a> SdoUnit<'a> {
+    /// `buffer` holds the mailbox messages and the uploaded data. None if the slave has no
+    /// mailbox or `buffer` is shorter than one of its mailboxes.
+    pub fn new(slave: &Slave, buffer: &'a mut [u8]) -> Option<Self> {
+        Some(Self {
+            mailbox: CyclicMailbox::new(slave, buffer)?,
+            object: None,
+            is_upload: false,
+            result: None,
+            has_cancelled: false,
+        })
+    }
+
+    /// Handlers of the emergency and other messages read while a response is awaited.
+    pub fn set_routing(&mut self, routing: MailboxRouting) {
+        self.mailbox.set_routing(routing);
+    }
+
+    pub fn station_address(&self) -> u16 {
+        self.mailbox.station_address()
+    }
+
+    /// False while a transfer, or the abort request of a cancelled one, is in progress.
+    pub fn is_idle(&self) -> bool {
+        self.object.is_none() && self.mailbox.is_idle()
+    }
+
+    pub fn start_upload(&mut self, index: u16, sub_index: u8) -> Result<(), SdoError> {
+        self.start(index, sub_index, true, SDO_REQUEST_LENGTH, |buf| {
+            write_sdo_request(buf, SDOCommand::UpReq as u8, index, sub_index, 0)
+        })
+    }
+
+    /// `data` of 1 to 4 bytes is downloaded expedited.
+    pub fn start_download(
+        &mut self,
+        index: u16,
+        sub_index: u8,
+        data: &[u8],
+    ) -> Result<(), SdoError> {
+        if let Some(command) = expedited_download_command(data.len()) {
+            let mut bytes = [0; SDO_DATA_LENGTH];
+            bytes[..data.len()].copy_from_slice(data);
+            let value = u32::from_le_bytes(bytes);
+            self.start(index, sub_index, false, SDO_REQUEST_LENGTH, |buf| {
+                write_sdo_request(buf, command as u8, index, sub_index, value)
+            })
+        } else {
+            let size = SDO_REQUEST_LENGTH + data.len();
+            self.start(index, sub_index, false, size, |buf| {
+                write_sdo_request(
+                    buf,
+                    SDOCommand::DownNormalReq as u8,
+                    index,
+                    sub_index,
+                    data.len() as u32,
+                );
+                buf[SDO_REQUEST_LENGTH..].copy_from_slice(data);
+            })
+        }
+    }
+
+    /// Result of the last transfer: the uploaded data, or empty data for a download.
+    /// None while the transfer is in progress and after `cancel`.
+    pub fn result(&self) -> Option<Result<&[u8], SdoError>> {
+        match self.result.as_ref()? {
+            Ok(size) => Some(Ok(&self.mailbox.buffer()[..*size])),
+            Err(err) => Some(Err(err.clone())),
+        }
+    }
+
+    /// Ends the transfer in progress. If the request may have reached the slave, the transfer
+    /// is aborted on the slave with `AbortCode::GeneralError`, and the unit is idle again once
+    /// the abort request is written. A late response to the request is discarded.
+    pub fn cancel(&mut self) {
+        let (index, sub_index) = match self.object.take() {
+            Some(object) => object,
+            None => return,
+        };
+        let is_written = self.mailbox.is_written();
+        self.mailbox.stop();
+        if is_written {
+            self.has_cancelled = true;
+            self.write_abort(index, sub_index, AbortCode::GeneralError);
+        }
+    }
+
+    fn start<F: FnOnce(&mut [u8])>(
+        &mut self,
+        index: u16,
+        sub_index: u8,
+        is_upload: bool,
+        size: usize,
+        data_writer: F,
+    ) -> Result<(), SdoError> {
+        if !self.is_idle() {
+            return Err(MailboxError::Busy.into());
+        }
+        if size > self.mailbox.max_data_size() {
+            return Err(SdoError::TooLargeData);
+        }
+        self.mailbox
+            .write(MailboxType::CoE, true, size, data_writer)?;
+        self.object = Some((index, sub_index));
+        self.is_upload = is_upload;
+        self.result = None;
+        Ok(())
+    }
+
+    fn write_abort(&mut self, index: u16, sub_index: u8, abort_code: AbortCode) {
+        // アボート要求には応答がない
+        let _ = self
+            .mailbox
+            .write(MailboxType::CoE, false, SDO_REQUEST_LENGTH, |buf| {
+                write_sdo_request(
+                    buf,
+                    SDOCommand::Abort as u8,
+                    index,
+                    sub_index,
+                    abort_code as u32,
+                )
+            });
+    }
+
+    pub(crate) fn data_size(&self) -> usize {
+        self.mailbox.data_size()
+    }
+
+    pub(crate) fn process(&mut self) -> Option<(Command, &[u8])> {
+        self.mailbox.process()
+    }
+
+    pub(crate) fn receive(&mut self, command: Command, data: &[u8], wkc: u16) -> bool {
+        let response = match self.mailbox.receive(command, data, wkc) {
+            Some(response) => response,
+            None => return true,
+        };
+        let (index, sub_index) = match self.object {
+            Some(object) => object,
+            None => return true,
+        };
+        let is_wkc_ok = !matches!(
+            response,
+            Err(MailboxError::Common(CommonError::UnexpectedWKC(_)))
+        );
+        let result = match response {
+            Ok(response) => {
+                // 中断した要求への応答が遅れて届いたら読み捨てる
+                if self.has_cancelled && !is_same_object(&response, index, sub_index) {
+                    self.has_cancelled = false;
+                    self.mailbox.wait_response();
+                    return true;
+                }
+                if self.is_upload {
+                    self.upload_result(&response, index, sub_index)
+                } else {
+                    download_result(&response, index, sub_index).map(|_| 0)
+                }
+            }
+            Err(err) => Err(err.into()),
+        };
+        self.has_cancelled = false;
+        self.object = None;
+        // スレーブ側の転送を終わらせておく
+        match &result {
+            Err(SdoError::ProtocolViolation(abort_code)) => {
+                self.write_abort(index, sub_index, *abort_code)
+            }
+            Err(SdoError::TooLargeData) => {
+                self.write_abort(index, sub_index, AbortCode::GeneralError)
+            }
+            _ => (),
+        }
+        self.result = Some(result);
+        is_wkc_ok
+    }
+
+    /// Copies the uploaded data to the beginning of the buffer and returns its size.
+    fn upload_result(
+        &mut self,
+        response: &MailboxPDU<&[u8]>,
+        index: u16,
+        sub_index: u8,
+    ) -> Result<usize, SdoError> {
+        let sdo = sdo_response(response, index, sub_index)?;
+        let command = sdo.command();
+        // ダウンロード応答以外のコマンド指定子は010
+        if command >> 5 != 0b010 {
+            return Err(SdoError::ProtocolViolation(AbortCode::UnknownClient));
+        }
+        let buffer = self.mailbox.buffer_mut();
+        if command & 0b10 != 0 {
+            let size = expedited_size(command);
+            buffer[..size].copy_from_slice(&sdo.data().to_le_bytes()[..size]);
+            return Ok(size);
+        }
+        let size = sdo.data() as usize;
+        let data = &response.data()[SDO_REQUEST_LENGTH..];
+        // 1つのメールボックスに収まらないセグメント転送は扱わない
+        if size > data.len() {
+            return Err(SdoError::TooLargeData);
+        }
+        buffer[..size].copy_from_slice(&data[..size]);
+        Ok(size)
+    }
+}
+
+fn expedited_response(response: &MailboxPDU<&[u8]>, request: SdoRequest) -> Result<u32, SdoError> {
+    let (index, sub_index) = request.index();
+    let sdo = sdo_response(response, index, sub_index)?;
+    let command = sdo.command();
+    match request {
+        SdoRequest::Download { .. } if command == SDOCommand::DownRes as u8 => Ok(0),
+        SdoRequest::Upload { .. } if command >> 5 == 0b010 => {
+            // 4バイトを超えるオブジェクトは通常転送で返ってくる
+            if command & 0b10 == 0 {
+                return Err(SdoError::TooLargeData);
+            }
+            let unused = if command & 0b01 != 0 {
+                (command >> 2) & 0b11
+            } else {
+                0
+            };
+            let mask = u32::MAX >> (unused as u32 * 8);
+            Ok(sdo.data() & mask)
+        }
+        _ => Err(SdoError::UnexpectedResponse),
+    }
+}
+
+fn expedited_download_command(size: usize) -> Option<SDOCommand> {
+    match size {
+        1 => Some(SDOCommand::DownExpReq1),
+        2 => Some(SDOCommand::DownExpReq2),
+        3 => Some(SDOCommand::DownExpReq3),
+        4 => Some(SDOCommand::DownExpReq4),
+        _ => None,
+    }
+}
+
+/// Data bytes of an expedited upload response with the command byte `command`.
+fn expedited_size(command: u8) -> usize {
+    // サイズ指定があれば未使用のバイト数が示される
+    if command & 0b01 != 0 {
+        SDO_DATA_LENGTH - ((command >> 2) & 0b11) as usize
+    } else {
+        SDO_DATA_LENGTH
+    }
+}
+
+fn download_result(
+    response: &MailboxPDU<&[u8]>,
+    index: u16,
+    sub_index: u8,
+) -> Result<(), SdoError> {
+    let sdo = sdo_response(response, index, sub_index)?;
+    if sdo.command() != SDOCommand::DownRes as u8 {
+        return Err(SdoError::ProtocolViolation(AbortCode::UnknownClient));
+    }
+    Ok(())
+}
+
+fn slave_mailbox_size(slave: &Slave) -> usize {
+    slave
+        .sm_mailbox_in
+        .map(|sm| sm.size as usize)
+        .unwrap_or(0)
+        .saturating_sub(crate::packet::MAILBOX_HEADER_LENGTH)
+}
+
+fn write_sdo_request(buf: &mut [u8], command: u8, index: u16, sub_index: u8, data: u32) {
+    let mut coe = CANOpenPDU::new_unchecked(&mut buf[..COE_HEADER_LENGTH]);
+    coe.set_number(0);
+    coe.set_service_type(CANOpenServiceType::SDOReq as u8);
+    let mut sdo = SDO::new_unchecked(&mut buf[COE_HEADER_LENGTH..SDO_REQUEST_LENGTH]);
+    sdo.set_command(command);
+    sdo.set_index(index);
+    sdo.set_sub_index(sub_index);
+    sdo.set_data(data);
+}
+
+fn write_sdo_segment(buf: &mut [u8], command: u8, data: &[u8]) {
+    let mut coe = CANOpenPDU::new_unchecked(&mut buf[..COE_HEADER_LENGTH]);
+    coe.set_number(0);
+    coe.set_service_type(CANOpenServiceType::SDOReq as u8);
+    buf[COE_HEADER_LENGTH] = command;
+    let offset = COE_HEADER_LENGTH + SDO_SEGMENT_HEADER_LENGTH;
+    buf[offset..offset + data.len()].copy_from_slice(data);
+}
+
+/// Returns the command byte of a segment response.
+fn sdo_segment_response(response: &MailboxPDU<&[u8]>) -> Result<u8, SdoError> {
+    if response.mailbox_type() != MailboxType::CoE as u8 {
+        return Err(SdoError::UnexpectedResponse);
+    }
+    let data = response.data();
+    let coe = CANOpenPDU::new(data).ok_or(SdoError::UnexpectedResponse)?;
+    if coe.service_type() != CANOpenServiceType::SDORes as u8 {
+        return Err(SdoError::UnexpectedResponse);
+    }
+    let command = *data
+        .get(COE_HEADER_LENGTH)
+        .ok_or(SdoError::UnexpectedResponse)?;
+    if command == SDOCommand::Abort as u8 {
+        let sdo = SDO::new(&data[COE_HEADER_LENGTH..]).ok_or(SdoError::UnexpectedResponse)?;
+        return Err(SdoError::Abort(AbortCode::from(sdo.data())));
+    }
+    Ok(command)
+}
+
+fn sdo_response<'r>(
+    response: &'r MailboxPDU<&[u8]>,
+    index: u16,
+    sub_index: u8,
+) -> Result<SDO<&'r [u8]>, SdoError> {
+    if response.mailbox_type() != MailboxType::CoE as u8 {
+        return Err(SdoError::UnexpectedResponse);
+    }
+    let data = response.data();
+    let coe = CANOpenPDU::new(data).ok_or(SdoError::UnexpectedResponse)?;
+    if coe.service_type() != CANOpenServiceType::SDORes as u8 {
+        return Err(SdoError::UnexpectedResponse);
+    }
+    let sdo = SDO::new(&data[COE_HEADER_LENGTH..]).ok_or(SdoError::UnexpectedResponse)?;
+    if sdo.command() == SDOCommand::Abort as u8 {
+        return Err(SdoError::Abort(AbortCode::from(sdo.data())));
+    }
+    if sdo.index() != index || sdo.sub_index() != sub_index {
+        return Err(SdoError::ProtocolViolation(AbortCode::GeneralError));
+    }
+    Ok(sdo)
+}
+
+/// Whether the SDO response or abort request is for the object.
+fn is_same_object(response: &MailboxPDU<&[u8]>, index: u16, sub_index: u8) -> bool {
+    response
+        .data()
+        .get(COE_HEADER_LENGTH..)
+        .and_then(SDO::new)
+        .map(|sdo| sdo.index() == index && sdo.sub_index() == sub_index)
+        .unwrap_or(false)
+}
+
+fn check_segment_command(command: u8, specifier: u8, toggle: u8) -> Result<(), SdoError> {
+    if command & SDO_COMMAND_SPECIFIER_MASK != specifier {
+        Err(SdoError::ProtocolViolation(AbortCode::UnknownClient))
+    } else if command & SDO_SEGMENT_TOGGLE != toggle {
+        Err(SdoError::ProtocolViolation(AbortCode::NoToggleBitChange))
+    } else {
+        Ok(())
+    }
+}
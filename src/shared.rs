@@ -0,0 +1,103 @@
+//! Sharing the master between the task running the cyclic exchange and other tasks.
+//!
+//! One task keeps calling `process_and_enqueue` and `poll`, and the other tasks get a
+//! `MasterHandle` to issue acyclic requests (SDO, diagnostics) between the cycles.
+//! With the `std` feature the master is guarded by `std::sync::Mutex`, otherwise by a
+//! critical section, which disables interrupts on single core MCUs.
+
+#[cfg(not(feature = "std"))]
+use core::cell::RefCell;
+
+pub struct SharedMaster<M> {
+    #[cfg(feature = "std")]
+    inner: std::sync::Mutex<M>,
+    #[cfg(not(feature = "std"))]
+    inner: critical_section::Mutex<RefCell<M>>,
+}
+
+impl<M> SharedMaster<M> {
+    #[cfg(feature = "std")]
+    pub const fn new(master: M) -> Self {
+        Self {
+            inner: std::sync::Mutex::new(master),
+        }
+    }
+
+    #[cfg(not(feature = "std"))]
+    pub const fn new(master: M) -> Self {
+        Self {
+            inner: critical_section::Mutex::new(RefCell::new(master)),
+        }
+    }
+
+    pub fn handle(&self) -> MasterHandle<'_, M> {
+        MasterHandle { shared: self }
+    }
+
+    /// Runs `f` with exclusive access to the master.
+    /// Keep `f` short; the cyclic task is blocked (or interrupts are disabled) meanwhile.
+    ///
+    /// # Panics
+    /// Panics if called again from inside `f`.
+    #[cfg(feature = "std")]
+    pub fn lock<R, F: FnOnce(&mut M) -> R>(&self, f: F) -> R {
+        // 他のタスクがパニックしてもマスターの状態自体は壊れていない
+        let mut master = self.inner.lock().unwrap_or_else(|err| err.into_inner());
+        f(&mut master)
+    }
+
+    #[cfg(not(feature = "std"))]
+    pub fn lock<R, F: FnOnce(&mut M) -> R>(&self, f: F) -> R {
+        critical_section::with(|cs| f(&mut self.inner.borrow_ref_mut(cs)))
+    }
+
+    /// Same as `lock`, but returns None instead of waiting when the master is in use.
+    #[cfg(feature = "std")]
+    pub fn try_lock<R, F: FnOnce(&mut M) -> R>(&self, f: F) -> Option<R> {
+        match self.inner.try_lock() {
+            Ok(mut master) => Some(f(&mut master)),
+            Err(std::sync::TryLockError::Poisoned(err)) => Some(f(&mut err.into_inner())),
+            Err(std::sync::TryLockError::WouldBlock) => None,
+        }
+    }
+
+    #[cfg(not(feature = "std"))]
+    pub fn try_lock<R, F: FnOnce(&mut M) -> R>(&self, f: F) -> Option<R> {
+        critical_section::with(|cs| {
+            let mut master = self.inner.borrow(cs).try_borrow_mut().ok()?;
+            Some(f(&mut master))
+        })
+    }
+
+    pub fn into_inner(self) -> M {
+        #[cfg(feature = "std")]
+        return self.inner.into_inner().unwrap_or_else(|err| err.into_inner());
+        #[cfg(not(feature = "std"))]
+        return self.inner.into_inner().into_inner();
+    }
+}
+
+/// A handle given to the tasks other than the cyclic one.
+pub struct MasterHandle<'a, M> {
+    shared: &'a SharedMaster<M>,
+}
+
+impl<'a, M> Clone for MasterHandle<'a, M> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, M> Copy for MasterHandle<'a, M> {}
+
+impl<'a, M> MasterHandle<'a, M> {
+    /// Issues a request with exclusive access to the master.
+    pub fn request<R, F: FnOnce(&mut M) -> R>(&self, f: F) -> R {
+        self.shared.lock(f)
+    }
+
+    /// Issues a request only if the master is not in use.
+    pub fn try_request<R, F: FnOnce(&mut M) -> R>(&self, f: F) -> Option<R> {
+        self.shared.try_lock(f)
+    }
+}
@@ -1,127 +1,134 @@
+// アドレスはワード単位
 pub mod sii_reg {
     pub struct PDIControl;
     impl PDIControl {
-        pub const ADDRESS: u16 = 0;
+        pub const ADDRESS: u16 = 0x0000;
         pub const SIZE: usize = 2;
+        /// Bits of the high byte (ESC configuration) enabling enhanced link detection.
+        pub const ENHANCED_LINK_DETECTION_ALL_PORTS: u16 = 1 << 9;
+        pub const ENHANCED_LINK_DETECTION_PORT0: u16 = 1 << 12;
+        pub const ENHANCED_LINK_DETECTION_PORT1: u16 = 1 << 13;
+        pub const ENHANCED_LINK_DETECTION_PORT2: u16 = 1 << 14;
+        pub const ENHANCED_LINK_DETECTION_PORT3: u16 = 1 << 15;
     }
 
     pub struct PDIConfig;
     impl PDIConfig {
-        pub const ADDRESS: u16 = 0;
+        pub const ADDRESS: u16 = 0x0001;
         pub const SIZE: usize = 2;
     }
 
     pub struct SyncImpulseLen;
     impl SyncImpulseLen {
-        pub const ADDRESS: u16 = 0;
+        pub const ADDRESS: u16 = 0x0002;
         pub const SIZE: usize = 2;
     }
 
     pub struct StationAlias;
     impl StationAlias {
-        pub const ADDRESS: u16 = 0;
+        pub const ADDRESS: u16 = 0x0004;
         pub const SIZE: usize = 2;
     }
 
     pub struct PDIConfig2;
     impl PDIConfig2 {
-        pub const ADDRESS: u16 = 0;
+        pub const ADDRESS: u16 = 0x0003;
         pub const SIZE: usize = 2;
     }
 
     pub struct Checksum;
     impl Checksum {
-        pub const ADDRESS: u16 = 0;
+        pub const ADDRESS: u16 = 0x0007;
         pub const SIZE: usize = 2;
     }
 
     pub struct VenderID;
     impl VenderID {
-        pub const ADDRESS: u16 = 0;
-        pub const SIZE: usize = 2;
+        pub const ADDRESS: u16 = 0x0008;
+        pub const SIZE: usize = 4;
     }
 
     pub struct ProductCode;
     impl ProductCode {
-        pub const ADDRESS: u16 = 0;
-        pub const SIZE: usize = 2;
+        pub const ADDRESS: u16 = 0x000A;
+        pub const SIZE: usize = 4;
     }
 
     pub struct RevisionNumber;
     impl RevisionNumber {
-        pub const ADDRESS: u16 = 0;
-        pub const SIZE: usize = 2;
+        pub const ADDRESS: u16 = 0x000C;
+        pub const SIZE: usize = 4;
     }
 
     pub struct SerialNumber;
     impl SerialNumber {
-        pub const ADDRESS: u16 = 0;
-        pub const SIZE: usize = 2;
+        pub const ADDRESS: u16 = 0x000E;
+        pub const SIZE: usize = 4;
     }
 
     pub struct BootstrapRxMailboxOffset;
     impl BootstrapRxMailboxOffset {
-        pub const ADDRESS: u16 = 0;
+        pub const ADDRESS: u16 = 0x0014;
         pub const SIZE: usize = 2;
     }
 
     pub struct BootstrapRxMailboxSize;
     impl BootstrapRxMailboxSize {
-        pub const ADDRESS: u16 = 0;
+        pub const ADDRESS: u16 = 0x0015;
         pub const SIZE: usize = 2;
     }
 
     pub struct BootstrapTxMailboxOffset;
     impl BootstrapTxMailboxOffset {
-        pub const ADDRESS: u16 = 0;
+        pub const ADDRESS: u16 = 0x0016;
         pub const SIZE: usize = 2;
     }
 
     pub struct BootstrapTxMailboxSize;
     impl BootstrapTxMailboxSize {
-        pub const ADDRESS: u16 = 0;
+        pub const ADDRESS: u16 = 0x0017;
         pub const SIZE: usize = 2;
     }
 
     pub struct StandardRxMailboxOffset;
     impl StandardRxMailboxOffset {
-        pub const ADDRESS: u16 = 0;
+        pub const ADDRESS: u16 = 0x0018;
         pub const SIZE: usize = 2;
     }
 
     pub struct StandardRxMailboxSize;
     impl StandardRxMailboxSize {
-        pub const ADDRESS: u16 = 0;
+        pub const ADDRESS: u16 = 0x0019;
         pub const SIZE: usize = 2;
     }
 
     pub struct StandardTxMailboxOffset;
     impl StandardTxMailboxOffset {
-        pub const ADDRESS: u16 = 0;
+        pub const ADDRESS: u16 = 0x001A;
         pub const SIZE: usize = 2;
     }
 
     pub struct StandardTxMailboxSize;
     impl StandardTxMailboxSize {
-        pub const ADDRESS: u16 = 0;
+        pub const ADDRESS: u16 = 0x001B;
         pub const SIZE: usize = 2;
     }
 
     pub struct MailboxProtocol;
     impl MailboxProtocol {
-        pub const ADDRESS: u16 = 0;
+        pub const ADDRESS: u16 = 0x001C;
         pub const SIZE: usize = 2;
     }
 
     pub struct Size;
     impl Size {
-        pub const ADDRESS: u16 = 0;
+        pub const ADDRESS: u16 = 0x003E;
         pub const SIZE: usize = 2;
     }
 
     pub struct Version;
     impl Version {
-        pub const ADDRESS: u16 = 0;
+        pub const ADDRESS: u16 = 0x003F;
         pub const SIZE: usize = 2;
     }
 }
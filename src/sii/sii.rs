@@ -1,7 +1,9 @@
 use crate::arch::*;
-use crate::error::CommonError;
+use crate::error::*;
+use crate::esc::SII_BUSY_POLLS_DEFAULT;
 use crate::interface::*;
 use crate::register::datalink::*;
+use crate::sii::sii_reg;
 use embedded_hal::timer::CountDown;
 use fugit::MicrosDurationU32;
 use log::*;
@@ -15,6 +17,26 @@ pub enum SIIError {
     CheckSumError,
     DeviceInfoError,
     CommandError,
+    /// The checksum of the config area (words 0 to 7) does not match.
+    ConfigAreaCorrupted,
+    /// The category header at the word address runs past the end of the EEPROM.
+    CategoryAreaCorrupted(u16),
+}
+
+impl Classify for SIIError {
+    fn classify(&self) -> (ErrorCategory, RecoveryHint) {
+        use ErrorCategory::*;
+        use RecoveryHint::*;
+        match self {
+            Self::Common(err) => err.classify(),
+            // PDIがEEPROMを使っている間は待てばよい
+            Self::PermittionDenied | Self::Busy | Self::CommandError => (SlaveConfig, Retry),
+            Self::CheckSumError | Self::DeviceInfoError => (SlaveConfig, PowerCycleSlave),
+            // EEPROMを書き直す必要がある
+            Self::ConfigAreaCorrupted | Self::CategoryAreaCorrupted(_) => (SlaveConfig, FixConfig),
+            Self::AddressSizeOver => (ApplicationBug, FixConfig),
+        }
+    }
 }
 
 impl From<CommonError> for SIIError {
@@ -23,21 +45,137 @@ impl From<CommonError> for SIIError {
     }
 }
 
+/// Config area of the SII, words 0 to 7.
+#[cfg(feature = "sii")]
+pub const SII_CONFIG_AREA_SIZE: usize = 16;
+
+/// First word of the category area.
+#[cfg(feature = "sii")]
+pub const SII_CATEGORY_START_ADDRESS: u16 = 0x0040;
+#[cfg(feature = "sii")]
+const SII_CATEGORY_END: u16 = 0xFFFF;
+
+/// Check of the config area read from the SII. Returns false if it is corrupted.
+#[cfg(feature = "sii")]
+pub type SiiConfigCheck = fn(&[u8; SII_CONFIG_AREA_SIZE]) -> bool;
+
+/// CRC-8 (x^8 + x^2 + x + 1, initial value 0xFF) of the first 14 bytes of the config area,
+/// which the ESC checks when loading it.
+#[cfg(feature = "sii")]
+pub fn sii_config_crc(config_area: &[u8]) -> u8 {
+    config_area.iter().take(14).fold(0xFF, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Standard check of the config area: the low byte of word 7 is `sii_config_crc`.
+#[cfg(feature = "sii")]
+pub fn check_sii_config_crc(config_area: &[u8; SII_CONFIG_AREA_SIZE]) -> bool {
+    sii_config_crc(config_area) == config_area[sii_reg::Checksum::ADDRESS as usize * 2]
+}
+
 pub struct SlaveInformationInterface<'a, 'b, D, T>
 where
     D: Device,
     T: CountDown<Time = MicrosDurationU32>,
 {
     iface: &'a mut EtherCATInterface<'b, D, T>,
+    eeprom_size: Option<usize>,
+    max_busy_polls: u32,
 }
 
+// 1アドレスバイトのアルゴリズムでアクセスできるのは16KBitまで
+const MAX_WORDS_ONE_ADDRESS_BYTE: u16 = 16 * 1024 / 16;
+
 impl<'a, 'b, D, T> SlaveInformationInterface<'a, 'b, D, T>
 where
     D: Device,
     T: CountDown<Time = MicrosDurationU32>,
 {
     pub fn new(iface: &'a mut EtherCATInterface<'b, D, T>) -> Self {
-        Self { iface }
+        Self {
+            iface,
+            eeprom_size: None,
+            max_busy_polls: SII_BUSY_POLLS_DEFAULT,
+        }
+    }
+
+    /// Polls of the busy flag before a read fails with `SIIError::Busy`.
+    pub fn set_max_busy_polls(&mut self, polls: u32) {
+        self.max_busy_polls = polls;
+    }
+
+    /// Limits reads to an EEPROM of `size` bytes, see `eeprom_size`.
+    pub fn set_eeprom_size(&mut self, size: usize) {
+        self.eeprom_size = Some(size);
+    }
+
+    /// Size of the EEPROM in bytes, from the size word of the SII.
+    pub fn eeprom_size(&mut self, slave_address: SlaveAddress) -> Result<usize, SIIError> {
+        let (data, _size) = self.read(slave_address, sii_reg::Size::ADDRESS)?;
+        // KBit単位のサイズから1を引いた値が入っている
+        let size_kbit = (data.sii_data() as u16) as usize + 1;
+        Ok(size_kbit * 1024 / 8)
+    }
+
+    /// Reads the config area and checks it with `check`, e.g. `check_sii_config_crc` or a
+    /// vendor-specific check.
+    #[cfg(feature = "sii")]
+    pub fn verify_config_area(
+        &mut self,
+        slave_address: SlaveAddress,
+        check: SiiConfigCheck,
+    ) -> Result<[u8; SII_CONFIG_AREA_SIZE], SIIError> {
+        let mut config_area = [0; SII_CONFIG_AREA_SIZE];
+        self.read_words(slave_address, 0, &mut config_area)?;
+        if check(&config_area) {
+            Ok(config_area)
+        } else {
+            Err(SIIError::ConfigAreaCorrupted)
+        }
+    }
+
+    /// Follows the category headers from `SII_CATEGORY_START_ADDRESS` to the end marker and
+    /// checks that none runs past the end of the EEPROM.
+    /// Returns the number of categories.
+    #[cfg(feature = "sii")]
+    pub fn verify_categories(&mut self, slave_address: SlaveAddress) -> Result<u16, SIIError> {
+        let eeprom_words = match self.eeprom_size {
+            Some(size) => size / 2,
+            None => self.eeprom_size(slave_address)? / 2,
+        };
+        let mut address = SII_CATEGORY_START_ADDRESS;
+        let mut categories = 0;
+        loop {
+            if address as usize + 1 > eeprom_words {
+                return Err(SIIError::CategoryAreaCorrupted(address));
+            }
+            // ヘッダはカテゴリの種類とワード単位のサイズ。最後のワードには終端マーカーしか入らない
+            let last_word = address as usize + 1 == eeprom_words;
+            let mut header = [0; 4];
+            let header_len = if last_word { 2 } else { 4 };
+            self.read_words(slave_address, address, &mut header[..header_len])?;
+            let category_type = u16::from_le_bytes([header[0], header[1]]);
+            if category_type == SII_CATEGORY_END {
+                return Ok(categories);
+            }
+            if last_word {
+                return Err(SIIError::CategoryAreaCorrupted(address));
+            }
+            let size = u16::from_le_bytes([header[2], header[3]]) as usize;
+            let next = address as usize + 2 + size;
+            if next > eeprom_words {
+                return Err(SIIError::CategoryAreaCorrupted(address));
+            }
+            address = next as u16;
+            categories += 1;
+        }
     }
 
     fn get_ownership(&mut self, slave_address: SlaveAddress) -> Result<(), SIIError> {
@@ -59,6 +197,24 @@ where
         }
     }
 
+    /// Reads consecutive words from `sii_address` into `buf`.
+    /// ESCs supporting 8 byte reads need half as many accesses.
+    pub fn read_words(
+        &mut self,
+        slave_address: SlaveAddress,
+        sii_address: u16,
+        buf: &mut [u8],
+    ) -> Result<(), SIIError> {
+        let mut offset = 0;
+        while offset < buf.len() {
+            let (data, read_size) = self.read(slave_address, sii_address + (offset / 2) as u16)?;
+            let len = read_size.min(buf.len() - offset);
+            buf[offset..offset + len].copy_from_slice(&data.0[..len]);
+            offset += len;
+        }
+        Ok(())
+    }
+
     // タプルの2番目のデータは読み取ったサイズで4もしくは8となる
     pub fn read(
         &mut self,
@@ -73,10 +229,17 @@ where
             return Err(SIIError::DeviceInfoError);
         }
 
-        // アドレスアルゴリズムが0なら、アドレスは1オクテットでなければならない。
-        if !sii_control.address_algorithm() && sii_address >> 8 != 0 {
+        // アドレスアルゴリズムが0なら、EEPROMは16KBitまで。
+        // 範囲外のアドレスは折り返して別のデータを読んでしまう。
+        if !sii_control.address_algorithm() && sii_address >= MAX_WORDS_ONE_ADDRESS_BYTE {
             return Err(SIIError::AddressSizeOver);
         }
+        // アドレスはワード単位
+        if let Some(eeprom_size) = self.eeprom_size {
+            if sii_address as usize * 2 >= eeprom_size {
+                return Err(SIIError::AddressSizeOver);
+            }
+        }
 
         let read_size = if sii_control.read_size() { 8 } else { 4 };
         info!("read_size {:?}", sii_control);
@@ -104,7 +267,7 @@ where
         self.iface
             .write_sii_control(slave_address, Some(sii_control))?;
 
-        // TODO:タイムアウトの追加
+        let mut polls = 0;
         loop {
             let sii_control = self.iface.read_sii_control(slave_address)?;
             if sii_control.command_error() {
@@ -113,6 +276,10 @@ where
             if !sii_control.busy() && !sii_control.read_operation() {
                 break;
             }
+            polls += 1;
+            if polls >= self.max_busy_polls {
+                return Err(SIIError::Busy);
+            }
         }
 
         let data = self.iface.read_sii_data(slave_address)?;
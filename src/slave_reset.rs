@@ -0,0 +1,129 @@
+use crate::arch::*;
+use crate::error::*;
+use crate::interface::*;
+#[cfg(feature = "coe")]
+use crate::mailbox::MailboxError;
+use crate::register::datalink::ECATReset;
+#[cfg(feature = "coe")]
+use crate::sdo::*;
+use crate::slave_status::*;
+use crate::wkc::check_command_wkc;
+use embedded_hal::timer::CountDown;
+use fugit::*;
+
+/// Bytes written to the ECAT reset register (0x0040) one after another.
+pub const ESC_RESET_SEQUENCE: [u8; 3] = [b'R', b'E', b'S'];
+
+/// How `SlaveReset` resets a slave.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetMethod {
+    /// Writes `ESC_RESET_SEQUENCE` to the ECAT reset register. Resets the ESC, and on most
+    /// devices the whole slave. ESCs without the register ignore it.
+    EscRegister,
+    /// Downloads `value` to a vendor-specific object, e.g. the reboot command of a drive.
+    /// A mailbox timeout is taken as the slave rebooting before it answered.
+    #[cfg(feature = "coe")]
+    CoE {
+        index: u16,
+        sub_index: u8,
+        value: u32,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum ResetError {
+    Common(CommonError),
+    #[cfg(feature = "coe")]
+    Sdo(SdoError),
+}
+
+impl Classify for ResetError {
+    fn classify(&self) -> (ErrorCategory, RecoveryHint) {
+        match self {
+            Self::Common(err) => err.classify(),
+            #[cfg(feature = "coe")]
+            Self::Sdo(err) => err.classify(),
+        }
+    }
+}
+
+impl From<CommonError> for ResetError {
+    fn from(err: CommonError) -> Self {
+        Self::Common(err)
+    }
+}
+
+#[cfg(feature = "coe")]
+impl From<SdoError> for ResetError {
+    fn from(err: SdoError) -> Self {
+        Self::Sdo(err)
+    }
+}
+
+/// Resets individual slaves, e.g. after a firmware update or to recover a wedged device
+/// without physical access.
+/// The slave loses its configuration, including the station address, and is marked stale.
+/// Rescan it with `SlaveInitilizer::rescan_slave` once it is back.
+pub struct SlaveReset<'a, 'b, D, T, U>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+    U: CountDown<Time = MicrosDurationU32>,
+{
+    iface: &'a mut EtherCATInterface<'b, D, T>,
+    #[cfg_attr(not(feature = "coe"), allow(dead_code))]
+    timer: &'a mut U,
+}
+
+impl<'a, 'b, D, T, U> SlaveReset<'a, 'b, D, T, U>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+    U: CountDown<Time = MicrosDurationU32>,
+{
+    pub fn new(iface: &'a mut EtherCATInterface<'b, D, T>, timer: &'a mut U) -> Self {
+        Self { iface, timer }
+    }
+
+    pub fn reset(&mut self, slave: &mut Slave, method: ResetMethod) -> Result<(), ResetError> {
+        match method {
+            ResetMethod::EscRegister => self.reset_esc(slave)?,
+            #[cfg(feature = "coe")]
+            ResetMethod::CoE {
+                index,
+                sub_index,
+                value,
+            } => {
+                let mut sdo = SdoClient::new(self.iface, self.timer);
+                // 応答する前に再起動するスレーブもある
+                match sdo.download(slave, index, sub_index, &value.to_le_bytes()) {
+                    Ok(_) | Err(SdoError::Mailbox(MailboxError::Timeout(_))) => (),
+                    Err(err) => return Err(err.into()),
+                }
+            }
+        }
+        slave.is_stale = true;
+        Ok(())
+    }
+
+    fn reset_esc(&mut self, slave: &Slave) -> Result<(), CommonError> {
+        let address = SlaveAddress::StationAddress(slave.configured_address);
+        for (i, byte) in ESC_RESET_SEQUENCE.iter().enumerate() {
+            let is_last = i + 1 == ESC_RESET_SEQUENCE.len();
+            let result =
+                self.iface
+                    .write_register(address, ECATReset::ADDRESS, ECATReset::SIZE, |buf| {
+                        buf[0] = *byte
+                    });
+            match (result, is_last) {
+                (Ok(pdu), false) => check_command_wkc(&pdu, 1)?,
+                // リセットでリンクが切れるので、最後の書き込みの応答は返ってこないことがある
+                (Ok(_), true)
+                | (Err(CommonError::ReceiveTimeout), true)
+                | (Err(CommonError::PacketDropped), true) => (),
+                (Err(err), _) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,3 +1,6 @@
+use crate::esc::*;
+use crate::initializer::ProcessImage;
+use crate::network::SlaveGroup;
 use crate::register::datalink::PortPhysics;
 use heapless::Deque;
 
@@ -46,7 +49,8 @@ pub enum SlaveError {
     SyncEventNotDetected,
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Identification {
     pub(crate) vender_id: u16,
     pub(crate) product_code: u16,
@@ -54,7 +58,7 @@ pub struct Identification {
 }
 
 #[derive(Debug, Default)]
-pub struct Slave {
+pub struct Slave<'a> {
     pub(crate) error: Option<SlaveError>,
     pub(crate) error_history: Deque<SlaveError, 10>,
 
@@ -64,19 +68,37 @@ pub struct Slave {
     pub(crate) al_state: AlState,
 
     pub(crate) mailbox_count: u8,
+    pub(crate) mailbox_request_pending: bool,
 
     pub(crate) ports: [Option<PortPhysics>; 4], // read 0x0E00
+    pub(crate) enhanced_link_detection: [bool; 4],
+    pub(crate) require_enhanced_link_detection: bool,
+    pub(crate) parent: Option<(u16, u8)>,
+    pub(crate) group: SlaveGroup,
+    pub(crate) is_stale: bool,
 
     pub(crate) ram_size_kb: u8,
+    pub(crate) eeprom_size: Option<usize>,
+    pub(crate) esc: EscInfo,
+    pub(crate) quirks: EscQuirks,
 
-    pub(crate) fmmu0: Option<u8>,
-    pub(crate) fmmu1: Option<u8>,
+    pub(crate) fmmu0: Option<u16>,
+    pub(crate) fmmu1: Option<u16>,
 
     pub(crate) number_of_sm: u8,
+    pub(crate) number_of_fmmu: u8,
     pub(crate) pdo_start_address: Option<u16>,
     pub(crate) pdo_ram_size: u16,
-    pub(crate) rx_pdo_mapping: Option<&'static mut [PDOMapping]>,
-    pub(crate) tx_pdo_mapping: Option<&'static mut [PDOMapping]>,
+    pub(crate) logical_address: Option<u32>,
+    pub(crate) output_buffer_type: SyncManagerBufferType,
+    pub(crate) input_buffer_type: SyncManagerBufferType,
+    pub(crate) rx_pdo_mapping: Option<&'a mut [PDOMapping<'a>]>,
+    pub(crate) tx_pdo_mapping: Option<&'a mut [PDOMapping<'a>]>,
+    pub(crate) fail_safe_outputs: Option<&'a [u8]>,
+    pub(crate) forced_outputs: Option<&'a [ForcedEntry<'a>]>,
+    pub(crate) forced_inputs: Option<&'a [ForcedEntry<'a>]>,
+    pub(crate) detect_input_changes: bool,
+    pub(crate) inputs_changed: bool,
     pub(crate) sm_mailbox_in: Option<MailboxSyncManager>,
     pub(crate) sm_mailbox_out: Option<MailboxSyncManager>,
     pub(crate) bootstrap_sm_mailbox_in: Option<MailboxSyncManager>,
@@ -84,6 +106,8 @@ pub struct Slave {
 
     pub(crate) support_dc: bool,
     pub(crate) is_dc_range_64bits: bool,
+    pub(crate) dc_propagation_delay: u32,
+    pub(crate) dc_receive_times: [u32; 4],
     pub(crate) support_fmmu_bit_operation: bool,
     pub(crate) support_lrw: bool,
     pub(crate) support_rw: bool,
@@ -94,6 +118,168 @@ pub struct Slave {
     pub(crate) has_foe: bool,
 }
 
+impl Identification {
+    pub fn vender_id(&self) -> u16 {
+        self.vender_id
+    }
+
+    pub fn product_code(&self) -> u16 {
+        self.product_code
+    }
+
+    pub fn revision_number(&self) -> u16 {
+        self.revision_number
+    }
+}
+
+impl<'a> Slave<'a> {
+    /// Position and port of the slave this slave hangs off, e.g. a port of a junction.
+    /// None for the first slave.
+    pub fn parent(&self) -> Option<(u16, u8)> {
+        self.parent
+    }
+
+    pub fn group(&self) -> SlaveGroup {
+        self.group
+    }
+
+    /// Whether a mailbox request to the slave waits for its response.
+    pub fn mailbox_request_pending(&self) -> bool {
+        self.mailbox_request_pending
+    }
+
+    /// Whether the information read from the slave is outdated, set by
+    /// `NetworkDescription::invalidate` until the slave is rescanned.
+    pub fn is_stale(&self) -> bool {
+        self.is_stale
+    }
+
+    /// Size of the EEPROM in bytes from the SII size word, None before the scan.
+    pub fn eeprom_size(&self) -> Option<usize> {
+        self.eeprom_size
+    }
+
+    /// Whether the slave answers LRW, false if its quirks avoid it.
+    /// Images with such a slave are exchanged with LWR and LRD.
+    pub fn support_lrw(&self) -> bool {
+        self.support_lrw
+    }
+
+    /// Whether the slave answers BRW, APRW and FPRW, false if its quirks avoid them.
+    /// The master sends none of them itself; check it before sending them with
+    /// `EtherCATMaster::send_custom`.
+    pub fn support_rw(&self) -> bool {
+        self.support_rw
+    }
+
+    pub fn esc(&self) -> EscInfo {
+        self.esc
+    }
+
+    /// Quirks applied to the slave, looked up by the ESC type during the scan.
+    pub fn quirks(&self) -> EscQuirks {
+        self.quirks
+    }
+
+    /// Whether enhanced link detection is enabled on `port` (0 to 3).
+    pub fn enhanced_link_detection(&self, port: usize) -> bool {
+        self.enhanced_link_detection.get(port).copied().unwrap_or(false)
+    }
+
+    /// Requires enhanced link detection on all connected ports, for PHYs that do not report
+    /// link loss reliably without it. It can only be enabled in the SII (word 0),
+    /// so `SlaveInitilizer::verify_link_detection` reports slaves without it.
+    pub fn set_enhanced_link_detection_required(&mut self, required: bool) {
+        self.require_enhanced_link_detection = required;
+    }
+
+    /// Replaces the output PDO mapping. Takes effect on the next
+    /// `SlaveInitilizer::configure_process_data`.
+    pub fn set_rx_pdo_mapping(&mut self, mapping: &'a mut [PDOMapping<'a>]) {
+        self.rx_pdo_mapping = Some(mapping);
+    }
+
+    /// Replaces the input PDO mapping. Takes effect on the next
+    /// `SlaveInitilizer::configure_process_data`.
+    pub fn set_tx_pdo_mapping(&mut self, mapping: &'a mut [PDOMapping<'a>]) {
+        self.tx_pdo_mapping = Some(mapping);
+    }
+
+    pub(crate) fn rx_pdo_size(&self) -> u16 {
+        pdo_mapping_size(&self.rx_pdo_mapping)
+    }
+
+    pub(crate) fn tx_pdo_size(&self) -> u16 {
+        pdo_mapping_size(&self.tx_pdo_mapping)
+    }
+
+    /// Offset in the inputs and length of the input PDO entry.
+    pub(crate) fn tx_pdo_entry_range(&self, index: u16, sub_index: u8) -> Option<(usize, usize)> {
+        let mut offset = 0;
+        for pdo in self
+            .tx_pdo_mapping
+            .as_ref()?
+            .iter()
+            .flat_map(|pdo_mapping| pdo_mapping.entries.iter())
+        {
+            let byte_length = pdo.byte_length as usize;
+            if pdo.index == index && pdo.sub_index == sub_index {
+                return Some((offset, byte_length));
+            }
+            offset += byte_length;
+        }
+        None
+    }
+
+    /// Selects the buffer type of SM2 (outputs) and SM3 (inputs).
+    /// Buffered (3-buffer) mode is the default and keeps the process data consistent.
+    /// Takes effect on the next `SlaveInitilizer::configure_process_data`.
+    pub fn set_process_data_buffer_types(
+        &mut self,
+        outputs: SyncManagerBufferType,
+        inputs: SyncManagerBufferType,
+    ) {
+        self.output_buffer_type = outputs;
+        self.input_buffer_type = inputs;
+    }
+
+    /// Registers the output image written instead of the application outputs
+    /// while the master is in the fail-safe state. Outputs are zeroed if not set.
+    pub fn set_fail_safe_outputs(&mut self, outputs: &'a [u8]) {
+        self.fail_safe_outputs = Some(outputs);
+    }
+
+    /// Forces output PDO entries to fixed values regardless of what the application writes,
+    /// e.g. for I/O checkout during commissioning.
+    pub fn set_forced_outputs(&mut self, forces: &'a [ForcedEntry<'a>]) {
+        self.forced_outputs = Some(forces);
+    }
+
+    /// Disconnects input PDO entries from the slave and substitutes simulated values.
+    pub fn set_forced_inputs(&mut self, forces: &'a [ForcedEntry<'a>]) {
+        self.forced_inputs = Some(forces);
+    }
+
+    /// Releases all forced outputs and inputs.
+    pub fn clear_forces(&mut self) {
+        self.forced_outputs = None;
+        self.forced_inputs = None;
+    }
+
+    /// Compares the inputs with the previous cycle when they are copied from the process image.
+    /// Disabled by default.
+    pub fn set_input_change_detection(&mut self, enabled: bool) {
+        self.detect_input_changes = enabled;
+        self.inputs_changed = false;
+    }
+
+    /// Whether any input changed since the last call. Always false unless enabled with
+    /// `set_input_change_detection`.
+    pub fn take_inputs_changed(&mut self) -> bool {
+        core::mem::replace(&mut self.inputs_changed, false)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Hash, Copy)]
 pub enum AlState {
     Init = 0x1,
@@ -130,12 +316,29 @@ impl Default for AlState {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MailboxSyncManager {
     pub size: u16,
     pub start_address: u16,
 }
 
+/// Buffer type of a sync manager (0x0804 bit 1..0).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SyncManagerBufferType {
+    /// 3-buffer mode
+    Buffered = 0b00,
+    /// 1-buffer mode with handshake
+    Mailbox = 0b10,
+}
+
+impl Default for SyncManagerBufferType {
+    fn default() -> Self {
+        SyncManagerBufferType::Buffered
+    }
+}
+
 //#[derive(Debug)]
 //pub struct ProcessDataSyncManager {
 //    start_address: u16,
@@ -143,6 +346,7 @@ pub struct MailboxSyncManager {
 //}
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum OperationMode {
     FreeRun,
     Sync0Event,
@@ -156,45 +360,385 @@ impl Default for OperationMode {
     }
 }
 
+/// PDO with its entries. The mappings are borrowed by the slave for as long as it is used,
+/// so they can live on the stack of the task that owns the slaves.
 #[derive(Debug)]
-pub struct PDOMapping {
+pub struct PDOMapping<'a> {
     index: u16,
-    entries: &'static mut [PDOEntry],
+    entries: &'a mut [PDOEntry<'a>],
+}
+
+impl<'a> PDOMapping<'a> {
+    pub fn new(index: u16, entries: &'a mut [PDOEntry<'a>]) -> Self {
+        Self { index, entries }
+    }
 }
 
 #[derive(Debug)]
-pub struct PDOEntry {
+pub struct PDOEntry<'a> {
     index: u16,
     sub_index: u8,
     byte_length: u8, // NOTE: not bit length
-    data: &'static mut [u8],
+    data: &'a mut [u8],
+}
+
+impl<'a> PDOEntry<'a> {
+    pub fn new(index: u16, sub_index: u8, data: &'a mut [u8]) -> Self {
+        let byte_length = data.len() as u8;
+        Self {
+            index,
+            sub_index,
+            byte_length,
+            data,
+        }
+    }
+}
+
+/// Value of a PDO entry overriding the process data while forced.
+/// A value shorter than the entry only forces the leading bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct ForcedEntry<'a> {
+    pub index: u16,
+    pub sub_index: u8,
+    pub value: &'a [u8],
+}
+
+impl<'a> ForcedEntry<'a> {
+    pub const fn new(index: u16, sub_index: u8, value: &'a [u8]) -> Self {
+        Self {
+            index,
+            sub_index,
+            value,
+        }
+    }
 }
 
-pub(crate) fn process_cyclic_data(datagram: &mut [u8], slaves: &mut [Slave]) {
+fn forced_value<'a>(forces: Option<&'a [ForcedEntry<'a>]>, pdo: &PDOEntry) -> Option<&'a [u8]> {
+    forces?
+        .iter()
+        .find(|force| force.index == pdo.index && force.sub_index == pdo.sub_index)
+        .map(|force| force.value)
+}
+
+fn pdo_mapping_size(mapping: &Option<&mut [PDOMapping]>) -> u16 {
+    mapping
+        .as_ref()
+        .map(|mapping| {
+            mapping
+                .iter()
+                .flat_map(|pdo_mapping| pdo_mapping.entries.iter())
+                .map(|pdo| pdo.byte_length as u16)
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+/// Direction of process data seen from the master.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PdoDirection {
+    /// RxPDO, written by the master.
+    Output,
+    /// TxPDO, read by the master.
+    Input,
+}
+
+/// Row of the process data layout returned by `mapping_report`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MappedEntry {
+    /// Position of the slave.
+    pub slave: u16,
+    pub station_address: u16,
+    pub pdo_index: u16,
+    pub index: u16,
+    pub sub_index: u8,
+    /// Offset from the logical start address of the image in bits.
+    pub bit_offset: u32,
+    pub bit_length: u16,
+    pub direction: PdoDirection,
+}
+
+/// Layout of the PDO entries of `slaves` in `image`, in the order of the logical addresses
+/// of each slave, e.g. to print it for documentation or to compare it with the variables of
+/// a PLC. Slaves without process data in the image are skipped.
+pub fn mapping_report<'a, 'b>(
+    slaves: &'a [Slave<'b>],
+    image: &ProcessImage,
+) -> impl Iterator<Item = MappedEntry> + use<'a, 'b> {
+    let logical_start_address = image.logical_start_address;
+    slaves.iter().flat_map(move |slave| {
+        let start = slave
+            .logical_address
+            .and_then(|address| address.checked_sub(logical_start_address));
+        // 出力、入力の順に並んでいる
+        let mappings = [
+            (&slave.rx_pdo_mapping, PdoDirection::Output),
+            (&slave.tx_pdo_mapping, PdoDirection::Input),
+        ];
+        let mut offset = start.unwrap_or(0) * 8;
+        mappings
+            .into_iter()
+            .filter(move |_| start.is_some())
+            .filter_map(|(mapping, direction)| Some((mapping.as_deref()?, direction)))
+            .flat_map(|(mapping, direction)| {
+                mapping.iter().flat_map(move |pdo_mapping| {
+                    pdo_mapping
+                        .entries
+                        .iter()
+                        .map(move |pdo| (pdo_mapping.index, pdo, direction))
+                })
+            })
+            .map(move |(pdo_index, pdo, direction)| {
+                let bit_length = pdo.byte_length as u16 * 8;
+                let entry = MappedEntry {
+                    slave: slave.position_address,
+                    station_address: slave.configured_address,
+                    pdo_index,
+                    index: pdo.index,
+                    sub_index: pdo.sub_index,
+                    bit_offset: offset,
+                    bit_length,
+                    direction,
+                };
+                offset += bit_length as u32;
+                entry
+            })
+    })
+}
+
+/// Copies the outputs of the PDO entries of `slaves` into the logical image `datagram`
+/// and the inputs from `datagram` into the PDO entries, in the layout of
+/// `SlaveInitilizer::configure_process_data`: for each slave the outputs (RxPDO, SM2)
+/// followed by the inputs (TxPDO, SM3).
+pub fn process_cyclic_data(datagram: &mut [u8], slaves: &mut [Slave]) {
+    write_outputs(datagram, slaves);
+    read_inputs(datagram, slaves);
+}
+
+/// Output half of `process_cyclic_data`, done before the image is sent.
+pub(crate) fn write_outputs(datagram: &mut [u8], slaves: &[Slave]) {
     let mut offset = 0;
-    let len = slaves.len();
-    for i in 0..len {
-        let slave = &mut slaves[i];
-        //先にRxPDOを並べているとする
-        if let Some(ref mut sm_in) = slave.rx_pdo_mapping {
-            for pdo_mapping in sm_in.iter_mut() {
-                for pdo in pdo_mapping.entries.iter_mut() {
-                    let byte_length = pdo.byte_length as usize;
-                    pdo.data
-                        .copy_from_slice(&datagram[offset..offset + byte_length]);
-                    offset += byte_length;
+    for slave in slaves.iter() {
+        //先にRxPDO(出力)を並べている
+        if let Some(ref mapping) = slave.rx_pdo_mapping {
+            for pdo in mapping
+                .iter()
+                .flat_map(|pdo_mapping| pdo_mapping.entries.iter())
+            {
+                let byte_length = pdo.byte_length as usize;
+                let buf = &mut datagram[offset..offset + byte_length];
+                buf.copy_from_slice(pdo.data);
+                // 強制値はアプリケーションの書き込みより優先する
+                if let Some(value) = forced_value(slave.forced_outputs, pdo) {
+                    for (b, v) in buf.iter_mut().zip(value) {
+                        *b = *v;
+                    }
                 }
+                offset += byte_length;
             }
         }
-        //RxPDOの後にTxPDOを並べているとする
-        if let Some(ref mut sm_out) = slave.tx_pdo_mapping {
-            for pdo_mapping in sm_out.iter_mut() {
-                for pdo in pdo_mapping.entries.iter_mut() {
-                    let byte_length = pdo.byte_length as usize;
-                    datagram[offset..offset + byte_length].copy_from_slice(&pdo.data);
-                    offset += byte_length;
+        offset += slave.tx_pdo_size() as usize;
+    }
+}
+
+/// Input half of `process_cyclic_data`, done after the image is received.
+pub(crate) fn read_inputs(datagram: &[u8], slaves: &mut [Slave]) {
+    let mut offset = 0;
+    for slave in slaves.iter_mut() {
+        let detect_changes = slave.detect_input_changes;
+        let forced_inputs = slave.forced_inputs;
+        let mut changed = false;
+        //RxPDOの後にTxPDO(入力)を並べている
+        offset += slave.rx_pdo_size() as usize;
+        if let Some(ref mut mapping) = slave.tx_pdo_mapping {
+            for pdo in mapping
+                .iter_mut()
+                .flat_map(|pdo_mapping| pdo_mapping.entries.iter_mut())
+            {
+                let byte_length = pdo.byte_length as usize;
+                let data = &datagram[offset..offset + byte_length];
+                if let Some(value) = forced_value(forced_inputs, pdo) {
+                    // 強制中の入力はスレーブの値の代わりにシミュレーション値を渡す
+                    for (j, b) in pdo.data.iter_mut().enumerate() {
+                        let v = value.get(j).copied().unwrap_or(data[j]);
+                        changed |= detect_changes && *b != v;
+                        *b = v;
+                    }
+                } else {
+                    // 上書きする前に前回値と比べる
+                    changed |= detect_changes && *pdo.data != *data;
+                    pdo.data.copy_from_slice(data);
                 }
+                offset += byte_length;
             }
         }
+        // アプリケーションが読むまでフラグを残す
+        slave.inputs_changed |= changed;
+    }
+}
+
+/// Writes the fail-safe outputs of `slaves` into the output areas of the logical image
+/// `datagram` and leaves the input areas untouched, in the layout of `process_cyclic_data`.
+pub(crate) fn process_fail_safe_data(datagram: &mut [u8], slaves: &[Slave]) {
+    let mut offset = 0;
+    for slave in slaves.iter() {
+        //process_cyclic_dataで出力を書き込む領域をフェイルセーフ値で上書きする
+        let output_size = slave.rx_pdo_size() as usize;
+        let mut pattern = slave.fail_safe_outputs.unwrap_or(&[]).iter();
+        for b in datagram[offset..offset + output_size].iter_mut() {
+            *b = pattern.next().copied().unwrap_or(0);
+        }
+        offset += output_size + slave.tx_pdo_size() as usize;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::{boxed::Box, vec, vec::Vec};
+
+    pub(crate) fn leak<'a, T>(values: Vec<T>) -> &'a mut [T] {
+        Box::leak(values.into_boxed_slice())
+    }
+
+    pub(crate) fn mapping<'a>(index: u16, entries: &[(u16, &[u8])]) -> &'a mut [PDOMapping<'a>] {
+        let entries = entries
+            .iter()
+            .map(|(entry_index, data)| PDOEntry::new(*entry_index, 1, leak(data.to_vec())))
+            .collect();
+        leak(vec![PDOMapping::new(index, leak(entries))])
+    }
+
+    pub(crate) fn slave<'a>(outputs: &[(u16, &[u8])], inputs: &[(u16, &[u8])]) -> Slave<'a> {
+        let mut slave = Slave::default();
+        slave.set_rx_pdo_mapping(mapping(0x1600, outputs));
+        slave.set_tx_pdo_mapping(mapping(0x1A00, inputs));
+        slave
+    }
+
+    pub(crate) fn entry_data<'a>(mapping: &'a Option<&mut [PDOMapping]>, n: usize) -> &'a [u8] {
+        mapping.as_ref().unwrap()[0].entries[n].data
+    }
+
+    fn entry_data_mut<'a>(mapping: &'a mut Option<&mut [PDOMapping]>, n: usize) -> &'a mut [u8] {
+        mapping.as_mut().unwrap()[0].entries[n].data
+    }
+
+    #[test]
+    fn cyclic_data_writes_outputs_before_inputs() {
+        let mut slaves = [
+            slave(&[(0x7000, &[1, 2])], &[(0x6000, &[0])]),
+            slave(&[(0x7000, &[3])], &[(0x6000, &[0, 0]), (0x6001, &[0])]),
+        ];
+        let mut image = [0, 0, 0xA1, 0, 0xB1, 0xB2, 0xB3];
+        process_cyclic_data(&mut image, &mut slaves);
+        assert_eq!(image, [1, 2, 0xA1, 3, 0xB1, 0xB2, 0xB3]);
+        assert_eq!(entry_data(&slaves[0].tx_pdo_mapping, 0), [0xA1]);
+        assert_eq!(entry_data(&slaves[1].tx_pdo_mapping, 0), [0xB1, 0xB2]);
+        assert_eq!(entry_data(&slaves[1].tx_pdo_mapping, 1), [0xB3]);
+        assert_eq!(entry_data(&slaves[0].rx_pdo_mapping, 0), [1, 2]);
+    }
+
+    #[test]
+    fn fail_safe_data_overwrites_only_outputs() {
+        let mut slaves = [
+            slave(&[(0x7000, &[1, 2])], &[(0x6000, &[0])]),
+            slave(&[(0x7000, &[3])], &[(0x6000, &[0, 0])]),
+        ];
+        slaves[0].set_fail_safe_outputs(&[0xF0]);
+        let mut image = [0, 0, 0xA1, 0, 0xB1, 0xB2];
+        process_cyclic_data(&mut image, &mut slaves);
+        process_fail_safe_data(&mut image, &slaves);
+        assert_eq!(image, [0xF0, 0, 0xA1, 0, 0xB1, 0xB2]);
+    }
+
+    #[test]
+    fn mapping_report_matches_cyclic_data() {
+        let mut slaves = [
+            slave(&[(0x7000, &[1, 2])], &[(0x6000, &[0])]),
+            slave(&[(0x7000, &[3])], &[(0x6000, &[0, 0]), (0x6001, &[0])]),
+        ];
+        slaves[0].logical_address = Some(0x1000);
+        slaves[1].logical_address = Some(0x1003);
+        slaves[1].position_address = 1;
+        let image = ProcessImage {
+            logical_start_address: 0x1000,
+            size: 7,
+            expected_wkc: 6,
+            use_lrw: true,
+        };
+        let mut data = [0, 0, 0xA1, 0, 0xB1, 0xB2, 0xB3];
+        process_cyclic_data(&mut data, &mut slaves);
+        let directions: Vec<_> = mapping_report(&slaves, &image)
+            .map(|entry| (entry.slave, entry.direction))
+            .collect();
+        assert_eq!(
+            directions,
+            [
+                (0, PdoDirection::Output),
+                (0, PdoDirection::Input),
+                (1, PdoDirection::Output),
+                (1, PdoDirection::Input),
+                (1, PdoDirection::Input),
+            ]
+        );
+        for entry in mapping_report(&slaves, &image) {
+            let slave = slaves
+                .iter()
+                .find(|slave| slave.position_address == entry.slave)
+                .unwrap();
+            let mapping = match entry.direction {
+                PdoDirection::Output => &slave.rx_pdo_mapping,
+                PdoDirection::Input => &slave.tx_pdo_mapping,
+            };
+            let pdo = mapping.as_ref().unwrap()[0]
+                .entries
+                .iter()
+                .find(|pdo| pdo.index == entry.index)
+                .unwrap();
+            let start = entry.bit_offset as usize / 8;
+            let end = start + entry.bit_length as usize / 8;
+            assert_eq!(data[start..end], *pdo.data);
+        }
+    }
+
+    #[test]
+    fn change_detection_compares_inputs() {
+        let mut slaves = [slave(&[(0x7000, &[1])], &[(0x6000, &[0])])];
+        slaves[0].set_input_change_detection(true);
+        let mut image = [0, 0];
+        process_cyclic_data(&mut image, &mut slaves);
+        assert!(!slaves[0].take_inputs_changed());
+        // 出力の変化は入力の変化として扱わない
+        entry_data_mut(&mut slaves[0].rx_pdo_mapping, 0)[0] = 2;
+        process_cyclic_data(&mut image, &mut slaves);
+        assert!(!slaves[0].take_inputs_changed());
+        image[1] = 0x55;
+        process_cyclic_data(&mut image, &mut slaves);
+        assert!(slaves[0].take_inputs_changed());
+        assert!(!slaves[0].take_inputs_changed());
+    }
+
+    #[test]
+    fn forces_override_process_data() {
+        static FORCED_OUTPUTS: [ForcedEntry; 1] = [ForcedEntry::new(0x7000, 1, &[0xF1])];
+        static FORCED_INPUTS: [ForcedEntry; 1] = [ForcedEntry::new(0x6000, 1, &[0xE1])];
+        let mut slaves = [slave(&[(0x7000, &[1, 2])], &[(0x6000, &[0, 0])])];
+        slaves[0].set_forced_outputs(&FORCED_OUTPUTS);
+        slaves[0].set_forced_inputs(&FORCED_INPUTS);
+        let mut image = [0, 0, 0xA1, 0xA2];
+        process_cyclic_data(&mut image, &mut slaves);
+        // 強制値より短い部分はスレーブ、アプリケーションの値のまま
+        assert_eq!(image[..2], [0xF1, 2]);
+        assert_eq!(entry_data(&slaves[0].tx_pdo_mapping, 0), [0xE1, 0xA2]);
+        assert_eq!(entry_data(&slaves[0].rx_pdo_mapping, 0), [1, 2]);
+
+        slaves[0].clear_forces();
+        process_cyclic_data(&mut image, &mut slaves);
+        assert_eq!(image[..2], [1, 2]);
+        assert_eq!(entry_data(&slaves[0].tx_pdo_mapping, 0), [0xA1, 0xA2]);
     }
 }
@@ -0,0 +1,79 @@
+use crate::master::MasterStatistics;
+use crate::network::NetworkDescription;
+use crate::slave_status::*;
+
+/// State of one slave in a `BusSnapshot`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlaveSnapshot {
+    pub configured_address: u16,
+    pub al_state: AlState,
+    pub has_error: bool,
+    /// System time difference in ns (0x092C), set with `BusSnapshot::set_dc_deviation`.
+    pub dc_deviation: Option<i32>,
+}
+
+impl Default for SlaveSnapshot {
+    fn default() -> Self {
+        Self {
+            configured_address: 0,
+            al_state: AlState::Invalid,
+            has_error: false,
+            dc_deviation: None,
+        }
+    }
+}
+
+/// Copy of the bus state of up to `N` slaves, which the application can take periodically and
+/// serve over its own protocol without holding references to the master.
+#[derive(Debug, Clone)]
+pub struct BusSnapshot<const N: usize> {
+    /// Number of slaves in the network. Only the first `N` are in `slaves`.
+    pub num_slaves: u16,
+    pub slaves: [SlaveSnapshot; N],
+    pub statistics: MasterStatistics,
+}
+
+impl<const N: usize> BusSnapshot<N> {
+    pub fn new(statistics: &MasterStatistics, network: &NetworkDescription) -> Self {
+        let mut slaves = [SlaveSnapshot::default(); N];
+        for (snapshot, slave) in slaves.iter_mut().zip(network.slaves()) {
+            *snapshot = SlaveSnapshot {
+                configured_address: slave.configured_address,
+                al_state: slave.al_state,
+                has_error: slave.error.is_some(),
+                dc_deviation: None,
+            };
+        }
+        Self {
+            num_slaves: network.num_slaves() as u16,
+            slaves,
+            statistics: statistics.clone(),
+        }
+    }
+
+    /// Records the system time difference read with
+    /// `EtherCATInterface::read_dc_system_time_difference`.
+    pub fn set_dc_deviation(&mut self, position: u16, deviation_ns: i32) {
+        if let Some(slave) = self.slaves.get_mut(position as usize) {
+            slave.dc_deviation = Some(deviation_ns);
+        }
+    }
+
+    /// Largest absolute system time difference of the recorded slaves.
+    pub fn max_dc_deviation(&self) -> Option<u32> {
+        self.slaves
+            .iter()
+            .filter_map(|slave| slave.dc_deviation)
+            .map(|deviation| deviation.unsigned_abs())
+            .max()
+    }
+
+    /// Number of slaves in `slaves`.
+    pub fn len(&self) -> usize {
+        (self.num_slaves as usize).min(N)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
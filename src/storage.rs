@@ -0,0 +1,63 @@
+//! Storage of the collections of the units.
+//!
+//! The units store their lists through `VecStorage`, implemented for `heapless::Vec` with a
+//! fixed capacity and, with the `alloc` feature, for `alloc::vec::Vec` growing on demand.
+
+/// List used by the units, so that hosts with an allocator are not limited by
+/// compile-time capacities.
+pub trait VecStorage<T> {
+    /// Returns `value` back if the storage is full.
+    fn push(&mut self, value: T) -> Result<(), T>;
+
+    fn as_slice(&self) -> &[T];
+
+    fn as_mut_slice(&mut self) -> &mut [T];
+
+    fn clear(&mut self);
+
+    fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T, const N: usize> VecStorage<T> for heapless::Vec<T, N> {
+    fn push(&mut self, value: T) -> Result<(), T> {
+        heapless::Vec::push(self, value)
+    }
+
+    fn as_slice(&self) -> &[T] {
+        self
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [T] {
+        self
+    }
+
+    fn clear(&mut self) {
+        heapless::Vec::clear(self)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<T> VecStorage<T> for alloc::vec::Vec<T> {
+    fn push(&mut self, value: T) -> Result<(), T> {
+        alloc::vec::Vec::push(self, value);
+        Ok(())
+    }
+
+    fn as_slice(&self) -> &[T] {
+        self
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [T] {
+        self
+    }
+
+    fn clear(&mut self) {
+        alloc::vec::Vec::clear(self)
+    }
+}
@@ -0,0 +1,263 @@
+use crate::arch::*;
+use crate::interface::*;
+use crate::sdo::*;
+use crate::slave_status::*;
+use embedded_hal::timer::CountDown;
+use fugit::*;
+
+pub const ERROR_SETTINGS_INDEX: u16 = 0x10F1;
+pub const SM_OUTPUT_PARAMETER_INDEX: u16 = 0x1C32;
+pub const SM_INPUT_PARAMETER_INDEX: u16 = 0x1C33;
+
+const LOCAL_ERROR_REACTION: u8 = 1;
+const SYNC_ERROR_COUNTER_LIMIT: u8 = 2;
+
+const SYNCHRONIZATION_TYPE: u8 = 1;
+const CYCLE_TIME: u8 = 2;
+const SHIFT_TIME: u8 = 3;
+const SYNC_TYPES_SUPPORTED: u8 = 4;
+const MINIMUM_CYCLE_TIME: u8 = 5;
+const SM_EVENT_MISSED: u8 = 0x0B;
+const CYCLE_TIME_TOO_SMALL: u8 = 0x0C;
+const SHIFT_TIME_TOO_SHORT: u8 = 0x0D;
+const SYNC_ERROR: u8 = 0x20;
+
+/// Sync manager parameter object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmParameters {
+    /// 0x1C32, the outputs of sync manager 2.
+    Outputs,
+    /// 0x1C33, the inputs of sync manager 3.
+    Inputs,
+}
+
+impl SmParameters {
+    pub fn index(&self) -> u16 {
+        match self {
+            Self::Outputs => SM_OUTPUT_PARAMETER_INDEX,
+            Self::Inputs => SM_INPUT_PARAMETER_INDEX,
+        }
+    }
+}
+
+/// Synchronization type (sub-index 1) of the sync manager parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncMode {
+    FreeRun = 0x00,
+    /// Synchronous with the event of the sync manager itself.
+    SmSynchronous = 0x01,
+    DcSync0 = 0x02,
+    DcSync1 = 0x03,
+    /// Inputs synchronous with the sync manager 2 event.
+    Sm2Synchronous = 0x22,
+    Unknown = 0xFFFF,
+}
+
+impl From<u16> for SyncMode {
+    fn from(value: u16) -> Self {
+        match value {
+            0x00 => Self::FreeRun,
+            0x01 => Self::SmSynchronous,
+            0x02 => Self::DcSync0,
+            0x03 => Self::DcSync1,
+            0x22 => Self::Sm2Synchronous,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// Timing of a sync manager read from its parameter object.
+/// Entries the slave does not have are None.
+#[derive(Debug, Clone, Default)]
+pub struct SyncTiming {
+    pub sync_mode: Option<u16>,
+    /// Cycle time in ns.
+    pub cycle_time: Option<u32>,
+    /// Shift time in ns.
+    pub shift_time: Option<u32>,
+    /// Bit field of the synchronization types the slave supports.
+    pub sync_types_supported: Option<u16>,
+    /// Minimum cycle time in ns.
+    pub minimum_cycle_time: Option<u32>,
+}
+
+/// Synchronization diagnostics of a sync manager.
+/// Entries the slave does not have are None.
+#[derive(Debug, Clone, Default)]
+pub struct SyncDiagnostics {
+    /// Sync manager events the application missed.
+    pub sm_event_missed: Option<u16>,
+    /// Cycles in which the application did not finish within the cycle time.
+    pub cycle_time_too_small: Option<u16>,
+    /// Cycles in which the shift time was too short for the outputs.
+    pub shift_time_too_short: Option<u16>,
+    /// Set if the sync error counter of 0x10F1 reached its limit.
+    pub sync_error: Option<bool>,
+}
+
+/// Helpers for the standard synchronization objects, the error settings (0x10F1) and
+/// the sync manager parameters (0x1C32 and 0x1C33), e.g. when tuning DC-synchronized drives.
+/// Most slaves accept writes to these objects only in PreOp.
+pub struct SyncParameterClient<'a, 'b, D, T, U>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+    U: CountDown<Time = MicrosDurationU32>,
+{
+    sdo: SdoClient<'a, 'b, D, T, U>,
+}
+
+impl<'a, 'b, D, T, U> SyncParameterClient<'a, 'b, D, T, U>
+where
+    D: Device,
+    T: CountDown<Time = MicrosDurationU32>,
+    U: CountDown<Time = MicrosDurationU32>,
+{
+    pub fn new(iface: &'a mut EtherCATInterface<'b, D, T>, timer: &'a mut U) -> Self {
+        Self {
+            sdo: SdoClient::new(iface, timer),
+        }
+    }
+
+    pub fn sdo(&mut self) -> &mut SdoClient<'a, 'b, D, T, U> {
+        &mut self.sdo
+    }
+
+    pub fn set_sync_mode(
+        &mut self,
+        slave: &mut Slave,
+        parameters: SmParameters,
+        mode: SyncMode,
+    ) -> Result<(), SdoError> {
+        let value = mode as u16;
+        self.sdo.download(
+            slave,
+            parameters.index(),
+            SYNCHRONIZATION_TYPE,
+            &value.to_le_bytes(),
+        )
+    }
+
+    pub fn sync_mode(
+        &mut self,
+        slave: &mut Slave,
+        parameters: SmParameters,
+    ) -> Result<SyncMode, SdoError> {
+        let value = self.read(slave, parameters.index(), SYNCHRONIZATION_TYPE)?;
+        Ok(SyncMode::from(value as u16))
+    }
+
+    /// Sets the cycle time in ns.
+    pub fn set_cycle_time(
+        &mut self,
+        slave: &mut Slave,
+        parameters: SmParameters,
+        cycle_time: u32,
+    ) -> Result<(), SdoError> {
+        self.sdo.download(
+            slave,
+            parameters.index(),
+            CYCLE_TIME,
+            &cycle_time.to_le_bytes(),
+        )
+    }
+
+    /// Cycle time in ns.
+    pub fn cycle_time(
+        &mut self,
+        slave: &mut Slave,
+        parameters: SmParameters,
+    ) -> Result<u32, SdoError> {
+        self.read(slave, parameters.index(), CYCLE_TIME)
+    }
+
+    /// Reads the timing entries. Entries the slave aborts are left None.
+    pub fn timing(
+        &mut self,
+        slave: &mut Slave,
+        parameters: SmParameters,
+    ) -> Result<SyncTiming, SdoError> {
+        let index = parameters.index();
+        Ok(SyncTiming {
+            sync_mode: self.read_u16(slave, index, SYNCHRONIZATION_TYPE)?,
+            cycle_time: self.read_optional(slave, index, CYCLE_TIME)?,
+            shift_time: self.read_optional(slave, index, SHIFT_TIME)?,
+            sync_types_supported: self.read_u16(slave, index, SYNC_TYPES_SUPPORTED)?,
+            minimum_cycle_time: self.read_optional(slave, index, MINIMUM_CYCLE_TIME)?,
+        })
+    }
+
+    /// Reads the diagnostic counters. Entries the slave aborts are left None.
+    pub fn diagnostics(
+        &mut self,
+        slave: &mut Slave,
+        parameters: SmParameters,
+    ) -> Result<SyncDiagnostics, SdoError> {
+        let index = parameters.index();
+        Ok(SyncDiagnostics {
+            sm_event_missed: self.read_u16(slave, index, SM_EVENT_MISSED)?,
+            cycle_time_too_small: self.read_u16(slave, index, CYCLE_TIME_TOO_SMALL)?,
+            shift_time_too_short: self.read_u16(slave, index, SHIFT_TIME_TOO_SHORT)?,
+            sync_error: self
+                .read_optional(slave, index, SYNC_ERROR)?
+                .map(|v| v & 1 != 0),
+        })
+    }
+
+    /// Sets the limit of the sync error counter. The counter is incremented by 3 for each
+    /// missed cycle and decremented by 1 for each good one; the slave falls to SafeOp when
+    /// it reaches the limit.
+    pub fn set_sync_error_counter_limit(
+        &mut self,
+        slave: &mut Slave,
+        limit: u16,
+    ) -> Result<(), SdoError> {
+        self.sdo.download(
+            slave,
+            ERROR_SETTINGS_INDEX,
+            SYNC_ERROR_COUNTER_LIMIT,
+            &limit.to_le_bytes(),
+        )
+    }
+
+    pub fn sync_error_counter_limit(&mut self, slave: &mut Slave) -> Result<u16, SdoError> {
+        let value = self.read(slave, ERROR_SETTINGS_INDEX, SYNC_ERROR_COUNTER_LIMIT)?;
+        Ok(value as u16)
+    }
+
+    /// Local error reaction (0x10F1:01), specific to the device profile.
+    pub fn local_error_reaction(&mut self, slave: &mut Slave) -> Result<u32, SdoError> {
+        self.read(slave, ERROR_SETTINGS_INDEX, LOCAL_ERROR_REACTION)
+    }
+
+    fn read(&mut self, slave: &mut Slave, index: u16, sub_index: u8) -> Result<u32, SdoError> {
+        // 1から4バイトの値を下位から詰める
+        let mut buf = [0; 4];
+        self.sdo.upload(slave, index, sub_index, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn read_optional(
+        &mut self,
+        slave: &mut Slave,
+        index: u16,
+        sub_index: u8,
+    ) -> Result<Option<u32>, SdoError> {
+        match self.read(slave, index, sub_index) {
+            Ok(value) => Ok(Some(value)),
+            Err(SdoError::Abort(_)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn read_u16(
+        &mut self,
+        slave: &mut Slave,
+        index: u16,
+        sub_index: u8,
+    ) -> Result<Option<u16>, SdoError> {
+        Ok(self
+            .read_optional(slave, index, sub_index)?
+            .map(|v| v as u16))
+    }
+}
@@ -14,12 +14,10 @@ pub fn check_wkc<B: AsRef<[u8]>>(
     }
 }
 
+// 各スレーブはADPをインクリメントして転送し、受け取ったADPが0のスレーブがアクセスされる。
+// そのためn番目のスレーブには-nを指定する。
 pub fn get_ap_adp(slave_number: u16) -> u16 {
-    if slave_number == 0 {
-        0
-    } else {
-        0xFFFF - (slave_number - 1)
-    }
+    slave_number.wrapping_neg()
 }
 
 // TODO: リードレジスターマクロを作る。
@@ -0,0 +1,166 @@
+use crate::master::Command;
+use crate::register::{application::*, datalink::*};
+use crate::slave_status::*;
+use crate::storage::VecStorage;
+use crate::wkc::expected_wkc;
+
+/// Number of slaves a `WatchdogMonitor` can watch.
+pub const WATCHDOG_MONITOR_MAX_SLAVES: usize = 32;
+
+/// What `WatchdogMonitor` does when the process data watchdog of a slave expired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogPolicy {
+    /// Only counts the expiration.
+    ReportOnly,
+    /// Acknowledges the error and requests Operational again, up to `max_attempts` times
+    /// per expiration.
+    ReenterOp { max_attempts: u8 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WatchdogPhase {
+    Check,
+    Acknowledge(u16),
+    Confirm(u16),
+}
+
+/// Default storage of the watched slaves.
+pub type WatchdogSlaves = heapless::Vec<u16, WATCHDOG_MONITOR_MAX_SLAVES>;
+
+/// Cyclic unit that reads the process data watchdog status (0x0440) of the output sync managers
+/// of one slave per cycle, and brings slaves that fell back to SafeOp after a master stall
+/// back to Operational according to the policy.
+/// The station addresses of the watched slaves are kept in `S`.
+#[derive(Debug, Clone)]
+pub struct WatchdogMonitor<S = WatchdogSlaves> {
+    slaves: S,
+    policy: WatchdogPolicy,
+    next: usize,
+    phase: WatchdogPhase,
+    attempts: u8,
+    buffer: [u8; ALControl::SIZE],
+    expirations: u32,
+    last_expired: Option<u16>,
+    failed: Option<u16>,
+}
+
+impl WatchdogMonitor {
+    pub fn new(policy: WatchdogPolicy) -> Self {
+        Self::with_storage(policy, WatchdogSlaves::new())
+    }
+}
+
+impl<S: VecStorage<u16>> WatchdogMonitor<S> {
+    /// Same as `new`, with the station addresses stored in `slaves`, e.g. an `alloc::vec::Vec`
+    /// to watch any number of slaves.
+    pub fn with_storage(policy: WatchdogPolicy, mut slaves: S) -> Self {
+        slaves.clear();
+        Self {
+            slaves,
+            policy,
+            next: 0,
+            phase: WatchdogPhase::Check,
+            attempts: 0,
+            buffer: [0; ALControl::SIZE],
+            expirations: 0,
+            last_expired: None,
+            failed: None,
+        }
+    }
+
+    /// Watches a slave with outputs. Returns false if the monitor is full.
+    pub fn add_slave(&mut self, slave: &Slave) -> bool {
+        self.slaves.push(slave.configured_address).is_ok()
+    }
+
+    /// Number of expirations detected.
+    pub fn expirations(&self) -> u32 {
+        self.expirations
+    }
+
+    /// Station address of the slave whose watchdog expired last.
+    pub fn last_expired(&self) -> Option<u16> {
+        self.last_expired
+    }
+
+    /// Station address of the slave that did not return to Operational within `max_attempts`.
+    pub fn failed(&self) -> Option<u16> {
+        self.failed
+    }
+
+    pub(crate) fn process(&mut self) -> Option<(Command, &[u8])> {
+        self.buffer = [0; ALControl::SIZE];
+        let command = match self.phase {
+            WatchdogPhase::Check => {
+                let address = *self.slaves.as_slice().get(self.next)?;
+                Command::new_fprd(address, SyncManagerChannelWDStatus::ADDRESS)
+            }
+            WatchdogPhase::Acknowledge(address) => {
+                // エラーを確認しつつOpを要求する
+                let mut al_control = ALControl(&mut self.buffer);
+                al_control.set_state(AlState::Operational as u8);
+                al_control.set_acknowledge(true);
+                Command::new_fpwr(address, ALControl::ADDRESS)
+            }
+            WatchdogPhase::Confirm(address) => Command::new_fprd(address, ALStatus::ADDRESS),
+        };
+        Some((command, &self.buffer))
+    }
+
+    pub(crate) fn receive(&mut self, command: Command, data: &[u8], wkc: u16) -> bool {
+        let is_ok = wkc == expected_wkc(command.command_type(), 1) && data.len() >= ALControl::SIZE;
+        match self.phase {
+            WatchdogPhase::Check => {
+                // 応答がなくても次のスレーブに進み、1台が止まっても他の監視を続ける
+                let address = match self.slaves.as_slice().get(self.next) {
+                    Some(address) => *address,
+                    None => return false,
+                };
+                self.next = (self.next + 1) % self.slaves.len();
+                if !is_ok {
+                    return false;
+                }
+                // 0は期限切れ
+                if !SyncManagerChannelWDStatus(data).sm_channel_wd_status() {
+                    self.expirations = self.expirations.wrapping_add(1);
+                    self.last_expired = Some(address);
+                    if let WatchdogPolicy::ReenterOp { .. } = self.policy {
+                        self.attempts = 0;
+                        self.phase = WatchdogPhase::Acknowledge(address);
+                    }
+                }
+            }
+            WatchdogPhase::Acknowledge(address) => {
+                if !is_ok {
+                    return false;
+                }
+                self.phase = WatchdogPhase::Confirm(address);
+            }
+            WatchdogPhase::Confirm(address) => {
+                if is_ok {
+                    let al_status = ALStatus(data);
+                    if AlState::from(al_status.state()) == AlState::Operational
+                        && !al_status.change_err()
+                    {
+                        self.phase = WatchdogPhase::Check;
+                        return true;
+                    }
+                }
+                // 応答がない場合も1回の試行として数え、止まったスレーブで監視が止まらないようにする
+                self.attempts = self.attempts.saturating_add(1);
+                let max_attempts = match self.policy {
+                    WatchdogPolicy::ReenterOp { max_attempts } => max_attempts,
+                    WatchdogPolicy::ReportOnly => 0,
+                };
+                self.phase = if self.attempts < max_attempts {
+                    WatchdogPhase::Acknowledge(address)
+                } else {
+                    self.failed = Some(address);
+                    WatchdogPhase::Check
+                };
+                return is_ok;
+            }
+        }
+        true
+    }
+}
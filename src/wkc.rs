@@ -0,0 +1,49 @@
+//! Working counter rules: how much each slave addressed by a datagram increments the WKC.
+
+use crate::error::CommonError;
+use crate::packet::*;
+use crate::util::check_wkc;
+
+/// Increment of a slave that reads the addressed memory.
+pub const WKC_READ: u16 = 1;
+/// Increment of a slave that writes the addressed memory.
+pub const WKC_WRITE: u16 = 2;
+
+/// Increment of one addressed slave for `command`, e.g. 1 for FPRD and FPWR, 3 for FPRW.
+/// For logical commands it is the increment of a slave with FMMUs for reading and writing;
+/// see `process_data_wkc` for slaves mapping only one direction.
+pub fn wkc_per_slave(command: CommandType) -> u16 {
+    use CommandType::*;
+    match command {
+        // 読み出しまたは書き込みだけのコマンドは1ずつ増える
+        APRD | FPRD | BRD | LRD | APWR | FPWR | BWR | LWR => 1,
+        // 読み書きするコマンドは読み出しで1、書き込みで2増える
+        APRW | FPRW | BRW | LRW => WKC_READ + WKC_WRITE,
+        // 読み出すスレーブも書き込むスレーブも1増える
+        ARMW | FRMW => 1,
+        NOP | Invalid => 0,
+    }
+}
+
+/// Expected WKC of `command` addressing `slaves` slaves, e.g. all slaves for a broadcast
+/// or the DC slaves for the FRMW of the system time.
+pub fn expected_wkc(command: CommandType, slaves: u16) -> u16 {
+    wkc_per_slave(command).saturating_mul(slaves)
+}
+
+/// Increment of a slave to the LRW of the process image: `WKC_WRITE` if it has outputs
+/// and `WKC_READ` if it has inputs.
+pub fn process_data_wkc(has_outputs: bool, has_inputs: bool) -> u16 {
+    let write = if has_outputs { WKC_WRITE } else { 0 };
+    let read = if has_inputs { WKC_READ } else { 0 };
+    write + read
+}
+
+/// Checks the WKC of `pdu` against `expected_wkc` of its command addressing `slaves` slaves.
+pub fn check_command_wkc<B: AsRef<[u8]>>(
+    pdu: &EtherCATPDU<B>,
+    slaves: u16,
+) -> Result<(), CommonError> {
+    let command = CommandType::new(pdu.command_type());
+    check_wkc(pdu, expected_wkc(command, slaves))
+}
@@ -0,0 +1,194 @@
+//! Device and helpers driving the master against `EscEmulator`.
+
+#![allow(dead_code)]
+
+use embedded_hal::timer::CountDown;
+use ethercat_master::arch::Device;
+use ethercat_master::emulation::EscEmulator;
+use ethercat_master::ethercat_frame::EtherCATFrame;
+use ethercat_master::initializer::SlaveInitilizer;
+use ethercat_master::interface::EtherCATInterface;
+use ethercat_master::packet::CommandType;
+use ethercat_master::sii::sii_config_crc;
+use ethercat_master::slave_status::Slave;
+use fugit::MicrosDurationU32;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+pub const BUFFER_SIZE: usize = 1500;
+
+/// Mailboxes in the process memory, 128 bytes each.
+pub const MAILBOX_OUT_ADDRESS: u16 = 0x1000;
+pub const MAILBOX_IN_ADDRESS: u16 = 0x1080;
+pub const MAILBOX_SIZE: u16 = 0x0080;
+
+/// Line of emulated slaves, shared by the interfaces created with `interface`, e.g. one for
+/// the initialization and one for the cyclic exchange. Each sent frame passes all emulators
+/// in order and is returned by the next `recv`.
+pub struct Wire {
+    pub slaves: Vec<EscEmulator<'static>>,
+    /// Frames as sent by the master.
+    pub sent: Vec<Vec<u8>>,
+    /// Number of the next frames lost on the way back.
+    pub drop_frames: usize,
+    frames: VecDeque<Vec<u8>>,
+}
+
+pub fn wire(slaves: usize) -> Rc<RefCell<Wire>> {
+    let sii: &'static [u8] = Box::leak(Box::new(sii_image()));
+    Rc::new(RefCell::new(Wire {
+        slaves: (0..slaves).map(|_| EscEmulator::new(sii)).collect(),
+        sent: Vec::new(),
+        drop_frames: 0,
+        frames: VecDeque::new(),
+    }))
+}
+
+pub struct EmulatedDevice {
+    tx: Vec<u8>,
+    wire: Rc<RefCell<Wire>>,
+}
+
+impl Device for EmulatedDevice {
+    fn send<R, F>(&mut self, len: usize, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut [u8]) -> Option<R>,
+    {
+        let result = f(&mut self.tx[..len]);
+        let mut frame = self.tx[..len].to_vec();
+        let mut wire = self.wire.borrow_mut();
+        wire.sent.push(frame.clone());
+        for slave in wire.slaves.iter_mut() {
+            slave.process_frame(&mut frame);
+        }
+        if wire.drop_frames > 0 {
+            wire.drop_frames -= 1;
+        } else {
+            wire.frames.push_back(frame);
+        }
+        result
+    }
+
+    fn recv<R, F>(&mut self, f: F) -> Option<R>
+    where
+        F: FnOnce(&[u8]) -> Option<R>,
+    {
+        // 届かないフレームは空のフレームで表す。インターフェースは他のトラフィックとして
+        // 読み飛ばし、タイムアウトまで待つ
+        let frame = self
+            .wire
+            .borrow_mut()
+            .frames
+            .pop_front()
+            .unwrap_or_default();
+        f(&frame)
+    }
+
+    fn max_transmission_unit(&self) -> usize {
+        BUFFER_SIZE
+    }
+}
+
+/// Expires after a fixed number of polls, so that timeouts do not depend on the host.
+pub struct PollTimer(u32);
+
+impl CountDown for PollTimer {
+    type Time = MicrosDurationU32;
+
+    fn start<C: Into<Self::Time>>(&mut self, _count: C) {
+        self.0 = 0;
+    }
+
+    fn wait(&mut self) -> nb::Result<(), void::Void> {
+        self.0 += 1;
+        if self.0 > 100 {
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+}
+
+pub fn timer() -> PollTimer {
+    PollTimer(0)
+}
+
+pub type EmulatedInterface<'a> = EtherCATInterface<'a, EmulatedDevice, PollTimer>;
+
+/// Interface to the slaves of `wire`. It is leaked, since the initializer and the master
+/// borrow it for its whole life.
+pub fn interface<'a>(wire: &Rc<RefCell<Wire>>) -> &'a mut EmulatedInterface<'a> {
+    let device = EmulatedDevice {
+        tx: vec![0; BUFFER_SIZE],
+        wire: wire.clone(),
+    };
+    let tx_buffer = leak(vec![0; BUFFER_SIZE]);
+    let rx_buffer = leak(vec![0; BUFFER_SIZE]);
+    Box::leak(Box::new(EtherCATInterface::new(
+        device,
+        timer(),
+        tx_buffer,
+        rx_buffer,
+    )))
+}
+
+/// Station address given to the slave at `position` by `init`.
+pub fn station_address(position: u16) -> u16 {
+    0x1001 + position
+}
+
+/// Scans the slaves of `wire` and gives them station addresses.
+pub fn init<'a>(wire: &Rc<RefCell<Wire>>) -> Vec<Slave<'a>> {
+    let num_slaves = wire.borrow().slaves.len();
+    let mut slaves: Vec<Slave> = (0..num_slaves).map(|_| Slave::default()).collect();
+    let mut timer = timer();
+    let mut initializer = SlaveInitilizer::new(interface(wire), &mut timer);
+    initializer.init_slaves(&mut slaves).unwrap();
+    for (position, slave) in slaves.iter_mut().enumerate() {
+        initializer
+            .set_station_address(slave, station_address(position as u16))
+            .unwrap();
+    }
+    slaves
+}
+
+/// Data of the first datagram with `command` in `frame`.
+pub fn datagram_data(frame: &[u8], command: CommandType) -> Option<Vec<u8>> {
+    let frame = EtherCATFrame::new(frame)?;
+    let data = frame
+        .iter_dlpdu()
+        .find(|pdu| CommandType::new(pdu.command_type()) == command)
+        .map(|pdu| pdu.data().to_vec());
+    data
+}
+
+pub fn leak<'a, T>(values: Vec<T>) -> &'a mut [T] {
+    Box::leak(values.into_boxed_slice())
+}
+
+/// EEPROM with the identity and the mailbox configuration of a CoE slave.
+pub fn sii_image() -> [u8; 256] {
+    let mut sii = [0xFF; 256];
+    sii[..0x40 * 2].iter_mut().for_each(|b| *b = 0);
+    let mut write_word = |word: usize, value: u16| {
+        sii[word * 2..word * 2 + 2].copy_from_slice(&value.to_le_bytes());
+    };
+    // Product code
+    write_word(0x000A, 0x0001);
+    // Standard mailbox
+    write_word(0x0018, MAILBOX_OUT_ADDRESS);
+    write_word(0x0019, MAILBOX_SIZE);
+    write_word(0x001A, MAILBOX_IN_ADDRESS);
+    write_word(0x001B, MAILBOX_SIZE);
+    // CoE
+    write_word(0x001C, 0x0004);
+    // Size in kbit - 1 and version
+    write_word(0x003E, 0x0001);
+    write_word(0x003F, 0x0001);
+    // カテゴリなし
+    write_word(0x0040, 0xFFFF);
+    // コンフィグ領域のチェックサム
+    sii[0x0007 * 2] = sii_config_crc(&sii);
+    sii
+}
@@ -0,0 +1,303 @@
+#![cfg(feature = "emulation")]
+
+mod common;
+
+use common::*;
+use ethercat_master::al_state_transfer::ALStateTransfer;
+use ethercat_master::ethercat_frame::EtherCATFrame;
+use ethercat_master::initializer::SlaveInitilizer;
+use ethercat_master::interface::SlaveAddress;
+use ethercat_master::mailbox::Mailbox;
+use ethercat_master::master::*;
+use ethercat_master::network::{NetworkDescription, SlaveGroup};
+use ethercat_master::packet::{AbortCode, CommandType, MailboxType, SDOCommand};
+use ethercat_master::process_data::ProcessDataUnit;
+use ethercat_master::register::application::ALStatus;
+use ethercat_master::register::datalink::SIIControl;
+use ethercat_master::sdo::{SdoClient, SdoError};
+use ethercat_master::slave_status::*;
+use ethercat_master::{CommonError, RECEIVE_TIMEOUT_DEFAULT};
+
+// プロセスデータはメールボックス(0x1000から0x10FF)の後ろに置かれる
+const OUTPUTS_ADDRESS: usize = 0x1100;
+
+const STRESS_SLAVES: usize = 100;
+const STRESS_SLAVES_PER_UNIT: usize = 8;
+const STRESS_CYCLES: u8 = 5;
+
+fn set_mappings(slave: &mut Slave, outputs: &[u8], inputs: &[u8]) {
+    let entries = leak(vec![PDOEntry::new(0x7000, 1, leak(outputs.to_vec()))]);
+    slave.set_rx_pdo_mapping(leak(vec![PDOMapping::new(0x1600, entries)]));
+    let entries = leak(vec![PDOEntry::new(0x6000, 1, leak(inputs.to_vec()))]);
+    slave.set_tx_pdo_mapping(leak(vec![PDOMapping::new(0x1A00, entries)]));
+}
+
+fn request_pre_op(wire: &std::rc::Rc<std::cell::RefCell<Wire>>, num_slaves: usize) {
+    let mut timer = timer();
+    let mut transfer = ALStateTransfer::new(interface(wire), &mut timer);
+    for position in 0..num_slaves {
+        let address = SlaveAddress::StationAddress(station_address(position as u16));
+        transfer
+            .change_al_state(address, AlState::PreOperational)
+            .unwrap();
+    }
+}
+
+#[test]
+fn scan_reads_the_slaves() {
+    let wire = wire(3);
+    let slaves = init(&wire);
+    assert_eq!(slaves.len(), 3);
+    for (position, slave) in slaves.iter().enumerate() {
+        assert_eq!(slave.eeprom_size(), Some(256));
+        let memory = wire.borrow().slaves[position].memory()[0x0010..0x0012].to_vec();
+        assert_eq!(
+            u16::from_le_bytes([memory[0], memory[1]]),
+            station_address(position as u16)
+        );
+    }
+}
+
+#[test]
+fn sii_reads_of_the_slaves_share_frames() {
+    let num_slaves = 20;
+    let wire = wire(num_slaves);
+    let slaves = init(&wire);
+    for (position, slave) in slaves.iter().enumerate() {
+        assert_eq!(slave.eeprom_size(), Some(256));
+        // SIIのメールボックス設定がSM0に書かれている
+        let memory = wire.borrow().slaves[position].memory()[0x0800..0x0804].to_vec();
+        assert_eq!(
+            u16::from_le_bytes([memory[0], memory[1]]),
+            MAILBOX_OUT_ADDRESS
+        );
+        assert_eq!(u16::from_le_bytes([memory[2], memory[3]]), MAILBOX_SIZE);
+    }
+    // 1フレームに並ぶSIIの読み出し要求は16スレーブ分まで
+    let max_requests = wire
+        .borrow()
+        .sent
+        .iter()
+        .map(|frame| {
+            let frame = EtherCATFrame::new(&frame[..]).unwrap();
+            frame
+                .iter_dlpdu()
+                .filter(|pdu| {
+                    CommandType::new(pdu.command_type()) == CommandType::APWR
+                        && pdu.address().ado() == SIIControl::ADDRESS
+                })
+                .count()
+        })
+        .max()
+        .unwrap();
+    assert_eq!(max_requests, 16);
+}
+
+#[test]
+fn transaction_refuses_commands_queued_before_it() {
+    let wire = wire(2);
+    init(&wire);
+    let iface = interface(&wire);
+    iface
+        .add_command(
+            0,
+            CommandType::BRD,
+            0,
+            ALStatus::ADDRESS,
+            ALStatus::SIZE,
+            |_| (),
+        )
+        .unwrap();
+    assert!(matches!(
+        iface.transaction(),
+        Err(CommonError::CommandsPending)
+    ));
+
+    iface.poll(RECEIVE_TIMEOUT_DEFAULT).unwrap();
+    let mut transaction = iface.transaction().unwrap();
+    let entries: Vec<usize> = (0..2)
+        .map(|position| {
+            let address = SlaveAddress::StationAddress(station_address(position));
+            transaction
+                .read(address, ALStatus::ADDRESS, ALStatus::SIZE)
+                .unwrap()
+        })
+        .collect();
+    let results = transaction.execute().unwrap();
+    for entry in entries {
+        let al_status = ALStatus(results.data(entry).unwrap());
+        assert_eq!(AlState::from(al_status.state()), AlState::Init);
+    }
+}
+
+#[test]
+fn process_data_is_exchanged() {
+    let wire = wire(2);
+    let mut slaves = init(&wire);
+    set_mappings(&mut slaves[0], &[0x11, 0x12], &[0]);
+    set_mappings(&mut slaves[1], &[0x21], &[0, 0]);
+    for slave in slaves.iter_mut() {
+        slave.set_input_change_detection(true);
+    }
+    request_pre_op(&wire, slaves.len());
+    let mut timer = timer();
+    let mut initializer = SlaveInitilizer::new(interface(&wire), &mut timer);
+    let image = initializer.configure_process_data(&mut slaves).unwrap();
+    assert_eq!(image.size, 6);
+    assert!(image.use_lrw);
+    {
+        let mut wire = wire.borrow_mut();
+        wire.slaves[0].memory_mut()[OUTPUTS_ADDRESS + 2] = 0xA1;
+        wire.slaves[1].memory_mut()[OUTPUTS_ADDRESS + 1..OUTPUTS_ADDRESS + 3]
+            .copy_from_slice(&[0xB1, 0xB2]);
+    }
+
+    let mut buffer = vec![0; image.size];
+    let unit = ProcessDataUnit::new(&mut slaves, image, &mut buffer).unwrap();
+    let mut units = [CyclicProcessingUnit::ProcessData(unit)];
+    let mut master = EtherCATMaster::new(interface(&wire), &mut units);
+    master.process_and_enqueue().unwrap();
+    assert!(master.poll(RECEIVE_TIMEOUT_DEFAULT).unwrap());
+
+    let sent = wire.borrow().sent.last().cloned().unwrap();
+    assert_eq!(
+        datagram_data(&sent, CommandType::LRW).unwrap(),
+        [0x11, 0x12, 0, 0x21, 0, 0]
+    );
+    let wire = wire.borrow();
+    assert_eq!(
+        wire.slaves[0].memory()[OUTPUTS_ADDRESS..OUTPUTS_ADDRESS + 2],
+        [0x11, 0x12]
+    );
+    assert_eq!(wire.slaves[1].memory()[OUTPUTS_ADDRESS], 0x21);
+    let unit = master.process_data_unit_mut(0).unwrap();
+    assert_eq!(unit.wkc(), 6);
+    for slave in unit.slaves_mut() {
+        assert!(slave.take_inputs_changed());
+    }
+}
+
+#[test]
+fn mailbox_messages_reach_each_slave() {
+    let wire = wire(2);
+    let mut slaves = init(&wire);
+    let mut timer = timer();
+    let mut mailbox = Mailbox::new(interface(&wire), &mut timer);
+    for (value, slave) in slaves.iter_mut().enumerate() {
+        let value = value as u8 + 1;
+        mailbox
+            .write(slave, MailboxType::CoE, 4, |buf| buf.fill(value))
+            .unwrap();
+    }
+    // エミュレータは要求をそのまま応答として返す
+    for (value, slave) in slaves.iter_mut().enumerate() {
+        let response = mailbox.read_response(slave, MailboxType::CoE).unwrap();
+        assert_eq!(response.data()[..4], [value as u8 + 1; 4]);
+    }
+}
+
+#[test]
+fn segmented_upload_is_reassembled_in_the_callers_buffer() {
+    let wire = wire(1);
+    let mut slaves = init(&wire);
+    // メールボックスにもフレームにも収まらない大きさのオブジェクト
+    let object: Vec<u8> = (0..BUFFER_SIZE * 3).map(|i| (i % 251) as u8).collect();
+    wire.borrow_mut().slaves[0].set_sdo_object(0x2000, 1, leak(object.clone()));
+    let mut timer = timer();
+    let mut sdo = SdoClient::new(interface(&wire), &mut timer);
+
+    let mut buf = vec![0; object.len() + 1];
+    let size = sdo.upload(&mut slaves[0], 0x2000, 1, &mut buf).unwrap();
+    assert_eq!(size, object.len());
+    assert_eq!(buf[..size], object[..]);
+}
+
+#[test]
+fn sub_index_0_is_downloaded_once_with_the_size_of_the_data() {
+    let wire = wire(1);
+    let mut slaves = init(&wire);
+    // ほかのオブジェクトへのダウンロードはアボートされる
+    wire.borrow_mut().slaves[0].set_sdo_object(0x2000, 1, &[0]);
+    let mut timer = timer();
+    let mut sdo = SdoClient::new(interface(&wire), &mut timer);
+
+    let result = sdo.download(&mut slaves[0], 0x1C12, 0, &[2, 0]);
+    assert!(matches!(
+        result,
+        Err(SdoError::Abort(AbortCode::DoesNotExistInDict))
+    ));
+    let requests: Vec<Vec<u8>> = wire
+        .borrow()
+        .sent
+        .iter()
+        .filter_map(|frame| datagram_data(frame, CommandType::FPWR))
+        .filter(|data| data.len() == MAILBOX_SIZE as usize)
+        .collect();
+    assert_eq!(requests.len(), 1);
+    // メールボックスヘッダ、CoEヘッダの後ろがSDOのコマンド
+    assert_eq!(requests[0][8], SDOCommand::DownExpReq2 as u8);
+    assert_eq!(requests[0][12..14], [2, 0]);
+}
+
+#[test]
+fn large_network_is_exchanged_by_many_units() {
+    let wire = wire(STRESS_SLAVES);
+    let mut slaves = init(&wire);
+    assert_eq!(slaves.len(), STRESS_SLAVES);
+    for slave in slaves.iter_mut() {
+        set_mappings(slave, &[0], &[0]);
+        slave.set_input_change_detection(true);
+    }
+    request_pre_op(&wire, slaves.len());
+
+    // 8台ずつのグループに分け、グループごとに別のイメージとユニットでやり取りする
+    let num_units = slaves.len().div_ceil(STRESS_SLAVES_PER_UNIT);
+    let mut images = Vec::new();
+    {
+        let num_slaves = slaves.len();
+        let mut network = NetworkDescription::new(&mut slaves, num_slaves);
+        for position in 0..num_slaves {
+            let group = SlaveGroup((position / STRESS_SLAVES_PER_UNIT) as u8);
+            assert!(network.set_group(position as u16, group));
+        }
+        let mut timer = timer();
+        let mut initializer = SlaveInitilizer::new(interface(&wire), &mut timer);
+        let mut logical_address = 0x0001_0000;
+        for group in 0..num_units {
+            let image = initializer
+                .configure_group_process_data(
+                    &mut network,
+                    SlaveGroup(group as u8),
+                    logical_address,
+                )
+                .unwrap();
+            logical_address += image.size as u32;
+            images.push(image);
+        }
+    }
+    assert!(num_units > 10);
+
+    let mut units: Vec<CyclicProcessingUnit> = slaves
+        .chunks_mut(STRESS_SLAVES_PER_UNIT)
+        .zip(images)
+        .map(|(slaves, image)| {
+            let buffer = leak(vec![0; image.size]);
+            CyclicProcessingUnit::ProcessData(ProcessDataUnit::new(slaves, image, buffer).unwrap())
+        })
+        .collect();
+    let mut master = EtherCATMaster::new(interface(&wire), &mut units);
+    for cycle in 1..=STRESS_CYCLES {
+        for (position, slave) in wire.borrow_mut().slaves.iter_mut().enumerate() {
+            slave.memory_mut()[OUTPUTS_ADDRESS + 1] = cycle.wrapping_add(position as u8);
+        }
+        master.process_and_enqueue().unwrap();
+        assert!(master.poll(RECEIVE_TIMEOUT_DEFAULT).unwrap());
+        for index in 0..num_units {
+            let unit = master.process_data_unit_mut(index).unwrap();
+            assert_eq!(unit.wkc(), unit.image().expected_wkc);
+            for slave in unit.slaves_mut() {
+                assert!(slave.take_inputs_changed());
+            }
+        }
+    }
+}
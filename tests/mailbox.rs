@@ -0,0 +1,124 @@
+#![cfg(feature = "emulation")]
+
+mod common;
+
+use common::*;
+use ethercat_master::mailbox::*;
+use ethercat_master::packet::MailboxType;
+use ethercat_master::sdo::{SdoClient, SdoError, SdoRequest};
+
+#[test]
+fn queued_requests_wait_for_the_response() {
+    let wire = wire(1);
+    let mut slaves = init(&wire);
+    let slave = &mut slaves[0];
+    let mut timer = timer();
+    let mut mailbox = Mailbox::new(interface(&wire), &mut timer);
+    let mut queue: MailboxQueue<4, 16> = MailboxQueue::new();
+
+    let request = |value: u8| move |buf: &mut [u8]| buf.fill(value);
+    let written = mailbox
+        .write_or_queue(
+            slave,
+            &mut queue,
+            MailboxType::CoE,
+            MailboxPriority::Lowest,
+            4,
+            request(1),
+        )
+        .unwrap();
+    assert!(written);
+    let written = mailbox
+        .write_or_queue(
+            slave,
+            &mut queue,
+            MailboxType::CoE,
+            MailboxPriority::Lowest,
+            4,
+            request(2),
+        )
+        .unwrap();
+    assert!(!written);
+    assert_eq!(queue.len(), 1);
+    assert!(matches!(
+        mailbox.write(slave, MailboxType::CoE, 4, request(3)),
+        Err(MailboxError::Busy)
+    ));
+    assert!(!mailbox.send_next(slave, &mut queue).unwrap());
+
+    // エミュレータは要求をそのまま応答として返す
+    let response = mailbox.read_response(slave, MailboxType::CoE).unwrap();
+    assert_eq!(response.data()[..4], [1; 4]);
+    assert!(!slave.mailbox_request_pending());
+
+    assert!(mailbox.send_next(slave, &mut queue).unwrap());
+    assert!(queue.is_empty());
+    assert!(slave.mailbox_request_pending());
+    let response = mailbox.read_response(slave, MailboxType::CoE).unwrap();
+    assert_eq!(response.data()[..4], [2; 4]);
+}
+
+#[test]
+fn timed_out_request_stays_pending() {
+    let wire = wire(1);
+    let mut slaves = init(&wire);
+    wire.borrow_mut().slaves[0].set_mailbox_echo(false);
+    let slave = &mut slaves[0];
+    let mut timer = timer();
+    let mut mailbox = Mailbox::new(interface(&wire), &mut timer);
+
+    mailbox
+        .write(slave, MailboxType::CoE, 4, |buf| buf.fill(1))
+        .unwrap();
+    assert!(matches!(
+        mailbox.read_response(slave, MailboxType::CoE),
+        Err(MailboxError::Timeout(_))
+    ));
+    // 応答が遅れて届くかもしれないので、次の要求は書かない
+    assert!(slave.mailbox_request_pending());
+    mailbox.end_request(slave);
+    assert!(!slave.mailbox_request_pending());
+}
+
+#[test]
+fn timed_out_transfer_many_keeps_the_request_pending() {
+    let wire = wire(2);
+    let mut slaves = init(&wire);
+    wire.borrow_mut().slaves[0].set_sdo_object(0x1018, 1, &[0x78, 0x56, 0x34, 0x12]);
+    wire.borrow_mut().slaves[1].set_mailbox_echo(false);
+    let mut timer = timer();
+    let mut sdo = SdoClient::new(interface(&wire), &mut timer);
+    let request = Some(SdoRequest::Upload {
+        index: 0x1018,
+        sub_index: 1,
+    });
+    let requests = [request, request];
+    let mut results = [Ok(0), Ok(0)];
+
+    sdo.transfer_many(&mut slaves, &requests, &mut results)
+        .unwrap();
+    assert_eq!(results[0].as_ref().unwrap(), &0x1234_5678);
+    assert!(!slaves[0].mailbox_request_pending());
+    // 応答が届かなかった要求は応答待ちのまま残り、次の要求は書かない
+    assert!(matches!(
+        results[1],
+        Err(SdoError::Mailbox(MailboxError::Timeout(_)))
+    ));
+    assert!(slaves[1].mailbox_request_pending());
+    let sent = wire.borrow().sent.len();
+    sdo.transfer_many(&mut slaves[1..], &requests[1..], &mut results[1..])
+        .unwrap();
+    assert!(matches!(
+        results[1],
+        Err(SdoError::Mailbox(MailboxError::Busy))
+    ));
+    assert_eq!(wire.borrow().sent.len(), sent);
+
+    // 諦めると次の要求を書ける
+    sdo.mailbox().end_request(&mut slaves[1]);
+    wire.borrow_mut().slaves[1].set_sdo_object(0x1018, 1, &[1]);
+    sdo.transfer_many(&mut slaves[1..], &requests[1..], &mut results[1..])
+        .unwrap();
+    assert_eq!(results[1].as_ref().unwrap(), &1);
+    assert!(!slaves[1].mailbox_request_pending());
+}
@@ -0,0 +1,364 @@
+#![cfg(feature = "emulation")]
+
+mod common;
+
+use common::*;
+use ethercat_master::al_state_transfer::AlTransitionUnit;
+use ethercat_master::ethercat_frame::EtherCATFrame;
+use ethercat_master::foe::{FoEUnit, FOE_NO_PASSWORD};
+use ethercat_master::initializer::ProcessImage;
+use ethercat_master::master::*;
+use ethercat_master::packet::{CommandType, DatagramAddress};
+use ethercat_master::process_data::ProcessDataUnit;
+use ethercat_master::sdo::SdoUnit;
+use ethercat_master::slave_status::*;
+use ethercat_master::{CommonError, RECEIVE_TIMEOUT_DEFAULT};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Sync manager 1 status register of the test SII and its mailbox full bit.
+const SM1_STATUS_ADDRESS: usize = 0x080D;
+const SM_MAILBOX_FULL: u8 = 0x08;
+
+/// Exchanges one frame and returns it as sent.
+fn cycle(
+    master: &mut EtherCATMaster<EmulatedDevice, PollTimer>,
+    wire: &Rc<RefCell<Wire>>,
+) -> Vec<u8> {
+    master.process_and_enqueue().unwrap();
+    let _ = master.poll(RECEIVE_TIMEOUT_DEFAULT);
+    wire.borrow().sent.last().cloned().unwrap()
+}
+
+/// Places a CoE message in the read mailbox of the slave, as if the slave had replied.
+fn put_coe_response(wire: &Rc<RefCell<Wire>>, sdo: &[u8]) {
+    let mut wire = wire.borrow_mut();
+    let memory = wire.slaves[0].memory_mut();
+    let start = MAILBOX_IN_ADDRESS as usize;
+    let length = (2 + sdo.len()) as u16;
+    memory[start..start + 2].copy_from_slice(&length.to_le_bytes());
+    memory[start + 2..start + 6].copy_from_slice(&[0, 0, 0, 0x13]);
+    // SDO応答のサービス
+    memory[start + 6..start + 8].copy_from_slice(&[0x00, 0x30]);
+    memory[start + 8..start + 8 + sdo.len()].copy_from_slice(sdo);
+    memory[SM1_STATUS_ADDRESS] |= SM_MAILBOX_FULL;
+}
+
+fn pdo_slave<'a>(outputs: &[u8], inputs: &[u8]) -> Slave<'a> {
+    let mut slave = Slave::default();
+    let entries = leak(vec![PDOEntry::new(0x7000, 1, leak(outputs.to_vec()))]);
+    slave.set_rx_pdo_mapping(leak(vec![PDOMapping::new(0x1600, entries)]));
+    let entries = leak(vec![PDOEntry::new(0x6000, 1, leak(inputs.to_vec()))]);
+    slave.set_tx_pdo_mapping(leak(vec![PDOMapping::new(0x1A00, entries)]));
+    slave
+}
+
+#[test]
+fn fail_safe_outputs_are_sent() {
+    let wire = wire(1);
+    let iface = interface(&wire);
+    // マッピングはスタック上に置いてもよい
+    let mut outputs = [1, 2];
+    let mut inputs = [0];
+    let mut rx_entries = [PDOEntry::new(0x7000, 1, &mut outputs)];
+    let mut tx_entries = [PDOEntry::new(0x6000, 1, &mut inputs)];
+    let mut rx_mapping = [PDOMapping::new(0x1600, &mut rx_entries)];
+    let mut tx_mapping = [PDOMapping::new(0x1A00, &mut tx_entries)];
+    let fail_safe_outputs = [0xF1, 0xF2];
+    let mut slaves = [Slave::default()];
+    slaves[0].set_rx_pdo_mapping(&mut rx_mapping);
+    slaves[0].set_tx_pdo_mapping(&mut tx_mapping);
+    slaves[0].set_fail_safe_outputs(&fail_safe_outputs);
+    let image = ProcessImage {
+        logical_start_address: 0x0001_0000,
+        size: 3,
+        expected_wkc: 3,
+        use_lrw: true,
+    };
+    let mut buffer = [0; 3];
+    let unit = ProcessDataUnit::new(&mut slaves, image, &mut buffer).unwrap();
+    let mut units = [CyclicProcessingUnit::ProcessData(unit)];
+    let mut master = EtherCATMaster::new(iface, &mut units);
+
+    master.process_and_enqueue().unwrap();
+    let _ = master.poll(RECEIVE_TIMEOUT_DEFAULT);
+    let sent = wire.borrow().sent.last().cloned().unwrap();
+    assert_eq!(datagram_data(&sent, CommandType::LRW).unwrap(), [1, 2, 0]);
+
+    master.stop();
+    master.process_and_enqueue().unwrap();
+    let _ = master.poll(RECEIVE_TIMEOUT_DEFAULT);
+    let sent = wire.borrow().sent.last().cloned().unwrap();
+    assert_eq!(
+        datagram_data(&sent, CommandType::LRW).unwrap(),
+        [0xF1, 0xF2, 0]
+    );
+}
+
+#[test]
+fn image_without_lrw_alternates_lwr_and_lrd() {
+    let wire = wire(1);
+    let iface = interface(&wire);
+    let mut slaves = [pdo_slave(&[1, 2], &[0])];
+    let image = ProcessImage {
+        logical_start_address: 0x0001_0000,
+        size: 3,
+        expected_wkc: 3,
+        use_lrw: false,
+    };
+    let mut buffer = [0; 3];
+    let unit = ProcessDataUnit::new(&mut slaves, image, &mut buffer).unwrap();
+    let mut units = [CyclicProcessingUnit::ProcessData(unit)];
+    let mut master = EtherCATMaster::new(iface, &mut units);
+
+    master.process_and_enqueue().unwrap();
+    let _ = master.poll(RECEIVE_TIMEOUT_DEFAULT);
+    let sent = wire.borrow().sent.last().cloned().unwrap();
+    assert_eq!(datagram_data(&sent, CommandType::LWR).unwrap(), [1, 2, 0]);
+    assert!(datagram_data(&sent, CommandType::LRW).is_none());
+
+    master.process_and_enqueue().unwrap();
+    let _ = master.poll(RECEIVE_TIMEOUT_DEFAULT);
+    let sent = wire.borrow().sent.last().cloned().unwrap();
+    assert!(datagram_data(&sent, CommandType::LRD).is_some());
+    assert!(datagram_data(&sent, CommandType::LWR).is_none());
+}
+
+/// Logical addresses of the LRW datagrams in `frame`.
+fn lrw_addresses(frame: &[u8]) -> Vec<u32> {
+    let frame = EtherCATFrame::new(frame).unwrap();
+    let addresses = frame
+        .iter_dlpdu()
+        .filter_map(|pdu| match pdu.address() {
+            DatagramAddress::Logical(address) => Some(address),
+            DatagramAddress::Physical { .. } => None,
+        })
+        .collect();
+    addresses
+}
+
+fn image_unit<'a>(logical_start_address: u32, size: usize) -> CyclicProcessingUnit<'a> {
+    let image = ProcessImage {
+        logical_start_address,
+        size,
+        expected_wkc: 0,
+        use_lrw: true,
+    };
+    let unit = ProcessDataUnit::new(leak(Vec::new()), image, leak(vec![0; size])).unwrap();
+    CyclicProcessingUnit::ProcessData(unit)
+}
+
+#[test]
+fn large_unit_does_not_hold_back_smaller_ones() {
+    let wire = wire(1);
+    let iface = interface(&wire);
+    // 2つ目のユニットは1つ目と同じフレームに入らない
+    let mut units = [
+        image_unit(0x0001_0000, 800),
+        image_unit(0x0002_0000, 800),
+        image_unit(0x0003_0000, 100),
+        image_unit(0x0004_0000, 100),
+    ];
+    let mut master = EtherCATMaster::new(iface, &mut units);
+
+    assert!(!master.process_and_enqueue().unwrap());
+    assert_eq!(master.backlog(), 1);
+    let _ = master.poll(RECEIVE_TIMEOUT_DEFAULT);
+    let sent = wire.borrow().sent.last().cloned().unwrap();
+    assert_eq!(
+        lrw_addresses(&sent),
+        [0x0001_0000, 0x0003_0000, 0x0004_0000]
+    );
+
+    // 入りきらなかったユニットが先に送られる
+    assert!(!master.process_and_enqueue().unwrap());
+    assert_eq!(master.backlog(), 1);
+    let _ = master.poll(RECEIVE_TIMEOUT_DEFAULT);
+    let sent = wire.borrow().sent.last().cloned().unwrap();
+    assert_eq!(
+        lrw_addresses(&sent),
+        [0x0002_0000, 0x0003_0000, 0x0004_0000]
+    );
+}
+
+#[test]
+fn copies_cover_a_lost_frame() {
+    let wire = wire(1);
+    let iface = interface(&wire);
+    let mut first = |_: Command, _: &[u8], _: u16| ();
+    let mut second = |_: Command, _: &[u8], _: u16| ();
+    let mut units = [
+        CyclicProcessingUnit::Custom(CustomCommandUnit::new()),
+        CyclicProcessingUnit::Custom(CustomCommandUnit::new()),
+    ];
+    let mut master = EtherCATMaster::new(iface, &mut units);
+    master.set_duplicated(0, true);
+    master.set_duplicated(1, true);
+    let command = Command::new_brd(0);
+    master.send_custom(command, &[0; 2], &mut first).unwrap();
+    master.send_custom(command, &[0; 2], &mut second).unwrap();
+
+    // 元のフレームが失われても、複製のフレームで全ユニットの応答が揃う
+    master.process_and_enqueue().unwrap();
+    wire.borrow_mut().drop_frames = 1;
+    assert!(master.poll(RECEIVE_TIMEOUT_DEFAULT).unwrap());
+    assert_eq!(master.statistics().recovered_frames, 1);
+    assert!(!master.custom_unit(0).unwrap().is_busy());
+    assert!(!master.custom_unit(1).unwrap().is_busy());
+    assert_eq!(wire.borrow().sent.len(), 2);
+}
+
+#[test]
+fn lost_frame_without_copies_times_out() {
+    let wire = wire(1);
+    let iface = interface(&wire);
+    let mut first = |_: Command, _: &[u8], _: u16| ();
+    let mut second = |_: Command, _: &[u8], _: u16| ();
+    let mut units = [
+        CyclicProcessingUnit::Custom(CustomCommandUnit::new()),
+        CyclicProcessingUnit::Custom(CustomCommandUnit::new()),
+    ];
+    let mut master = EtherCATMaster::new(iface, &mut units);
+    master.set_duplicated(0, true);
+    let command = Command::new_brd(0);
+    master.send_custom(command, &[0; 2], &mut first).unwrap();
+    master.send_custom(command, &[0; 2], &mut second).unwrap();
+
+    master.process_and_enqueue().unwrap();
+    wire.borrow_mut().drop_frames = 1;
+    assert!(matches!(
+        master.poll(RECEIVE_TIMEOUT_DEFAULT),
+        Err(CommonError::ReceiveTimeout)
+    ));
+    assert_eq!(master.statistics().recovered_frames, 0);
+    assert_eq!(master.statistics().receive_timeouts, 1);
+}
+
+#[test]
+fn custom_handler_keeps_the_response() {
+    let wire = wire(1);
+    let iface = interface(&wire);
+    let mut response = None;
+    let mut handler = |_: Command, data: &[u8], wkc: u16| response = Some((data.len(), wkc));
+    let mut ignore = |_: Command, _: &[u8], _: u16| ();
+    let mut units: [CyclicProcessingUnit<128>; 1] =
+        [CyclicProcessingUnit::Custom(CustomCommandUnit::new())];
+    let mut master = EtherCATMaster::with_custom_length(iface, &mut units);
+    let command = Command::new_brd(0);
+
+    // 既定の長さを超えるデータも送れる
+    assert!(master.send_custom(command, &[0; 129], &mut ignore).is_err());
+    master
+        .send_custom(command, &[0; 100], &mut handler)
+        .unwrap();
+    master.process_and_enqueue().unwrap();
+    assert!(master.poll(RECEIVE_TIMEOUT_DEFAULT).unwrap());
+    drop(master);
+    assert_eq!(response, Some((100, 1)));
+}
+
+#[test]
+fn cancelled_sdo_transfer_is_aborted() {
+    let wire = wire(1);
+    let slaves = init(&wire);
+    wire.borrow_mut().slaves[0].set_mailbox_echo(false);
+    let mut buffer = [0; MAILBOX_SIZE as usize];
+    let unit = SdoUnit::new(&slaves[0], &mut buffer).unwrap();
+    let mut units = [CyclicProcessingUnit::Sdo(unit)];
+    let mut master = EtherCATMaster::new(interface(&wire), &mut units);
+
+    master
+        .sdo_unit_mut(0)
+        .unwrap()
+        .start_upload(0x1018, 1)
+        .unwrap();
+    let sent = cycle(&mut master, &wire);
+    assert!(datagram_data(&sent, CommandType::FPWR).is_some());
+    cycle(&mut master, &wire);
+    assert!(master.sdo_unit_mut(0).unwrap().result().is_none());
+
+    // 中断すると、アボート要求を書いてからアイドルに戻る
+    master.sdo_unit_mut(0).unwrap().cancel();
+    assert!(!master.sdo_unit_mut(0).unwrap().is_idle());
+    let sent = cycle(&mut master, &wire);
+    let message = datagram_data(&sent, CommandType::FPWR).unwrap();
+    assert_eq!(message[8..12], [0x80, 0x18, 0x10, 1]);
+    assert_eq!(message[12..16], 0x0800_0000u32.to_le_bytes());
+    let unit = master.sdo_unit_mut(0).unwrap();
+    assert!(unit.is_idle());
+    assert!(unit.result().is_none());
+
+    // 中断した要求への応答は読み捨て、次の要求の応答を返す
+    unit.start_upload(0x1018, 2).unwrap();
+    cycle(&mut master, &wire);
+    put_coe_response(&wire, &[0x4F, 0x18, 0x10, 1, 0xAA, 0, 0, 0]);
+    cycle(&mut master, &wire);
+    cycle(&mut master, &wire);
+    assert!(master.sdo_unit_mut(0).unwrap().result().is_none());
+    put_coe_response(&wire, &[0x4B, 0x18, 0x10, 2, 0x34, 0x12, 0, 0]);
+    cycle(&mut master, &wire);
+    cycle(&mut master, &wire);
+    let unit = master.sdo_unit_mut(0).unwrap();
+    assert_eq!(unit.result().unwrap().unwrap(), [0x34, 0x12]);
+    assert!(unit.is_idle());
+}
+
+#[test]
+fn cancelled_foe_write_sends_an_error_request() {
+    let wire = wire(1);
+    let slaves = init(&wire);
+    wire.borrow_mut().slaves[0].set_mailbox_echo(false);
+    let mut buffer = [0; MAILBOX_SIZE as usize];
+    let unit = FoEUnit::new(&slaves[0], &mut buffer).unwrap();
+    let mut units = [CyclicProcessingUnit::FoE(unit)];
+    let mut master = EtherCATMaster::new(interface(&wire), &mut units);
+
+    let data = [0; 300];
+    master
+        .foe_unit_mut(0)
+        .unwrap()
+        .start_write("firmware", FOE_NO_PASSWORD, &data)
+        .unwrap();
+    cycle(&mut master, &wire);
+    master.foe_unit_mut(0).unwrap().cancel();
+    let sent = cycle(&mut master, &wire);
+    let message = datagram_data(&sent, CommandType::FPWR).unwrap();
+    // FoEのエラー要求
+    assert_eq!(message[5] & 0x0F, 0x04);
+    assert_eq!(message[6], 0x05);
+    let unit = master.foe_unit_mut(0).unwrap();
+    assert!(unit.is_idle());
+    assert!(unit.result().is_none());
+}
+
+#[test]
+fn cancelled_al_transition_requests_the_previous_state() {
+    let wire = wire(1);
+    let slaves = init(&wire);
+    let unit = AlTransitionUnit::new(&slaves[0]);
+    let mut units = [CyclicProcessingUnit::AlTransition(unit)];
+    let mut master = EtherCATMaster::new(interface(&wire), &mut units);
+
+    master
+        .al_transition_unit_mut(0)
+        .unwrap()
+        .start(AlState::PreOperational)
+        .unwrap();
+    cycle(&mut master, &wire);
+    let sent = cycle(&mut master, &wire);
+    assert_eq!(datagram_data(&sent, CommandType::FPWR).unwrap(), [0x02, 0]);
+    cycle(&mut master, &wire);
+    let unit = master.al_transition_unit_mut(0).unwrap();
+    assert!(unit.result().unwrap().is_ok());
+
+    unit.start(AlState::SafeOperational).unwrap();
+    cycle(&mut master, &wire);
+    cycle(&mut master, &wire);
+    master.al_transition_unit_mut(0).unwrap().cancel();
+    let sent = cycle(&mut master, &wire);
+    assert_eq!(datagram_data(&sent, CommandType::FPWR).unwrap(), [0x02, 0]);
+    let unit = master.al_transition_unit_mut(0).unwrap();
+    assert!(unit.is_idle());
+    assert!(unit.result().is_none());
+    assert_eq!(wire.borrow().slaves[0].memory()[0x0130], 0x02);
+}